@@ -0,0 +1,44 @@
+-- Gas sponsorship policies shared by every relay instance
+CREATE TABLE gas_policies (
+    id          TEXT PRIMARY KEY,
+    name        TEXT NOT NULL,
+    policy_type TEXT NOT NULL,
+    target      TEXT,
+    enabled     BOOLEAN NOT NULL DEFAULT TRUE,
+    updated_at  TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE rate_limits (
+    policy_id      TEXT NOT NULL REFERENCES gas_policies (id) ON DELETE CASCADE,
+    position       INTEGER NOT NULL,
+    limit_type     TEXT NOT NULL,
+    limit_value    TEXT NOT NULL,
+    window_seconds BIGINT NOT NULL,
+    algorithm      TEXT NOT NULL DEFAULT 'FixedWindow',
+    burst          TEXT,
+    PRIMARY KEY (policy_id, position)
+);
+
+-- Relays LISTEN on gas_policy_changed and reload the policy whose id is the payload.
+-- TG_ARGV[0] names the column holding that id.
+CREATE FUNCTION notify_gas_policy_changed() RETURNS TRIGGER AS $$
+DECLARE
+    changed RECORD;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        changed := OLD;
+    ELSE
+        changed := NEW;
+    END IF;
+    PERFORM pg_notify('gas_policy_changed', to_jsonb(changed) ->> TG_ARGV[0]);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER gas_policies_changed
+    AFTER INSERT OR UPDATE OR DELETE ON gas_policies
+    FOR EACH ROW EXECUTE FUNCTION notify_gas_policy_changed('id');
+
+CREATE TRIGGER rate_limits_changed
+    AFTER INSERT OR UPDATE OR DELETE ON rate_limits
+    FOR EACH ROW EXECUTE FUNCTION notify_gas_policy_changed('policy_id');
@@ -0,0 +1,16 @@
+-- Per-contract function and argument constraints of a gas policy
+CREATE TABLE call_rules (
+    policy_id           TEXT NOT NULL REFERENCES gas_policies (id) ON DELETE CASCADE,
+    position            INTEGER NOT NULL,
+    target              TEXT NOT NULL,
+    allowed_selectors   TEXT[] NOT NULL DEFAULT '{}',
+    denied_selectors    TEXT[] NOT NULL DEFAULT '{}',
+    max_value           TEXT,
+    allowed_recipients  TEXT[] NOT NULL DEFAULT '{}',
+    max_transfer_amount TEXT,
+    PRIMARY KEY (policy_id, position)
+);
+
+CREATE TRIGGER call_rules_changed
+    AFTER INSERT OR UPDATE OR DELETE ON call_rules
+    FOR EACH ROW EXECUTE FUNCTION notify_gas_policy_changed('policy_id');
@@ -0,0 +1,14 @@
+-- The webhook a custom gas policy asks for decisions
+CREATE TABLE policy_webhooks (
+    policy_id     TEXT PRIMARY KEY REFERENCES gas_policies (id) ON DELETE CASCADE,
+    url           TEXT NOT NULL,
+    secret        TEXT,
+    timeout_ms    BIGINT NOT NULL,
+    retries       INTEGER NOT NULL,
+    cache_seconds BIGINT NOT NULL DEFAULT 0,
+    fail_open     BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TRIGGER policy_webhooks_changed
+    AFTER INSERT OR UPDATE OR DELETE ON policy_webhooks
+    FOR EACH ROW EXECUTE FUNCTION notify_gas_policy_changed('policy_id');
@@ -0,0 +1,8 @@
+-- Evaluation order, allow/deny effects and inheritance between gas policies
+ALTER TABLE gas_policies
+    ADD COLUMN priority           INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN effect             TEXT NOT NULL DEFAULT 'Limit',
+    -- No foreign key: the parent may come from a policy file rather than this table
+    ADD COLUMN inherits           TEXT,
+    ADD COLUMN amount_multiplier  DOUBLE PRECISION,
+    ADD COLUMN request_multiplier DOUBLE PRECISION;
@@ -0,0 +1,6 @@
+-- Activation windows and recurring hours of gas policies
+ALTER TABLE gas_policies
+    ADD COLUMN starts_at          BIGINT,
+    ADD COLUMN ends_at            BIGINT,
+    ADD COLUMN recurring          TEXT[] NOT NULL DEFAULT '{}',
+    ADD COLUMN utc_offset_minutes INTEGER NOT NULL DEFAULT 0;
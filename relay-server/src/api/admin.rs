@@ -0,0 +1,469 @@
+use crate::api::ApiState;
+use crate::core::circuit_breaker::CircuitBreakerStatus;
+use crate::core::policy_engine::{PolicyDecision, PolicyEvaluation, PolicySource, PolicyStatus};
+use crate::core::types::*;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+
+/// Policy administration over REST, behind `Authorization: Bearer <admin.api_key>`
+///
+/// Changes are written to Postgres when the relay has a policy repository, so every
+/// instance picks them up; otherwise they only live in this process. Policies loaded
+/// from YAML files are read-only here.
+pub fn router(state: ApiState) -> Router<ApiState> {
+    Router::new()
+        .route("/policies", get(list_policies).post(create_policy))
+        .route("/policies/:id", get(get_policy).put(update_policy).delete(delete_policy))
+        .route("/policies/:id/enable", post(enable_policy))
+        .route("/policies/:id/disable", post(disable_policy))
+        .route("/policies/:id/status", get(policy_status))
+        .route("/policies/:id/test", post(test_policy))
+        .route("/evaluate", post(evaluate_policies))
+        .route("/circuit-breaker", get(circuit_breaker_status))
+        .route("/circuit-breaker/:scope/reset", post(reset_circuit_breaker))
+        .route_layer(middleware::from_fn_with_state(state, require_api_key))
+}
+
+/// A policy and where it was loaded from
+#[derive(Debug, Serialize)]
+pub struct PolicyEntry {
+    #[serde(flatten)]
+    pub policy: GasPolicy,
+    pub source: PolicySource,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TestPolicyRequest {
+    pub request: SponsorRequest,
+}
+
+/// Error response: `{"error": message}` with a matching status code
+#[derive(Debug)]
+pub struct AdminError {
+    status: StatusCode,
+    message: String,
+}
+
+impl AdminError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self { status, message: message.into() }
+    }
+
+    fn not_found(policy_id: &str) -> Self {
+        Self::new(StatusCode::NOT_FOUND, format!("Policy {} not found", policy_id))
+    }
+}
+
+impl From<PaymasterError> for AdminError {
+    fn from(error: PaymasterError) -> Self {
+        let status = match &error {
+            PaymasterError::DatabaseError(_) | PaymasterError::BlockchainError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        Self::new(status, error.to_string())
+    }
+}
+
+impl From<JsonRejection> for AdminError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, rejection.body_text())
+    }
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        if self.status.is_server_error() {
+            warn!("Admin request failed: {}", self.message);
+        }
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+async fn require_api_key(State(state): State<ApiState>, request: Request, next: Next) -> Response {
+    let token = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match (&state.admin_api_key, token) {
+        (Some(api_key), Some(token)) if constant_time_eq(api_key.as_bytes(), token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => AdminError::new(StatusCode::UNAUTHORIZED, "Missing or invalid admin API key").into_response(),
+    }
+}
+
+/// Compare without exiting early, so response timing does not reveal matching prefixes
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn list_policies(State(state): State<ApiState>) -> Json<Vec<PolicyEntry>> {
+    let policies = state.policy_engine.list_policies().await;
+    Json(policies.into_iter().map(|(policy, source)| PolicyEntry { policy, source }).collect())
+}
+
+async fn get_policy(State(state): State<ApiState>, Path(policy_id): Path<String>) -> Result<Json<PolicyEntry>, AdminError> {
+    let source = state.policy_engine.policy_source(&policy_id).await;
+    match (state.policy_engine.get_policy(&policy_id).await, source) {
+        (Some(policy), Some(source)) => Ok(Json(PolicyEntry { policy, source })),
+        _ => Err(AdminError::not_found(&policy_id)),
+    }
+}
+
+async fn create_policy(
+    State(state): State<ApiState>,
+    body: Result<Json<GasPolicy>, JsonRejection>,
+) -> Result<(StatusCode, Json<GasPolicy>), AdminError> {
+    let Json(policy) = body?;
+    if state.policy_engine.policy_source(&policy.id).await.is_some() {
+        return Err(AdminError::new(StatusCode::CONFLICT, format!("Policy {} already exists", policy.id)));
+    }
+
+    store_policy(&state, &policy).await?;
+    Ok((StatusCode::CREATED, Json(policy)))
+}
+
+async fn update_policy(
+    State(state): State<ApiState>,
+    Path(policy_id): Path<String>,
+    body: Result<Json<GasPolicy>, JsonRejection>,
+) -> Result<Json<GasPolicy>, AdminError> {
+    let Json(mut policy) = body?;
+    policy.id = policy_id;
+    writable_source(&state, &policy.id).await?;
+
+    // Webhook secrets are never read back, so an update that omits one keeps the current secret
+    let current = state.policy_engine.get_policy(&policy.id).await.and_then(|current| current.webhook);
+    if let (Some(webhook), Some(current)) = (policy.webhook.as_mut(), current) {
+        if webhook.secret.is_none() {
+            webhook.secret = current.secret;
+        }
+    }
+
+    store_policy(&state, &policy).await?;
+    Ok(Json(policy))
+}
+
+async fn delete_policy(State(state): State<ApiState>, Path(policy_id): Path<String>) -> Result<StatusCode, AdminError> {
+    let source = writable_source(&state, &policy_id).await?;
+    let children = state.policy_engine.inheriting_policies(&policy_id).await;
+    if !children.is_empty() {
+        return Err(AdminError::new(
+            StatusCode::CONFLICT,
+            format!("Policy {} is inherited by {}", policy_id, children.join(", ")),
+        ));
+    }
+
+    if let (PolicySource::Database, Some(repository)) = (source, &state.policy_repository) {
+        repository.delete(&policy_id).await?;
+    }
+    state.policy_engine.unload_policy(source, &policy_id).await;
+
+    info!("Admin deleted policy {}", policy_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn enable_policy(State(state): State<ApiState>, Path(policy_id): Path<String>) -> Result<Json<GasPolicy>, AdminError> {
+    set_enabled(&state, &policy_id, true).await
+}
+
+async fn disable_policy(State(state): State<ApiState>, Path(policy_id): Path<String>) -> Result<Json<GasPolicy>, AdminError> {
+    set_enabled(&state, &policy_id, false).await
+}
+
+async fn policy_status(State(state): State<ApiState>, Path(policy_id): Path<String>) -> Result<Json<PolicyStatus>, AdminError> {
+    if state.policy_engine.policy_source(&policy_id).await.is_none() {
+        return Err(AdminError::not_found(&policy_id));
+    }
+
+    Ok(Json(state.policy_engine.get_policy_status(&policy_id).await?))
+}
+
+/// Dry run: report whether the policy would admit the request, without taking any budget
+async fn test_policy(
+    State(state): State<ApiState>,
+    Path(policy_id): Path<String>,
+    body: Result<Json<TestPolicyRequest>, JsonRejection>,
+) -> Result<Json<PolicyDecision>, AdminError> {
+    let Json(test) = body?;
+    if state.policy_engine.policy_source(&policy_id).await.is_none() {
+        return Err(AdminError::not_found(&policy_id));
+    }
+
+    Ok(Json(state.policy_engine.test_policy(&policy_id, &test.request).await?))
+}
+
+/// Dry run of every enabled policy in evaluation order, with the trace of what each decided
+async fn evaluate_policies(
+    State(state): State<ApiState>,
+    body: Result<Json<TestPolicyRequest>, JsonRejection>,
+) -> Result<Json<PolicyEvaluation>, AdminError> {
+    let Json(test) = body?;
+    Ok(Json(state.policy_engine.evaluate_policies(&test.request).await?))
+}
+
+/// Spend and state of every circuit breaker scope with a budget: `global`, then `chain:<id>` per chain
+async fn circuit_breaker_status(State(state): State<ApiState>) -> Result<Json<Vec<CircuitBreakerStatus>>, AdminError> {
+    Ok(Json(state.policy_engine.circuit_breaker().status().await?))
+}
+
+/// Close a tripped breaker and clear its spend in the current windows, on every relay instance
+async fn reset_circuit_breaker(
+    State(state): State<ApiState>,
+    Path(scope): Path<String>,
+) -> Result<Json<CircuitBreakerStatus>, AdminError> {
+    let breaker = state.policy_engine.circuit_breaker();
+    if !breaker.reset(&scope).await? {
+        return Err(AdminError::new(StatusCode::NOT_FOUND, format!("Circuit breaker scope {} not found", scope)));
+    }
+
+    info!("Admin reset circuit breaker {}", scope);
+    let status = breaker.status().await?.into_iter().find(|status| status.scope == scope);
+    status.map(Json).ok_or_else(|| AdminError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Circuit breaker {} was reset but reports no status", scope),
+    ))
+}
+
+async fn set_enabled(state: &ApiState, policy_id: &str, enabled: bool) -> Result<Json<GasPolicy>, AdminError> {
+    writable_source(state, policy_id).await?;
+    let mut policy = state.policy_engine.get_policy(policy_id).await
+        .ok_or_else(|| AdminError::not_found(policy_id))?;
+
+    policy.enabled = enabled;
+    store_policy(state, &policy).await?;
+    Ok(Json(policy))
+}
+
+/// The source of an existing policy, refusing policies owned by YAML files
+async fn writable_source(state: &ApiState, policy_id: &str) -> Result<PolicySource, AdminError> {
+    match state.policy_engine.policy_source(policy_id).await {
+        None => Err(AdminError::not_found(policy_id)),
+        Some(PolicySource::File) => Err(AdminError::new(
+            StatusCode::CONFLICT,
+            format!("Policy {} is defined in a policy file; edit the file instead", policy_id),
+        )),
+        Some(source) => Ok(source),
+    }
+}
+
+/// Validate, persist when a repository is configured, and apply to this instance right away
+async fn store_policy(state: &ApiState, policy: &GasPolicy) -> Result<(), AdminError> {
+    policy.validate()
+        .map_err(|e| AdminError::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    // Policies added in code stay in this process
+    let existing = state.policy_engine.policy_source(&policy.id).await;
+    match (&state.policy_repository, existing) {
+        (Some(repository), None | Some(PolicySource::Database)) => {
+            repository.upsert(policy).await?;
+            state.policy_engine.load_policy(PolicySource::Database, policy.clone()).await?;
+        }
+        _ => state.policy_engine.load_policy(PolicySource::Runtime, policy.clone()).await?,
+    }
+
+    info!("Admin stored policy {}", policy.id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::router as api_router;
+    use axum::body::{to_bytes, Body};
+    use crate::test_support::*;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    const API_KEY: &str = "test-admin-key-0123456789abcdef0123";
+
+    async fn state() -> ApiState {
+        let mut settings = settings();
+        settings.admin.api_key = API_KEY.to_string();
+        api_state(settings).await
+    }
+
+    async fn send(state: &ApiState, method: &str, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+        let request = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", API_KEY))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+            .unwrap();
+
+        let response = api_router(state.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn wallet_policy_body() -> Value {
+        json!(wallet_policy(RateLimitType::Request, "1"))
+    }
+
+    fn sample_request() -> Value {
+        json!({ "request": request("0x186a0") })
+    }
+
+    #[tokio::test]
+    async fn evaluates_every_policy_with_a_trace() {
+        let state = state().await;
+        send(&state, "POST", "/admin/policies", Some(wallet_policy_body())).await;
+
+        let (status, body) = send(&state, "POST", "/admin/evaluate", Some(sample_request())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["allowed"], true);
+        assert_eq!(body["trace"][1]["policyId"], "wallet_budget");
+        assert_eq!(body["trace"][1]["outcome"], "Passed");
+    }
+
+    #[tokio::test]
+    async fn requires_the_api_key() {
+        let state = state().await;
+        let request = axum::http::Request::get("/admin/policies")
+            .header(header::AUTHORIZATION, "Bearer wrong")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = api_router(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn manages_policies() {
+        let state = state().await;
+
+        let (status, _) = send(&state, "POST", "/admin/policies", Some(wallet_policy_body())).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = send(&state, "POST", "/admin/policies", Some(wallet_policy_body())).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, policies) = send(&state, "GET", "/admin/policies", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(policies[0]["id"], "wallet_budget");
+        assert_eq!(policies[0]["source"], "Runtime");
+
+        let (_, policy) = send(&state, "POST", "/admin/policies/wallet_budget/disable", None).await;
+        assert_eq!(policy["enabled"], false);
+        assert!(!state.policy_engine.get_policy("wallet_budget").await.unwrap().enabled);
+
+        let mut invalid = wallet_policy_body();
+        invalid["rate_limits"][0]["window"] = json!(0);
+        let (status, error) = send(&state, "PUT", "/admin/policies/wallet_budget", Some(invalid)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error["error"].as_str().unwrap().contains("rate_limits[0].window"));
+
+        // Inheriting an unknown policy is refused, and a parent cannot be deleted under its child
+        let mut child = wallet_policy_body();
+        child["id"] = json!("wallet_vip");
+        child["inherits"] = json!("wallet_missing");
+        let (status, _) = send(&state, "POST", "/admin/policies", Some(child.clone())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        child["inherits"] = json!("wallet_budget");
+        let (status, _) = send(&state, "POST", "/admin/policies", Some(child)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = send(&state, "DELETE", "/admin/policies/wallet_budget", None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, _) = send(&state, "DELETE", "/admin/policies/wallet_vip", None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = send(&state, "DELETE", "/admin/policies/wallet_budget", None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = send(&state, "GET", "/admin/policies/wallet_budget", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn never_echoes_webhook_secrets() {
+        let state = state().await;
+        let policy = json!({
+            "id": "custom",
+            "name": "Custom",
+            "policy_type": "Custom",
+            "enabled": true,
+            "rate_limits": [],
+            "webhook": { "url": "https://policies.example.com/decide", "secret": "webhook-hmac-key" }
+        });
+
+        let (status, created) = send(&state, "POST", "/admin/policies", Some(policy.clone())).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, listed) = send(&state, "GET", "/admin/policies", None).await;
+        let (_, fetched) = send(&state, "GET", "/admin/policies/custom", None).await;
+        for body in [&created, &listed, &fetched] {
+            assert!(!body.to_string().contains("webhook-hmac-key"), "secret leaked in {}", body);
+        }
+
+        // An update without the secret keeps it
+        let mut update = policy;
+        update["webhook"].as_object_mut().unwrap().remove("secret");
+        let (_, updated) = send(&state, "PUT", "/admin/policies/custom", Some(update)).await;
+        assert!(!updated.to_string().contains("webhook-hmac-key"));
+        let stored = state.policy_engine.get_policy("custom").await.unwrap();
+        assert_eq!(stored.webhook.unwrap().secret.as_deref(), Some("webhook-hmac-key"));
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_consumes_no_budget() {
+        let state = state().await;
+        send(&state, "POST", "/admin/policies", Some(wallet_policy_body())).await;
+
+        // The limit admits one request, yet every dry run reports it would be allowed
+        for _ in 0..3 {
+            let (status, decision) = send(&state, "POST", "/admin/policies/wallet_budget/test", Some(sample_request())).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(decision["applies"], true);
+            assert_eq!(decision["allowed"], true);
+        }
+
+        let request = request("0x186a0");
+        let reservation = state.policy_engine.check_policies(&request).await.unwrap();
+        state.policy_engine.hold(1, ethers::types::H256::repeat_byte(1), reservation, u64::MAX).await;
+
+        let (_, decision) = send(&state, "POST", "/admin/policies/wallet_budget/test", Some(sample_request())).await;
+        assert_eq!(decision["allowed"], false);
+        assert!(decision["reason"].as_str().unwrap().contains("Request limit exceeded"));
+    }
+
+    #[tokio::test]
+    async fn resets_a_tripped_circuit_breaker() {
+        let mut settings = settings();
+        settings.admin.api_key = API_KEY.to_string();
+        // Exactly one sample operation, whose worst case is 221000 gas at 1 gwei
+        settings.policy.circuit_breaker.chains.insert("1".to_string(), crate::config::settings::SpendBudget {
+            hourly_wei: Some("221000000000000".to_string()),
+            daily_wei: None,
+        });
+        let state = api_state(settings).await;
+
+        let request = request("0x186a0");
+        state.policy_engine.check_policies(&request).await.unwrap();
+        assert!(matches!(
+            state.policy_engine.check_policies(&request).await,
+            Err(PaymasterError::CircuitBreakerOpen { .. })
+        ));
+
+        let (status, breakers) = send(&state, "GET", "/admin/circuit-breaker", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(breakers[0]["scope"], "chain:1");
+        assert_eq!(breakers[0]["tripped"], true);
+
+        let (status, breaker) = send(&state, "POST", "/admin/circuit-breaker/chain:1/reset", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(breaker["tripped"], false);
+        assert_eq!(breaker["windows"][0]["spentWei"], 0);
+        assert!(state.policy_engine.check_policies(&request).await.is_ok());
+
+        let (status, _) = send(&state, "POST", "/admin/circuit-breaker/chain:10/reset", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::api::{to_result, ApiState, JsonRpcError};
+use crate::core::types::*;
+use crate::core::user_operation::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// ERC-7677 `context` parameter; unknown keys are ignored
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymasterContext {
+    pub token: Option<String>, // Rejected: there is no ERC-20 paymaster to charge tokens through
+    pub validity_seconds: Option<u64>,
+    pub sponsorship_policy_id: Option<String>,
+}
+
+/// Result of `pm_getPaymasterStubData` and `pm_getPaymasterData`
+///
+/// v0.6 operations get `paymasterAndData`; v0.7+ operations get the split fields.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymasterRpcResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paymaster_and_data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paymaster: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paymaster_data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paymaster_verification_gas_limit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paymaster_post_op_gas_limit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_final: Option<bool>,
+}
+
+/// `pm_getPaymasterStubData`: placeholder paymaster fields and gas limits for estimation
+pub async fn get_paymaster_stub_data(state: &ApiState, params: Value) -> Result<Value, JsonRpcError> {
+    let request = parse_params(params)?;
+    let result = state.paymaster.get_paymaster_stub_data(&request).await?;
+
+    let mut response = match request.user_operation.version() {
+        EntryPointVersion::V06 => PaymasterRpcResult {
+            paymaster_and_data: Some(result.paymaster_and_data),
+            ..Default::default()
+        },
+        _ => PaymasterRpcResult {
+            paymaster: result.paymaster,
+            paymaster_data: result.paymaster_data,
+            paymaster_verification_gas_limit: result.paymaster_verification_gas_limit.as_deref().map(to_hex_quantity).transpose()?,
+            paymaster_post_op_gas_limit: result.paymaster_post_op_gas_limit.as_deref().map(to_hex_quantity).transpose()?,
+            ..Default::default()
+        },
+    };
+    // The stub signature must be replaced through pm_getPaymasterData
+    response.is_final = Some(false);
+
+    to_result(response)
+}
+
+/// `pm_getPaymasterData`: the final signed paymaster fields
+pub async fn get_paymaster_data(state: &ApiState, params: Value) -> Result<Value, JsonRpcError> {
+    let request = parse_params(params)?;
+
+    let result = state.paymaster.sponsor_user_operation(&request).await?;
+
+    let response = match request.user_operation.version() {
+        EntryPointVersion::V06 => PaymasterRpcResult {
+            paymaster_and_data: Some(result.paymaster_and_data),
+            ..Default::default()
+        },
+        _ => PaymasterRpcResult {
+            paymaster: result.paymaster,
+            paymaster_data: result.paymaster_data,
+            ..Default::default()
+        },
+    };
+
+    to_result(response)
+}
+
+/// Parse the positional `[userOp, entryPoint, chainId, context]` parameters
+pub fn parse_params(params: Value) -> Result<SponsorRequest, JsonRpcError> {
+    let mut params = match params {
+        Value::Array(params) if (3..=4).contains(&params.len()) => params.into_iter(),
+        _ => {
+            return Err(JsonRpcError::invalid_params(
+                "Expected params [userOp, entryPoint, chainId, context]"
+            ));
+        }
+    };
+
+    let user_operation = params.next().unwrap_or_default();
+    let entry_point = params.next()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .ok_or_else(|| JsonRpcError::invalid_params("Entry point must be an address string"))?;
+    // The operation's layout follows the EntryPoint it targets
+    let user_operation = VersionedUserOperation::from_value(user_operation, &entry_point)?;
+
+    let chain_id = params.next()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .ok_or_else(|| JsonRpcError::invalid_params("Chain id must be a hex string"))?;
+    let chain_id = parse_quantity("chain id", &chain_id)?;
+    if chain_id > u64::MAX.into() {
+        return Err(JsonRpcError::invalid_params("Chain id out of range"));
+    }
+
+    let context: PaymasterContext = match params.next() {
+        Some(Value::Null) | None => PaymasterContext::default(),
+        Some(context) => serde_json::from_value(context)
+            .map_err(|e| JsonRpcError::invalid_params(format!("Invalid context: {}", e)))?,
+    };
+    if context.token.is_some() {
+        return Err(JsonRpcError::invalid_params("context.token is not supported"));
+    }
+
+    Ok(SponsorRequest {
+        user_operation,
+        entry_point,
+        chain_id: chain_id.as_u64(),
+        validity_seconds: context.validity_seconds,
+        sponsorship_policy_id: context.sponsorship_policy_id,
+    })
+}
+
+/// Normalize a decimal or hex quantity to 0x-prefixed hex
+pub fn to_hex_quantity(value: &str) -> Result<String, JsonRpcError> {
+    Ok(format!("{:#x}", parse_quantity("quantity", value)?))
+}
@@ -0,0 +1,510 @@
+use crate::api::{erc7677, pimlico, zerodev, ApiState};
+use crate::core::types::*;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{body::Bytes, extract::State, Json};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+// JSON-RPC 2.0 error codes
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+// ERC-4337 bundler error code for operations the paymaster refuses
+pub const PAYMASTER_REJECTED: i64 = -32501;
+
+#[derive(Debug, Deserialize)]
+pub struct JsonRpcRequest {
+    pub jsonrpc: String,
+    /// `None` for notifications; an explicit `null` id is still a call
+    #[serde(default, deserialize_with = "present")]
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonRpcResponse {
+    pub jsonrpc: &'static str,
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<JsonRpcError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl JsonRpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+}
+
+impl From<PaymasterError> for JsonRpcError {
+    fn from(error: PaymasterError) -> Self {
+        let code = match &error {
+            PaymasterError::InvalidUserOperation(_) | PaymasterError::UnsupportedChain(_) => INVALID_PARAMS,
+            PaymasterError::PolicyViolation(_)
+            | PaymasterError::PolicyRejected { .. }
+            | PaymasterError::CircuitBreakerOpen { .. }
+            | PaymasterError::InsufficientBalance(_) => PAYMASTER_REJECTED,
+            PaymasterError::GasEstimationFailed(_)
+            | PaymasterError::BlockchainError(_)
+            | PaymasterError::DatabaseError(_)
+            | PaymasterError::ConfigurationError(_) => INTERNAL_ERROR,
+        };
+
+        let mut rpc_error = Self::new(code, error.to_string());
+        // Tell the client which policy refused the operation
+        match &error {
+            PaymasterError::PolicyRejected { policy_id, .. } => {
+                rpc_error.data = Some(serde_json::json!({ "policyId": policy_id }));
+            }
+            PaymasterError::CircuitBreakerOpen { scope, .. } => {
+                rpc_error.data = Some(serde_json::json!({ "circuitBreaker": scope }));
+            }
+            _ => {}
+        }
+        rpc_error
+    }
+}
+
+impl JsonRpcResponse {
+    fn success(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn failure(id: Value, error: JsonRpcError) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// Axum handler for single and batched JSON-RPC 2.0 requests
+///
+/// Notifications are executed but never answered; a request or batch of only
+/// notifications gets an empty `204 No Content`.
+pub async fn handle_rpc(State(state): State<ApiState>, body: Bytes) -> Response {
+    let payload: Value = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(e) => {
+            let response = JsonRpcResponse::failure(Value::Null, JsonRpcError::new(PARSE_ERROR, format!("Parse error: {}", e)));
+            return Json(serde_json::to_value(response).unwrap_or_default()).into_response();
+        }
+    };
+
+    let response = match payload {
+        Value::Array(requests) if !requests.is_empty() => {
+            let mut responses = Vec::with_capacity(requests.len());
+            for request in requests {
+                responses.extend(handle_request(&state, request).await);
+            }
+            if responses.is_empty() {
+                return StatusCode::NO_CONTENT.into_response();
+            }
+            serde_json::to_value(responses)
+        }
+        Value::Array(_) => serde_json::to_value(JsonRpcResponse::failure(
+            Value::Null,
+            JsonRpcError::new(INVALID_REQUEST, "Empty batch"),
+        )),
+        request => match handle_request(&state, request).await {
+            Some(response) => serde_json::to_value(response),
+            None => return StatusCode::NO_CONTENT.into_response(),
+        },
+    };
+
+    Json(response.unwrap_or_default()).into_response()
+}
+
+/// Handle one request; `None` when it is a notification
+async fn handle_request(state: &ApiState, request: Value) -> Option<JsonRpcResponse> {
+    let request: JsonRpcRequest = match serde_json::from_value(request) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(JsonRpcResponse::failure(Value::Null, JsonRpcError::new(INVALID_REQUEST, format!("Invalid request: {}", e))));
+        }
+    };
+
+    if request.jsonrpc != "2.0" {
+        let id = request.id.unwrap_or_default();
+        return Some(JsonRpcResponse::failure(id, JsonRpcError::new(INVALID_REQUEST, "Unsupported JSON-RPC version")));
+    }
+
+    info!("JSON-RPC request: {}", request.method);
+    let result = dispatch(state, &request.method, request.params).await;
+    if let Err(error) = &result {
+        warn!("JSON-RPC {} failed: {}", request.method, error.message);
+    }
+
+    let id = request.id?;
+    Some(match result {
+        Ok(result) => JsonRpcResponse::success(id, result),
+        Err(error) => JsonRpcResponse::failure(id, error),
+    })
+}
+
+/// Route a method call to its implementation
+async fn dispatch(state: &ApiState, method: &str, params: Value) -> Result<Value, JsonRpcError> {
+    match method {
+        "pm_getPaymasterStubData" => erc7677::get_paymaster_stub_data(state, params).await,
+        "pm_getPaymasterData" => erc7677::get_paymaster_data(state, params).await,
+        "pm_sponsorUserOperation" => pimlico::sponsor_user_operation(state, params).await,
+        "pm_validateSponsorshipPolicies" => pimlico::validate_sponsorship_policies(state, params).await,
+        "zd_sponsorUserOperation" => zerodev::sponsor_user_operation(state, params).await,
+        _ => Err(JsonRpcError::new(METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+    }
+}
+
+/// Serialize a method result into the response value
+pub fn to_result<T: Serialize>(result: T) -> Result<Value, JsonRpcError> {
+    serde_json::to_value(result)
+        .map_err(|e| JsonRpcError::new(INTERNAL_ERROR, format!("Failed to serialize result: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::router;
+    use crate::core::user_operation::{VersionedUserOperation, ENTRY_POINT_V07};
+    use crate::test_support::{api_state, settings};
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    async fn state() -> ApiState {
+        let mut settings = settings();
+        settings.blockchain.chains.push(ChainConfig {
+            chain_id: 11155111,
+            name: "sepolia".to_string(),
+            rpc_url: "https://eth-sepolia.g.alchemy.com/v2/YOUR_KEY".to_string(),
+            ..settings.blockchain.chains[0].clone()
+        });
+        api_state(settings).await
+    }
+
+    async fn send_to(state: ApiState, body: &str) -> (StatusCode, Bytes) {
+        let response = router(state)
+            .oneshot(Request::post("/").body(Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        (status, to_bytes(response.into_body(), usize::MAX).await.unwrap())
+    }
+
+    async fn send(body: &str) -> (StatusCode, Bytes) {
+        send_to(state().await, body).await
+    }
+
+    async fn call(body: &str) -> Value {
+        let (_, body) = send(body).await;
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_json() {
+        let response = call("{not json").await;
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_method() {
+        let response = call(r#"{"jsonrpc":"2.0","id":7,"method":"eth_chainId","params":[]}"#).await;
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn stub_data_for_v07_includes_paymaster_gas_limits() {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "pm_getPaymasterStubData",
+            "params": [
+                {
+                    "sender": "0x1306b01bc3e4ad202612d3843387e94737673f53",
+                    "nonce": "0x0",
+                    "callData": "0xb61d27f6",
+                    "callGasLimit": "0x0",
+                    "verificationGasLimit": "0x0",
+                    "preVerificationGas": "0x0",
+                    "maxFeePerGas": "0x77359400",
+                    "maxPriorityFeePerGas": "0x3b9aca00",
+                    "signature": "0x"
+                },
+                "0x0000000071727De22E5E9d8BAf0edAc6f37da032",
+                "0xaa36a7",
+                {}
+            ]
+        });
+
+        let response = call(&request.to_string()).await;
+        let result = &response["result"];
+        assert_eq!(result["paymaster"], "0x9D7f74d0C41E726EC95884E0e97Fa6129e3b5E99");
+        assert_eq!(result["paymasterVerificationGasLimit"], "0xea60");
+        assert_eq!(result["paymasterPostOpGasLimit"], "0x0");
+        assert_eq!(result["isFinal"], false);
+        // abi.encode(validUntil, validAfter) followed by a 65-byte signature
+        assert_eq!(result["paymasterData"].as_str().unwrap().len(), 2 + (64 + 65) * 2);
+        assert!(result.get("paymasterAndData").is_none());
+    }
+
+    #[tokio::test]
+    async fn maps_paymaster_errors_to_invalid_params() {
+        // A well-formed operation for an EntryPoint the paymaster does not know
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "pm_getPaymasterStubData",
+            "params": [
+                {
+                    "sender": "0x1306b01bc3e4ad202612d3843387e94737673f53",
+                    "nonce": "0x0",
+                    "callData": "0xb61d27f6",
+                    "callGasLimit": "0x0",
+                    "verificationGasLimit": "0x0",
+                    "preVerificationGas": "0x0",
+                    "maxFeePerGas": "0x77359400",
+                    "maxPriorityFeePerGas": "0x3b9aca00",
+                    "signature": "0x"
+                },
+                "0x0000000000000000000000000000000000000001",
+                "0xaa36a7",
+                {}
+            ]
+        });
+
+        let response = call(&request.to_string()).await;
+        assert_eq!(response["id"], 2);
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        assert_eq!(
+            response["error"]["message"],
+            "Invalid user operation: Unsupported entry point: 0x0000000000000000000000000000000000000001"
+        );
+    }
+
+    #[tokio::test]
+    async fn erc7677_rejects_token_payment() {
+        for method in ["pm_getPaymasterStubData", "pm_getPaymasterData"] {
+            let request = json!({
+                "jsonrpc": "2.0",
+                "id": 3,
+                "method": method,
+                "params": [
+                    sender_user_op(),
+                    "0x0000000071727De22E5E9d8BAf0edAc6f37da032",
+                    "0x1",
+                    { "token": "0xdac17f958d2ee523a2206206994597c13d831ec7", "maxTokenAmount": "0xf4240" }
+                ]
+            });
+
+            let response = call(&request.to_string()).await;
+            assert_eq!(response["error"]["code"], INVALID_PARAMS);
+            assert_eq!(response["error"]["message"], "context.token is not supported");
+        }
+    }
+
+    #[tokio::test]
+    async fn notifications_get_no_response() {
+        let (status, body) = send(r#"{"jsonrpc":"2.0","method":"eth_chainId","params":[]}"#).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(body.is_empty());
+
+        // Only the call in a batch is answered
+        let response = call(r#"[
+            {"jsonrpc":"2.0","method":"eth_chainId","params":[]},
+            {"jsonrpc":"2.0","id":null,"method":"eth_chainId","params":[]}
+        ]"#).await;
+        let responses = response.as_array().unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["id"], Value::Null);
+        assert_eq!(responses[0]["error"]["code"], METHOD_NOT_FOUND);
+
+        let (status, body) = send(r#"[{"jsonrpc":"2.0","method":"eth_chainId"}]"#).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_chains() {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 4,
+            "method": "pm_getPaymasterStubData",
+            "params": [
+                {
+                    "sender": "0x1306b01bc3e4ad202612d3843387e94737673f53",
+                    "nonce": "0x0",
+                    "callData": "0xb61d27f6",
+                    "callGasLimit": "0x0",
+                    "verificationGasLimit": "0x0",
+                    "preVerificationGas": "0x0",
+                    "maxFeePerGas": "0x77359400",
+                    "maxPriorityFeePerGas": "0x3b9aca00",
+                    "signature": "0x"
+                },
+                "0x0000000071727De22E5E9d8BAf0edAc6f37da032",
+                "0x89",
+                {}
+            ]
+        });
+
+        let response = call(&request.to_string()).await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        assert_eq!(response["error"]["message"], "Chain 137 is not supported");
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_sponsorship_policy() {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "method": "pm_sponsorUserOperation",
+            "params": [
+                {
+                    "sender": "0x1306b01bc3e4ad202612d3843387e94737673f53",
+                    "nonce": "0x0",
+                    "callData": "0x",
+                    "callGasLimit": "0x0",
+                    "verificationGasLimit": "0x0",
+                    "preVerificationGas": "0x0",
+                    "maxFeePerGas": "0x0",
+                    "maxPriorityFeePerGas": "0x0",
+                    "signature": "0x"
+                },
+                "0x0000000071727De22E5E9d8BAf0edAc6f37da032",
+                { "sponsorshipPolicyId": "sp_unknown" }
+            ]
+        });
+
+        let response = call(&request.to_string()).await;
+        assert_eq!(response["error"]["code"], PAYMASTER_REJECTED);
+    }
+
+    fn sender_user_op() -> Value {
+        json!({
+            "sender": "0x1306b01bc3e4ad202612d3843387e94737673f53",
+            "nonce": "0x0",
+            "callData": "0xb61d27f6",
+            "callGasLimit": "0x0",
+            "verificationGasLimit": "0x0",
+            "preVerificationGas": "0x0",
+            "maxFeePerGas": "0x77359400",
+            "maxPriorityFeePerGas": "0x3b9aca00",
+            "signature": "0x"
+        })
+    }
+
+    #[tokio::test]
+    async fn validating_sponsorship_policies_checks_their_rate_limits() {
+        let state = state().await;
+        state.policy_engine.add_policy(GasPolicy {
+            id: "sp_wallet".to_string(),
+            name: "Wallet".to_string(),
+            policy_type: PolicyType::Wallet,
+            target: Some("0x1306b01bc3e4ad202612d3843387e94737673f53".to_string()),
+            rate_limits: vec![RateLimit {
+                limit_type: RateLimitType::Request,
+                limit: "1".to_string(),
+                window: 3600,
+                algorithm: RateLimitAlgorithm::FixedWindow,
+                burst: None,
+            }],
+            call_rules: vec![],
+            webhook: None,
+            priority: 0,
+            effect: PolicyEffect::Allow,
+            inherits: None,
+            multipliers: LimitMultipliers::default(),
+            schedule: None,
+            enabled: true,
+        }).await.unwrap();
+
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 5,
+            "method": "pm_validateSponsorshipPolicies",
+            "params": [sender_user_op(), "0x0000000071727De22E5E9d8BAf0edAc6f37da032", ["sp_wallet", "sp_unknown"]]
+        }).to_string();
+
+        let (_, body) = send_to(state.clone(), &request).await;
+        let response: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["result"][0]["sponsorshipPolicyId"], "sp_wallet");
+        assert_eq!(response["result"].as_array().unwrap().len(), 1);
+
+        // Use up the wallet's one request
+        let sponsor = SponsorRequest {
+            user_operation: VersionedUserOperation::from_value(sender_user_op(), ENTRY_POINT_V07).unwrap(),
+            entry_point: ENTRY_POINT_V07.to_string(),
+            chain_id: 1,
+            validity_seconds: None,
+            sponsorship_policy_id: None,
+        };
+        state.policy_engine.check_policies(&sponsor).await.unwrap();
+
+        let (_, body) = send_to(state, &request).await;
+        let response: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response["result"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn zerodev_rejects_unbounded_or_unsupported_requests() {
+        let request = |extra: Value| {
+            let mut params = json!({
+                "chainId": 1,
+                "userOp": sender_user_op(),
+                "entryPointAddress": "0x0000000071727De22E5E9d8BAf0edAc6f37da032"
+            });
+            params.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            json!({ "jsonrpc": "2.0", "id": 6, "method": "zd_sponsorUserOperation", "params": [params] }).to_string()
+        };
+
+        // There is no ERC-20 paymaster whose signature tokens could be charged through
+        let gas_token = json!({ "tokenAddress": "0xdac17f958d2ee523a2206206994597c13d831ec7", "maxTokenAmount": "0xf4240" });
+        let response = call(&request(json!({ "gasTokenData": gas_token }))).await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        assert_eq!(response["error"]["message"], "gasTokenData is not supported");
+
+        let response = call(&request(json!({ "manualGasEstimation": true }))).await;
+        assert_eq!(response["error"]["message"], "manualGasEstimation is not supported");
+
+        let response = call(&request(json!({ "shouldOverrideFee": true }))).await;
+        assert_eq!(response["error"]["message"], "shouldOverrideFee is not supported");
+
+        let response = call(&request(json!({ "shouldConsume": false }))).await;
+        assert_eq!(response["error"]["message"], "shouldConsume: false is not supported");
+    }
+}
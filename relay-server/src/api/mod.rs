@@ -0,0 +1,73 @@
+pub mod admin;
+pub mod erc7677;
+pub mod jsonrpc;
+pub mod pimlico;
+pub mod zerodev;
+
+pub use jsonrpc::*;
+
+use crate::config::Settings;
+use crate::core::types::*;
+use crate::core::{ChainRegistry, GasEstimatorService, PaymasterService, PolicyEngine};
+use crate::database::PolicyRepository;
+use axum::{routing::post, Router};
+use std::sync::Arc;
+use tower_http::cors::CorsLayer;
+use tower_http::trace::TraceLayer;
+
+/// Shared state handed to every API handler
+#[derive(Clone)]
+pub struct ApiState {
+    pub chains: Arc<ChainRegistry>,
+    pub paymaster: Arc<PaymasterService>,
+    pub policy_engine: Arc<PolicyEngine>,
+    pub gas_estimator: Arc<GasEstimatorService>,
+    pub policy_repository: Option<PolicyRepository>, // Admin policy edits are persisted here when set
+    pub admin_api_key: Option<String>,
+}
+
+impl ApiState {
+    /// Build the services from settings
+    pub async fn new(settings: Settings) -> PaymasterResult<Self> {
+        let policy_engine = Arc::new(PolicyEngine::new(&settings.redis.url, &settings.policy)?);
+        Self::with_policy_engine(settings, policy_engine).await
+    }
+
+    /// Build the services from settings around an existing policy engine, which the paymaster shares
+    pub async fn with_policy_engine(settings: Settings, policy_engine: Arc<PolicyEngine>) -> PaymasterResult<Self> {
+        let admin_api_key = Some(settings.admin.api_key.clone()).filter(|key| !key.is_empty());
+        let chains = Arc::new(ChainRegistry::new(&settings)?);
+        let gas_estimator = GasEstimatorService::new(&chains);
+        let paymaster = PaymasterService::new(settings, chains.clone(), policy_engine.clone()).await?;
+
+        Ok(Self {
+            chains,
+            paymaster: Arc::new(paymaster),
+            policy_engine,
+            gas_estimator: Arc::new(gas_estimator),
+            policy_repository: None,
+            admin_api_key,
+        })
+    }
+
+    /// Persist admin policy edits to Postgres so every relay instance applies them
+    pub fn with_policy_repository(mut self, repository: PolicyRepository) -> Self {
+        self.policy_repository = Some(repository);
+        self
+    }
+}
+
+/// Build the API router; JSON-RPC is served on `/` and `/rpc`, policy administration under `/admin`
+pub fn router(state: ApiState) -> Router {
+    let mut router = Router::new()
+        .route("/", post(handle_rpc))
+        .route("/rpc", post(handle_rpc));
+    if state.admin_api_key.is_some() {
+        router = router.nest("/admin", admin::router(state.clone()));
+    }
+
+    router
+        .layer(TraceLayer::new_for_http())
+        .layer(CorsLayer::permissive())
+        .with_state(state)
+}
@@ -0,0 +1,173 @@
+use crate::api::erc7677::to_hex_quantity;
+use crate::api::{to_result, ApiState, JsonRpcError};
+use crate::core::types::*;
+use crate::core::user_operation::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+/// Optional third parameter of `pm_sponsorUserOperation`
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SponsorshipPolicyData {
+    pub sponsorship_policy_id: Option<String>,
+}
+
+/// Result of `pm_sponsorUserOperation` / `zd_sponsorUserOperation`
+///
+/// v0.6 operations get `paymasterAndData`; v0.7+ operations get the split fields.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SponsorUserOperationResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paymaster_and_data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paymaster: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paymaster_data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paymaster_verification_gas_limit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paymaster_post_op_gas_limit: Option<String>,
+    pub pre_verification_gas: String,
+    pub verification_gas_limit: String,
+    pub call_gas_limit: String,
+}
+
+impl SponsorUserOperationResult {
+    pub fn new(version: EntryPointVersion, result: PaymasterResponse) -> Result<Self, JsonRpcError> {
+        let mut response = Self {
+            pre_verification_gas: to_hex_quantity(&result.pre_verification_gas)?,
+            verification_gas_limit: to_hex_quantity(&result.verification_gas_limit)?,
+            call_gas_limit: to_hex_quantity(&result.call_gas_limit)?,
+            ..Default::default()
+        };
+
+        if version == EntryPointVersion::V06 {
+            response.paymaster_and_data = Some(result.paymaster_and_data);
+        } else {
+            response.paymaster = result.paymaster;
+            response.paymaster_data = result.paymaster_data;
+            response.paymaster_verification_gas_limit = result.paymaster_verification_gas_limit.as_deref().map(to_hex_quantity).transpose()?;
+            response.paymaster_post_op_gas_limit = result.paymaster_post_op_gas_limit.as_deref().map(to_hex_quantity).transpose()?;
+        }
+
+        Ok(response)
+    }
+}
+
+/// Entry in the `pm_validateSponsorshipPolicies` result
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SponsorshipPolicyInfo {
+    pub sponsorship_policy_id: String,
+    pub data: SponsorshipPolicyDetails,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SponsorshipPolicyDetails {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    pub icon: Option<String>,
+    pub description: Option<String>,
+}
+
+/// `pm_sponsorUserOperation`: `[userOp, entryPoint, { sponsorshipPolicyId }?]`
+pub async fn sponsor_user_operation(state: &ApiState, params: Value) -> Result<Value, JsonRpcError> {
+    let mut params = positional_params(params, 2, 3, "[userOp, entryPoint, sponsorshipPolicyData?]")?;
+
+    let user_operation = params.next();
+    let entry_point = parse_entry_point(params.next())?;
+    let user_operation = parse_user_operation(user_operation, &entry_point)?;
+    let policy_data: SponsorshipPolicyData = match params.next() {
+        Some(Value::Null) | None => SponsorshipPolicyData::default(),
+        Some(data) => serde_json::from_value(data)
+            .map_err(|e| JsonRpcError::invalid_params(format!("Invalid sponsorship policy data: {}", e)))?,
+    };
+
+    // Pimlico endpoints are per chain, so the chain comes from the server
+    let request = SponsorRequest {
+        user_operation,
+        entry_point,
+        chain_id: state.paymaster.default_chain_id(),
+        validity_seconds: None,
+        sponsorship_policy_id: policy_data.sponsorship_policy_id,
+    };
+
+    let result = state.paymaster.sponsor_user_operation(&request).await?;
+    to_result(SponsorUserOperationResult::new(request.user_operation.version(), result)?)
+}
+
+/// `pm_validateSponsorshipPolicies`: `[userOp, entryPoint, [sponsorshipPolicyId]]`
+///
+/// Returns the subset of policies willing to sponsor the operation, rate limits included,
+/// without consuming any budget.
+pub async fn validate_sponsorship_policies(state: &ApiState, params: Value) -> Result<Value, JsonRpcError> {
+    let mut params = positional_params(params, 3, 3, "[userOp, entryPoint, sponsorshipPolicyIds]")?;
+
+    let user_operation = params.next();
+    let entry_point = parse_entry_point(params.next())?;
+    let user_operation = parse_user_operation(user_operation, &entry_point)?;
+    let policy_ids: Vec<String> = serde_json::from_value(params.next().unwrap_or_default())
+        .map_err(|e| JsonRpcError::invalid_params(format!("Invalid sponsorship policy ids: {}", e)))?;
+
+    let request = SponsorRequest {
+        user_operation,
+        entry_point,
+        chain_id: state.paymaster.default_chain_id(),
+        validity_seconds: None,
+        sponsorship_policy_id: None,
+    };
+
+    let mut accepted = Vec::new();
+    for policy_id in policy_ids {
+        // A dry run of the policy's limits, so an exhausted policy is not offered
+        let decision = match state.policy_engine.test_policy(&policy_id, &request).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                warn!("Skipping sponsorship policy {}: {}", policy_id, e);
+                continue;
+            }
+        };
+        let Some(policy) = state.policy_engine.get_policy(&policy_id).await else {
+            continue;
+        };
+
+        if decision.enabled && decision.applies && decision.allowed && policy.effect != PolicyEffect::Deny {
+            accepted.push(SponsorshipPolicyInfo {
+                sponsorship_policy_id: policy.id,
+                data: SponsorshipPolicyDetails {
+                    name: Some(policy.name),
+                    author: None,
+                    icon: None,
+                    description: None,
+                },
+            });
+        }
+    }
+
+    to_result(accepted)
+}
+
+/// Split array params, checking the parameter count
+pub fn positional_params(
+    params: Value,
+    min: usize,
+    max: usize,
+    expected: &str,
+) -> Result<std::vec::IntoIter<Value>, JsonRpcError> {
+    match params {
+        Value::Array(params) if (min..=max).contains(&params.len()) => Ok(params.into_iter()),
+        _ => Err(JsonRpcError::invalid_params(format!("Expected params {}", expected))),
+    }
+}
+
+/// Parse the operation in the layout of the EntryPoint it targets
+fn parse_user_operation(value: Option<Value>, entry_point: &str) -> Result<VersionedUserOperation, JsonRpcError> {
+    Ok(VersionedUserOperation::from_value(value.unwrap_or_default(), entry_point)?)
+}
+
+fn parse_entry_point(value: Option<Value>) -> Result<String, JsonRpcError> {
+    value.and_then(|value| value.as_str().map(str::to_string))
+        .ok_or_else(|| JsonRpcError::invalid_params("Entry point must be an address string"))
+}
@@ -0,0 +1,58 @@
+use crate::api::pimlico::{positional_params, SponsorUserOperationResult};
+use crate::api::{to_result, ApiState, JsonRpcError};
+use crate::core::types::*;
+use crate::core::user_operation::*;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Single object parameter of `zd_sponsorUserOperation`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ZeroDevSponsorParams {
+    pub chain_id: u64,
+    pub user_op: Value, // Parsed in the layout of `entry_point_address`
+    pub entry_point_address: String,
+    #[serde(default)]
+    pub gas_token_data: Option<Value>, // Rejected: there is no ERC-20 paymaster to charge tokens through
+    #[serde(default)]
+    pub should_override_fee: bool,
+    #[serde(default)]
+    pub manual_gas_estimation: bool,
+    #[serde(default)]
+    pub should_consume: Option<bool>,
+}
+
+/// `zd_sponsorUserOperation`: sponsor the operation
+pub async fn sponsor_user_operation(state: &ApiState, params: Value) -> Result<Value, JsonRpcError> {
+    let mut params = positional_params(params, 1, 1, "[{ chainId, userOp, entryPointAddress, ... }]")?;
+    let params: ZeroDevSponsorParams = serde_json::from_value(params.next().unwrap_or_default())
+        .map_err(|e| JsonRpcError::invalid_params(format!("Invalid sponsor params: {}", e)))?;
+
+    // Gas is always estimated, fees are the client's to set, and every signed sponsorship reserves budget
+    if params.manual_gas_estimation {
+        return Err(JsonRpcError::invalid_params("manualGasEstimation is not supported"));
+    }
+    if params.should_override_fee {
+        return Err(JsonRpcError::invalid_params("shouldOverrideFee is not supported"));
+    }
+    if params.should_consume == Some(false) {
+        return Err(JsonRpcError::invalid_params("shouldConsume: false is not supported"));
+    }
+    if params.gas_token_data.is_some() {
+        return Err(JsonRpcError::invalid_params("gasTokenData is not supported"));
+    }
+
+    let user_op = VersionedUserOperation::from_value(params.user_op, &params.entry_point_address)?;
+    let version = user_op.version();
+
+    let request = SponsorRequest {
+        user_operation: user_op,
+        entry_point: params.entry_point_address,
+        chain_id: params.chain_id,
+        validity_seconds: None,
+        sponsorship_policy_id: None,
+    };
+    let result = state.paymaster.sponsor_user_operation(&request).await?;
+
+    to_result(SponsorUserOperationResult::new(version, result)?)
+}
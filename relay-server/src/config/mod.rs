@@ -1,3 +1,4 @@
+pub mod policies;
 pub mod settings;
 
 pub use settings::Settings;
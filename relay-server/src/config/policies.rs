@@ -0,0 +1,218 @@
+use crate::core::policy_engine::{PolicyEngine, PolicySource};
+use crate::core::types::GasPolicy;
+use config::{Config, ConfigError, File, FileFormat};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// One policy file: a `policies` list of `GasPolicy` entries
+#[derive(Debug, Deserialize)]
+struct PolicyFile {
+    #[serde(default)]
+    policies: Vec<GasPolicy>,
+}
+
+/// Load and validate every `*.yaml`/`*.yml` file in `directory`, in file name order
+///
+/// A missing directory holds no policies. Errors name the file and the offending field.
+pub fn load_policy_files(directory: &str) -> Result<Vec<GasPolicy>, ConfigError> {
+    let mut policies = Vec::new();
+    let mut defined_in: HashMap<String, PathBuf> = HashMap::new();
+
+    for path in policy_files(directory)? {
+        let file: PolicyFile = Config::builder()
+            .add_source(File::from(path.as_path()).format(FileFormat::Yaml))
+            .build()
+            .and_then(Config::try_deserialize)
+            .map_err(|e| ConfigError::Message(format!("{}: {}", path.display(), e)))?;
+
+        for (index, policy) in file.policies.into_iter().enumerate() {
+            policy.validate()
+                .map_err(|e| ConfigError::Message(format!("{}: policies[{}].{}", path.display(), index, e)))?;
+
+            if let Some(first) = defined_in.insert(policy.id.clone(), path.clone()) {
+                return Err(ConfigError::Message(format!(
+                    "{}: policies[{}].id {:?} is already defined in {}",
+                    path.display(), index, policy.id, first.display()
+                )));
+            }
+            policies.push(policy);
+        }
+    }
+
+    Ok(policies)
+}
+
+fn policy_files(directory: &str) -> Result<Vec<PathBuf>, ConfigError> {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(ConfigError::Message(format!("{}: {}", directory, e))),
+    };
+
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && is_yaml(path))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn is_yaml(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "yaml" || extension == "yml")
+}
+
+/// Reloads the policy directory into a `PolicyEngine` whenever a file in it changes
+///
+/// An invalid edit is logged and the previously loaded policies stay in effect.
+pub struct PolicyFileWatcher {
+    directory: String,
+    policy_engine: Arc<PolicyEngine>,
+    poll_interval: Duration,
+}
+
+impl PolicyFileWatcher {
+    pub fn new(directory: &str, policy_engine: Arc<PolicyEngine>, poll_interval: Duration) -> Self {
+        Self {
+            directory: directory.to_string(),
+            policy_engine,
+            poll_interval,
+        }
+    }
+
+    /// Poll for changes until the process exits
+    pub async fn run(self) {
+        let mut loaded = self.fingerprint();
+        let mut interval = tokio::time::interval(self.poll_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let current = self.fingerprint();
+            if current == loaded {
+                continue;
+            }
+            loaded = current;
+
+            match load_policy_files(&self.directory) {
+                Ok(policies) => {
+                    info!("Reloaded {} policies from {}", policies.len(), self.directory);
+                    self.policy_engine.replace_policies(PolicySource::File, policies).await;
+                }
+                Err(e) => warn!("Keeping the previous policies: {}", e),
+            }
+        }
+    }
+
+    /// Name, size and modification time of every policy file
+    fn fingerprint(&self) -> Vec<(PathBuf, u64, Option<SystemTime>)> {
+        policy_files(&self.directory)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|path| {
+                let metadata = std::fs::metadata(&path).ok();
+                let size = metadata.as_ref().map_or(0, |metadata| metadata.len());
+                let modified = metadata.and_then(|metadata| metadata.modified().ok());
+                (path, size, modified)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::{PolicyType, RateLimitAlgorithm};
+
+    fn policy_dir(files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("policies_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        for (name, contents) in files {
+            std::fs::write(directory.join(name), contents).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn loads_policies_from_yaml_files() {
+        let directory = policy_dir(&[
+            ("wallets.yaml", r#"
+policies:
+  - id: wallet_budget
+    name: Wallet budget
+    policy_type: Wallet
+    target: "0x1306b01bc3e4ad202612d3843387e94737673f53"
+    enabled: true
+    rate_limits:
+      - limit_type: Request
+        limit: 10
+        window: 60
+        algorithm: TokenBucket
+        burst: 5
+"#),
+            ("notes.txt", "not a policy file"),
+        ]);
+
+        let policies = load_policy_files(directory.to_str().unwrap()).unwrap();
+        assert_eq!(policies.len(), 1);
+        assert!(matches!(policies[0].policy_type, PolicyType::Wallet));
+        assert_eq!(policies[0].rate_limits[0].limit, "10");
+        assert_eq!(policies[0].rate_limits[0].algorithm, RateLimitAlgorithm::TokenBucket);
+        assert_eq!(policies[0].rate_limits[0].burst.as_deref(), Some("5"));
+    }
+
+    #[test]
+    fn errors_name_the_file_and_field() {
+        let directory = policy_dir(&[("project.yaml", r#"
+policies:
+  - id: project_budget
+    name: Project budget
+    policy_type: Project
+    enabled: true
+    rate_limits:
+      - limit_type: Amount
+        limit: 1000
+        window: 0
+"#)]);
+
+        let error = load_policy_files(directory.to_str().unwrap()).unwrap_err().to_string();
+        assert!(error.contains("project.yaml"), "{}", error);
+        assert!(error.contains("policies[0].rate_limits[0].window"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_custom_deny_policies() {
+        let directory = policy_dir(&[("custom.yaml", r#"
+policies:
+  - id: screening
+    name: Screening
+    policy_type: Custom
+    effect: Deny
+    enabled: true
+    rate_limits: []
+    webhook:
+      url: https://policy.example.com/decide
+"#)]);
+
+        let error = load_policy_files(directory.to_str().unwrap()).unwrap_err().to_string();
+        assert!(error.contains("policies[0].effect"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_duplicate_ids_across_files() {
+        let policy = "policies:\n  - {id: shared, name: Shared, policy_type: Project, enabled: true, rate_limits: []}\n";
+        let directory = policy_dir(&[("a.yaml", policy), ("b.yml", policy)]);
+
+        let error = load_policy_files(directory.to_str().unwrap()).unwrap_err().to_string();
+        assert!(error.contains("b.yml") && error.contains("a.yaml"), "{}", error);
+    }
+
+    #[test]
+    fn missing_directory_holds_no_policies() {
+        assert!(load_policy_files("/nonexistent/policies").unwrap().is_empty());
+    }
+}
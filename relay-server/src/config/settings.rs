@@ -1,5 +1,12 @@
+use crate::config::policies::load_policy_files;
+use crate::core::l1_fee::L1FeeModel;
+use crate::core::types::{parse_quantity, ChainConfig, GasPolicy, TokenConfig, UnknownCallData};
+use crate::core::user_operation::{EntryPointVersion, ENTRY_POINT_V06, ENTRY_POINT_V07, ENTRY_POINT_V08};
 use config::{Config, ConfigError, File};
+use ethers::signers::LocalWallet;
+use ethers::types::Address;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
 
 #[derive(Debug, Deserialize, Clone)]
@@ -9,6 +16,10 @@ pub struct Settings {
     pub redis: RedisSettings,
     pub blockchain: BlockchainSettings,
     pub paymaster: PaymasterSettings,
+    pub policy: PolicySettings,
+    pub admin: AdminSettings,
+    #[serde(skip)]
+    pub policies: Vec<GasPolicy>, // Loaded from the YAML files in policy.directory
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -31,19 +42,55 @@ pub struct RedisSettings {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct BlockchainSettings {
-    pub ethereum_rpc: String,
-    pub polygon_rpc: String,
-    pub base_rpc: String,
-    pub arbitrum_rpc: String,
-    pub entry_point: String,
+    pub default_chain_id: u64, // Used when a request does not name a chain
+    pub chains: Vec<ChainConfig>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct PaymasterSettings {
-    pub private_key: String,
-    pub address: String,
-    pub supported_tokens: Vec<String>,
+    pub private_key: String, // Signs for every chain without its own key
+    pub address: String,     // Paymaster on every chain without its own address
     pub gas_markup_percentage: f64,
+    pub validity_window_seconds: u64,     // Default lifetime of a paymaster signature
+    pub max_validity_window_seconds: u64, // Upper bound for per-request overrides
+    pub eip7702_delegates: Vec<String>,   // Allowed EIP-7702 delegate implementations; empty rejects delegated senders
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PolicySettings {
+    pub max_call_gas_limit: u64,          // Global safety cap applied to every operation, sponsored or ERC20-paid
+    pub settlement_poll_seconds: u64,     // How often to scan for UserOperationEvent logs
+    pub settlement_grace_seconds: u64,    // Budget stays reserved this long past validUntil, covering log lag
+    #[serde(default)]
+    pub unknown_call_data: UnknownCallData, // Contract policies on calldata no known account layout matches
+    pub directory: String,                // YAML policy files, reloaded when they change
+    pub reload_seconds: u64,              // How often to check the policy files for changes
+    pub schedule_poll_seconds: u64,       // How often to disable policies whose schedule ended
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerSettings,
+}
+
+/// Budgets on the gas cost sponsored per hour and per day; exceeding one stops sponsorship
+/// in its scope until the window ends or an admin resets it
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CircuitBreakerSettings {
+    #[serde(default)]
+    pub global: SpendBudget,
+    #[serde(default)]
+    pub chains: HashMap<String, SpendBudget>, // Keyed by chain id
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SpendBudget {
+    #[serde(default)]
+    pub hourly_wei: Option<String>, // Unset for no hourly budget; at most i64::MAX wei (~9.22 ETH)
+    #[serde(default)]
+    pub daily_wei: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AdminSettings {
+    pub api_key: String, // Bearer token for the /admin API; empty disables it
 }
 
 impl Settings {
@@ -54,10 +101,110 @@ impl Settings {
             .add_source(File::with_name("config/default"))
             .add_source(File::with_name(&format!("config/{}", run_mode)).required(false))
             .add_source(File::with_name("config/local").required(false))
-            .add_source(config::Environment::with_prefix("APP"))
+            // e.g. APP_SERVER__PORT=3001 overrides server.port
+            .add_source(config::Environment::with_prefix("APP").prefix_separator("_").separator("__"))
             .build()?;
 
-        s.try_deserialize()
+        let mut settings: Self = s.try_deserialize()?;
+        settings.validate()?;
+        settings.policies = load_policy_files(&settings.policy.directory)?;
+        Ok(settings)
+    }
+
+    /// Reject configuration the services would otherwise fail on at request time
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Message(message));
+
+        let chains = &self.blockchain.chains;
+        if !chains.iter().any(|chain| chain.chain_id == self.blockchain.default_chain_id) {
+            return invalid(format!("blockchain.default_chain_id {} is not among blockchain.chains", self.blockchain.default_chain_id));
+        }
+        for (i, chain) in chains.iter().enumerate() {
+            let field = |name: &str| format!("blockchain.chains[{}].{}", i, name);
+
+            if chains[..i].iter().any(|other| other.chain_id == chain.chain_id) {
+                return invalid(format!("blockchain.chains lists chain {} twice", chain.chain_id));
+            }
+            if chain.private_key.is_none() && self.paymaster.private_key.parse::<LocalWallet>().is_err() {
+                return invalid("paymaster.private_key is not a valid secp256k1 private key".to_string());
+            }
+            if chain.private_key.as_ref().is_some_and(|key| key.parse::<LocalWallet>().is_err()) {
+                return invalid(format!("{} is not a valid secp256k1 private key", field("private_key")));
+            }
+            if reqwest::Url::parse(&chain.rpc_url).is_err() {
+                return invalid(format!("{} is not a valid URL: {:?}", field("rpc_url"), chain.rpc_url));
+            }
+            if chain.entry_points.is_empty() {
+                return invalid(format!("{} must list at least one EntryPoint", field("entry_points")));
+            }
+            if let Some(entry_point) = chain.entry_points.iter().find(|address| EntryPointVersion::from_address(address).is_err()) {
+                return invalid(format!("{} contains an unknown EntryPoint: {:?}", field("entry_points"), entry_point));
+            }
+            if let Some(model) = chain.l1_fee_model.as_deref().filter(|model| L1FeeModel::from_name(model).is_none()) {
+                return invalid(format!("{} must be none, op_stack or arbitrum, not {:?}", field("l1_fee_model"), model));
+            }
+
+            let mut addresses = vec![match &chain.paymaster_address {
+                Some(address) => (field("paymaster_address"), address),
+                None => ("paymaster.address".to_string(), &self.paymaster.address),
+            }];
+            addresses.extend(chain.entry_point_simulations_v07.iter().map(|address| (field("entry_point_simulations_v07"), address)));
+            addresses.extend(chain.entry_point_simulations_v08.iter().map(|address| (field("entry_point_simulations_v08"), address)));
+            addresses.extend(chain.tokens.iter().map(|token| (field(&format!("tokens.{}", token.symbol)), &token.address)));
+            for (field, value) in addresses {
+                if value.parse::<Address>().is_err() {
+                    return invalid(format!("{} is not a valid address: {:?}", field, value));
+                }
+            }
+            if let Some(token) = chain.tokens.iter().find(|token| !token.price_path.starts_with('/')) {
+                return invalid(format!("{} must be a JSON Pointer, e.g. \"/usd-coin/usd\"", field(&format!("tokens.{}.price_path", token.symbol))));
+            }
+        }
+        for delegate in &self.paymaster.eip7702_delegates {
+            if delegate.parse::<Address>().is_err() {
+                return invalid(format!("paymaster.eip7702_delegates contains an invalid address: {:?}", delegate));
+            }
+        }
+        if self.paymaster.validity_window_seconds == 0
+            || self.paymaster.validity_window_seconds > self.paymaster.max_validity_window_seconds
+        {
+            return invalid(
+                "paymaster.validity_window_seconds must be positive and at most max_validity_window_seconds".to_string()
+            );
+        }
+        if self.database.url.parse::<sqlx::postgres::PgConnectOptions>().is_err() {
+            return invalid(format!("database.url is not a valid Postgres URL: {:?}", self.database.url));
+        }
+        if !self.admin.api_key.is_empty() && self.admin.api_key.len() < 32 {
+            return invalid("admin.api_key must be at least 32 characters, or empty to disable the admin API".to_string());
+        }
+        if self.redis.url.parse::<redis::ConnectionInfo>().is_err() {
+            return invalid(format!("redis.url is not a valid Redis URL: {:?}", self.redis.url));
+        }
+        let breaker = &self.policy.circuit_breaker;
+        let budgets = std::iter::once(("global".to_string(), &breaker.global))
+            .chain(breaker.chains.iter().map(|(chain_id, budget)| (format!("chains.{}", chain_id), budget)));
+        for (scope, budget) in budgets {
+            if let Some(chain_id) = scope.strip_prefix("chains.") {
+                let Ok(chain_id) = chain_id.parse::<u64>() else {
+                    return invalid(format!("policy.circuit_breaker.{} is not keyed by a chain id", scope));
+                };
+                if !self.blockchain.chains.iter().any(|chain| chain.chain_id == chain_id) {
+                    return invalid(format!("policy.circuit_breaker.{} is not a configured chain", scope));
+                }
+            }
+            for (name, wei) in [("hourly_wei", &budget.hourly_wei), ("daily_wei", &budget.daily_wei)] {
+                // Redis counters are signed 64-bit
+                let valid = wei.as_deref().is_none_or(|wei| {
+                    parse_quantity(name, wei).is_ok_and(|wei| wei <= i64::MAX.into())
+                });
+                if !valid {
+                    return invalid(format!("policy.circuit_breaker.{}.{} must be an integer of at most {} wei", scope, name, i64::MAX));
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -77,21 +224,133 @@ impl Default for Settings {
                 max_connections: 10,
             },
             blockchain: BlockchainSettings {
-                ethereum_rpc: "https://eth-mainnet.g.alchemy.com/v2/YOUR_KEY".to_string(),
-                polygon_rpc: "https://polygon-mainnet.g.alchemy.com/v2/YOUR_KEY".to_string(),
-                base_rpc: "https://base-mainnet.g.alchemy.com/v2/YOUR_KEY".to_string(),
-                arbitrum_rpc: "https://arb-mainnet.g.alchemy.com/v2/YOUR_KEY".to_string(),
-                entry_point: "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789".to_string(),
+                default_chain_id: 1,
+                chains: vec![ChainConfig {
+                    chain_id: 1,
+                    name: "ethereum".to_string(),
+                    rpc_url: "https://eth-mainnet.g.alchemy.com/v2/YOUR_KEY".to_string(),
+                    entry_points: [ENTRY_POINT_V06, ENTRY_POINT_V07, ENTRY_POINT_V08].map(String::from).to_vec(),
+                    paymaster_address: None,
+                    private_key: None,
+                    entry_point_simulations_v07: None,
+                    entry_point_simulations_v08: None,
+                    tokens: vec![
+                        TokenConfig {
+                            address: "0xA0b86a33E6441c8C0c45F2d7a6c6e5B8E6A8C8D2".to_string(),
+                            symbol: "USDC".to_string(),
+                            decimals: 6,
+                            price_feed: "https://api.coingecko.com/api/v3/simple/price?ids=usd-coin&vs_currencies=usd".to_string(),
+                            price_path: "/usd-coin/usd".to_string(),
+                        },
+                        TokenConfig {
+                            address: "0xdAC17F958D2ee523a2206206994597C13D831ec7".to_string(),
+                            symbol: "USDT".to_string(),
+                            decimals: 6,
+                            price_feed: "https://api.coingecko.com/api/v3/simple/price?ids=tether&vs_currencies=usd".to_string(),
+                            price_path: "/tether/usd".to_string(),
+                        },
+                    ],
+                    l1_fee_model: None,
+                }],
             },
             paymaster: PaymasterSettings {
                 private_key: "0x".to_string(),
                 address: "0x".to_string(),
-                supported_tokens: vec![
-                    "0xA0b86a33E6441c8C0c45F2d7a6c6e5B8E6A8C8D2".to_string(), // USDC
-                    "0xdAC17F958D2ee523a2206206994597C13D831ec7".to_string(), // USDT
-                ],
                 gas_markup_percentage: 5.0,
+                validity_window_seconds: 600,
+                max_validity_window_seconds: 3600,
+                eip7702_delegates: vec![],
+            },
+            policy: PolicySettings {
+                max_call_gas_limit: 10_000_000,
+                settlement_poll_seconds: 12,
+                settlement_grace_seconds: 120,
+                unknown_call_data: UnknownCallData::Reject,
+                directory: "config/policies".to_string(),
+                reload_seconds: 5,
+                schedule_poll_seconds: 30,
+                circuit_breaker: CircuitBreakerSettings::default(),
+            },
+            admin: AdminSettings {
+                api_key: String::new(),
             },
+            policies: Vec::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::settings;
+
+    #[test]
+    fn rejects_placeholder_signing_key() {
+        let error = Settings::default().validate().unwrap_err();
+        assert!(error.to_string().contains("paymaster.private_key"));
+
+        let mut settings = settings();
+        assert!(settings.validate().is_ok());
+
+        settings.paymaster.validity_window_seconds = 0;
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_circuit_breaker_budgets() {
+        let mut settings = settings();
+        let budget = |wei: &str| SpendBudget { hourly_wei: Some(wei.to_string()), daily_wei: None };
+
+        settings.policy.circuit_breaker.chains.insert("1".to_string(), budget("1000000000000000000"));
+        assert!(settings.validate().is_ok());
+
+        // Budgets for chains the relay does not serve would never be spent against
+        settings.policy.circuit_breaker.chains.insert("8453".to_string(), budget("1"));
+        assert!(settings.validate().is_err());
+        settings.policy.circuit_breaker.chains.remove("8453");
+
+        settings.policy.circuit_breaker.global = budget("10000000000000000000");
+        assert!(settings.validate().is_err());
+
+        settings.policy.circuit_breaker.global = SpendBudget::default();
+        settings.policy.circuit_breaker.chains.insert("base".to_string(), budget("1"));
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_chains() {
+        let mut settings = settings();
+        let base = ChainConfig {
+            chain_id: 8453,
+            name: "base".to_string(),
+            rpc_url: "https://base-mainnet.g.alchemy.com/v2/YOUR_KEY".to_string(),
+            ..settings.blockchain.chains[0].clone()
+        };
+        settings.blockchain.chains.push(base.clone());
+        assert!(settings.validate().is_ok());
+
+        let mut invalid = settings.clone();
+        invalid.blockchain.default_chain_id = 10;
+        assert!(invalid.validate().unwrap_err().to_string().contains("default_chain_id"));
+
+        let mut invalid = settings.clone();
+        invalid.blockchain.chains.push(base.clone());
+        assert!(invalid.validate().unwrap_err().to_string().contains("twice"));
+
+        let mut invalid = settings.clone();
+        invalid.blockchain.chains[1].entry_points = vec!["0x0000000000000000000000000000000000000001".to_string()];
+        assert!(invalid.validate().unwrap_err().to_string().contains("blockchain.chains[1].entry_points"));
+
+        let mut invalid = settings.clone();
+        invalid.blockchain.chains[1].private_key = Some("0x".to_string());
+        assert!(invalid.validate().unwrap_err().to_string().contains("blockchain.chains[1].private_key"));
+
+        let mut invalid = settings.clone();
+        invalid.blockchain.chains[1].tokens[0].price_path = "usd-coin.usd".to_string();
+        assert!(invalid.validate().unwrap_err().to_string().contains("blockchain.chains[1].tokens.USDC.price_path"));
+
+        let mut invalid = settings.clone();
+        invalid.blockchain.chains[1].l1_fee_model = Some("optimism".to_string());
+        assert!(invalid.validate().unwrap_err().to_string().contains("blockchain.chains[1].l1_fee_model"));
+    }
+}
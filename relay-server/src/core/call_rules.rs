@@ -0,0 +1,209 @@
+use crate::core::call_targets::Call;
+use crate::core::types::*;
+use ethers::types::{Address, U256};
+use ethers::utils::id;
+use once_cell::sync::Lazy;
+
+/// Calls that move tokens: the argument holding the recipient (or spender) and, where it can
+/// be told apart from a token id, the argument holding the amount
+///
+/// ERC-721 `transferFrom` shares its selector with ERC-20's, so its token id reads as an amount.
+const TOKEN_CALLS: &[(&str, usize, Option<usize>)] = &[
+    ("transfer(address,uint256)", 0, Some(1)),
+    ("transferFrom(address,address,uint256)", 1, Some(2)),
+    ("approve(address,uint256)", 0, Some(1)),
+    ("increaseAllowance(address,uint256)", 0, Some(1)),
+    ("permit(address,address,uint256,uint256,uint8,bytes32,bytes32)", 1, Some(2)),
+    ("safeTransferFrom(address,address,uint256)", 1, None),
+    ("safeTransferFrom(address,address,uint256,bytes)", 1, None),
+    ("safeTransferFrom(address,address,uint256,uint256,bytes)", 1, Some(3)),
+    ("mint(address,uint256)", 0, None),
+];
+
+struct TokenCall {
+    selector: [u8; 4],
+    recipient: usize,
+    amount: Option<usize>,
+}
+
+static TOKEN_SELECTORS: Lazy<Vec<TokenCall>> = Lazy::new(|| {
+    TOKEN_CALLS.iter()
+        .map(|(signature, recipient, amount)| TokenCall { selector: id(signature), recipient: *recipient, amount: *amount })
+        .collect()
+});
+
+/// Parse a selector given as a function signature, e.g. `mint(address,uint256)`, or as `0x` and 4 hex bytes
+pub fn parse_selector(selector: &str) -> Option<[u8; 4]> {
+    if let Some(hex) = selector.strip_prefix("0x") {
+        let bytes = ethers::utils::hex::decode(hex).ok()?;
+        return bytes.try_into().ok();
+    }
+
+    let signature: String = selector.chars().filter(|c| !c.is_whitespace()).collect();
+    let name_end = signature.find('(')?;
+    (name_end > 0 && signature.ends_with(')')).then(|| id(&signature))
+}
+
+/// Check every call against the rule for its target; calls to contracts without a rule are rejected
+pub fn check_call_rules(rules: &[CallRule], calls: &[Call]) -> PaymasterResult<()> {
+    for call in calls {
+        let rule = rules.iter()
+            .find(|rule| rule.target.parse::<Address>().ok() == Some(call.target))
+            .ok_or_else(|| violation(call, "contract is not sponsored"))?;
+        check_call(rule, call)?;
+    }
+    Ok(())
+}
+
+fn check_call(rule: &CallRule, call: &Call) -> PaymasterResult<()> {
+    let selector = call.selector();
+    let listed = |selectors: &[String]| selectors.iter().any(|listed| parse_selector(listed) == selector);
+
+    if !rule.allowed_selectors.is_empty() && !listed(&rule.allowed_selectors) {
+        return Err(violation(call, "function is not allowed"));
+    }
+    if listed(&rule.denied_selectors) {
+        return Err(violation(call, "function is denied"));
+    }
+    if let Some(max_value) = &rule.max_value {
+        if call.value > parse_quantity("max value", max_value)? {
+            return Err(violation(call, &format!("value {} exceeds maximum {}", call.value, max_value)));
+        }
+    }
+
+    let (recipient, amount) = token_arguments(call);
+    if !rule.allowed_recipients.is_empty() {
+        let allowed = recipient.is_some_and(|recipient| {
+            rule.allowed_recipients.iter().any(|listed| listed.parse::<Address>().ok() == Some(recipient))
+        });
+        if !allowed {
+            return Err(violation(call, "recipient is not allowed"));
+        }
+    }
+    if let Some(max_amount) = &rule.max_transfer_amount {
+        // Like the recipient, an amount that cannot be read cannot be allowed
+        let amount = amount.ok_or_else(|| violation(call, "transfer amount cannot be read"))?;
+        if amount > parse_quantity("max transfer amount", max_amount)? {
+            return Err(violation(call, &format!("transfer amount {} exceeds maximum {}", amount, max_amount)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Recipient and amount of a token call; a plain value transfer's are its target and value
+fn token_arguments(call: &Call) -> (Option<Address>, Option<U256>) {
+    if call.data.is_empty() {
+        return (Some(call.target), Some(call.value));
+    }
+    let Some(token_call) = TOKEN_SELECTORS.iter().find(|token_call| Some(token_call.selector) == call.selector()) else {
+        return (None, None);
+    };
+
+    let word = |index: usize| call.data.get(4 + 32 * index..4 + 32 * (index + 1));
+    let recipient = word(token_call.recipient)
+        .filter(|word| word[..12].iter().all(|byte| *byte == 0))
+        .map(|word| Address::from_slice(&word[12..]));
+    let amount = token_call.amount.and_then(word).map(U256::from_big_endian);
+    (recipient, amount)
+}
+
+fn violation(call: &Call, reason: &str) -> PaymasterError {
+    let selector = call.selector()
+        .map(|selector| format!("0x{}", ethers::utils::hex::encode(selector)))
+        .unwrap_or_else(|| "value transfer".to_string());
+    PaymasterError::PolicyViolation(format!("Call to {:?} ({}): {}", call.target, selector, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::{encode, Token};
+
+    fn address(byte: u8) -> Address {
+        Address::repeat_byte(byte)
+    }
+
+    fn call(target: Address, signature: &str, arguments: &[Token]) -> Call {
+        Call { target, value: U256::zero(), data: [id(signature).to_vec(), encode(arguments)].concat() }
+    }
+
+    fn transfer(to: Address, amount: u64) -> Call {
+        call(address(0x11), "transfer(address,uint256)", &[Token::Address(to), Token::Uint(amount.into())])
+    }
+
+    #[test]
+    fn parses_signatures_and_hex_selectors() {
+        assert_eq!(parse_selector("mint(address,uint256)"), Some([0x40, 0xc1, 0x0f, 0x19]));
+        assert_eq!(parse_selector("mint(address, uint256)"), Some([0x40, 0xc1, 0x0f, 0x19]));
+        assert_eq!(parse_selector("0x40c10f19"), Some([0x40, 0xc1, 0x0f, 0x19]));
+        assert_eq!(parse_selector("0x40c10f"), None);
+        assert_eq!(parse_selector("mint"), None);
+    }
+
+    #[test]
+    fn selectors_are_allowed_or_denied_per_target() {
+        let rules = vec![CallRule {
+            target: format!("{:?}", address(0x11)),
+            allowed_selectors: vec!["mint(address,uint256)".to_string()],
+            ..CallRule::default()
+        }];
+        let mint = call(address(0x11), "mint(address,uint256)", &[Token::Address(address(0x33)), Token::Uint(1.into())]);
+
+        assert!(check_call_rules(&rules, std::slice::from_ref(&mint)).is_ok());
+        assert!(check_call_rules(&rules, &[mint.clone(), transfer(address(0x33), 1)]).is_err());
+        // Same function on a contract without a rule
+        assert!(check_call_rules(&rules, &[Call { target: address(0x22), ..mint }]).is_err());
+
+        let rules = vec![CallRule {
+            target: format!("{:?}", address(0x11)),
+            denied_selectors: vec!["0xa9059cbb".to_string()],
+            ..CallRule::default()
+        }];
+        assert!(check_call_rules(&rules, &[transfer(address(0x33), 1)]).is_err());
+    }
+
+    #[test]
+    fn arguments_are_constrained() {
+        let rules = vec![CallRule {
+            target: format!("{:?}", address(0x11)),
+            max_value: Some("100".to_string()),
+            allowed_recipients: vec![format!("{:?}", address(0x33))],
+            max_transfer_amount: Some("1000".to_string()),
+            ..CallRule::default()
+        }];
+
+        assert!(check_call_rules(&rules, &[transfer(address(0x33), 1000)]).is_ok());
+        assert!(check_call_rules(&rules, &[transfer(address(0x33), 1001)]).is_err());
+        assert!(check_call_rules(&rules, &[transfer(address(0x44), 1)]).is_err());
+        assert!(check_call_rules(&rules, &[Call { value: 101.into(), ..transfer(address(0x33), 1) }]).is_err());
+
+        // No recipient to check against the allow-list
+        let unknown = call(address(0x11), "setApprovalForAll(address,bool)", &[Token::Address(address(0x33)), Token::Bool(true)]);
+        assert!(check_call_rules(&rules, &[unknown]).is_err());
+    }
+
+    #[test]
+    fn amounts_that_cannot_be_read_exceed_any_maximum() {
+        let rules = vec![CallRule {
+            target: format!("{:?}", address(0x11)),
+            max_transfer_amount: Some("1000".to_string()),
+            ..CallRule::default()
+        }];
+        let to = Token::Address(address(0x33));
+
+        let erc1155 = |amount: u64| call(address(0x11), "safeTransferFrom(address,address,uint256,uint256,bytes)", &[
+            Token::Address(address(0x22)), to.clone(), Token::Uint(7.into()), Token::Uint(amount.into()), Token::Bytes(vec![]),
+        ]);
+        assert!(check_call_rules(&rules, &[erc1155(1000)]).is_ok());
+        assert!(check_call_rules(&rules, &[erc1155(1001)]).is_err());
+        let increase = call(address(0x11), "increaseAllowance(address,uint256)", &[to.clone(), Token::Uint(1001.into())]);
+        assert!(check_call_rules(&rules, &[increase]).is_err());
+
+        // A mint's second argument may be an amount or a token id
+        let mint = call(address(0x11), "mint(address,uint256)", &[to.clone(), Token::Uint(1.into())]);
+        assert!(check_call_rules(&rules, &[mint]).is_err());
+        let value = Call { target: address(0x11), value: 1001.into(), data: vec![] };
+        assert!(check_call_rules(&rules, &[value]).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use ethers::abi::{self, ParamType, Token};
+use ethers::types::{Address, U256};
+use ethers::utils::id;
+use once_cell::sync::Lazy;
+
+/// How an account's execute function lays out the calls it makes
+#[derive(Debug, Clone, Copy)]
+enum Layout {
+    /// `(address target, uint256 value, bytes data)`
+    Single,
+    /// `(address target, uint256 value, bytes data, uint8 operation)`, operation 1 being a delegatecall
+    SingleWithOperation,
+    /// `(address[] targets, bytes[] data)`
+    BatchTargets,
+    /// `(address[] targets, uint256[] values, bytes[] data)`
+    BatchTargetsValues,
+    /// `((address target, uint256 value, bytes data)[] calls)`
+    BatchCalls,
+    /// ERC-7579 `(bytes32 mode, bytes executionCalldata)`
+    Erc7579,
+    /// EntryPoint v0.7 `executeUserOp` prefix followed by the account's own calldata
+    ExecuteUserOp,
+}
+
+const LAYOUTS: &[(&str, Layout)] = &[
+    // SimpleAccount, LightAccount, Biconomy
+    ("execute(address,uint256,bytes)", Layout::Single),
+    ("execute_ncC(address,uint256,bytes)", Layout::Single),
+    // Kernel v2
+    ("execute(address,uint256,bytes,uint8)", Layout::SingleWithOperation),
+    // Safe4337Module
+    ("executeUserOp(address,uint256,bytes,uint8)", Layout::SingleWithOperation),
+    ("executeUserOpWithErrorString(address,uint256,bytes,uint8)", Layout::SingleWithOperation),
+    // SimpleAccount v0.6, LightAccount
+    ("executeBatch(address[],bytes[])", Layout::BatchTargets),
+    // SimpleAccount v0.7, LightAccount, Biconomy
+    ("executeBatch(address[],uint256[],bytes[])", Layout::BatchTargetsValues),
+    ("executeBatch_y6U(address[],uint256[],bytes[])", Layout::BatchTargetsValues),
+    // Kernel v2, SimpleAccount v0.8
+    ("executeBatch((address,uint256,bytes)[])", Layout::BatchCalls),
+    // Kernel v3 and other ERC-7579 accounts
+    ("execute(bytes32,bytes)", Layout::Erc7579),
+    (
+        "executeUserOp((address,uint256,bytes,bytes,bytes32,uint256,bytes32,bytes,bytes),bytes32)",
+        Layout::ExecuteUserOp,
+    ),
+];
+
+static SELECTORS: Lazy<Vec<([u8; 4], Layout)>> = Lazy::new(|| {
+    LAYOUTS.iter().map(|(signature, layout)| (id(signature), *layout)).collect()
+});
+
+/// Safe's MultiSend and MultiSendCallOnly deployments (v1.3.0 and v1.4.1), the only
+/// delegatecall targets whose effect can be read from the calldata
+const MULTI_SEND: &[&str] = &[
+    "0xA238CBeb142c10Ef7Ad8442C6D1f9E89e07e7761",
+    "0x40A2aCCbd92BCA938b02010E17A5b8929b49130D",
+    "0x38869bf66a61cF6bDB996A6aE40D5853Fd43B526",
+    "0x9641d764fc13c8B624c04430C7356C1C7C8102e2",
+];
+
+const DELEGATE_CALL: u8 = 1;
+
+/// One call a smart account makes while executing its calldata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    pub target: Address,
+    pub value: U256,
+    pub data: Vec<u8>,
+}
+
+impl Call {
+    /// The function selector, if the call carries one
+    pub fn selector(&self) -> Option<[u8; 4]> {
+        self.data.get(..4).map(|selector| selector.try_into().expect("4 bytes"))
+    }
+}
+
+/// The contracts a smart account would call when executing `call_data`
+///
+/// Understands the execute functions of SimpleAccount, LightAccount, Kernel, Safe4337 and
+/// Biconomy accounts. Returns `None` for any other layout, including delegatecalls whose
+/// effect cannot be read from the calldata. Empty calldata makes no calls.
+pub fn decode_call_targets(call_data: &[u8]) -> Option<Vec<Address>> {
+    decode_calls(call_data).map(|calls| calls.into_iter().map(|call| call.target).collect())
+}
+
+/// The calls a smart account would make when executing `call_data`; see `decode_call_targets`
+pub fn decode_calls(call_data: &[u8]) -> Option<Vec<Call>> {
+    decode(call_data, true)
+}
+
+fn decode(call_data: &[u8], allow_prefix: bool) -> Option<Vec<Call>> {
+    if call_data.is_empty() {
+        return Some(Vec::new());
+    }
+    if call_data.len() < 4 {
+        return None;
+    }
+
+    let (selector, arguments) = call_data.split_at(4);
+    let layout = SELECTORS.iter()
+        .find(|(known, _)| known == selector)
+        .map(|(_, layout)| *layout)?;
+
+    match layout {
+        Layout::Single => {
+            let tokens = abi::decode(&[ParamType::Address, ParamType::Uint(256), ParamType::Bytes], arguments).ok()?;
+            Some(vec![execution(tokens)?])
+        }
+        Layout::SingleWithOperation => {
+            let tokens = abi::decode(
+                &[ParamType::Address, ParamType::Uint(256), ParamType::Bytes, ParamType::Uint(8)],
+                arguments,
+            ).ok()?;
+            if tokens[3].clone().into_uint()? != DELEGATE_CALL.into() {
+                return Some(vec![execution(tokens)?]);
+            }
+            decode_multi_send(tokens[0].clone().into_address()?, &tokens[2].clone().into_bytes()?)
+        }
+        Layout::BatchTargets => {
+            let tokens = abi::decode(
+                &[ParamType::Array(Box::new(ParamType::Address)), ParamType::Array(Box::new(ParamType::Bytes))],
+                arguments,
+            ).ok()?;
+            let targets = addresses(&tokens[0])?;
+            let data = tokens[1].clone().into_array()?;
+            let values = vec![Token::Uint(U256::zero()); targets.len()];
+            batch(targets, values, data)
+        }
+        Layout::BatchTargetsValues => {
+            let tokens = abi::decode(
+                &[
+                    ParamType::Array(Box::new(ParamType::Address)),
+                    ParamType::Array(Box::new(ParamType::Uint(256))),
+                    ParamType::Array(Box::new(ParamType::Bytes)),
+                ],
+                arguments,
+            ).ok()?;
+            let targets = addresses(&tokens[0])?;
+            batch(targets, tokens[1].clone().into_array()?, tokens[2].clone().into_array()?)
+        }
+        Layout::BatchCalls => decode_executions(arguments),
+        Layout::Erc7579 => {
+            let tokens = abi::decode(&[ParamType::FixedBytes(32), ParamType::Bytes], arguments).ok()?;
+            let mode = tokens[0].clone().into_fixed_bytes()?;
+            let execution = tokens[1].clone().into_bytes()?;
+            match mode[0] {
+                // Single call: abi.encodePacked(target, value, data)
+                0x00 => (execution.len() >= 52).then(|| vec![Call {
+                    target: Address::from_slice(&execution[..20]),
+                    value: U256::from_big_endian(&execution[20..52]),
+                    data: execution[52..].to_vec(),
+                }]),
+                // Batch: abi.encode(Execution[])
+                0x01 => decode_executions(&execution),
+                // Delegatecall and anything newer
+                _ => None,
+            }
+        }
+        Layout::ExecuteUserOp if allow_prefix => decode(arguments, false),
+        Layout::ExecuteUserOp => None,
+    }
+}
+
+/// `abi.encode((address target, uint256 value, bytes data)[])`
+fn decode_executions(data: &[u8]) -> Option<Vec<Call>> {
+    let execution_type = ParamType::Tuple(vec![ParamType::Address, ParamType::Uint(256), ParamType::Bytes]);
+    let tokens = abi::decode(&[ParamType::Array(Box::new(execution_type))], data).ok()?;
+
+    tokens[0].clone().into_array()?
+        .into_iter()
+        .map(|call| execution(call.into_tuple()?))
+        .collect()
+}
+
+/// A call from decoded `(address target, uint256 value, bytes data, ...)` tokens
+fn execution(tokens: Vec<Token>) -> Option<Call> {
+    let mut tokens = tokens.into_iter();
+    Some(Call {
+        target: tokens.next()?.into_address()?,
+        value: tokens.next()?.into_uint()?,
+        data: tokens.next()?.into_bytes()?,
+    })
+}
+
+/// Calls from parallel target, value and data arrays, which must be the same length
+fn batch(targets: Vec<Address>, values: Vec<Token>, data: Vec<Token>) -> Option<Vec<Call>> {
+    if values.len() != targets.len() || data.len() != targets.len() {
+        return None;
+    }
+    targets.into_iter()
+        .zip(values.into_iter().zip(data))
+        .map(|(target, (value, data))| Some(Call { target, value: value.into_uint()?, data: data.into_bytes()? }))
+        .collect()
+}
+
+/// Calls batched through a delegatecall to Safe's `multiSend(bytes)`, packed as
+/// `operation (1) | to (20) | value (32) | data length (32) | data` per call
+fn decode_multi_send(delegate: Address, data: &[u8]) -> Option<Vec<Call>> {
+    if !MULTI_SEND.iter().any(|address| address.parse::<Address>().ok() == Some(delegate)) {
+        return None;
+    }
+    if data.len() < 4 || data[..4] != id("multiSend(bytes)") {
+        return None;
+    }
+
+    let transactions = abi::decode(&[ParamType::Bytes], &data[4..]).ok()?[0].clone().into_bytes()?;
+    let mut calls = Vec::new();
+    let mut offset = 0;
+    while offset < transactions.len() {
+        let header = transactions.get(offset..offset + 85)?;
+        if header[0] == DELEGATE_CALL {
+            return None;
+        }
+        let length = U256::from_big_endian(&header[53..85]);
+        if length > transactions.len().into() {
+            return None;
+        }
+        let data = transactions.get(offset + 85..offset + 85 + length.as_usize())?;
+        calls.push(Call {
+            target: Address::from_slice(&header[1..21]),
+            value: U256::from_big_endian(&header[21..53]),
+            data: data.to_vec(),
+        });
+        offset += 85 + length.as_usize();
+    }
+    Some(calls)
+}
+
+fn addresses(token: &Token) -> Option<Vec<Address>> {
+    token.clone().into_array()?.into_iter().map(Token::into_address).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::Bytes;
+
+    fn call(signature: &str, arguments: &[Token]) -> Vec<u8> {
+        [id(signature).to_vec(), abi::encode(arguments)].concat()
+    }
+
+    fn address(byte: u8) -> Address {
+        Address::repeat_byte(byte)
+    }
+
+    fn inner_call() -> Token {
+        // An ERC-20 transfer mentioning address(0x33) only as an argument
+        Token::Bytes(call("transfer(address,uint256)", &[Token::Address(address(0x33)), Token::Uint(1.into())]))
+    }
+
+    #[test]
+    fn selectors_match_the_deployed_accounts() {
+        let selector = |signature: &str| format!("0x{}", ethers::utils::hex::encode(id(signature)));
+        assert_eq!(selector("execute(address,uint256,bytes)"), "0xb61d27f6");
+        assert_eq!(selector("executeBatch(address[],bytes[])"), "0x18dfb3c7");
+        assert_eq!(selector("executeBatch(address[],uint256[],bytes[])"), "0x47e1da2a");
+        assert_eq!(selector("executeBatch((address,uint256,bytes)[])"), "0x34fcd5be");
+        assert_eq!(selector("execute(bytes32,bytes)"), "0xe9ae5c53");
+        assert_eq!(selector("executeUserOp(address,uint256,bytes,uint8)"), "0x7bb37428");
+        assert_eq!(selector("execute_ncC(address,uint256,bytes)"), "0x0000189a");
+        assert_eq!(selector("executeBatch_y6U(address[],uint256[],bytes[])"), "0x00004680");
+        assert_eq!(
+            selector("executeUserOp((address,uint256,bytes,bytes,bytes32,uint256,bytes32,bytes,bytes),bytes32)"),
+            "0x8dd7712f"
+        );
+    }
+
+    #[test]
+    fn decodes_single_calls_by_target_not_by_argument() {
+        let data = call("execute(address,uint256,bytes)", &[Token::Address(address(0x11)), Token::Uint(0.into()), inner_call()]);
+        assert_eq!(decode_call_targets(&data), Some(vec![address(0x11)]));
+    }
+
+    #[test]
+    fn decodes_batches() {
+        let targets = Token::Array(vec![Token::Address(address(0x11)), Token::Address(address(0x22))]);
+        let data = call("executeBatch(address[],bytes[])", &[targets, Token::Array(vec![inner_call(), inner_call()])]);
+        assert_eq!(decode_call_targets(&data), Some(vec![address(0x11), address(0x22)]));
+
+        // Mismatched array lengths are malformed
+        let data = call("executeBatch(address[],bytes[])", &[Token::Array(vec![Token::Address(address(0x11))]), Token::Array(vec![])]);
+        assert_eq!(decode_call_targets(&data), None);
+
+        let calls = Token::Array(vec![
+            Token::Tuple(vec![Token::Address(address(0x11)), Token::Uint(0.into()), inner_call()]),
+            Token::Tuple(vec![Token::Address(address(0x22)), Token::Uint(0.into()), inner_call()]),
+        ]);
+        let data = call("executeBatch((address,uint256,bytes)[])", std::slice::from_ref(&calls));
+        assert_eq!(decode_call_targets(&data), Some(vec![address(0x11), address(0x22)]));
+
+        // Kernel v3: ERC-7579 batch mode behind the v0.7 executeUserOp prefix
+        let mut mode = [0u8; 32];
+        mode[0] = 0x01;
+        let execute = call("execute(bytes32,bytes)", &[Token::FixedBytes(mode.to_vec()), Token::Bytes(abi::encode(&[calls]))]);
+        let data = [id("executeUserOp((address,uint256,bytes,bytes,bytes32,uint256,bytes32,bytes,bytes),bytes32)").to_vec(), execute].concat();
+        assert_eq!(decode_call_targets(&data), Some(vec![address(0x11), address(0x22)]));
+    }
+
+    #[test]
+    fn decodes_erc7579_single_calls() {
+        let mut value = [0u8; 32];
+        U256::from(7).to_big_endian(&mut value);
+        let execution = [address(0x11).as_bytes(), &value, &[0xab, 0xcd, 0xef, 0x01]].concat();
+        let data = call("execute(bytes32,bytes)", &[Token::FixedBytes(vec![0; 32]), Token::Bytes(execution)]);
+
+        let calls = decode_calls(&data).unwrap();
+        assert_eq!(calls, vec![Call { target: address(0x11), value: 7.into(), data: vec![0xab, 0xcd, 0xef, 0x01] }]);
+        assert_eq!(calls[0].selector(), Some([0xab, 0xcd, 0xef, 0x01]));
+    }
+
+    #[test]
+    fn decodes_safe_multi_send_batches() {
+        let packed: Vec<u8> = [address(0x11), address(0x22)].iter()
+            .flat_map(|to| {
+                let mut length = [0u8; 32];
+                U256::from(2).to_big_endian(&mut length);
+                [&[0u8][..], to.as_bytes(), &[0u8; 32], &length, &[0xab, 0xcd]].concat()
+            })
+            .collect();
+        let multi_send = call("multiSend(bytes)", &[Token::Bytes(packed)]);
+        let delegate = MULTI_SEND[1].parse::<Address>().unwrap();
+
+        let data = call(
+            "executeUserOp(address,uint256,bytes,uint8)",
+            &[Token::Address(delegate), Token::Uint(0.into()), Token::Bytes(multi_send.clone()), Token::Uint(1.into())],
+        );
+        assert_eq!(decode_call_targets(&data), Some(vec![address(0x11), address(0x22)]));
+
+        // A delegatecall to anything else could do anything
+        let data = call(
+            "executeUserOp(address,uint256,bytes,uint8)",
+            &[Token::Address(address(0x44)), Token::Uint(0.into()), Token::Bytes(multi_send), Token::Uint(1.into())],
+        );
+        assert_eq!(decode_call_targets(&data), None);
+    }
+
+    #[test]
+    fn unknown_layouts_are_not_guessed() {
+        assert_eq!(decode_call_targets(&[]), Some(vec![]));
+        assert_eq!(decode_call_targets(&[0xde, 0xad, 0xbe, 0xef]), None);
+        assert_eq!(decode_call_targets(Bytes::from(vec![0xb6, 0x1d]).as_ref()), None);
+    }
+}
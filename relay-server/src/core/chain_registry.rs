@@ -0,0 +1,212 @@
+use crate::config::Settings;
+use crate::core::l1_fee::{L1FeeEstimator, L1FeeModel};
+use crate::core::simulation::UserOperationSimulator;
+use crate::core::types::*;
+use crate::core::user_operation::EntryPointVersion;
+use ethers::prelude::*;
+use reqwest::Client;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::info;
+
+/// A chain the paymaster serves, with its own RPC provider, EntryPoints, paymaster and signer
+pub struct Chain {
+    pub chain_id: u64,
+    pub name: String,
+    pub provider: Arc<Provider<Http>>,
+    pub entry_points: HashMap<Address, EntryPointVersion>,
+    pub paymaster_address: Address,
+    pub signer: LocalWallet,
+    pub tokens: Vec<TokenConfig>,
+    pub simulator: UserOperationSimulator,
+    pub l1_fee_model: L1FeeModel,
+}
+
+impl Chain {
+    fn connect(config: &ChainConfig, settings: &Settings, client: &Client) -> PaymasterResult<Self> {
+        let invalid = |field: &str, error: String| {
+            PaymasterError::ConfigurationError(format!("Invalid {} for chain {}: {}", field, config.chain_id, error))
+        };
+
+        let url = config.rpc_url.parse::<reqwest::Url>().map_err(|e| invalid("RPC URL", e.to_string()))?;
+        let provider = Arc::new(Provider::new(Http::new_with_client(url, client.clone())));
+
+        let mut entry_points = HashMap::new();
+        for entry_point in &config.entry_points {
+            let version = EntryPointVersion::from_address(entry_point).map_err(|e| invalid("entry point", e.to_string()))?;
+            entry_points.insert(parse_config_address(entry_point).map_err(|e| invalid("entry point", e))?, version);
+        }
+
+        let signer = config.private_key.as_deref()
+            .unwrap_or(&settings.paymaster.private_key)
+            .parse::<LocalWallet>()
+            .map_err(|e| invalid("private key", e.to_string()))?;
+
+        let paymaster_address = parse_config_address(config.paymaster_address.as_deref().unwrap_or(&settings.paymaster.address))
+            .map_err(|e| invalid("paymaster address", e))?;
+
+        let mut simulations = HashMap::new();
+        for (version, address) in [
+            (EntryPointVersion::V07, &config.entry_point_simulations_v07),
+            (EntryPointVersion::V08, &config.entry_point_simulations_v08),
+        ] {
+            if let Some(address) = address {
+                simulations.insert(version, parse_config_address(address).map_err(|e| invalid("EntryPointSimulations address", e))?);
+            }
+        }
+        let simulator = UserOperationSimulator::new(provider.clone(), simulations, settings.policy.max_call_gas_limit);
+        let l1_fee_model = L1FeeModel::for_chain(config)?;
+
+        Ok(Self {
+            chain_id: config.chain_id,
+            name: config.name.clone(),
+            provider,
+            entry_points,
+            paymaster_address,
+            signer,
+            tokens: config.tokens.clone(),
+            simulator,
+            l1_fee_model,
+        })
+    }
+
+    /// Resolve an EntryPoint's version, rejecting EntryPoints this chain does not serve
+    pub fn entry_point_version(&self, entry_point: &str) -> PaymasterResult<EntryPointVersion> {
+        let version = EntryPointVersion::from_address(entry_point)?;
+        let served = entry_point.parse::<Address>().is_ok_and(|address| self.entry_points.contains_key(&address));
+        if !served {
+            return Err(PaymasterError::InvalidUserOperation(
+                format!("Entry point {} is not served on chain {}", entry_point, self.chain_id)
+            ));
+        }
+
+        Ok(version)
+    }
+
+    /// The accepted token at `address`
+    pub fn token(&self, address: &str) -> Option<&TokenConfig> {
+        self.tokens.iter().find(|token| token.address.eq_ignore_ascii_case(address))
+    }
+}
+
+/// Every chain loaded from `[[blockchain.chains]]`, keyed by chain id
+pub struct ChainRegistry {
+    chains: HashMap<u64, Chain>,
+    default_chain_id: u64,
+}
+
+impl ChainRegistry {
+    /// Connect to each configured chain; chains without their own key or paymaster use `[paymaster]`'s
+    pub fn new(settings: &Settings) -> PaymasterResult<Self> {
+        // One HTTP client, so its connection pool and TLS roots are shared across chains
+        let client = Client::new();
+
+        let mut chains = HashMap::new();
+        for config in &settings.blockchain.chains {
+            let chain = Chain::connect(config, settings, &client)?;
+            if chains.insert(config.chain_id, chain).is_some() {
+                return Err(PaymasterError::ConfigurationError(format!("Chain {} is configured twice", config.chain_id)));
+            }
+        }
+
+        let default_chain_id = settings.blockchain.default_chain_id;
+        if !chains.contains_key(&default_chain_id) {
+            return Err(PaymasterError::ConfigurationError(
+                format!("Default chain {} is not configured", default_chain_id)
+            ));
+        }
+
+        let mut chain_ids: Vec<_> = chains.keys().collect();
+        chain_ids.sort();
+        info!("Serving chains {:?}", chain_ids);
+
+        Ok(Self { chains, default_chain_id })
+    }
+
+    /// The chain with this id, or `UnsupportedChain`
+    pub fn get(&self, chain_id: u64) -> PaymasterResult<&Chain> {
+        self.chains.get(&chain_id).ok_or(PaymasterError::UnsupportedChain(chain_id))
+    }
+
+    /// Chain for requests that do not name one
+    pub fn default_chain_id(&self) -> u64 {
+        self.default_chain_id
+    }
+
+    pub fn chains(&self) -> impl Iterator<Item = &Chain> {
+        self.chains.values()
+    }
+
+    /// The RPC provider of each chain, by chain id
+    pub fn providers(&self) -> HashMap<u64, Arc<Provider<Http>>> {
+        self.chains.iter().map(|(chain_id, chain)| (*chain_id, chain.provider.clone())).collect()
+    }
+
+    /// Estimator of each chain's L1 data fee, through its RPC provider
+    pub fn l1_fees(&self) -> L1FeeEstimator {
+        L1FeeEstimator::new(self.chains.iter()
+            .map(|(chain_id, chain)| (*chain_id, (chain.provider.clone(), chain.l1_fee_model)))
+            .collect())
+    }
+}
+
+fn parse_config_address(value: &str) -> Result<Address, String> {
+    value.parse::<Address>().map_err(|_| format!("{:?} is not an address", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::user_operation::{ENTRY_POINT_V06, ENTRY_POINT_V07};
+    use crate::test_support::PAYMASTER;
+
+    const BASE_PAYMASTER: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+
+    fn settings() -> Settings {
+        let mut settings = crate::test_support::settings();
+        settings.blockchain.chains.push(ChainConfig {
+            chain_id: 8453,
+            name: "base".to_string(),
+            rpc_url: "https://base-mainnet.g.alchemy.com/v2/YOUR_KEY".to_string(),
+            entry_points: vec![ENTRY_POINT_V07.to_string()],
+            paymaster_address: Some(BASE_PAYMASTER.to_string()),
+            private_key: Some("0x59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d".to_string()),
+            entry_point_simulations_v07: None,
+            entry_point_simulations_v08: None,
+            tokens: vec![],
+            l1_fee_model: None,
+        });
+        settings
+    }
+
+    #[test]
+    fn routes_by_chain_id() {
+        let registry = ChainRegistry::new(&settings()).unwrap();
+
+        let mainnet = registry.get(1).unwrap();
+        assert_eq!(mainnet.paymaster_address, PAYMASTER.parse::<Address>().unwrap());
+        assert_eq!(mainnet.signer.address(), "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266".parse::<Address>().unwrap());
+        assert_eq!(mainnet.entry_point_version(ENTRY_POINT_V06).unwrap(), EntryPointVersion::V06);
+        assert!(mainnet.token("0xdac17f958d2ee523a2206206994597c13d831ec7").is_some());
+
+        let base = registry.get(8453).unwrap();
+        assert_eq!(base.paymaster_address, BASE_PAYMASTER.parse::<Address>().unwrap());
+        assert_eq!(base.signer.address(), BASE_PAYMASTER.parse::<Address>().unwrap());
+        assert_eq!(base.entry_point_version(ENTRY_POINT_V07).unwrap(), EntryPointVersion::V07);
+        assert!(base.entry_point_version(ENTRY_POINT_V06).is_err());
+        assert!(base.token("0xdac17f958d2ee523a2206206994597c13d831ec7").is_none());
+
+        assert!(matches!(registry.get(137), Err(PaymasterError::UnsupportedChain(137))));
+    }
+
+    #[test]
+    fn rejects_duplicate_and_missing_default_chains() {
+        let mut settings = settings();
+        settings.blockchain.chains.push(settings.blockchain.chains[0].clone());
+        assert!(ChainRegistry::new(&settings).is_err());
+
+        let mut settings = self::settings();
+        settings.blockchain.default_chain_id = 10;
+        assert!(ChainRegistry::new(&settings).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use crate::config::settings::{CircuitBreakerSettings, SpendBudget};
+use crate::core::clock::*;
+use crate::core::counter_store::*;
+use crate::core::types::*;
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Scope of the budget covering every chain
+pub const GLOBAL_SCOPE: &str = "global";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum BudgetWindow {
+    Hourly,
+    Daily,
+}
+
+impl BudgetWindow {
+    const ALL: [BudgetWindow; 2] = [BudgetWindow::Hourly, BudgetWindow::Daily];
+
+    fn seconds(self) -> u64 {
+        match self {
+            BudgetWindow::Hourly => 3_600,
+            BudgetWindow::Daily => 86_400,
+        }
+    }
+
+    fn budget(self, budget: &SpendBudget) -> Option<&str> {
+        match self {
+            BudgetWindow::Hourly => budget.hourly_wei.as_deref(),
+            BudgetWindow::Daily => budget.daily_wei.as_deref(),
+        }
+    }
+}
+
+/// Stops sponsorship once the gas cost sponsored within an hour or a day reaches its budget
+///
+/// Spend and trips are kept in the shared counter store, so a breaker tripped by one relay
+/// instance is open on all of them. A tripped scope stays open until the window that tripped
+/// it ends or it is reset.
+pub struct CircuitBreaker {
+    counters: Arc<dyn CounterStore>,
+    clock: Arc<dyn Clock>,
+    settings: CircuitBreakerSettings,
+}
+
+/// Spend against one scope's budgets
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CircuitBreakerStatus {
+    pub scope: String,
+    pub tripped: bool,
+    pub windows: Vec<WindowSpend>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WindowSpend {
+    pub window: BudgetWindow,
+    pub budget_wei: u64,
+    pub spent_wei: u64,
+    pub resets_in_seconds: u64,
+}
+
+impl CircuitBreaker {
+    pub fn new(counters: Arc<dyn CounterStore>, clock: Arc<dyn Clock>, settings: CircuitBreakerSettings) -> Self {
+        Self { counters, clock, settings }
+    }
+
+    /// Count `amount` wei against every budget covering `chain_id`
+    ///
+    /// Returns the window counters it was added to, for the caller to settle or release. An amount
+    /// that would exceed a budget is refused and nothing is counted; a scope trips once its spend
+    /// reaches the budget.
+    pub async fn reserve(&self, chain_id: u64, amount: u64) -> PaymasterResult<Vec<String>> {
+        let scopes = self.scopes_covering(chain_id);
+        for (scope, _) in &scopes {
+            if self.is_tripped(scope).await? {
+                return Err(PaymasterError::CircuitBreakerOpen {
+                    scope: scope.clone(),
+                    reason: "sponsorship is paused until the budget window ends or the breaker is reset".to_string(),
+                });
+            }
+        }
+
+        let mut counted = Vec::new();
+        let mut spent = Vec::new();
+        for (scope, budget) in &scopes {
+            for window in BudgetWindow::ALL {
+                let Some(limit) = window.budget(budget) else {
+                    continue;
+                };
+                let limit = parse_budget(scope, limit)?;
+                let key = self.spend_key(scope, window);
+                let total = self.counters.increment(&key, amount, window.seconds()).await?;
+                counted.push(key);
+
+                if total > limit {
+                    // Too big for what is left, which says nothing about the budget being spent
+                    for key in &counted {
+                        self.counters.decrement(key, amount).await?;
+                    }
+                    return Err(PaymasterError::PolicyViolation(format!(
+                        "{:?} budget of {} exceeded: {} + {} > {} wei",
+                        window, scope, total - amount, amount, limit
+                    )));
+                }
+                if total == limit {
+                    spent.push((scope, window));
+                }
+            }
+        }
+        for (scope, window) in spent {
+            self.trip(scope, window).await?;
+        }
+        Ok(counted)
+    }
+
+    /// Spend and state of every scope with a budget
+    pub async fn status(&self) -> PaymasterResult<Vec<CircuitBreakerStatus>> {
+        let mut statuses = Vec::new();
+        for (scope, budget) in self.scopes() {
+            let mut windows = Vec::new();
+            for window in BudgetWindow::ALL {
+                let Some(limit) = window.budget(budget) else {
+                    continue;
+                };
+                windows.push(WindowSpend {
+                    window,
+                    budget_wei: parse_budget(&scope, limit)?,
+                    spent_wei: self.counters.get(&self.spend_key(&scope, window)).await?,
+                    resets_in_seconds: self.remaining_seconds(window),
+                });
+            }
+            statuses.push(CircuitBreakerStatus { tripped: self.is_tripped(&scope).await?, scope, windows });
+        }
+        Ok(statuses)
+    }
+
+    /// Close a scope's breaker and clear its current spend; returns false for an unknown scope
+    pub async fn reset(&self, scope: &str) -> PaymasterResult<bool> {
+        if !self.scopes().iter().any(|(known, _)| known == scope) {
+            return Ok(false);
+        }
+
+        self.counters.delete(&trip_key(scope)).await?;
+        for window in BudgetWindow::ALL {
+            self.counters.delete(&self.spend_key(scope, window)).await?;
+        }
+        Ok(true)
+    }
+
+    /// Scopes with a budget: the global one, then each chain in id order
+    fn scopes(&self) -> Vec<(String, &SpendBudget)> {
+        let mut chains: Vec<(u64, &SpendBudget)> = self.settings.chains.iter()
+            .filter_map(|(chain_id, budget)| Some((chain_id.parse().ok()?, budget)))
+            .collect();
+        chains.sort_by_key(|(chain_id, _)| *chain_id);
+
+        std::iter::once((GLOBAL_SCOPE.to_string(), &self.settings.global))
+            .chain(chains.into_iter().map(|(chain_id, budget)| (chain_scope(chain_id), budget)))
+            .filter(|(_, budget)| budget.hourly_wei.is_some() || budget.daily_wei.is_some())
+            .collect()
+    }
+
+    fn scopes_covering(&self, chain_id: u64) -> Vec<(String, &SpendBudget)> {
+        let chain = chain_scope(chain_id);
+        self.scopes().into_iter()
+            .filter(|(scope, _)| scope == GLOBAL_SCOPE || *scope == chain)
+            .collect()
+    }
+
+    async fn is_tripped(&self, scope: &str) -> PaymasterResult<bool> {
+        Ok(self.counters.get(&trip_key(scope)).await? > 0)
+    }
+
+    async fn trip(&self, scope: &str, window: BudgetWindow) -> PaymasterResult<()> {
+        let open_seconds = self.remaining_seconds(window);
+        self.counters.increment(&trip_key(scope), 1, open_seconds).await?;
+        warn!("Circuit breaker for {} tripped on its {:?} budget; open for {}s", scope, window, open_seconds);
+        Ok(())
+    }
+
+    /// Counter of the spend in the window that is current now
+    fn spend_key(&self, scope: &str, window: BudgetWindow) -> String {
+        format!("circuit_breaker:{}:{:?}:{}", scope, window, self.clock.now_seconds() / window.seconds())
+    }
+
+    fn remaining_seconds(&self, window: BudgetWindow) -> u64 {
+        window.seconds() - self.clock.now_seconds() % window.seconds()
+    }
+}
+
+fn chain_scope(chain_id: u64) -> String {
+    format!("chain:{}", chain_id)
+}
+
+fn trip_key(scope: &str) -> String {
+    format!("circuit_breaker:{}:tripped", scope)
+}
+
+fn parse_budget(scope: &str, budget: &str) -> PaymasterResult<u64> {
+    let wei = parse_quantity("circuit breaker budget", budget)?;
+    u64::try_from(wei).map_err(|_| {
+        PaymasterError::ConfigurationError(format!("Circuit breaker budget for {} out of range: {}", scope, budget))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> (CircuitBreaker, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new(0));
+        let mut settings = CircuitBreakerSettings {
+            global: SpendBudget { hourly_wei: None, daily_wei: Some("250".to_string()) },
+            ..CircuitBreakerSettings::default()
+        };
+        settings.chains.insert("8453".to_string(), SpendBudget { hourly_wei: Some("100".to_string()), daily_wei: None });
+        let counters = Arc::new(MemoryCounterStore::with_clock(clock.clone()));
+        (CircuitBreaker::new(counters, clock.clone(), settings), clock)
+    }
+
+    fn is_open(result: PaymasterResult<Vec<String>>, expected_scope: &str) -> bool {
+        matches!(result, Err(PaymasterError::CircuitBreakerOpen { scope, .. }) if scope == expected_scope)
+    }
+
+    #[tokio::test]
+    async fn trips_per_chain_and_stays_open_until_the_window_ends() {
+        let (breaker, clock) = breaker();
+
+        assert_eq!(breaker.reserve(8453, 60).await.unwrap().len(), 2);
+        assert!(breaker.reserve(8453, 40).await.is_ok());
+        assert!(is_open(breaker.reserve(8453, 1).await, "chain:8453"));
+        assert_eq!(breaker.reserve(1, 60).await.unwrap(), vec!["circuit_breaker:global:Daily:0".to_string()]);
+
+        clock.set_millis(3_600_000);
+        assert!(breaker.reserve(8453, 60).await.is_ok());
+        // 60 + 40 + 60 + 60 of the daily 250 are spent
+        assert!(breaker.reserve(1, 30).await.is_ok());
+        assert!(is_open(breaker.reserve(8453, 1).await, GLOBAL_SCOPE));
+    }
+
+    #[tokio::test]
+    async fn refuses_an_oversized_amount_without_tripping() {
+        let (breaker, _clock) = breaker();
+        breaker.reserve(8453, 60).await.unwrap();
+
+        assert!(matches!(breaker.reserve(8453, 41).await, Err(PaymasterError::PolicyViolation(_))));
+        assert!(matches!(breaker.reserve(8453, 1_000).await, Err(PaymasterError::PolicyViolation(_))));
+        assert!(!breaker.status().await.unwrap()[1].tripped);
+        // Nothing of the refused amounts was counted, globally or on the chain
+        assert!(breaker.reserve(8453, 40).await.is_ok());
+        assert_eq!(breaker.status().await.unwrap()[0].windows[0].spent_wei, 100);
+    }
+
+    #[tokio::test]
+    async fn reset_closes_the_breaker_and_clears_its_spend() {
+        let (breaker, _clock) = breaker();
+        breaker.reserve(8453, 100).await.unwrap();
+        assert!(breaker.reserve(8453, 1).await.is_err());
+
+        let status = breaker.status().await.unwrap();
+        assert_eq!(status[1].scope, "chain:8453");
+        assert!(status[1].tripped);
+        assert_eq!((status[1].windows[0].spent_wei, status[1].windows[0].resets_in_seconds), (100, 3_600));
+
+        assert!(breaker.reset("chain:8453").await.unwrap());
+        assert!(!breaker.reset("chain:10").await.unwrap());
+        assert!(breaker.reserve(8453, 100).await.is_ok());
+        assert_eq!(breaker.status().await.unwrap()[0].windows[0].spent_wei, 200);
+    }
+}
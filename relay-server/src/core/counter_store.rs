@@ -0,0 +1,402 @@
+use crate::core::clock::*;
+use crate::core::types::*;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OnceCell};
+
+/// Shared counters backing the windowed rate limits
+///
+/// `increment` must be atomic: concurrent callers each observe a distinct total.
+/// A caller that pushes a counter past its limit undoes its own increment with `decrement`,
+/// so the amount committed within a window never exceeds the limit.
+#[async_trait]
+pub trait CounterStore: Send + Sync {
+    /// Add `amount` to `key`, expiring it after `ttl_seconds`, and return the new total
+    async fn increment(&self, key: &str, amount: u64, ttl_seconds: u64) -> PaymasterResult<u64>;
+
+    /// Remove `amount` previously added to `key`; a key that has expired stays unset
+    async fn decrement(&self, key: &str, amount: u64) -> PaymasterResult<()>;
+
+    /// Current total of `key`, zero when unset
+    async fn get(&self, key: &str) -> PaymasterResult<u64>;
+
+    /// Unset the counter at `key`
+    async fn delete(&self, key: &str) -> PaymasterResult<()>;
+
+    /// Set `key` to `value`, with no expiry
+    async fn set(&self, key: &str, value: u64) -> PaymasterResult<()>;
+
+    /// Atomically refill the bucket at `key` up to `now_millis`, then take `amount` if that many tokens are available
+    async fn take_tokens(&self, key: &str, amount: u64, bucket: &TokenBucket, now_millis: u64) -> PaymasterResult<bool>;
+
+    /// Put back tokens taken from the bucket at `key`, never above its capacity
+    async fn return_tokens(&self, key: &str, amount: u64, bucket: &TokenBucket) -> PaymasterResult<()>;
+
+    /// Whole tokens the bucket at `key` would hold at `now_millis`, without taking any
+    async fn available_tokens(&self, key: &str, bucket: &TokenBucket, now_millis: u64) -> PaymasterResult<u64>;
+
+    /// Keep `record` at `key` for `ttl_seconds`, listed in `index` as due for release at `due_at` (unix seconds)
+    async fn hold_pending(&self, index: &str, key: &str, record: &str, due_at: u64, ttl_seconds: u64) -> PaymasterResult<()>;
+
+    /// Atomically remove and return the record at `key`, so exactly one caller settles or releases it
+    async fn take_pending(&self, index: &str, key: &str) -> PaymasterResult<Option<String>>;
+
+    /// Keys of the records in `index` due for release before `due_before` (unix seconds)
+    async fn due_pending(&self, index: &str, due_before: u64) -> PaymasterResult<Vec<String>>;
+}
+
+/// Shape of a token bucket: `capacity` tokens, refilled by `refill` every `period_millis`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TokenBucket {
+    pub capacity: u64,
+    pub refill: u64,
+    pub period_millis: u64,
+}
+
+impl TokenBucket {
+    fn refill_per_milli(&self) -> f64 {
+        self.refill as f64 / self.period_millis.max(1) as f64
+    }
+
+    /// Tokens held at `now_millis` by a bucket that had `tokens` at `updated_millis`
+    fn refilled(&self, tokens: f64, updated_millis: u64, now_millis: u64) -> f64 {
+        (self.capacity as f64).min(tokens + now_millis.saturating_sub(updated_millis) as f64 * self.refill_per_milli())
+    }
+
+    /// Seconds for an empty bucket to fill, after which its state can be dropped
+    fn ttl_seconds(&self) -> u64 {
+        let fill_millis = self.capacity as f64 / self.refill_per_milli().max(f64::MIN_POSITIVE);
+        (fill_millis / 1_000.0).ceil().clamp(1.0, u32::MAX as f64) as u64
+    }
+}
+
+/// Redis counters shared by every relay instance
+pub struct RedisCounterStore {
+    client: redis::Client,
+    connection: OnceCell<redis::aio::MultiplexedConnection>,
+}
+
+impl RedisCounterStore {
+    pub fn new(redis_url: &str) -> PaymasterResult<Self> {
+        let client = redis::Client::open(redis_url)
+            .map_err(|e| PaymasterError::ConfigurationError(format!("Redis connection failed: {}", e)))?;
+
+        Ok(Self {
+            client,
+            connection: OnceCell::new(),
+        })
+    }
+
+    async fn connection(&self) -> PaymasterResult<redis::aio::MultiplexedConnection> {
+        self.connection
+            .get_or_try_init(|| self.client.get_multiplexed_async_connection())
+            .await
+            .cloned()
+            .map_err(|e| PaymasterError::DatabaseError(format!("Redis connection failed: {}", e)))
+    }
+}
+
+const TAKE_PENDING: &str =
+    "local record = redis.call('GET', KEYS[1]) redis.call('DEL', KEYS[1]) redis.call('ZREM', KEYS[2], KEYS[1]) return record";
+
+const DECREMENT_EXISTING: &str =
+    "if redis.call('EXISTS', KEYS[1]) == 1 then redis.call('DECRBY', KEYS[1], ARGV[1]) end return 0";
+
+// Buckets are hashes of {tokens, updated}; token counts are Lua doubles, exact below 2^53
+const TAKE_TOKENS: &str = r#"
+local capacity = tonumber(ARGV[1])
+local refill_per_milli = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local amount = tonumber(ARGV[4])
+local tokens = tonumber(redis.call('HGET', KEYS[1], 'tokens')) or capacity
+local updated = tonumber(redis.call('HGET', KEYS[1], 'updated')) or now
+if now > updated then
+    tokens = math.min(capacity, tokens + (now - updated) * refill_per_milli)
+    updated = now
+end
+local taken = 0
+if tokens >= amount then
+    tokens = tokens - amount
+    taken = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', string.format('%.17g', tokens), 'updated', string.format('%d', updated))
+redis.call('EXPIRE', KEYS[1], ARGV[5])
+return taken
+"#;
+
+const RETURN_TOKENS: &str = r#"
+local tokens = tonumber(redis.call('HGET', KEYS[1], 'tokens'))
+if tokens then
+    tokens = math.min(tonumber(ARGV[2]), tokens + tonumber(ARGV[1]))
+    redis.call('HSET', KEYS[1], 'tokens', string.format('%.17g', tokens))
+end
+return 0
+"#;
+
+/// Redis counters are signed 64-bit integers
+fn to_redis_amount(amount: u64) -> PaymasterResult<i64> {
+    i64::try_from(amount)
+        .map_err(|_| PaymasterError::PolicyViolation(format!("Amount {} exceeds the counter range", amount)))
+}
+
+#[async_trait]
+impl CounterStore for RedisCounterStore {
+    async fn increment(&self, key: &str, amount: u64, ttl_seconds: u64) -> PaymasterResult<u64> {
+        let mut conn = self.connection().await?;
+        // MULTI/EXEC keeps the increment and its expiry together
+        let (total,): (i64,) = redis::pipe()
+            .atomic()
+            .incr(key, to_redis_amount(amount)?)
+            .expire(key, ttl_seconds.max(1) as i64)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))?;
+
+        Ok(total.max(0) as u64)
+    }
+
+    async fn decrement(&self, key: &str, amount: u64) -> PaymasterResult<()> {
+        let mut conn = self.connection().await?;
+        // A counter whose window already expired must not come back negative
+        redis::Script::new(DECREMENT_EXISTING)
+            .key(key)
+            .arg(to_redis_amount(amount)?)
+            .invoke_async::<_, i64>(&mut conn)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> PaymasterResult<u64> {
+        let mut conn = self.connection().await?;
+        let total: Option<i64> = redis::cmd("GET")
+            .arg(key)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))?;
+
+        Ok(total.unwrap_or(0).max(0) as u64)
+    }
+
+    async fn delete(&self, key: &str) -> PaymasterResult<()> {
+        let mut conn = self.connection().await?;
+        redis::cmd("DEL")
+            .arg(key)
+            .query_async::<_, i64>(&mut conn)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn set(&self, key: &str, value: u64) -> PaymasterResult<()> {
+        let mut conn = self.connection().await?;
+        redis::cmd("SET")
+            .arg(key)
+            .arg(to_redis_amount(value)?)
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))
+    }
+
+    async fn take_tokens(&self, key: &str, amount: u64, bucket: &TokenBucket, now_millis: u64) -> PaymasterResult<bool> {
+        let mut conn = self.connection().await?;
+        let taken: i64 = redis::Script::new(TAKE_TOKENS)
+            .key(key)
+            .arg(bucket.capacity)
+            .arg(bucket.refill_per_milli())
+            .arg(now_millis)
+            .arg(amount)
+            .arg(bucket.ttl_seconds())
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))?;
+
+        Ok(taken == 1)
+    }
+
+    async fn return_tokens(&self, key: &str, amount: u64, bucket: &TokenBucket) -> PaymasterResult<()> {
+        let mut conn = self.connection().await?;
+        redis::Script::new(RETURN_TOKENS)
+            .key(key)
+            .arg(amount)
+            .arg(bucket.capacity)
+            .invoke_async::<_, i64>(&mut conn)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn available_tokens(&self, key: &str, bucket: &TokenBucket, now_millis: u64) -> PaymasterResult<u64> {
+        let mut conn = self.connection().await?;
+        let (tokens, updated): (Option<f64>, Option<u64>) = redis::cmd("HMGET")
+            .arg(key)
+            .arg("tokens")
+            .arg("updated")
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))?;
+
+        Ok(match (tokens, updated) {
+            (Some(tokens), Some(updated)) => bucket.refilled(tokens, updated, now_millis) as u64,
+            _ => bucket.capacity,
+        })
+    }
+
+    async fn hold_pending(&self, index: &str, key: &str, record: &str, due_at: u64, ttl_seconds: u64) -> PaymasterResult<()> {
+        let mut conn = self.connection().await?;
+        // The index is a sorted set of record keys, scored by when they are due
+        redis::pipe()
+            .atomic()
+            .set_ex(key, record, ttl_seconds.clamp(1, u32::MAX as u64))
+            .ignore()
+            .zadd(index, key, due_at)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))
+    }
+
+    async fn take_pending(&self, index: &str, key: &str) -> PaymasterResult<Option<String>> {
+        let mut conn = self.connection().await?;
+        redis::Script::new(TAKE_PENDING)
+            .key(key)
+            .key(index)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))
+    }
+
+    async fn due_pending(&self, index: &str, due_before: u64) -> PaymasterResult<Vec<String>> {
+        let mut conn = self.connection().await?;
+        redis::cmd("ZRANGEBYSCORE")
+            .arg(index)
+            .arg("-inf")
+            .arg(format!("({}", due_before))
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))
+    }
+}
+
+/// In-process counters for a single relay instance and for tests
+pub struct MemoryCounterStore {
+    clock: Arc<dyn Clock>,
+    counters: Mutex<HashMap<String, (u64, u64)>>, // key -> (total, expiry millis)
+    buckets: Mutex<HashMap<String, (f64, u64)>>,  // key -> (tokens, updated millis)
+    pending: Mutex<HashMap<String, (String, String, u64, u64)>>, // key -> (index, record, due at, expiry millis)
+}
+
+impl MemoryCounterStore {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            counters: Mutex::new(HashMap::new()),
+            buckets: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Default for MemoryCounterStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl CounterStore for MemoryCounterStore {
+    async fn increment(&self, key: &str, amount: u64, ttl_seconds: u64) -> PaymasterResult<u64> {
+        let now = self.clock.now_millis();
+        let mut counters = self.counters.lock().await;
+        let counter = counters.entry(key.to_string()).or_insert((0, now));
+        if counter.1 <= now {
+            counter.0 = 0;
+        }
+
+        counter.0 = counter.0.saturating_add(amount);
+        counter.1 = now + ttl_seconds.max(1) * 1_000;
+        Ok(counter.0)
+    }
+
+    async fn decrement(&self, key: &str, amount: u64) -> PaymasterResult<()> {
+        if let Some(counter) = self.counters.lock().await.get_mut(key) {
+            counter.0 = counter.0.saturating_sub(amount);
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> PaymasterResult<u64> {
+        let now = self.clock.now_millis();
+        Ok(self.counters.lock().await.get(key)
+            .filter(|(_, expiry)| *expiry > now)
+            .map_or(0, |(total, _)| *total))
+    }
+
+    async fn delete(&self, key: &str) -> PaymasterResult<()> {
+        self.counters.lock().await.remove(key);
+        Ok(())
+    }
+
+    async fn set(&self, key: &str, value: u64) -> PaymasterResult<()> {
+        self.counters.lock().await.insert(key.to_string(), (value, u64::MAX));
+        Ok(())
+    }
+
+    async fn take_tokens(&self, key: &str, amount: u64, bucket: &TokenBucket, now_millis: u64) -> PaymasterResult<bool> {
+        let capacity = bucket.capacity as f64;
+        let mut buckets = self.buckets.lock().await;
+        let (tokens, updated) = buckets.entry(key.to_string()).or_insert((capacity, now_millis));
+        if now_millis > *updated {
+            *tokens = bucket.refilled(*tokens, *updated, now_millis);
+            *updated = now_millis;
+        }
+
+        if *tokens >= amount as f64 {
+            *tokens -= amount as f64;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    async fn return_tokens(&self, key: &str, amount: u64, bucket: &TokenBucket) -> PaymasterResult<()> {
+        if let Some((tokens, _)) = self.buckets.lock().await.get_mut(key) {
+            *tokens = (bucket.capacity as f64).min(*tokens + amount as f64);
+        }
+        Ok(())
+    }
+
+    async fn available_tokens(&self, key: &str, bucket: &TokenBucket, now_millis: u64) -> PaymasterResult<u64> {
+        Ok(self.buckets.lock().await.get(key)
+            .map_or(bucket.capacity, |(tokens, updated)| bucket.refilled(*tokens, *updated, now_millis) as u64))
+    }
+
+    async fn hold_pending(&self, index: &str, key: &str, record: &str, due_at: u64, ttl_seconds: u64) -> PaymasterResult<()> {
+        let expiry = self.clock.now_millis().saturating_add(ttl_seconds.max(1).saturating_mul(1_000));
+        self.pending.lock().await.insert(key.to_string(), (index.to_string(), record.to_string(), due_at, expiry));
+        Ok(())
+    }
+
+    async fn take_pending(&self, _index: &str, key: &str) -> PaymasterResult<Option<String>> {
+        let now = self.clock.now_millis();
+        Ok(self.pending.lock().await.remove(key)
+            .filter(|(_, _, _, expiry)| *expiry > now)
+            .map(|(_, record, _, _)| record))
+    }
+
+    async fn due_pending(&self, index: &str, due_before: u64) -> PaymasterResult<Vec<String>> {
+        Ok(self.pending.lock().await.iter()
+            .filter(|(_, (held_in, _, due_at, _))| held_in == index && *due_at < due_before)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+}
@@ -1,38 +1,63 @@
+use crate::core::chain_registry::ChainRegistry;
+use crate::core::l1_fee::L1FeeEstimator;
 use crate::core::types::*;
+use crate::core::user_operation::{VersionedUserOperation, ENTRY_POINT_V06};
+use ethers::providers::{Http, Middleware, Provider};
+use ethers::types::{Address, BlockNumber, FeeHistory, U256};
 use reqwest::Client;
+use serde::Serialize;
 use serde_json::Value;
 use std::collections::HashMap;
-use tracing::{info, warn, error};
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+/// Blocks of fee history the recommendations are drawn from
+const FEE_HISTORY_BLOCKS: u64 = 20;
+
+/// Priority fee percentiles asked of `eth_feeHistory` for the slow, standard, fast and instant tiers
+const TIER_PERCENTILES: [f64; 4] = [10.0, 50.0, 75.0, 95.0];
 
 /// Gas estimation service for calculating gas costs and token conversions
 pub struct GasEstimatorService {
     http_client: Client,
-    price_feeds: HashMap<String, String>, // Token address -> price feed URL
+    providers: HashMap<u64, Arc<Provider<Http>>>, // By chain id
+    default_chain_id: u64,
+    l1_fees: L1FeeEstimator,
+    tokens: HashMap<(u64, String), TokenConfig>, // By chain id and lowercase token address
+    recommendations: Mutex<HashMap<u64, GasPriceRecommendations>>, // For the latest block seen on each chain
 }
 
 impl GasEstimatorService {
-    pub fn new() -> Self {
-        let mut price_feeds = HashMap::new();
-        
-        // Add popular token price feeds (using CoinGecko as example)
-        price_feeds.insert(
-            "0xA0b86a33E6441c8C0c45F2d7a6c6e5B8E6A8C8D2".to_lowercase(), // USDC
-            "https://api.coingecko.com/api/v3/simple/price?ids=usd-coin&vs_currencies=usd".to_string()
-        );
-        price_feeds.insert(
-            "0xdAC17F958D2ee523a2206206994597C13D831ec7".to_lowercase(), // USDT
-            "https://api.coingecko.com/api/v3/simple/price?ids=tether&vs_currencies=usd".to_string()
-        );
+    /// Gas prices are read from each registered chain's RPC; requests that name no chain use its default
+    pub fn new(chains: &ChainRegistry) -> Self {
+        let providers = chains.providers();
+
+        // Price feeds of every chain's accepted tokens
+        let tokens = chains.chains()
+            .flat_map(|chain| chain.tokens.iter().map(move |token| ((chain.chain_id, token.address.to_lowercase()), token.clone())))
+            .collect();
 
         Self {
             http_client: Client::new(),
-            price_feeds,
+            l1_fees: chains.l1_fees(),
+            providers,
+            default_chain_id: chains.default_chain_id(),
+            tokens,
+            recommendations: Mutex::new(HashMap::new()),
         }
     }
 
-    /// Estimate gas costs for a user operation
+    /// Estimate gas costs for a user operation on the default chain
     pub async fn estimate_gas(&self, request: &GasEstimateRequest) -> PaymasterResult<GasEstimate> {
-        info!("Estimating gas for user operation");
+        self.estimate_gas_for_chain(request, self.default_chain_id).await
+    }
+
+    /// Estimate gas costs for a user operation on `chain_id`
+    ///
+    /// On rollups the bundler recovers the L1 data fee through `preVerificationGas`, so the gas
+    /// paying for it at the chain's gas price counts toward the limit and the cost.
+    pub async fn estimate_gas_for_chain(&self, request: &GasEstimateRequest, chain_id: u64) -> PaymasterResult<GasEstimate> {
+        info!("Estimating gas for user operation on chain {}", chain_id);
 
         // Calculate total gas limit
         let call_gas_limit: u64 = request.user_operation.call_gas_limit.parse()
@@ -44,17 +69,25 @@ impl GasEstimatorService {
         let pre_verification_gas: u64 = request.user_operation.pre_verification_gas.parse()
             .map_err(|_| PaymasterError::GasEstimationFailed("Invalid pre verification gas".to_string()))?;
 
-        let total_gas_limit = call_gas_limit + verification_gas_limit + pre_verification_gas;
-
         // Get current gas price
-        let gas_price = self.get_current_gas_price().await?;
+        let gas_price = self.get_current_gas_price(chain_id).await?;
+
+        let entry_point: Address = ENTRY_POINT_V06.parse().expect("valid entry point address");
+        let user_op = VersionedUserOperation::V06(request.user_operation.clone());
+        let l1_fee = self.l1_fees.estimate(chain_id, entry_point, &user_op).await?;
+        let l1_gas = l1_fee.map(|fee| saturate_u64(fee.gas_at(gas_price.into()))).unwrap_or(0);
+
+        let total_gas_limit = call_gas_limit
+            .saturating_add(verification_gas_limit)
+            .saturating_add(pre_verification_gas)
+            .saturating_add(l1_gas);
 
         // Calculate total cost in ETH (wei)
-        let total_cost_wei = total_gas_limit * gas_price;
+        let total_cost_wei = total_gas_limit.saturating_mul(gas_price);
 
         // Convert to token amount if requested
         let total_cost_token = if let Some(token) = &request.token {
-            Some(self.convert_eth_to_token(total_cost_wei, token).await?)
+            Some(self.convert_eth_to_token(chain_id, total_cost_wei, token).await?)
         } else {
             None
         };
@@ -64,34 +97,28 @@ impl GasEstimatorService {
             gas_price: gas_price.to_string(),
             total_cost_eth: total_cost_wei.to_string(),
             total_cost_token: total_cost_token.map(|amount| amount.to_string()),
+            l1_data_fee: l1_fee.map(|fee| fee.fee_wei).unwrap_or_default().to_string(),
         };
 
         info!("Gas estimation completed: {} gas at {} wei/gas", total_gas_limit, gas_price);
         Ok(estimate)
     }
 
-    /// Get current gas price from network
-    async fn get_current_gas_price(&self) -> PaymasterResult<u64> {
-        // This would integrate with gas price oracles like:
-        // - EthGasStation
-        // - Blocknative
-        // - Alchemy Gas API
-        // - Direct blockchain RPC calls
+    /// Get current gas price from network: the standard recommendation
+    async fn get_current_gas_price(&self, chain_id: u64) -> PaymasterResult<u64> {
+        let gas_price = self.get_gas_price_recommendations_for_chain(chain_id).await?.standard;
 
-        // For now, return a reasonable default (20 gwei)
-        let gas_price = 20_000_000_000u64; // 20 gwei in wei
-        
-        info!("Current gas price: {} wei", gas_price);
+        info!("Current gas price on chain {}: {} wei", chain_id, gas_price);
         Ok(gas_price)
     }
 
-    /// Convert ETH amount to token amount
-    async fn convert_eth_to_token(&self, eth_amount_wei: u64, token_address: &str) -> PaymasterResult<u64> {
-        let token_key = token_address.to_lowercase();
+    /// Convert ETH amount to an amount of a token accepted on `chain_id`
+    async fn convert_eth_to_token(&self, chain_id: u64, eth_amount_wei: u64, token_address: &str) -> PaymasterResult<u64> {
+        let token_key = (chain_id, token_address.to_lowercase());
         
-        if !self.price_feeds.contains_key(&token_key) {
+        if !self.tokens.contains_key(&token_key) {
             return Err(PaymasterError::GasEstimationFailed(
-                format!("No price feed available for token: {}", token_address)
+                format!("No price feed available for token {} on chain {}", token_address, chain_id)
             ));
         }
 
@@ -108,7 +135,7 @@ impl GasEstimatorService {
         // Convert USD to token amount
         let token_amount = usd_amount / token_price_usd;
 
-        // Apply token decimals (assuming 6 for USDC/USDT, 18 for others)
+        // Apply token decimals
         let decimals = self.get_token_decimals(&token_key);
         let token_amount_with_decimals = (token_amount * (10f64.powi(decimals as i32))) as u64;
 
@@ -135,12 +162,12 @@ impl GasEstimatorService {
         Ok(eth_price)
     }
 
-    /// Get token price in USD
-    async fn get_token_price_usd(&self, token_key: &str) -> PaymasterResult<f64> {
-        let price_feed_url = self.price_feeds.get(token_key)
+    /// Get token price in USD, read from its feed's response at the token's `price_path`
+    async fn get_token_price_usd(&self, token_key: &(u64, String)) -> PaymasterResult<f64> {
+        let token = self.tokens.get(token_key)
             .ok_or_else(|| PaymasterError::GasEstimationFailed("Price feed not found".to_string()))?;
 
-        let response = self.http_client.get(price_feed_url)
+        let response = self.http_client.get(&token.price_feed)
             .send()
             .await
             .map_err(|e| PaymasterError::GasEstimationFailed(format!("Token price API request failed: {}", e)))?;
@@ -148,31 +175,14 @@ impl GasEstimatorService {
         let json: Value = response.json().await
             .map_err(|e| PaymasterError::GasEstimationFailed(format!("Token price API response parse failed: {}", e)))?;
 
-        // This is simplified - would need to handle different API response formats
-        let token_price = if token_key.contains("A0b86a33E6441c8C0c45F2d7a6c6e5B8E6A8C8D2") {
-            // USDC
-            json["usd-coin"]["usd"].as_f64().unwrap_or(1.0)
-        } else if token_key.contains("dAC17F958D2ee523a2206206994597C13D831ec7") {
-            // USDT
-            json["tether"]["usd"].as_f64().unwrap_or(1.0)
-        } else {
-            1.0 // Default for unknown tokens
-        };
-
-        info!("Token {} price: ${}", token_key, token_price);
+        let token_price = read_price(&json, &token.price_path)?;
+        info!("Token {} price on chain {}: ${}", token.symbol, token_key.0, token_price);
         Ok(token_price)
     }
 
-    /// Get token decimals
-    fn get_token_decimals(&self, token_key: &str) -> u8 {
-        // This would query the token contract for decimals
-        // For now, return common values
-        if token_key.contains("A0b86a33E6441c8C0c45F2d7a6c6e5B8E6A8C8D2") ||  // USDC
-           token_key.contains("dAC17F958D2ee523a2206206994597C13D831ec7") {   // USDT
-            6
-        } else {
-            18 // Default for most ERC20 tokens
-        }
+    /// Get token decimals, from the chain configuration
+    fn get_token_decimals(&self, token_key: &(u64, String)) -> u8 {
+        self.tokens.get(token_key).map_or(18, |token| token.decimals)
     }
 
     /// Estimate gas for batch transactions
@@ -193,37 +203,268 @@ impl GasEstimatorService {
         Ok(estimates)
     }
 
-    /// Get gas price recommendations
+    /// Gas price recommendations for the next block on the default chain
     pub async fn get_gas_price_recommendations(&self) -> PaymasterResult<GasPriceRecommendations> {
-        // This would integrate with gas price prediction services
-        // For now, return static recommendations
-        Ok(GasPriceRecommendations {
-            slow: 15_000_000_000,    // 15 gwei
-            standard: 20_000_000_000, // 20 gwei
-            fast: 25_000_000_000,    // 25 gwei
-            instant: 30_000_000_000, // 30 gwei
-        })
+        self.get_gas_price_recommendations_for_chain(self.default_chain_id).await
     }
 
-    /// Estimate gas for specific chain
-    pub async fn estimate_gas_for_chain(&self, request: &GasEstimateRequest, chain_id: u64) -> PaymasterResult<GasEstimate> {
-        // This would use chain-specific gas estimation logic
-        // For now, use the same logic as the main estimate_gas method
-        info!("Estimating gas for chain: {}", chain_id);
-        self.estimate_gas(request).await
+    /// Get gas price recommendations for the next block on `chain_id`, fetched once per block
+    ///
+    /// Each tier is the next block's base fee plus a priority fee: the median over recent blocks
+    /// of the tier's reward percentile. The node's `eth_maxPriorityFeePerGas` is the least the
+    /// standard tier pays, and faster tiers never pay less than slower ones.
+    pub async fn get_gas_price_recommendations_for_chain(&self, chain_id: u64) -> PaymasterResult<GasPriceRecommendations> {
+        let provider = self.providers.get(&chain_id).ok_or(PaymasterError::UnsupportedChain(chain_id))?;
+        let block_number = provider.get_block_number().await.map_err(rpc_error)?.as_u64();
+        if let Some(cached) = self.recommendations.lock().expect("gas price cache poisoned").get(&chain_id) {
+            if cached.block_number == block_number {
+                return Ok(cached.clone());
+            }
+        }
+
+        let history = provider
+            .fee_history(FEE_HISTORY_BLOCKS, BlockNumber::Number(block_number.into()), &TIER_PERCENTILES)
+            .await
+            .map_err(rpc_error)?;
+        let node_priority_fee: U256 = provider
+            .request("eth_maxPriorityFeePerGas", ())
+            .await
+            .map_err(rpc_error)?;
+
+        let recommendations = recommend(block_number, &history, node_priority_fee)?;
+        info!(
+            "Gas prices on chain {} at block {}: base fee {}, tiers {}/{}/{}/{} wei",
+            chain_id, block_number, recommendations.base_fee_per_gas,
+            recommendations.slow, recommendations.standard, recommendations.fast, recommendations.instant
+        );
+        self.recommendations.lock().expect("gas price cache poisoned").insert(chain_id, recommendations.clone());
+        Ok(recommendations)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct GasPriceRecommendations {
+    pub block_number: u64,      // Latest block when fetched
+    pub base_fee_per_gas: u64,  // Of the next block
     pub slow: u64,     // wei per gas
     pub standard: u64, // wei per gas
     pub fast: u64,     // wei per gas
     pub instant: u64,  // wei per gas
 }
 
-impl Default for GasEstimatorService {
-    fn default() -> Self {
-        Self::new()
+/// Tier prices from a fee history ending at `block_number`
+fn recommend(block_number: u64, history: &FeeHistory, node_priority_fee: U256) -> PaymasterResult<GasPriceRecommendations> {
+    // One more base fee than blocks: the last is the next block's
+    let base_fee = history.base_fee_per_gas.last()
+        .copied()
+        .ok_or_else(|| PaymasterError::GasEstimationFailed("Fee history has no base fee; is the chain pre-London?".to_string()))?;
+
+    // Empty blocks report zero rewards, which say nothing about the going rate
+    let busy_blocks: Vec<&Vec<U256>> = history.reward.iter()
+        .zip(history.gas_used_ratio.iter().chain(std::iter::repeat(&1.0)))
+        .filter(|(_, ratio)| **ratio > 0.0)
+        .map(|(rewards, _)| rewards)
+        .collect();
+
+    let mut priority_fees = [U256::zero(); 4];
+    for (tier, fee) in priority_fees.iter_mut().enumerate() {
+        let mut rewards: Vec<U256> = busy_blocks.iter().filter_map(|rewards| rewards.get(tier).copied()).collect();
+        rewards.sort();
+        *fee = rewards.get(rewards.len() / 2).copied().unwrap_or(node_priority_fee);
+    }
+    priority_fees[1] = priority_fees[1].max(node_priority_fee);
+    for tier in 1..priority_fees.len() {
+        priority_fees[tier] = priority_fees[tier].max(priority_fees[tier - 1]);
+    }
+
+    let price = |priority_fee: U256| saturate_u64(base_fee.saturating_add(priority_fee));
+    Ok(GasPriceRecommendations {
+        block_number,
+        base_fee_per_gas: saturate_u64(base_fee),
+        slow: price(priority_fees[0]),
+        standard: price(priority_fees[1]),
+        fast: price(priority_fees[2]),
+        instant: price(priority_fees[3]),
+    })
+}
+
+/// A positive price at `path` in a price feed's response, given as a number or a decimal string
+fn read_price(json: &Value, path: &str) -> PaymasterResult<f64> {
+    json.pointer(path)
+        .and_then(|price| price.as_f64().or_else(|| price.as_str()?.parse().ok()))
+        .filter(|price| price.is_finite() && *price > 0.0)
+        .ok_or_else(|| PaymasterError::GasEstimationFailed(format!("Token price feed has no price at {}: {}", path, json)))
+}
+
+fn saturate_u64(value: U256) -> u64 {
+    value.min(U256::from(u64::MAX)).as_u64()
+}
+
+fn rpc_error(error: impl std::fmt::Display) -> PaymasterError {
+    PaymasterError::GasEstimationFailed(format!("Gas price RPC failed: {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::settings;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const GWEI: u64 = 1_000_000_000;
+
+    fn gwei(amount: u64) -> String {
+        format!("{:#x}", amount * GWEI)
+    }
+
+    /// A node whose latest block is `block`; counts the `eth_feeHistory` calls it serves
+    async fn mock_node(block: Arc<AtomicU64>, fee_history_calls: Arc<AtomicUsize>) -> String {
+        let router = Router::new().route("/", post(move |Json(request): Json<Value>| async move {
+            let result = match request["method"].as_str().unwrap() {
+                "eth_blockNumber" => json!(format!("{:#x}", block.load(Ordering::SeqCst))),
+                "eth_feeHistory" => {
+                    fee_history_calls.fetch_add(1, Ordering::SeqCst);
+                    json!({
+                        "oldestBlock": "0x10",
+                        "baseFeePerGas": [gwei(10), gwei(11), gwei(12), gwei(13), gwei(14)],
+                        "gasUsedRatio": [0.5, 0.0, 0.9, 0.7],
+                        "reward": [
+                            [gwei(1), gwei(2), gwei(3), gwei(4)],
+                            ["0x0", "0x0", "0x0", "0x0"],
+                            [gwei(3), gwei(4), gwei(5), gwei(6)],
+                            [gwei(2), gwei(3), gwei(4), gwei(5)],
+                        ],
+                    })
+                }
+                "eth_maxPriorityFeePerGas" => json!(gwei(4)),
+                // GasPriceOracle.getL1Fee
+                "eth_call" => json!(format!("{:#066x}", 210_000 * GWEI)),
+                method => panic!("unexpected {}", method),
+            };
+            Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+        }));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", address)
+    }
+
+    fn estimator(chain_id: u64, url: String) -> GasEstimatorService {
+        let mut settings = settings();
+        settings.blockchain.default_chain_id = chain_id;
+        settings.blockchain.chains[0].chain_id = chain_id;
+        settings.blockchain.chains[0].rpc_url = url;
+        GasEstimatorService::new(&ChainRegistry::new(&settings).unwrap())
+    }
+
+    #[tokio::test]
+    async fn recommends_base_fee_plus_priority_fee_percentiles() {
+        let url = mock_node(Arc::new(AtomicU64::new(0x13)), Arc::new(AtomicUsize::new(0))).await;
+        let estimator = estimator(1, url);
+
+        let recommendations = estimator.get_gas_price_recommendations().await.unwrap();
+        // Medians over the three busy blocks are 2/3/4/5 gwei; the node's 4 gwei lifts standard and fast
+        assert_eq!(recommendations, GasPriceRecommendations {
+            block_number: 0x13,
+            base_fee_per_gas: 14 * GWEI,
+            slow: 16 * GWEI,
+            standard: 18 * GWEI,
+            fast: 18 * GWEI,
+            instant: 19 * GWEI,
+        });
+        assert_eq!(estimator.get_current_gas_price(1).await.unwrap(), 18 * GWEI);
+    }
+
+    #[tokio::test]
+    async fn fetches_fee_history_once_per_block() {
+        let block = Arc::new(AtomicU64::new(100));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let estimator = estimator(1, mock_node(block.clone(), calls.clone()).await);
+
+        estimator.get_gas_price_recommendations().await.unwrap();
+        estimator.get_gas_price_recommendations().await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        block.store(101, Ordering::SeqCst);
+        assert_eq!(estimator.get_gas_price_recommendations().await.unwrap().block_number, 101);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn charges_the_l1_data_fee_on_rollups() {
+        let url = mock_node(Arc::new(AtomicU64::new(0x13)), Arc::new(AtomicUsize::new(0))).await;
+        let request = GasEstimateRequest {
+            user_operation: UserOperation {
+                sender: "0x1306b01bc3e4ad202612d3843387e94737673f53".to_string(),
+                nonce: "0".to_string(),
+                init_code: "0x".to_string(),
+                call_data: "0xb61d27f6".to_string(),
+                call_gas_limit: "100000".to_string(),
+                verification_gas_limit: "150000".to_string(),
+                pre_verification_gas: "50000".to_string(),
+                max_fee_per_gas: "20000000000".to_string(),
+                max_priority_fee_per_gas: "1000000000".to_string(),
+                paymaster_and_data: "0x".to_string(),
+                signature: "0x".to_string(),
+            },
+            token: None,
+        };
+
+        let estimate = estimator(8453, url).estimate_gas_for_chain(&request, 8453).await.unwrap();
+        // 210,000 gwei of L1 fee is 11,667 gas at the standard 18 gwei
+        assert_eq!(estimate.l1_data_fee, (210_000 * GWEI).to_string());
+        assert_eq!(estimate.total_gas_limit, (300_000 + 11_667).to_string());
+        assert_eq!(estimate.total_cost_eth, ((300_000 + 11_667) * 18 * GWEI).to_string());
+    }
+
+    #[test]
+    fn falls_back_to_the_node_priority_fee_when_blocks_are_empty() {
+        let history = FeeHistory {
+            base_fee_per_gas: vec![U256::from(GWEI), U256::from(GWEI)],
+            gas_used_ratio: vec![0.0],
+            oldest_block: U256::from(1),
+            reward: vec![vec![U256::zero(); 4]],
+        };
+
+        let recommendations = recommend(1, &history, U256::from(2 * GWEI)).unwrap();
+        assert_eq!((recommendations.slow, recommendations.instant), (3 * GWEI, 3 * GWEI));
+        assert!(recommend(1, &FeeHistory { base_fee_per_gas: Vec::new(), ..history }, U256::zero()).is_err());
+    }
+
+    #[tokio::test]
+    async fn looks_tokens_up_on_the_requested_chain() {
+        let mut settings = settings();
+        // The same address is a different token on Base
+        let mut base = settings.blockchain.chains[0].clone();
+        base.chain_id = 8453;
+        base.tokens.truncate(1);
+        base.tokens[0].decimals = 18;
+        settings.blockchain.chains.push(base);
+        let estimator = GasEstimatorService::new(&ChainRegistry::new(&settings).unwrap());
+
+        let usdc = "0xa0b86a33e6441c8c0c45f2d7a6c6e5b8e6a8c8d2".to_string();
+        assert_eq!(estimator.get_token_decimals(&(1, usdc.clone())), 6);
+        assert_eq!(estimator.get_token_decimals(&(8453, usdc.clone())), 18);
+
+        let usdt = "0xdAC17F958D2ee523a2206206994597C13D831ec7";
+        let error = estimator.convert_eth_to_token(8453, 1_000, usdt).await.unwrap_err();
+        assert_eq!(error.to_string(), format!("Gas estimation failed: No price feed available for token {} on chain 8453", usdt));
+    }
+
+    #[test]
+    fn reads_prices_at_the_configured_path() {
+        let coingecko = json!({ "usd-coin": { "usd": 0.9998 } });
+        assert_eq!(read_price(&coingecko, "/usd-coin/usd").unwrap(), 0.9998);
+        assert_eq!(read_price(&json!({ "data": { "price": "1.0002" } }), "/data/price").unwrap(), 1.0002);
+
+        // A response of another shape is an error, not a price of $1
+        assert!(read_price(&coingecko, "/tether/usd").is_err());
+        assert!(read_price(&json!({ "usd-coin": {} }), "/usd-coin/usd").is_err());
+        assert!(read_price(&json!({ "usd-coin": { "usd": 0 } }), "/usd-coin/usd").is_err());
     }
 }
@@ -0,0 +1,303 @@
+use crate::core::simulation::signed_user_op_tokens;
+use crate::core::types::*;
+use crate::core::user_operation::*;
+use ethers::abi::{self, ParamType, Token};
+use ethers::providers::{Http, Middleware, Provider};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::{Address, BlockNumber, Bytes, Eip1559TransactionRequest, TransactionRequest, U256};
+use ethers::utils::id;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::info;
+
+/// OP Stack `GasPriceOracle` predeploy
+pub const OP_GAS_PRICE_ORACLE: &str = "0x420000000000000000000000000000000000000F";
+/// Arbitrum `NodeInterface`, served by the node rather than deployed
+pub const ARBITRUM_NODE_INTERFACE: &str = "0x00000000000000000000000000000000000000C8";
+
+/// Bundle beneficiary assumed when encoding `handleOps`; non-zero bytes keep the fee an upper bound
+const PLACEHOLDER_BENEFICIARY: [u8; 20] = [0xff; 20];
+
+/// How a chain charges for posting its transactions to L1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum L1FeeModel {
+    None,
+    OpStack,
+    Arbitrum,
+}
+
+impl L1FeeModel {
+    /// The model a chain configures, or else the one its chain id is known to use
+    pub fn for_chain(config: &ChainConfig) -> PaymasterResult<Self> {
+        match &config.l1_fee_model {
+            Some(name) => Self::from_name(name).ok_or_else(|| PaymasterError::ConfigurationError(
+                format!("Unknown L1 fee model for chain {}: {:?}", config.chain_id, name)
+            )),
+            None => Ok(Self::default_for_chain(config.chain_id)),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Self::None),
+            "op_stack" => Some(Self::OpStack),
+            "arbitrum" => Some(Self::Arbitrum),
+            _ => None,
+        }
+    }
+
+    fn default_for_chain(chain_id: u64) -> Self {
+        match chain_id {
+            // OP Mainnet, Base, Zora, Mode and the OP and Base testnets
+            10 | 8453 | 7777777 | 34443 | 11155420 | 84532 => Self::OpStack,
+            // Arbitrum One, Nova and Sepolia
+            42161 | 42170 | 421614 => Self::Arbitrum,
+            _ => Self::None,
+        }
+    }
+}
+
+/// The L1 data fee of bundling an operation on a rollup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct L1DataFee {
+    pub fee_wei: U256,
+    pub l2_base_fee: U256,
+}
+
+impl L1DataFee {
+    /// L2 gas that pays the fee at `gas_price`, rounded up
+    ///
+    /// This is what a bundler needs added to `preVerificationGas` to be reimbursed. Nothing can
+    /// be reimbursed at a zero gas price, so that gets none.
+    pub fn gas_at(&self, gas_price: U256) -> U256 {
+        if gas_price.is_zero() {
+            return U256::zero();
+        }
+        (self.fee_wei + gas_price - 1) / gas_price
+    }
+
+    /// The price an operation pays per gas: min(maxFeePerGas, baseFee + maxPriorityFeePerGas)
+    pub fn effective_gas_price(&self, max_fee_per_gas: U256, max_priority_fee_per_gas: U256) -> U256 {
+        max_fee_per_gas.min(self.l2_base_fee.saturating_add(max_priority_fee_per_gas))
+    }
+}
+
+/// Asks each rollup's own fee oracle what posting an operation's bundle to L1 costs
+///
+/// OP Stack chains answer through `GasPriceOracle.getL1Fee`, which applies whichever of the
+/// Bedrock, Ecotone (blob) or Fjord (compressed size) formulas the chain runs. Arbitrum
+/// answers through `NodeInterface.gasEstimateL1Component`.
+pub struct L1FeeEstimator {
+    chains: HashMap<u64, (Arc<Provider<Http>>, L1FeeModel)>, // By chain id
+}
+
+impl L1FeeEstimator {
+    pub fn new(chains: HashMap<u64, (Arc<Provider<Http>>, L1FeeModel)>) -> Self {
+        Self { chains }
+    }
+
+    /// Data fee of a bundle holding only `user_op`; `None` on chains without one
+    pub async fn estimate(
+        &self,
+        chain_id: u64,
+        entry_point: Address,
+        user_op: &VersionedUserOperation,
+    ) -> PaymasterResult<Option<L1DataFee>> {
+        let (provider, model) = self.chains.get(&chain_id).ok_or_else(|| {
+            PaymasterError::GasEstimationFailed(format!("No RPC configured for chain {} to price its L1 data fee", chain_id))
+        })?;
+        let handle_ops = handle_ops_call_data(user_op)?;
+        let fee = match model {
+            L1FeeModel::None => return Ok(None),
+            L1FeeModel::OpStack => {
+                let transaction = bundle_transaction(user_op, chain_id, entry_point, handle_ops)?;
+                let output = call(provider, OP_GAS_PRICE_ORACLE, "getL1Fee(bytes)", &[Token::Bytes(transaction.to_vec())]).await?;
+                let fee_wei = decode(&[ParamType::Uint(256)], &output)?[0].clone().into_uint().unwrap_or_default();
+
+                let history = provider.fee_history(1u64, BlockNumber::Latest, &[]).await.map_err(rpc_error)?;
+                let l2_base_fee = history.base_fee_per_gas.last().copied().unwrap_or_default();
+                L1DataFee { fee_wei, l2_base_fee }
+            }
+            L1FeeModel::Arbitrum => {
+                let arguments = [Token::Address(entry_point), Token::Bool(false), Token::Bytes(handle_ops.to_vec())];
+                let signature = "gasEstimateL1Component(address,bool,bytes)";
+                let output = call(provider, ARBITRUM_NODE_INTERFACE, signature, &arguments).await?;
+                let decoded = decode(&[ParamType::Uint(64), ParamType::Uint(256), ParamType::Uint(256)], &output)?;
+                let l1_gas = decoded[0].clone().into_uint().unwrap_or_default();
+                let l2_base_fee = decoded[1].clone().into_uint().unwrap_or_default();
+                L1DataFee { fee_wei: l1_gas * l2_base_fee, l2_base_fee }
+            }
+        };
+
+        info!("L1 data fee on chain {} ({:?}): {} wei", chain_id, model, fee.fee_wei);
+        Ok(Some(fee))
+    }
+}
+
+/// `EntryPoint.handleOps([user_op], beneficiary)` calldata, priced as if the operation were signed
+pub fn handle_ops_call_data(user_op: &VersionedUserOperation) -> PaymasterResult<Bytes> {
+    let signature = match user_op {
+        VersionedUserOperation::V06(_) => {
+            "handleOps((address,uint256,bytes,bytes,uint256,uint256,uint256,uint256,uint256,bytes,bytes)[],address)"
+        }
+        VersionedUserOperation::V07(_) => {
+            "handleOps((address,uint256,bytes,bytes,bytes32,uint256,bytes32,bytes,bytes)[],address)"
+        }
+    };
+
+    let mut data = id(signature).to_vec();
+    data.extend(abi::encode(&[
+        Token::Array(vec![Token::Tuple(signed_user_op_tokens(user_op)?)]),
+        Token::Address(Address::from(PLACEHOLDER_BENEFICIARY)),
+    ]));
+    Ok(data.into())
+}
+
+/// The unsigned EIP-1559 bundle transaction, as `getL1Fee` expects it
+fn bundle_transaction(user_op: &VersionedUserOperation, chain_id: u64, entry_point: Address, handle_ops: Bytes) -> PaymasterResult<Bytes> {
+    let gas = [user_op.pre_verification_gas(), user_op.verification_gas_limit(), user_op.call_gas_limit()]
+        .into_iter()
+        .try_fold(U256::zero(), |total, gas| Ok::<_, PaymasterError>(total.saturating_add(parse_quantity("gas limit", gas)?)))?;
+
+    let transaction = Eip1559TransactionRequest::new()
+        .to(entry_point)
+        .data(handle_ops)
+        .gas(gas)
+        .nonce(u32::MAX)
+        .chain_id(chain_id)
+        .max_fee_per_gas(parse_quantity("max fee per gas", user_op.max_fee_per_gas())?)
+        .max_priority_fee_per_gas(parse_quantity("max priority fee per gas", user_op.max_priority_fee_per_gas())?);
+    Ok(TypedTransaction::Eip1559(transaction).rlp())
+}
+
+async fn call(provider: &Provider<Http>, to: &str, signature: &str, arguments: &[Token]) -> PaymasterResult<Bytes> {
+    let mut data = id(signature).to_vec();
+    data.extend(abi::encode(arguments));
+    let call: TypedTransaction = TransactionRequest::new()
+        .to(to.parse::<Address>().expect("valid oracle address"))
+        .data(data)
+        .into();
+
+    provider.call(&call, None).await.map_err(rpc_error)
+}
+
+fn decode(types: &[ParamType], data: &[u8]) -> PaymasterResult<Vec<Token>> {
+    abi::decode(types, data)
+        .map_err(|e| PaymasterError::GasEstimationFailed(format!("Unexpected L1 fee oracle result: {}", e)))
+}
+
+fn rpc_error(error: impl std::fmt::Display) -> PaymasterError {
+    PaymasterError::GasEstimationFailed(format!("L1 fee RPC failed: {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+
+    const GWEI: u64 = 1_000_000_000;
+
+    /// An OP Stack node charging 0.00021 ETH of L1 fee, and an Arbitrum node charging 50k L1 gas
+    async fn mock_node() -> Arc<Provider<Http>> {
+        let router = Router::new().route("/", post(|Json(request): Json<Value>| async move {
+            let result = match request["method"].as_str().unwrap() {
+                "eth_call" => {
+                    let call = &request["params"][0];
+                    let data: Bytes = serde_json::from_value(call["data"].clone()).unwrap();
+                    let output = if call["to"].as_str().unwrap().eq_ignore_ascii_case(OP_GAS_PRICE_ORACLE) {
+                        assert_eq!(&data[..4], &id("getL1Fee(bytes)"));
+                        let transaction = abi::decode(&[ParamType::Bytes], &data[4..]).unwrap()[0].clone().into_bytes().unwrap();
+                        assert_eq!(transaction[0], 0x02);
+                        abi::encode(&[Token::Uint((210_000 * GWEI).into())])
+                    } else {
+                        assert_eq!(&data[..4], &id("gasEstimateL1Component(address,bool,bytes)"));
+                        abi::encode(&[Token::Uint(50_000.into()), Token::Uint((GWEI / 100).into()), Token::Uint((30 * GWEI).into())])
+                    };
+                    json!(Bytes::from(output))
+                }
+                "eth_feeHistory" => json!({
+                    "oldestBlock": "0x1",
+                    "baseFeePerGas": [format!("{:#x}", GWEI / 1000), format!("{:#x}", GWEI / 1000)],
+                    "gasUsedRatio": [0.5],
+                }),
+                method => panic!("unexpected {}", method),
+            };
+            Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+        }));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        Arc::new(Provider::<Http>::try_from(format!("http://{}", address)).unwrap())
+    }
+
+    fn user_op() -> VersionedUserOperation {
+        VersionedUserOperation::V06(UserOperation {
+            sender: "0x1306b01bc3e4ad202612d3843387e94737673f53".to_string(),
+            nonce: "0".to_string(),
+            init_code: "0x".to_string(),
+            call_data: "0xb61d27f6".to_string(),
+            call_gas_limit: "100000".to_string(),
+            verification_gas_limit: "150000".to_string(),
+            pre_verification_gas: "50000".to_string(),
+            max_fee_per_gas: "0x3b9aca00".to_string(),
+            max_priority_fee_per_gas: "0x0".to_string(),
+            paymaster_and_data: "0x".to_string(),
+            signature: "0x".to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn asks_each_rollup_for_its_l1_fee() {
+        let node = mock_node().await;
+        let estimator = L1FeeEstimator::new(HashMap::from([
+            (1, (node.clone(), L1FeeModel::None)),
+            (8453, (node.clone(), L1FeeModel::OpStack)),
+            (42161, (node, L1FeeModel::Arbitrum)),
+        ]));
+        let entry_point: Address = ENTRY_POINT_V06.parse().unwrap();
+
+        let base = estimator.estimate(8453, entry_point, &user_op()).await.unwrap().unwrap();
+        assert_eq!(base, L1DataFee { fee_wei: (210_000 * GWEI).into(), l2_base_fee: (GWEI / 1000).into() });
+
+        let arbitrum = estimator.estimate(42161, entry_point, &user_op()).await.unwrap().unwrap();
+        assert_eq!(arbitrum, L1DataFee { fee_wei: (50_000 * GWEI / 100).into(), l2_base_fee: (GWEI / 100).into() });
+
+        assert_eq!(estimator.estimate(1, entry_point, &user_op()).await.unwrap(), None);
+        assert!(estimator.estimate(10, entry_point, &user_op()).await.is_err());
+    }
+
+    #[test]
+    fn chains_configure_their_model_or_default_by_id() {
+        let mut config = crate::config::Settings::default().blockchain.chains[0].clone();
+        assert_eq!(L1FeeModel::for_chain(&config).unwrap(), L1FeeModel::None);
+        config.chain_id = 8453;
+        assert_eq!(L1FeeModel::for_chain(&config).unwrap(), L1FeeModel::OpStack);
+
+        // A chain the defaults do not know, e.g. a new OP Stack chain
+        config.chain_id = 1868;
+        config.l1_fee_model = Some("op_stack".to_string());
+        assert_eq!(L1FeeModel::for_chain(&config).unwrap(), L1FeeModel::OpStack);
+        config.l1_fee_model = Some("optimism".to_string());
+        assert!(L1FeeModel::for_chain(&config).is_err());
+    }
+
+    #[test]
+    fn prices_unsigned_operations_as_signed() {
+        let VersionedUserOperation::V06(mut signed) = user_op() else { unreachable!() };
+        signed.signature = format!("0x{}", "01".repeat(65));
+        let signed = handle_ops_call_data(&VersionedUserOperation::V06(signed)).unwrap();
+        assert_eq!(handle_ops_call_data(&user_op()).unwrap(), signed);
+    }
+
+    #[test]
+    fn converts_the_fee_to_gas_at_the_effective_price() {
+        let fee = L1DataFee { fee_wei: 1_000_001u64.into(), l2_base_fee: 80.into() };
+        // Pays min(maxFee, baseFee + priorityFee), rounded up to whole gas
+        assert_eq!(fee.gas_at(fee.effective_gas_price(1_000.into(), 20.into())), U256::from(10_001));
+        assert_eq!(fee.gas_at(fee.effective_gas_price(50.into(), 20.into())), U256::from(20_001));
+        assert_eq!(fee.gas_at(U256::zero()), U256::zero());
+    }
+}
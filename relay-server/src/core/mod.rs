@@ -1,11 +1,33 @@
+pub mod call_rules;
+pub mod call_targets;
+pub mod chain_registry;
+pub mod circuit_breaker;
+pub mod clock;
+pub mod counter_store;
 pub mod gas_estimator;
+pub mod l1_fee;
 pub mod paymaster;
 pub mod policy_engine;
-pub mod relay_service;
+pub mod schedule;
+pub mod settlement;
+pub mod simulation;
 pub mod types;
+pub mod user_operation;
+pub mod webhook;
 
+pub use call_rules::*;
+pub use call_targets::*;
+pub use chain_registry::*;
+pub use circuit_breaker::*;
+pub use clock::*;
+pub use counter_store::*;
 pub use gas_estimator::*;
+pub use l1_fee::*;
 pub use paymaster::*;
 pub use policy_engine::*;
-pub use relay_service::*;
+pub use schedule::*;
+pub use settlement::*;
+pub use simulation::*;
 pub use types::*;
+pub use user_operation::*;
+pub use webhook::*;
@@ -1,197 +1,618 @@
+use crate::core::chain_registry::{Chain, ChainRegistry};
+use crate::core::l1_fee::L1FeeEstimator;
+use crate::core::policy_engine::{PolicyEngine, PolicyReservation};
+use crate::core::simulation::DUMMY_SIGNATURE;
 use crate::core::types::*;
+use crate::core::user_operation::*;
 use crate::config::Settings;
+use ethers::abi::{self, ParamType, Token};
 use ethers::prelude::*;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::utils::{id, keccak256, to_checksum};
 use std::sync::Arc;
-use tracing::{info, warn, error};
+use tracing::info;
 
 /// Core paymaster service that handles gas sponsorship and ERC20 payments
+///
+/// Each request is served by the chain its `chain_id` names; unknown chains are rejected.
 pub struct PaymasterService {
     settings: Settings,
-    ethereum_client: Arc<Provider<Http>>,
-    signer: LocalWallet,
+    chains: Arc<ChainRegistry>,
+    policy_engine: Arc<PolicyEngine>,
+    l1_fees: L1FeeEstimator,
 }
 
 impl PaymasterService {
-    pub async fn new(settings: Settings) -> PaymasterResult<Self> {
-        // Initialize Ethereum client
-        let provider = Provider::<Http>::try_from(&settings.blockchain.ethereum_rpc)
-            .map_err(|e| PaymasterError::ConfigurationError(e.to_string()))?;
-        
-        // Initialize signer from private key
-        let signer = settings.paymaster.private_key
-            .parse::<LocalWallet>()
-            .map_err(|e| PaymasterError::ConfigurationError(format!("Invalid private key: {}", e)))?;
+    pub async fn new(settings: Settings, chains: Arc<ChainRegistry>, policy_engine: Arc<PolicyEngine>) -> PaymasterResult<Self> {
+        let l1_fees = chains.l1_fees();
 
         Ok(Self {
             settings,
-            ethereum_client: Arc::new(provider),
-            signer,
+            chains,
+            policy_engine,
+            l1_fees,
         })
     }
 
+    /// Chain for requests that do not name one
+    pub fn default_chain_id(&self) -> u64 {
+        self.chains.default_chain_id()
+    }
+
     /// Sponsor a user operation by generating paymaster signature
+    ///
+    /// The operation's worst-case cost is reserved against policy budgets when it is signed
+    /// and settled once its `UserOperationEvent` is observed (see `SettlementWatcher`).
     pub async fn sponsor_user_operation(
         &self,
         request: &SponsorRequest,
-    ) -> PaymasterResult<PaymasterResult> {
-        info!("Sponsoring user operation for sender: {}", request.user_operation.sender);
+    ) -> PaymasterResult<PaymasterResponse> {
+        info!("Sponsoring user operation for sender: {} on chain {}", request.user_operation.sender(), request.chain_id);
+        let chain = self.chains.get(request.chain_id)?;
+
+        // The operation layout must match the EntryPoint it targets
+        let entry_point_version = self.check_entry_point(chain, &request.entry_point, &request.user_operation)?;
+
+        // A named sponsorship policy must exist and cover the operation
+        self.policy_engine.check_sponsorship_policy(request).await?;
 
         // Validate the user operation
-        self.validate_user_operation(&request.user_operation).await?;
+        self.validate_user_operation(chain, &request.user_operation).await?;
+
+        // Calculate gas limits; the signature covers them since the client submits them as returned
+        let (valid_until, valid_after) = self.validity_window(request.validity_seconds)?;
+        let gas_estimates = self.estimate_gas_limits(chain, request, entry_point_version, valid_until, valid_after).await?;
+        let user_op = apply_gas_limits(&request.user_operation, &gas_estimates);
 
-        // Check gas policies
-        self.check_gas_policies(request).await?;
+        // Check gas policies against the operation as it will be submitted
+        let reservation = self.check_gas_policies(&SponsorRequest {
+            user_operation: user_op.clone(),
+            ..request.clone()
+        }).await?;
 
         // Generate paymaster signature
-        let paymaster_and_data = self.generate_paymaster_signature(&request.user_operation).await?;
+        let signed = self.sign_sponsorship(chain, &user_op, request, &gas_estimates, valid_until, valid_after).await;
+        self.hold_reservation(signed, &user_op, entry_point_version, &request.entry_point, request.chain_id, reservation).await
+    }
+
+    /// Build the signed paymaster fields for an operation that already carries its final gas limits
+    async fn sign_sponsorship(
+        &self,
+        chain: &Chain,
+        user_op: &VersionedUserOperation,
+        request: &SponsorRequest,
+        gas_estimates: &GasLimits,
+        valid_until: u64,
+        valid_after: u64,
+    ) -> PaymasterResult<PaymasterResponse> {
+        match user_op {
+            VersionedUserOperation::V06(user_op) => {
+                let paymaster_and_data = self.generate_paymaster_signature(
+                    chain,
+                    user_op,
+                    valid_until,
+                    valid_after,
+                ).await?;
 
-        // Calculate gas limits
-        let gas_estimates = self.estimate_gas_limits(&request.user_operation).await?;
+                Ok(PaymasterResponse {
+                    paymaster_and_data,
+                    pre_verification_gas: gas_estimates.pre_verification_gas.clone(),
+                    verification_gas_limit: gas_estimates.verification_gas_limit.clone(),
+                    call_gas_limit: gas_estimates.call_gas_limit.clone(),
+                    valid_until,
+                    valid_after,
+                    paymaster: None,
+                    paymaster_data: None,
+                    paymaster_verification_gas_limit: None,
+                    paymaster_post_op_gas_limit: None,
+                })
+            }
+            VersionedUserOperation::V07(user_op) => {
+                let (paymaster_data, paymaster_and_data) = self.generate_paymaster_signature_v07(
+                    chain,
+                    user_op,
+                    chain.entry_point_version(&request.entry_point)?,
+                    valid_until,
+                    valid_after,
+                ).await?;
 
-        Ok(PaymasterResult {
-            paymaster_and_data,
-            pre_verification_gas: gas_estimates.pre_verification_gas,
-            verification_gas_limit: gas_estimates.verification_gas_limit,
-            call_gas_limit: gas_estimates.call_gas_limit,
-        })
+                Ok(PaymasterResponse {
+                    paymaster_and_data,
+                    pre_verification_gas: gas_estimates.pre_verification_gas.clone(),
+                    verification_gas_limit: gas_estimates.verification_gas_limit.clone(),
+                    call_gas_limit: gas_estimates.call_gas_limit.clone(),
+                    valid_until,
+                    valid_after,
+                    paymaster: Some(to_checksum(&chain.paymaster_address, None)),
+                    paymaster_data: Some(paymaster_data),
+                    paymaster_verification_gas_limit: Some(gas_estimates.paymaster_verification_gas_limit.clone()),
+                    paymaster_post_op_gas_limit: Some(gas_estimates.paymaster_post_op_gas_limit.clone()),
+                })
+            }
+        }
     }
 
     /// Process ERC20 token payment for gas
     pub async fn process_erc20_payment(
         &self,
         request: &ERC20PaymentRequest,
-    ) -> PaymasterResult<PaymasterResult> {
-        info!("Processing ERC20 payment for token: {}", request.token);
+    ) -> PaymasterResult<PaymasterResponse> {
+        info!("Processing ERC20 payment for token: {} on chain {}", request.token, request.chain_id);
+        let chain = self.chains.get(request.chain_id)?;
 
         // Validate token is supported
-        if !self.settings.paymaster.supported_tokens.contains(&request.token) {
+        if chain.token(&request.token).is_none() {
             return Err(PaymasterError::InvalidUserOperation(
-                format!("Token {} is not supported", request.token)
+                format!("Token {} is not supported on chain {}", request.token, request.chain_id)
             ));
         }
 
+        let entry_point_version = self.check_entry_point(chain, &request.entry_point, &request.user_operation)?;
+
         // Validate user operation
-        self.validate_user_operation(&request.user_operation).await?;
+        self.validate_user_operation(chain, &request.user_operation).await?;
+
+        let (valid_until, valid_after) = self.validity_window(request.validity_seconds)?;
+        let mut policy_request = request.to_sponsor_request();
+        let gas_estimates = self.estimate_gas_limits(chain, &policy_request, entry_point_version, valid_until, valid_after).await?;
+        let user_op = apply_gas_limits(&request.user_operation, &gas_estimates);
+
+        // Policies apply to ERC20-paid operations too, e.g. the global gas cap
+        policy_request.user_operation = user_op.clone();
+        let reservation = self.policy_engine.check_policies(&policy_request).await?;
 
+        let signed = self.sign_erc20_payment(chain, request, &user_op, &gas_estimates, valid_until, valid_after).await;
+        self.hold_reservation(signed, &user_op, entry_point_version, &request.entry_point, request.chain_id, reservation).await
+    }
+
+    /// Price the operation in tokens and build the ERC20 paymaster fields
+    async fn sign_erc20_payment(
+        &self,
+        chain: &Chain,
+        request: &ERC20PaymentRequest,
+        user_op: &VersionedUserOperation,
+        gas_estimates: &GasLimits,
+        valid_until: u64,
+        valid_after: u64,
+    ) -> PaymasterResult<PaymasterResponse> {
         // Check token allowance
-        self.check_token_allowance(&request.user_operation.sender, &request.token).await?;
+        self.check_token_allowance(user_op.sender(), &request.token).await?;
 
         // Calculate token amount needed
-        let token_amount = self.calculate_token_amount(&request.user_operation, &request.token).await?;
+        let token_amount = self.calculate_token_amount(user_op, &request.token).await?;
 
         // Verify user has enough tokens
-        if token_amount > request.max_token_amount.parse::<u64>().unwrap_or(0) {
+        if U256::from(token_amount) > parse_quantity("max token amount", &request.max_token_amount)? {
             return Err(PaymasterError::InsufficientBalance(
                 format!("Required {} tokens, max allowed {}", token_amount, request.max_token_amount)
             ));
         }
 
         // Generate ERC20 paymaster signature
-        let paymaster_and_data = self.generate_erc20_paymaster_signature(
-            &request.user_operation,
+        let paymaster_data = self.generate_erc20_paymaster_signature(
             &request.token,
             token_amount,
+            valid_until,
+            valid_after,
         ).await?;
 
-        let gas_estimates = self.estimate_gas_limits(&request.user_operation).await?;
+        let paymaster_address = format!("{:?}", chain.paymaster_address);
+        let mut result = PaymasterResponse {
+            paymaster_and_data: format!("{}{}", paymaster_address, paymaster_data),
+            pre_verification_gas: gas_estimates.pre_verification_gas.clone(),
+            verification_gas_limit: gas_estimates.verification_gas_limit.clone(),
+            call_gas_limit: gas_estimates.call_gas_limit.clone(),
+            valid_until,
+            valid_after,
+            paymaster: None,
+            paymaster_data: None,
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+        };
 
-        Ok(PaymasterResult {
-            paymaster_and_data,
-            pre_verification_gas: gas_estimates.pre_verification_gas,
-            verification_gas_limit: gas_estimates.verification_gas_limit,
-            call_gas_limit: gas_estimates.call_gas_limit,
-        })
+        // v0.7 places the paymaster gas limits between the address and the paymaster data
+        if user_op.version() == EntryPointVersion::V07 {
+            let verification_gas = parse_quantity("paymaster verification gas limit", &gas_estimates.paymaster_verification_gas_limit)?;
+            let post_op_gas = parse_quantity("paymaster post op gas limit", &gas_estimates.paymaster_post_op_gas_limit)?;
+
+            result.paymaster_and_data = format!("{}{:032x}{:032x}{}", paymaster_address, verification_gas, post_op_gas, paymaster_data);
+            result.paymaster = Some(to_checksum(&chain.paymaster_address, None));
+            result.paymaster_data = Some(format!("0x{}", paymaster_data));
+            result.paymaster_verification_gas_limit = Some(gas_estimates.paymaster_verification_gas_limit.clone());
+            result.paymaster_post_op_gas_limit = Some(gas_estimates.paymaster_post_op_gas_limit.clone());
+        }
+
+        Ok(result)
+    }
+
+    /// Hold a signed operation's policy reservation under its userOpHash, or return it if signing failed
+    async fn hold_reservation(
+        &self,
+        signed: PaymasterResult<PaymasterResponse>,
+        user_op: &VersionedUserOperation,
+        entry_point_version: EntryPointVersion,
+        entry_point: &str,
+        chain_id: u64,
+        reservation: PolicyReservation,
+    ) -> PaymasterResult<PaymasterResponse> {
+        let signed = signed.and_then(|response| {
+            submitted_user_op_hash(user_op, entry_point_version, entry_point, chain_id, &response)
+                .map(|user_op_hash| (response, user_op_hash))
+        });
+
+        match signed {
+            Ok((response, user_op_hash)) => {
+                self.policy_engine.hold(chain_id, user_op_hash, reservation, response.valid_until).await;
+                Ok(response)
+            }
+            Err(e) => {
+                self.policy_engine.cancel(reservation).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Build placeholder paymaster fields for gas estimation (ERC-7677 stub data)
+    ///
+    /// Nothing is signed and no policy budget is consumed. The dummy signature
+    /// has the shape of a real one so the paymaster's validation gas is representative.
+    pub async fn get_paymaster_stub_data(&self, request: &SponsorRequest) -> PaymasterResult<PaymasterResponse> {
+        let chain = self.chains.get(request.chain_id)?;
+        self.check_entry_point(chain, &request.entry_point, &request.user_operation)?;
+
+        // The bundler estimates gas with the stub in place, so no simulation is run here
+        let gas_estimates = stub_gas_limits(&request.user_operation);
+        let (valid_until, valid_after) = self.validity_window(request.validity_seconds)?;
+        let paymaster_data = stub_paymaster_data(valid_until, valid_after)?;
+        let stubbed = with_paymaster_stub(chain, &request.user_operation, &gas_estimates, &paymaster_data)?;
+
+        match stubbed {
+            VersionedUserOperation::V06(user_op) => {
+                Ok(PaymasterResponse {
+                    paymaster_and_data: user_op.paymaster_and_data,
+                    pre_verification_gas: gas_estimates.pre_verification_gas,
+                    verification_gas_limit: gas_estimates.verification_gas_limit,
+                    call_gas_limit: gas_estimates.call_gas_limit,
+                    valid_until,
+                    valid_after,
+                    paymaster: None,
+                    paymaster_data: None,
+                    paymaster_verification_gas_limit: None,
+                    paymaster_post_op_gas_limit: None,
+                })
+            }
+            VersionedUserOperation::V07(user_op) => {
+                Ok(PaymasterResponse {
+                    paymaster_and_data: user_op.pack()?.paymaster_and_data.to_string(),
+                    pre_verification_gas: gas_estimates.pre_verification_gas,
+                    verification_gas_limit: gas_estimates.verification_gas_limit,
+                    call_gas_limit: gas_estimates.call_gas_limit,
+                    valid_until,
+                    valid_after,
+                    paymaster: Some(to_checksum(&chain.paymaster_address, None)),
+                    paymaster_data: Some(paymaster_data.to_string()),
+                    paymaster_verification_gas_limit: Some(gas_estimates.paymaster_verification_gas_limit),
+                    paymaster_post_op_gas_limit: Some(gas_estimates.paymaster_post_op_gas_limit),
+                })
+            }
+        }
+    }
+
+    /// Check the chain serves the EntryPoint and the user operation layout matches its version
+    fn check_entry_point(&self, chain: &Chain, entry_point: &str, user_op: &VersionedUserOperation) -> PaymasterResult<EntryPointVersion> {
+        let version = chain.entry_point_version(entry_point)?;
+        if user_op.version() != version.layout() {
+            return Err(PaymasterError::InvalidUserOperation(
+                format!("User operation format does not match entry point {} ({:?})", entry_point, version)
+            ));
+        }
+
+        if user_op.eip7702_auth().is_some() && version != EntryPointVersion::V08 {
+            return Err(PaymasterError::InvalidUserOperation(
+                "EIP-7702 authorizations require EntryPoint v0.8".to_string()
+            ));
+        }
+
+        Ok(version)
+    }
+
+    /// Compute the (validUntil, validAfter) window for a new signature
+    ///
+    /// Uses the configured default lifetime unless the request asks for a
+    /// shorter or longer one, which is capped at the configured maximum.
+    fn validity_window(&self, requested_seconds: Option<u64>) -> PaymasterResult<(u64, u64)> {
+        let settings = &self.settings.paymaster;
+        let lifetime = requested_seconds
+            .unwrap_or(settings.validity_window_seconds)
+            .min(settings.max_validity_window_seconds);
+
+        if lifetime == 0 {
+            return Err(PaymasterError::InvalidUserOperation(
+                "Validity window must be greater than zero".to_string()
+            ));
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(|e| PaymasterError::ConfigurationError(format!("System clock error: {}", e)))?
+            .as_secs();
+
+        Ok((now + lifetime, now))
     }
 
     /// Validate user operation structure and signature
-    async fn validate_user_operation(&self, user_op: &UserOperation) -> PaymasterResult<()> {
+    async fn validate_user_operation(&self, chain: &Chain, user_op: &VersionedUserOperation) -> PaymasterResult<()> {
         // Basic validation
-        if user_op.sender.is_empty() || user_op.call_data.is_empty() {
+        if user_op.sender().is_empty() || user_op.call_data().is_empty() {
             return Err(PaymasterError::InvalidUserOperation(
                 "Sender and call data are required".to_string()
             ));
         }
 
         // Validate sender is a contract or will be deployed
-        let sender_address: Address = user_op.sender.parse()
-            .map_err(|_| PaymasterError::InvalidUserOperation("Invalid sender address".to_string()))?;
+        let sender_address = user_op.sender_address()?;
 
         // Check if sender exists or has init code
-        let code = self.ethereum_client.get_code(sender_address, None).await
+        let code = chain.provider.get_code(sender_address, None).await
             .map_err(|e| PaymasterError::BlockchainError(e.to_string()))?;
 
-        if code.is_empty() && user_op.init_code.is_empty() {
+        if let Some(auth) = user_op.eip7702_auth() {
+            // An EOA sender delegating through the attached authorization
+            let account_nonce = chain.provider.get_transaction_count(sender_address, None).await
+                .map_err(|e| PaymasterError::BlockchainError(e.to_string()))?;
+            let delegate = Self::verify_eip7702_authorization(auth, sender_address, chain.chain_id, account_nonce)?;
+            self.check_eip7702_delegate(chain, delegate).await?;
+        } else if code.starts_with(&EIP7702_DELEGATION_PREFIX) {
+            // An EOA sender that is already delegated
+            self.check_eip7702_delegate(chain, Address::from_slice(&code[EIP7702_DELEGATION_PREFIX.len()..])).await?;
+        } else if code.is_empty() && !user_op.has_init_code() {
             return Err(PaymasterError::InvalidUserOperation(
                 "Sender must be deployed or have init code".to_string()
             ));
         }
 
         // Additional validation can be added here
-        info!("User operation validation passed for sender: {}", user_op.sender);
+        info!("User operation validation passed for sender: {}", user_op.sender());
         Ok(())
     }
 
-    /// Check if operation violates any gas policies
-    async fn check_gas_policies(&self, request: &SponsorRequest) -> PaymasterResult<()> {
-        // This would integrate with the policy engine
-        // For now, implement basic checks
-        
-        let gas_limit: u64 = request.user_operation.call_gas_limit.parse()
-            .unwrap_or(0);
-        
-        // Example: Check if gas limit is reasonable
-        if gas_limit > 10_000_000 {
-            return Err(PaymasterError::PolicyViolation(
-                "Gas limit exceeds maximum allowed".to_string()
+    /// Verify an EIP-7702 authorization was signed by the sender for this chain at its
+    /// current account nonce, returning its delegate
+    fn verify_eip7702_authorization(
+        auth: &Eip7702Auth,
+        sender: Address,
+        chain_id: u64,
+        account_nonce: U256,
+    ) -> PaymasterResult<Address> {
+        // Chain id 0 authorizes the delegation on every chain
+        let auth_chain_id = auth.chain_id()?;
+        if !auth_chain_id.is_zero() && auth_chain_id != U256::from(chain_id) {
+            return Err(PaymasterError::InvalidUserOperation(
+                format!("Authorization is for chain {}, not {}", auth_chain_id, chain_id)
+            ));
+        }
+
+        let authority = auth.recover_authority()?;
+        if authority != sender {
+            return Err(PaymasterError::InvalidUserOperation(
+                format!("Authorization signed by {:?}, not sender {:?}", authority, sender)
+            ));
+        }
+
+        // The bundler, not the sender, submits the transaction, so the authority's nonce is not bumped first
+        let auth_nonce = auth.nonce()?;
+        if auth_nonce != account_nonce {
+            return Err(PaymasterError::InvalidUserOperation(
+                format!("Authorization nonce {} does not match sender nonce {}", auth_nonce, account_nonce)
+            ));
+        }
+
+        auth.delegate()
+    }
+
+    /// Check an EIP-7702 delegate is an allowed, deployed account implementation
+    async fn check_eip7702_delegate(&self, chain: &Chain, delegate: Address) -> PaymasterResult<()> {
+        // Deny by default: a delegate controls the account, so each must be listed explicitly
+        let allowed = &self.settings.paymaster.eip7702_delegates;
+        if !allowed.iter().any(|address| address.parse::<Address>().ok() == Some(delegate)) {
+            return Err(PaymasterError::InvalidUserOperation(
+                format!("EIP-7702 delegate {:?} is not allowed", delegate)
+            ));
+        }
+
+        let code = chain.provider.get_code(delegate, None).await
+            .map_err(|e| PaymasterError::BlockchainError(e.to_string()))?;
+        if code.is_empty() {
+            return Err(PaymasterError::InvalidUserOperation(
+                format!("EIP-7702 delegate {:?} has no code", delegate)
             ));
         }
 
-        info!("Gas policy checks passed for request");
         Ok(())
     }
 
+    /// Run every configured policy, including the global gas cap, against the request
+    async fn check_gas_policies(&self, request: &SponsorRequest) -> PaymasterResult<PolicyReservation> {
+        let reservation = self.policy_engine.check_policies(request).await?;
+
+        info!("Gas policy checks passed for request");
+        Ok(reservation)
+    }
+
     /// Generate paymaster signature for sponsored operation
-    async fn generate_paymaster_signature(&self, user_op: &UserOperation) -> PaymasterResult<String> {
-        // This is a simplified implementation
-        // In production, this would involve proper signature generation
-        let paymaster_address = &self.settings.paymaster.address;
-        
-        // Format: paymaster_address + signature_data
-        let paymaster_and_data = format!("{}000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000", paymaster_address);
-        
-        info!("Generated paymaster signature for user operation");
-        Ok(paymaster_and_data)
+    ///
+    /// Encodes `paymasterAndData` in the verifying paymaster layout:
+    /// paymaster address || abi.encode(validUntil, validAfter) || signature
+    async fn generate_paymaster_signature(
+        &self,
+        chain: &Chain,
+        user_op: &UserOperation,
+        valid_until: u64,
+        valid_after: u64,
+    ) -> PaymasterResult<String> {
+        let sender_nonce = sender_nonce(chain, user_op.sender_address()?).await?;
+        let hash = Self::get_hash_v06(user_op, chain.chain_id, chain.paymaster_address, sender_nonce, valid_until, valid_after)?;
+
+        let mut paymaster_and_data = chain.paymaster_address.as_bytes().to_vec();
+        paymaster_and_data.extend(sign_paymaster_data(chain, hash, valid_until, valid_after).await?);
+
+        info!("Generated paymaster signature for user operation hash: {:?}", hash);
+        Ok(Bytes::from(paymaster_and_data).to_string())
+    }
+
+    /// Generate paymaster fields for a v0.7 or v0.8 operation
+    ///
+    /// The operation must already carry the paymaster gas limits. Returns
+    /// `paymasterData` (abi.encode(validUntil, validAfter) || signature) and
+    /// the complete packed `paymasterAndData`.
+    async fn generate_paymaster_signature_v07(
+        &self,
+        chain: &Chain,
+        user_op: &UserOperationV07,
+        entry_point_version: EntryPointVersion,
+        valid_until: u64,
+        valid_after: u64,
+    ) -> PaymasterResult<(String, String)> {
+        let mut user_op = user_op.clone();
+        user_op.paymaster = Some(format!("{:?}", chain.paymaster_address));
+        user_op.paymaster_data = None;
+
+        let packed = user_op.pack()?;
+        // The v0.8 VerifyingPaymaster hashes the packed operation exactly as v0.7 does
+        let hash = Self::get_hash_v07(&packed, chain.chain_id, chain.paymaster_address, valid_until, valid_after)?;
+        let paymaster_data = sign_paymaster_data(chain, hash, valid_until, valid_after).await?;
+
+        let mut paymaster_and_data = packed.paymaster_and_data.to_vec();
+        paymaster_and_data.extend_from_slice(&paymaster_data);
+
+        info!("Generated {:?} paymaster signature for user operation hash: {:?}", entry_point_version, hash);
+        Ok((
+            Bytes::from(paymaster_data).to_string(),
+            Bytes::from(paymaster_and_data).to_string(),
+        ))
+    }
+
+    /// Compute the v0.6 paymaster hash, matching `VerifyingPaymaster.getHash`
+    ///
+    /// The v0.6 contract also hashes `senderNonce[sender]`, which it bumps on every validation,
+    /// so a signature covers only the sender's next operation through this paymaster.
+    pub fn get_hash_v06(
+        user_op: &UserOperation,
+        chain_id: u64,
+        paymaster: Address,
+        sender_nonce: U256,
+        valid_until: u64,
+        valid_after: u64,
+    ) -> PaymasterResult<H256> {
+        let init_code = parse_hex_bytes("init code", &user_op.init_code)?;
+        let call_data = parse_hex_bytes("call data", &user_op.call_data)?;
+
+        let encoded = abi::encode(&[
+            Token::Address(user_op.sender_address()?),
+            Token::Uint(parse_quantity("nonce", &user_op.nonce)?),
+            Token::FixedBytes(keccak256(&init_code).to_vec()),
+            Token::FixedBytes(keccak256(&call_data).to_vec()),
+            Token::Uint(parse_quantity("call gas limit", &user_op.call_gas_limit)?),
+            Token::Uint(parse_quantity("verification gas limit", &user_op.verification_gas_limit)?),
+            Token::Uint(parse_quantity("pre verification gas", &user_op.pre_verification_gas)?),
+            Token::Uint(parse_quantity("max fee per gas", &user_op.max_fee_per_gas)?),
+            Token::Uint(parse_quantity("max priority fee per gas", &user_op.max_priority_fee_per_gas)?),
+            Token::Uint(chain_id.into()),
+            Token::Address(paymaster),
+            Token::Uint(sender_nonce),
+            Token::Uint(valid_until.into()),
+            Token::Uint(valid_after.into()),
+        ]);
+
+        Ok(H256(keccak256(encoded)))
+    }
+
+    /// Compute the v0.7 and v0.8 paymaster hash, matching `VerifyingPaymaster.getHash`
+    pub fn get_hash_v07(
+        user_op: &PackedUserOperation,
+        chain_id: u64,
+        paymaster: Address,
+        valid_until: u64,
+        valid_after: u64,
+    ) -> PaymasterResult<H256> {
+        let encoded = abi::encode(&[
+            Token::Address(user_op.sender),
+            Token::Uint(user_op.nonce),
+            Token::FixedBytes(keccak256(&user_op.init_code).to_vec()),
+            Token::FixedBytes(keccak256(&user_op.call_data).to_vec()),
+            Token::FixedBytes(user_op.account_gas_limits.as_bytes().to_vec()),
+            Token::Uint(user_op.paymaster_gas_limits()?),
+            Token::Uint(user_op.pre_verification_gas),
+            Token::FixedBytes(user_op.gas_fees.as_bytes().to_vec()),
+            Token::Uint(chain_id.into()),
+            Token::Address(paymaster),
+            Token::Uint(valid_until.into()),
+            Token::Uint(valid_after.into()),
+        ]);
+
+        Ok(H256(keccak256(encoded)))
     }
 
     /// Generate ERC20 paymaster signature
+    ///
+    /// Returns the paymaster data that follows the paymaster address (and, on
+    /// v0.7, the paymaster gas limits) as unprefixed hex.
     async fn generate_erc20_paymaster_signature(
         &self,
-        user_op: &UserOperation,
         token: &str,
         amount: u64,
+        valid_until: u64,
+        valid_after: u64,
     ) -> PaymasterResult<String> {
         // This would involve ERC20 paymaster contract interaction
-        let paymaster_address = &self.settings.paymaster.address;
-        
-        // Include token address and amount in the signature data
-        let paymaster_and_data = format!("{}{}{}",
-            paymaster_address,
+        // Include the validity window, token address and amount in the signature data
+        let paymaster_data = format!("{:064x}{:064x}{}{:064x}",
+            valid_until,
+            valid_after,
             token.trim_start_matches("0x"),
-            format!("{:064x}", amount)
+            amount
         );
         
         info!("Generated ERC20 paymaster signature for token: {}", token);
-        Ok(paymaster_and_data)
+        Ok(paymaster_data)
     }
 
-    /// Estimate gas limits for the operation
-    async fn estimate_gas_limits(&self, user_op: &UserOperation) -> PaymasterResult<GasLimits> {
-        // This would involve actual gas estimation
-        // For now, return reasonable defaults
+    /// Estimate gas limits for the operation by simulating it with stub paymaster data
+    ///
+    /// Limits the client has set are kept when they cover the estimate, since the paymaster
+    /// signature must cover exactly the values the client submits. Limits that could not be
+    /// simulated keep the client's value or a default.
+    async fn estimate_gas_limits(
+        &self,
+        chain: &Chain,
+        request: &SponsorRequest,
+        entry_point_version: EntryPointVersion,
+        valid_until: u64,
+        valid_after: u64,
+    ) -> PaymasterResult<GasLimits> {
+        let user_op = &request.user_operation;
+        let entry_point = request.entry_point.parse::<Address>()
+            .map_err(|_| PaymasterError::InvalidUserOperation(format!("Invalid entry point: {}", request.entry_point)))?;
+
+        let defaults = stub_gas_limits(user_op);
+        let stubbed = with_paymaster_stub(chain, user_op, &defaults, &stub_paymaster_data(valid_until, valid_after)?)?;
+        let simulated = chain.simulator.estimate(&stubbed, entry_point, entry_point_version).await?;
+
+        // On rollups the bundler recovers the L1 data fee through preVerificationGas
+        let l1_gas = match self.l1_fees.estimate(chain.chain_id, entry_point, &stubbed).await? {
+            Some(fee) => fee.gas_at(fee.effective_gas_price(
+                parse_quantity("max fee per gas", user_op.max_fee_per_gas())?,
+                parse_quantity("max priority fee per gas", user_op.max_priority_fee_per_gas())?,
+            )),
+            None => U256::zero(),
+        };
+        let pre_verification_gas = simulated.pre_verification_gas.saturating_add(l1_gas);
+
         Ok(GasLimits {
-            pre_verification_gas: "21000".to_string(),
-            verification_gas_limit: "100000".to_string(),
-            call_gas_limit: user_op.call_gas_limit.clone(),
+            pre_verification_gas: client_or_estimate(user_op.pre_verification_gas(), Some(pre_verification_gas), &defaults.pre_verification_gas),
+            verification_gas_limit: client_or_estimate(user_op.verification_gas_limit(), simulated.verification_gas_limit, &defaults.verification_gas_limit),
+            call_gas_limit: client_or_estimate(user_op.call_gas_limit(), simulated.call_gas_limit, &defaults.call_gas_limit),
+            ..defaults
         })
     }
 
@@ -203,20 +624,20 @@ impl PaymasterService {
     }
 
     /// Calculate required token amount for gas payment
-    async fn calculate_token_amount(&self, user_op: &UserOperation, token: &str) -> PaymasterResult<u64> {
+    async fn calculate_token_amount(&self, user_op: &VersionedUserOperation, _token: &str) -> PaymasterResult<u64> {
         // This would involve:
         // 1. Estimating gas cost in ETH
         // 2. Converting ETH to token amount using price oracle
         // 3. Adding markup percentage
         
-        let gas_limit: u64 = user_op.call_gas_limit.parse().unwrap_or(100000);
-        let gas_price: u64 = user_op.max_fee_per_gas.parse().unwrap_or(20_000_000_000); // 20 gwei
-        
-        let gas_cost_wei = gas_limit * gas_price;
+        // Every gas limit, so the L1 data fee in preVerificationGas is charged on rollups
+        let gas_cost_wei = user_op.max_gas_cost()?;
         
         // Simplified conversion (in production, use real price oracle)
         // Assume 1 ETH = 2000 USDC, 1 USDC = 1e6 (6 decimals)
-        let token_amount = (gas_cost_wei / 1_000_000_000_000_000_000) * 2000 * 1_000_000;
+        let token_amount = (gas_cost_wei.saturating_mul(U256::from(2000u64 * 1_000_000)) / U256::exp10(18))
+            .min(U256::from(u64::MAX))
+            .as_u64();
         
         // Add markup
         let markup_multiplier = 1.0 + (self.settings.paymaster.gas_markup_percentage / 100.0);
@@ -227,9 +648,563 @@ impl PaymasterService {
     }
 }
 
+/// The v0.6 paymaster's `senderNonce(sender)`, which its `getHash` covers
+async fn sender_nonce(chain: &Chain, sender: Address) -> PaymasterResult<U256> {
+    let mut data = id("senderNonce(address)").to_vec();
+    data.extend(abi::encode(&[Token::Address(sender)]));
+    let call: TypedTransaction = TransactionRequest::new().to(chain.paymaster_address).data(data).into();
+
+    let output = chain.provider.call(&call, None).await
+        .map_err(|e| PaymasterError::BlockchainError(format!("Failed to read the paymaster's sender nonce: {}", e)))?;
+    abi::decode(&[ParamType::Uint(256)], &output).ok()
+        .and_then(|decoded| decoded[0].clone().into_uint())
+        .ok_or_else(|| PaymasterError::BlockchainError(format!("Unexpected senderNonce result: {}", output)))
+}
+
+/// Sign a paymaster hash, returning abi.encode(validUntil, validAfter) || signature
+async fn sign_paymaster_data(chain: &Chain, hash: H256, valid_until: u64, valid_after: u64) -> PaymasterResult<Vec<u8>> {
+    // The contract recovers the signer from the EIP-191 prefixed hash
+    let signature = chain.signer.sign_message(hash.as_bytes()).await
+        .map_err(|e| PaymasterError::ConfigurationError(format!("Paymaster signing failed: {}", e)))?;
+
+    let mut paymaster_data = abi::encode(&[
+        Token::Uint(valid_until.into()),
+        Token::Uint(valid_after.into()),
+    ]);
+    paymaster_data.extend(signature.to_vec());
+    Ok(paymaster_data)
+}
+
+/// The operation with the gas limits the client will submit
+fn apply_gas_limits(user_op: &VersionedUserOperation, gas_estimates: &GasLimits) -> VersionedUserOperation {
+    match user_op {
+        VersionedUserOperation::V06(user_op) => {
+            let mut user_op = user_op.clone();
+            user_op.pre_verification_gas = gas_estimates.pre_verification_gas.clone();
+            user_op.verification_gas_limit = gas_estimates.verification_gas_limit.clone();
+            user_op.call_gas_limit = gas_estimates.call_gas_limit.clone();
+            VersionedUserOperation::V06(user_op)
+        }
+        VersionedUserOperation::V07(user_op) => {
+            let mut user_op = user_op.clone();
+            user_op.pre_verification_gas = gas_estimates.pre_verification_gas.clone();
+            user_op.verification_gas_limit = gas_estimates.verification_gas_limit.clone();
+            user_op.call_gas_limit = gas_estimates.call_gas_limit.clone();
+            user_op.paymaster_verification_gas_limit = Some(gas_estimates.paymaster_verification_gas_limit.clone());
+            user_op.paymaster_post_op_gas_limit = Some(gas_estimates.paymaster_post_op_gas_limit.clone());
+            VersionedUserOperation::V07(user_op)
+        }
+    }
+}
+
+/// The userOpHash the EntryPoint will emit for the operation submitted with these paymaster fields
+fn submitted_user_op_hash(
+    user_op: &VersionedUserOperation,
+    entry_point_version: EntryPointVersion,
+    entry_point: &str,
+    chain_id: u64,
+    response: &PaymasterResponse,
+) -> PaymasterResult<H256> {
+    let entry_point = entry_point.parse::<Address>()
+        .map_err(|_| PaymasterError::InvalidUserOperation(format!("Invalid entry point: {}", entry_point)))?;
+
+    match user_op {
+        VersionedUserOperation::V06(user_op) => {
+            let mut user_op = user_op.clone();
+            user_op.paymaster_and_data = response.paymaster_and_data.clone();
+            user_op.user_op_hash(entry_point, chain_id)
+        }
+        VersionedUserOperation::V07(user_op) => {
+            let mut user_op = user_op.clone();
+            user_op.paymaster = response.paymaster.clone();
+            user_op.paymaster_data = response.paymaster_data.clone();
+            let packed = user_op.pack()?;
+
+            Ok(match entry_point_version {
+                EntryPointVersion::V08 => {
+                    let delegate = user_op.eip7702_auth.as_deref().map(Eip7702Auth::delegate).transpose()?;
+                    packed.eip712_hash(entry_point, chain_id, delegate)
+                }
+                _ => packed.user_op_hash(entry_point, chain_id),
+            })
+        }
+    }
+}
+
+/// Gas limits for stub data: the client's where set, otherwise defaults
+fn stub_gas_limits(user_op: &VersionedUserOperation) -> GasLimits {
+    let (paymaster_verification_gas_limit, paymaster_post_op_gas_limit) = user_op.paymaster_gas_limits();
+
+    GasLimits {
+        pre_verification_gas: client_or_default(Some(user_op.pre_verification_gas()), "21000"),
+        verification_gas_limit: client_or_default(Some(user_op.verification_gas_limit()), "100000"),
+        call_gas_limit: user_op.call_gas_limit().to_string(),
+        paymaster_verification_gas_limit: client_or_default(paymaster_verification_gas_limit, "60000"),
+        paymaster_post_op_gas_limit: client_or_default(paymaster_post_op_gas_limit, "0"),
+    }
+}
+
+/// The operation carrying this paymaster's address, stub data and paymaster gas limits
+fn with_paymaster_stub(
+    chain: &Chain,
+    user_op: &VersionedUserOperation,
+    gas_estimates: &GasLimits,
+    paymaster_data: &Bytes,
+) -> PaymasterResult<VersionedUserOperation> {
+    Ok(match user_op {
+        VersionedUserOperation::V06(user_op) => {
+            let mut paymaster_and_data = chain.paymaster_address.as_bytes().to_vec();
+            paymaster_and_data.extend_from_slice(paymaster_data);
+
+            let mut user_op = user_op.clone();
+            user_op.paymaster_and_data = Bytes::from(paymaster_and_data).to_string();
+            VersionedUserOperation::V06(user_op)
+        }
+        VersionedUserOperation::V07(user_op) => {
+            let mut user_op = user_op.clone();
+            user_op.paymaster = Some(format!("{:?}", chain.paymaster_address));
+            user_op.paymaster_verification_gas_limit = Some(gas_estimates.paymaster_verification_gas_limit.clone());
+            user_op.paymaster_post_op_gas_limit = Some(gas_estimates.paymaster_post_op_gas_limit.clone());
+            user_op.paymaster_data = Some(paymaster_data.to_string());
+            VersionedUserOperation::V07(user_op)
+        }
+    })
+}
+
+/// abi.encode(validUntil, validAfter) followed by the dummy signature
+fn stub_paymaster_data(valid_until: u64, valid_after: u64) -> PaymasterResult<Bytes> {
+    let mut paymaster_data = abi::encode(&[
+        Token::Uint(valid_until.into()),
+        Token::Uint(valid_after.into()),
+    ]);
+    paymaster_data.extend_from_slice(&parse_hex_bytes("dummy signature", DUMMY_SIGNATURE)?);
+    Ok(Bytes::from(paymaster_data))
+}
+
+/// Keep the client's gas value when it covers the estimate, otherwise use the estimate
+///
+/// Without an estimate this falls back to `client_or_default`.
+fn client_or_estimate(value: &str, estimate: Option<U256>, default: &str) -> String {
+    let Some(estimate) = estimate else {
+        return client_or_default(Some(value), default);
+    };
+
+    match parse_quantity("gas limit", value) {
+        Ok(gas) if gas >= estimate => value.to_string(),
+        _ => format!("{:#x}", estimate),
+    }
+}
+
+/// Keep a non-zero gas value supplied by the client, otherwise use the default
+fn client_or_default(value: Option<&str>, default: &str) -> String {
+    match value {
+        Some(value) if parse_quantity("gas limit", value).is_ok_and(|gas| !gas.is_zero()) => value.to_string(),
+        _ => default.to_string(),
+    }
+}
+
 #[derive(Debug)]
 struct GasLimits {
     pub pre_verification_gas: String,
     pub verification_gas_limit: String,
     pub call_gas_limit: String,
+    pub paymaster_verification_gas_limit: String, // v0.7 only
+    pub paymaster_post_op_gas_limit: String,      // v0.7 only
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{settings, PAYMASTER};
+
+    // Expected hashes are abi.encode of the fields the eth-infinitism VerifyingPaymaster.getHash
+    // of each version hashes, computed with a Keccak-256 and ABI encoder independent of this
+    // crate; signatures are the `settings()` signer's (Hardhat account #0) over those hashes.
+
+    /// `senderNonce` the mock paymaster reports for every sender
+    const SENDER_NONCE: u64 = 5;
+
+    /// A node whose v0.6 paymaster at PAYMASTER reports `SENDER_NONCE`
+    async fn mock_node() -> String {
+        use axum::{routing::post, Json, Router};
+        use serde_json::{json, Value};
+
+        let router = Router::new().route("/", post(|Json(request): Json<Value>| async move {
+            assert_eq!(request["method"], "eth_call");
+            let call = &request["params"][0];
+            assert!(call["to"].as_str().unwrap().eq_ignore_ascii_case(PAYMASTER));
+            let data: Bytes = serde_json::from_value(call["data"].clone()).unwrap();
+            assert_eq!(&data[..4], &id("senderNonce(address)"));
+            let result = Bytes::from(abi::encode(&[Token::Uint(SENDER_NONCE.into())]));
+            Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+        }));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", address)
+    }
+
+    async fn test_service() -> PaymasterService {
+        let mut settings = settings();
+        settings.blockchain.chains[0].rpc_url = mock_node().await;
+        settings.blockchain.chains.push(ChainConfig {
+            chain_id: 11155111,
+            name: "sepolia".to_string(),
+            ..settings.blockchain.chains[0].clone()
+        });
+        let chains = Arc::new(ChainRegistry::new(&settings).unwrap());
+        let policy_engine = PolicyEngine::new(&settings.redis.url, &settings.policy).unwrap();
+        PaymasterService::new(settings, chains, Arc::new(policy_engine)).await.unwrap()
+    }
+
+    fn chain(service: &PaymasterService, chain_id: u64) -> &Chain {
+        service.chains.get(chain_id).unwrap()
+    }
+
+    fn simple_user_op() -> UserOperation {
+        UserOperation {
+            sender: "0x1306b01bc3e4ad202612d3843387e94737673f53".to_string(),
+            nonce: "0".to_string(),
+            init_code: "0x".to_string(),
+            call_data: "0xb61d27f6".to_string(),
+            call_gas_limit: "100000".to_string(),
+            verification_gas_limit: "150000".to_string(),
+            pre_verification_gas: "21000".to_string(),
+            max_fee_per_gas: "20000000000".to_string(),
+            max_priority_fee_per_gas: "1000000000".to_string(),
+            paymaster_and_data: "0x".to_string(),
+            signature: "0x".to_string(),
+        }
+    }
+
+    fn deploying_user_op() -> UserOperation {
+        UserOperation {
+            sender: "0x1306b01bC3e4AD202612D3843387e94737673F53".to_string(),
+            nonce: "0x7".to_string(),
+            init_code: "0x9406cc6185a346906296840746125a0e449764545fbfb9cf000000000000000000000000f39fd6e51aad88f6f4ce6ab8827279cfffb922660000000000000000000000000000000000000000000000000000000000000000".to_string(),
+            call_data: "0xb61d27f6000000000000000000000000f39fd6e51aad88f6f4ce6ab8827279cfffb92266000000000000000000000000000000000000000000000000000de0b6b3a764000000000000000000000000000000000000000000000000000000000000000000600000000000000000000000000000000000000000000000000000000000000000".to_string(),
+            call_gas_limit: "0x88b8".to_string(),
+            verification_gas_limit: "0x61a80".to_string(),
+            pre_verification_gas: "0xbb80".to_string(),
+            max_fee_per_gas: "0xb2d05e00".to_string(),
+            max_priority_fee_per_gas: "0x59682f00".to_string(),
+            paymaster_and_data: "0x".to_string(),
+            signature: "0x".to_string(),
+        }
+    }
+
+    fn deploying_user_op_v07() -> UserOperationV07 {
+        UserOperationV07 {
+            sender: "0x1306b01bc3e4ad202612d3843387e94737673f53".to_string(),
+            nonce: "3".to_string(),
+            factory: Some("0x91E60e0613810449d098b0b5Ec8b51A0FE8c8985".to_string()),
+            factory_data: Some("0x5fbfb9cf000000000000000000000000f39fd6e51aad88f6f4ce6ab8827279cfffb922660000000000000000000000000000000000000000000000000000000000000000".to_string()),
+            call_data: "0xb61d27f6000000000000000000000000f39fd6e51aad88f6f4ce6ab8827279cfffb92266000000000000000000000000000000000000000000000000000de0b6b3a764000000000000000000000000000000000000000000000000000000000000000000600000000000000000000000000000000000000000000000000000000000000000".to_string(),
+            call_gas_limit: "35000".to_string(),
+            verification_gas_limit: "400000".to_string(),
+            pre_verification_gas: "48000".to_string(),
+            max_fee_per_gas: "3000000000".to_string(),
+            max_priority_fee_per_gas: "1500000000".to_string(),
+            paymaster: None,
+            paymaster_verification_gas_limit: Some("60000".to_string()),
+            paymaster_post_op_gas_limit: Some("0".to_string()),
+            paymaster_data: None,
+            signature: "0x".to_string(),
+            eip7702_auth: None,
+        }
+    }
+
+    #[test]
+    fn get_hash_matches_contract() {
+        let paymaster: Address = PAYMASTER.parse().unwrap();
+
+        let hash = PaymasterService::get_hash_v06(&simple_user_op(), 1, paymaster, U256::zero(), 0, 0).unwrap();
+        assert_eq!(
+            format!("{:?}", hash),
+            "0xa16081e8e5b25b11130f524009dad5b0ba7cf5ef1314a02641cbdab813f42d04"
+        );
+
+        let nonce = U256::from(SENDER_NONCE);
+        let hash = PaymasterService::get_hash_v06(&simple_user_op(), 1, paymaster, nonce, 0, 0).unwrap();
+        assert_eq!(
+            format!("{:?}", hash),
+            "0x50985478ecafd54a002d49a6a31eb7eafa06146036b80ec2fac5aac6d71f044f"
+        );
+
+        let hash = PaymasterService::get_hash_v06(&deploying_user_op(), 11155111, paymaster, nonce, 1767225600, 1767222000).unwrap();
+        assert_eq!(
+            format!("{:?}", hash),
+            "0x48b506e6dfaf4d71e9dacdfcdaaa8058d34ded85e8837e6844360217d733182a"
+        );
+    }
+
+    #[tokio::test]
+    async fn paymaster_and_data_matches_contract_layout() {
+        let service = test_service().await;
+
+        let paymaster_and_data = service
+            .generate_paymaster_signature(chain(&service, 1), &simple_user_op(), 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            paymaster_and_data,
+            "0x9d7f74d0c41e726ec95884e0e97fa6129e3b5e99\
+             0000000000000000000000000000000000000000000000000000000000000000\
+             0000000000000000000000000000000000000000000000000000000000000000\
+             15a6e56ae7486737269f33baca1930d219e05c303f22d2f1c308765776287fdc\
+             16fd5325b871d9a130a0b222c8dfe24df0c9440fdc6f4382e2f57afe80714258\
+             1b"
+        );
+
+        let paymaster_and_data = service
+            .generate_paymaster_signature(chain(&service, 11155111), &deploying_user_op(), 1767225600, 1767222000)
+            .await
+            .unwrap();
+        assert_eq!(
+            paymaster_and_data,
+            "0x9d7f74d0c41e726ec95884e0e97fa6129e3b5e99\
+             000000000000000000000000000000000000000000000000000000006955b900\
+             000000000000000000000000000000000000000000000000000000006955aaf0\
+             da920b689f8d5bae7c9768c697a55552696f110253636a606344b6d4f2820083\
+             69f1c775f563efcfdaa166e7e3d613cfa53b51a9691fb29fc73751c1397b1718\
+             1b"
+        );
+    }
+
+    #[test]
+    fn get_hash_v07_matches_contract() {
+        let paymaster: Address = PAYMASTER.parse().unwrap();
+        let mut user_op = deploying_user_op_v07();
+        user_op.paymaster = Some(PAYMASTER.to_string());
+
+        let hash = PaymasterService::get_hash_v07(&user_op.pack().unwrap(), 11155111, paymaster, 1767225600, 1767222000).unwrap();
+        assert_eq!(
+            format!("{:?}", hash),
+            "0x13df54963eb51f48a35f2d703227ad082c26655fe120cdf8a689a41c0e4d2d11"
+        );
+    }
+
+    #[tokio::test]
+    async fn paymaster_data_v07_matches_contract_layout() {
+        let service = test_service().await;
+
+        let (paymaster_data, paymaster_and_data) = service
+            .generate_paymaster_signature_v07(chain(&service, 11155111), &deploying_user_op_v07(), EntryPointVersion::V07, 1767225600, 1767222000)
+            .await
+            .unwrap();
+
+        let expected_paymaster_data = "0x\
+             000000000000000000000000000000000000000000000000000000006955b900\
+             000000000000000000000000000000000000000000000000000000006955aaf0\
+             dbe6541b4708b03fd18cc051c119f68bbf5121b1032b1de37a1400289ac748a6\
+             0910d12c772e8b2730f28cbe685d7d3c2b80bb25c7b62d01dfff5964ff1bfe16\
+             1b";
+        assert_eq!(paymaster_data, expected_paymaster_data);
+        assert_eq!(
+            paymaster_and_data,
+            format!(
+                "0x9d7f74d0c41e726ec95884e0e97fa6129e3b5e99\
+                 0000000000000000000000000000ea60\
+                 00000000000000000000000000000000{}",
+                &expected_paymaster_data[2..]
+            )
+        );
+    }
+
+    // Hardhat account #1 delegating to DELEGATE on Sepolia
+    const DELEGATE: &str = "0xe6Cae83BdE06E4c305530e199D7217f42808555B";
+
+    fn delegated_user_op_v08() -> UserOperationV07 {
+        UserOperationV07 {
+            sender: "0x70997970C51812dc3A010C7d01b50e0d17dc79C8".to_string(),
+            nonce: "0".to_string(),
+            factory: Some(EIP7702_FACTORY_MARKER.to_string()),
+            factory_data: None,
+            call_data: "0xb61d27f6".to_string(),
+            call_gas_limit: "50000".to_string(),
+            verification_gas_limit: "150000".to_string(),
+            pre_verification_gas: "60000".to_string(),
+            max_fee_per_gas: "2000000000".to_string(),
+            max_priority_fee_per_gas: "1000000000".to_string(),
+            paymaster: Some(PAYMASTER.to_string()),
+            paymaster_verification_gas_limit: Some("60000".to_string()),
+            paymaster_post_op_gas_limit: Some("0".to_string()),
+            paymaster_data: None,
+            signature: "0x".to_string(),
+            eip7702_auth: Some(Box::new(Eip7702Auth {
+                chain_id: "0xaa36a7".to_string(),
+                address: DELEGATE.to_string(),
+                nonce: "0x0".to_string(),
+                y_parity: "0x0".to_string(),
+                r: "0x0e332d000092b4164e5c6afa0996299d60eb762c1fe711356871f6dd61a0fa35".to_string(),
+                s: "0x3360e6ecfb56d6e191c8889d2cea740e710b3f99ffc07cdde50745d8bec9df42".to_string(),
+            })),
+        }
+    }
+
+    #[test]
+    fn get_hash_v08_matches_contract() {
+        // initCode is the 0x7702 marker; the paymaster hashes it as sent, not the delegate
+        let paymaster: Address = PAYMASTER.parse().unwrap();
+        let packed = delegated_user_op_v08().pack().unwrap();
+
+        let hash = PaymasterService::get_hash_v07(&packed, 11155111, paymaster, 1767225600, 1767222000).unwrap();
+        assert_eq!(
+            format!("{:?}", hash),
+            "0x5cfe3c6546d0cb45447cccc0e1e9176bd6ccb7426ecb016b326e57478537e45f"
+        );
+
+        // The EntryPoint's own userOpHash is EIP-712 and substitutes the delegate
+        let entry_point: Address = ENTRY_POINT_V08.parse().unwrap();
+        assert_eq!(
+            format!("{:?}", packed.eip712_hash(entry_point, 11155111, Some(DELEGATE.parse().unwrap()))),
+            "0x95043e025b2c13dd16aec987c14b9bbeadf816dc059f19df6f6f709177083450"
+        );
+    }
+
+    #[test]
+    fn eip7702_authorization_must_be_signed_by_sender() {
+        let user_op = delegated_user_op_v08();
+        let auth = user_op.eip7702_auth.as_ref().unwrap();
+        let sender: Address = user_op.sender.parse().unwrap();
+
+        assert_eq!(
+            format!("{:?}", auth.signing_hash().unwrap()),
+            "0x25cfe23323205cb1d5abd43056028d6f6831c9b560a86eb47529a4c7890ce615"
+        );
+        assert_eq!(
+            PaymasterService::verify_eip7702_authorization(auth, sender, 11155111, U256::zero()).unwrap(),
+            DELEGATE.parse::<Address>().unwrap()
+        );
+
+        // Wrong chain
+        assert!(PaymasterService::verify_eip7702_authorization(auth, sender, 1, U256::zero()).is_err());
+
+        // Signed by someone other than the sender
+        let other: Address = PAYMASTER.parse().unwrap();
+        assert!(PaymasterService::verify_eip7702_authorization(auth, other, 11155111, U256::zero()).is_err());
+
+        // Stale nonce: the sender has sent a transaction since signing
+        assert!(PaymasterService::verify_eip7702_authorization(auth, sender, 11155111, U256::one()).is_err());
+    }
+
+    #[tokio::test]
+    async fn eip7702_delegates_are_denied_unless_listed() {
+        let service = test_service().await;
+        let result = service.check_eip7702_delegate(chain(&service, 11155111), DELEGATE.parse().unwrap()).await;
+        assert!(matches!(result, Err(PaymasterError::InvalidUserOperation(message)) if message.contains("not allowed")));
+    }
+
+    #[tokio::test]
+    async fn eip7702_authorization_requires_v08_entry_point() {
+        let service = test_service().await;
+        let user_op = VersionedUserOperation::V07(delegated_user_op_v08());
+
+        let sepolia = chain(&service, 11155111);
+
+        assert_eq!(service.check_entry_point(sepolia, ENTRY_POINT_V08, &user_op).unwrap(), EntryPointVersion::V08);
+        assert!(service.check_entry_point(sepolia, ENTRY_POINT_V07, &user_op).is_err());
+        assert!(service.check_entry_point(sepolia, ENTRY_POINT_V06, &user_op).is_err());
+        assert!(!user_op.has_init_code());
+    }
+
+    #[tokio::test]
+    async fn signature_recovers_to_paymaster_signer() {
+        let service = test_service().await;
+        let user_op = simple_user_op();
+
+        let paymaster_and_data: Bytes = service
+            .generate_paymaster_signature(chain(&service, 1), &user_op, 0, 0)
+            .await
+            .unwrap()
+            .parse()
+            .unwrap();
+        let signature = Signature::try_from(&paymaster_and_data[84..]).unwrap();
+        let paymaster = chain(&service, 1).paymaster_address;
+        let hash = PaymasterService::get_hash_v06(&user_op, 1, paymaster, SENDER_NONCE.into(), 0, 0).unwrap();
+
+        assert_eq!(signature.recover(hash.as_bytes()).unwrap(), chain(&service, 1).signer.address());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_chains() {
+        let service = test_service().await;
+        let mut request = SponsorRequest {
+            user_operation: VersionedUserOperation::V06(simple_user_op()),
+            entry_point: ENTRY_POINT_V06.to_string(),
+            chain_id: 1,
+            validity_seconds: None,
+            sponsorship_policy_id: None,
+        };
+        assert!(service.get_paymaster_stub_data(&request).await.is_ok());
+
+        request.chain_id = 10;
+        assert!(matches!(service.get_paymaster_stub_data(&request).await, Err(PaymasterError::UnsupportedChain(10))));
+        assert!(matches!(service.sponsor_user_operation(&request).await, Err(PaymasterError::UnsupportedChain(10))));
+    }
+
+    #[tokio::test]
+    async fn validity_window_is_capped_by_server() {
+        let service = test_service().await;
+
+        let (valid_until, valid_after) = service.validity_window(None).unwrap();
+        assert_eq!(valid_until - valid_after, 600);
+
+        let (valid_until, valid_after) = service.validity_window(Some(60)).unwrap();
+        assert_eq!(valid_until - valid_after, 60);
+
+        let (valid_until, valid_after) = service.validity_window(Some(86_400)).unwrap();
+        assert_eq!(valid_until - valid_after, 3600);
+
+        assert!(service.validity_window(Some(0)).is_err());
+    }
+
+    fn paymaster_response(paymaster_and_data: &str) -> PaymasterResponse {
+        PaymasterResponse {
+            paymaster_and_data: paymaster_and_data.to_string(),
+            pre_verification_gas: String::new(),
+            verification_gas_limit: String::new(),
+            call_gas_limit: String::new(),
+            valid_until: 0,
+            valid_after: 0,
+            paymaster: None,
+            paymaster_data: None,
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+        }
+    }
+
+    #[test]
+    fn submitted_user_op_hash_matches_entry_point_v06() {
+        let user_op = VersionedUserOperation::V06(simple_user_op());
+        let response = paymaster_response("0x9d7f74d0c41e726ec95884e0e97fa6129e3b5e991234");
+
+        let hash = submitted_user_op_hash(&user_op, EntryPointVersion::V06, ENTRY_POINT_V06, 1, &response).unwrap();
+        assert_eq!(
+            format!("{:?}", hash),
+            "0x1b2c99461ccbeb93d0cb5f6ced66e2c7d9f3f5cbb666d03efd96c3b1cdc274cc"
+        );
+    }
+
+    #[test]
+    fn submitted_user_op_hash_matches_entry_point_v07() {
+        let mut user_op = deploying_user_op_v07();
+        user_op.nonce = "3".to_string();
+        user_op.factory = None;
+        user_op.factory_data = None;
+        user_op.call_data = "0xb61d27f6".to_string();
+        let user_op = VersionedUserOperation::V07(user_op);
+
+        let mut response = paymaster_response("");
+        response.paymaster = Some(PAYMASTER.to_string());
+        response.paymaster_data = Some("0x1234".to_string());
+
+        let hash = submitted_user_op_hash(&user_op, EntryPointVersion::V07, ENTRY_POINT_V07, 11155111, &response).unwrap();
+        assert_eq!(
+            format!("{:?}", hash),
+            "0xf5dcf20c9c550a55e76bf225f2e1d15ba08f82081cfb4c8e7548b42b909aae0d"
+        );
+    }
 }
+
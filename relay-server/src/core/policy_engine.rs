@@ -1,39 +1,221 @@
+use crate::config::settings::PolicySettings;
 use crate::core::types::*;
-use redis::AsyncCommands;
+use crate::core::user_operation::*;
+use crate::core::webhook::*;
+use crate::core::call_rules::*;
+use crate::core::call_targets::*;
+use crate::core::circuit_breaker::*;
+use crate::core::clock::*;
+use crate::core::counter_store::*;
+use ethers::types::{Address, H256, U256};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{info, warn, error};
+use tokio::sync::{broadcast, RwLock};
+use tracing::{info, warn};
+
+/// Id reported when the global call gas cap rejects an operation
+pub const GLOBAL_GAS_CAP_POLICY_ID: &str = "global_gas_cap";
+
+/// Policy id reported when allow policies exist but none of them covers the operation
+pub const DEFAULT_DENY_POLICY_ID: &str = "default_deny";
 
 /// Policy engine for enforcing gas sponsorship policies
 pub struct PolicyEngine {
-    policies: Arc<RwLock<HashMap<String, GasPolicy>>>,
-    redis_client: redis::Client,
+    policies: Arc<RwLock<HashMap<String, LoadedPolicy>>>,
+    counters: Arc<dyn CounterStore>,
+    clock: Arc<dyn Clock>,
+    max_call_gas_limit: u64,
+    settlement_grace_seconds: u64,
+    unknown_call_data: UnknownCallData,
+    webhooks: WebhookClient,
+    events: broadcast::Sender<PolicyEvent>,
+    circuit_breaker: CircuitBreaker,
+}
+
+/// Where a policy was loaded from, so each source can be reloaded without touching the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PolicySource {
+    Runtime,
+    Database,
+    File,
+}
+
+/// Something that happened to a policy without an operator changing it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "event")]
+pub enum PolicyEvent {
+    /// The policy's schedule ended, so it was disabled
+    Expired { policy_id: String, source: PolicySource, ended_at: u64 },
+}
+
+struct LoadedPolicy {
+    policy: GasPolicy,
+    source: PolicySource,
+}
+
+/// An enabled policy due for evaluation, with its inherited settings folded in
+struct Candidate {
+    policy: GasPolicy,
+    resolved: Result<GasPolicy, String>,
+}
+
+/// Budget taken from one rate limit counter
+#[derive(Debug, Serialize, Deserialize)]
+struct Reservation {
+    counter: ReservedCounter,
+    amount: u64,
+    settles_to_gas_cost: bool, // Amount counters are corrected to the actual cost; request counts stand
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ReservedCounter {
+    Window { key: String },
+    Bucket { key: String, bucket: TokenBucket },
+}
+
+/// Worst-case budget reserved by `check_policies` for one operation
+///
+/// Hand it back through `hold` once the operation is signed, or `cancel` if signing failed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PolicyReservation {
+    reservations: Vec<Reservation>,
+}
+
+/// How long a pending sponsorship is kept past its release time, so a late sweep still returns its budget
+const PENDING_RETENTION_SECONDS: u64 = 86_400;
+
+/// Counter store key of the budget a signed operation holds until its `UserOperationEvent`
+fn pending_key(chain_id: u64, user_op_hash: H256) -> String {
+    format!("pending_sponsorship:{}:{:?}", chain_id, user_op_hash)
+}
+
+/// Index of a chain's pending sponsorships, by when they are due for release
+fn pending_index(chain_id: u64) -> String {
+    format!("pending_sponsorships:{}", chain_id)
+}
+
+/// Counter store key of the first block a chain's settlement has not scanned
+fn settlement_cursor_key(chain_id: u64) -> String {
+    format!("settlement:{}:next_block", chain_id)
 }
 
 impl PolicyEngine {
-    pub fn new(redis_url: &str) -> PaymasterResult<Self> {
-        let redis_client = redis::Client::open(redis_url)
-            .map_err(|e| PaymasterError::ConfigurationError(format!("Redis connection failed: {}", e)))?;
+    pub fn new(redis_url: &str, settings: &PolicySettings) -> PaymasterResult<Self> {
+        let counters = RedisCounterStore::new(redis_url)?;
+        Ok(Self::with_store(Arc::new(counters), Arc::new(SystemClock), settings))
+    }
 
-        Ok(Self {
+    /// Build an engine on a specific counter store and clock
+    pub fn with_store(counters: Arc<dyn CounterStore>, clock: Arc<dyn Clock>, settings: &PolicySettings) -> Self {
+        Self {
             policies: Arc::new(RwLock::new(HashMap::new())),
-            redis_client,
-        })
+            circuit_breaker: CircuitBreaker::new(counters.clone(), clock.clone(), settings.circuit_breaker.clone()),
+            counters,
+            webhooks: WebhookClient::new(clock.clone()),
+            clock,
+            max_call_gas_limit: settings.max_call_gas_limit,
+            settlement_grace_seconds: settings.settlement_grace_seconds,
+            unknown_call_data: settings.unknown_call_data,
+            events: broadcast::channel(64).0,
+        }
+    }
+
+    /// Spend budgets checked after every policy has passed
+    pub fn circuit_breaker(&self) -> &CircuitBreaker {
+        &self.circuit_breaker
+    }
+
+    /// Receive policy events from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<PolicyEvent> {
+        self.events.subscribe()
     }
 
     /// Add or update a gas policy
     pub async fn add_policy(&self, policy: GasPolicy) -> PaymasterResult<()> {
+        self.load_policy(PolicySource::Runtime, policy).await
+    }
+
+    /// Add or update a policy owned by `source`
+    ///
+    /// Policy ids are unique across sources; a policy owned by another source is left in place.
+    pub async fn load_policy(&self, source: PolicySource, policy: GasPolicy) -> PaymasterResult<()> {
         let mut policies = self.policies.write().await;
-        policies.insert(policy.id.clone(), policy.clone());
-        
-        info!("Added policy: {} of type: {:?}", policy.name, policy.policy_type);
+        if let Some(existing) = policies.get(&policy.id).filter(|existing| existing.source != source) {
+            return Err(PaymasterError::ConfigurationError(
+                format!("Policy {} is already defined by {:?}", policy.id, existing.source)
+            ));
+        }
+
+        // Its parent must be loaded and its lineage free of cycles
+        let (id, name, policy_type) = (policy.id.clone(), policy.name.clone(), policy.policy_type.clone());
+        let previous = policies.insert(id.clone(), LoadedPolicy { policy, source });
+        if let Err(reason) = effective_policy(&policies, &policies[&id].policy) {
+            match previous {
+                Some(previous) => policies.insert(id, previous),
+                None => policies.remove(&id),
+            };
+            return Err(PaymasterError::ConfigurationError(reason));
+        }
+
+        info!("Added policy: {} of type: {:?}", name, policy_type);
         Ok(())
     }
 
-    /// Remove a gas policy
+    /// Replace every policy owned by `source` at once, e.g. with those reloaded from storage
+    pub async fn replace_policies(&self, source: PolicySource, replacements: Vec<GasPolicy>) {
+        let mut policies = self.policies.write().await;
+        policies.retain(|_, loaded| loaded.source != source);
+
+        for policy in replacements {
+            if let Some(existing) = policies.get(&policy.id) {
+                warn!("Skipping policy {} from {:?}: already defined by {:?}", policy.id, source, existing.source);
+                continue;
+            }
+            policies.insert(policy.id.clone(), LoadedPolicy { policy, source });
+        }
+
+        // Drop replacements whose parent is missing or whose lineage cycles, then their children in turn
+        loop {
+            let unresolvable: Vec<(String, String)> = policies.values()
+                .filter(|loaded| loaded.source == source)
+                .filter_map(|loaded| effective_policy(&policies, &loaded.policy).err().map(|reason| (loaded.policy.id.clone(), reason)))
+                .collect();
+            if unresolvable.is_empty() {
+                break;
+            }
+            for (policy_id, reason) in unresolvable {
+                warn!("Skipping policy {} from {:?}: {}", policy_id, source, reason);
+                policies.remove(&policy_id);
+            }
+        }
+    }
+
+    /// Remove a policy if `source` owns it; returns whether it was removed
+    pub async fn unload_policy(&self, source: PolicySource, policy_id: &str) -> bool {
+        let mut policies = self.policies.write().await;
+        if !policies.get(policy_id).is_some_and(|loaded| loaded.source == source) {
+            return false;
+        }
+
+        policies.remove(policy_id);
+        info!("Removed policy: {}", policy_id);
+        let orphans = inheriting(&policies, policy_id);
+        if !orphans.is_empty() {
+            warn!("Policies {:?} inherit removed policy {} and are skipped until it returns", orphans, policy_id);
+        }
+        true
+    }
+
+    /// Remove a gas policy, unless other policies inherit it
     pub async fn remove_policy(&self, policy_id: &str) -> PaymasterResult<()> {
         let mut policies = self.policies.write().await;
+        let children = inheriting(&policies, policy_id);
+        if !children.is_empty() {
+            return Err(PaymasterError::ConfigurationError(
+                format!("Policy {} is inherited by {}", policy_id, children.join(", "))
+            ));
+        }
         if policies.remove(policy_id).is_some() {
             info!("Removed policy: {}", policy_id);
             Ok(())
@@ -44,164 +226,644 @@ impl PolicyEngine {
         }
     }
 
-    /// Check if a sponsor request violates any policies
-    pub async fn check_policies(&self, request: &SponsorRequest) -> PaymasterResult<()> {
+    /// Look up a policy by id
+    pub async fn get_policy(&self, policy_id: &str) -> Option<GasPolicy> {
+        self.policies.read().await.get(policy_id).map(|loaded| loaded.policy.clone())
+    }
+
+    /// A policy with everything it inherits folded in
+    pub async fn effective_policy(&self, policy_id: &str) -> PaymasterResult<GasPolicy> {
         let policies = self.policies.read().await;
-        
-        for policy in policies.values() {
-            if !policy.enabled {
+        let policy = policies.get(policy_id).ok_or_else(|| {
+            PaymasterError::ConfigurationError(format!("Policy {} not found", policy_id))
+        })?;
+        effective_policy(&policies, &policy.policy).map_err(PaymasterError::ConfigurationError)
+    }
+
+    /// Ids of the policies that directly inherit this one, ordered by id
+    pub async fn inheriting_policies(&self, policy_id: &str) -> Vec<String> {
+        inheriting(&*self.policies.read().await, policy_id)
+    }
+
+    /// Which source a loaded policy came from
+    pub async fn policy_source(&self, policy_id: &str) -> Option<PolicySource> {
+        self.policies.read().await.get(policy_id).map(|loaded| loaded.source)
+    }
+
+    /// Every loaded policy with its source, ordered by id
+    pub async fn list_policies(&self) -> Vec<(GasPolicy, PolicySource)> {
+        let mut policies: Vec<_> = self.policies.read().await.values()
+            .map(|loaded| (loaded.policy.clone(), loaded.source))
+            .collect();
+        policies.sort_by(|(a, _), (b, _)| a.id.cmp(&b.id));
+        policies
+    }
+
+    /// Evaluate one policy against a request as `check_policies` would, without taking any budget
+    ///
+    /// Disabled policies are evaluated as if they were enabled, so they can be tried out first.
+    pub async fn test_policy(&self, policy_id: &str, request: &SponsorRequest) -> PaymasterResult<PolicyDecision> {
+        let policy = self.effective_policy(policy_id).await?;
+
+        let (applies, result) = self.apply_policy(&policy, request, None).await;
+        let reason = match result {
+            Ok(()) => None,
+            Err(PaymasterError::PolicyViolation(reason)) => Some(reason),
+            Err(e) => return Err(e),
+        };
+
+        Ok(PolicyDecision {
+            policy_id: policy.id,
+            enabled: policy.enabled,
+            applies,
+            allowed: reason.is_none(),
+            reason,
+        })
+    }
+
+    /// Whether a policy is in effect now and its target covers the request, without touching
+    /// any rate limit counters
+    pub fn policy_applies(&self, policy: &GasPolicy, request: &SponsorRequest) -> bool {
+        if !self.schedule_active(policy) {
+            return false;
+        }
+        match policy.policy_type {
+            PolicyType::Project | PolicyType::Custom => true,
+            PolicyType::Contract => policy.target.as_ref()
+                .is_some_and(|target| self.request_targets_contract(request, target)),
+            PolicyType::Wallet => policy.target.as_ref()
+                .is_some_and(|target| request.user_operation.sender().eq_ignore_ascii_case(target)),
+        }
+    }
+
+    /// Whether the policy's schedule, if any, has it in effect now
+    pub fn schedule_active(&self, policy: &GasPolicy) -> bool {
+        policy.schedule.as_ref().is_none_or(|schedule| schedule.is_active(self.clock.now_seconds()))
+    }
+
+    /// Disable enabled policies whose schedule, their own or inherited, has ended, announcing
+    /// each as `PolicyEvent::Expired`
+    pub async fn disable_ended_policies(&self) -> Vec<PolicyEvent> {
+        let now = self.clock.now_seconds();
+        let mut policies = self.policies.write().await;
+
+        let ended: Vec<(String, u64)> = policies.values()
+            .filter(|loaded| loaded.policy.enabled)
+            .filter_map(|loaded| {
+                let schedule = effective_policy(&policies, &loaded.policy).ok()?.schedule?;
+                let ended_at = schedule.ends_at.filter(|_| schedule.has_ended(now))?;
+                Some((loaded.policy.id.clone(), ended_at))
+            })
+            .collect();
+
+        let mut events = Vec::new();
+        for (policy_id, ended_at) in ended {
+            let Some(loaded) = policies.get_mut(&policy_id) else {
                 continue;
+            };
+            loaded.policy.enabled = false;
+            info!("Disabled policy {}: its schedule ended at {}", policy_id, ended_at);
+            events.push(PolicyEvent::Expired { policy_id, source: loaded.source, ended_at });
+        }
+        drop(policies);
+
+        for event in &events {
+            // Nobody listening is fine
+            let _ = self.events.send(event.clone());
+        }
+        events
+    }
+
+    /// Check the policy a request asks to be sponsored under exists, is enabled and covers it
+    pub async fn check_sponsorship_policy(&self, request: &SponsorRequest) -> PaymasterResult<()> {
+        let Some(policy_id) = &request.sponsorship_policy_id else {
+            return Ok(());
+        };
+
+        if self.get_policy(policy_id).await.is_none() {
+            return Err(PaymasterError::PolicyViolation(
+                format!("Sponsorship policy {} not found", policy_id)
+            ));
+        }
+        match self.effective_policy(policy_id).await {
+            Ok(policy) if policy.enabled && policy.effect != PolicyEffect::Deny && self.policy_applies(&policy, request) => Ok(()),
+            _ => Err(PaymasterError::PolicyRejected {
+                policy_id: policy_id.clone(),
+                reason: "Sponsorship policy does not cover this operation".to_string(),
+            }),
+        }
+    }
+
+    /// Check if a sponsor request violates any policies
+    ///
+    /// Enabled policies are evaluated by priority, then id. Windowed limits reserve the
+    /// operation's worst-case cost as each policy passes. When a later policy rejects the
+    /// operation those reservations are released, so a rejected operation consumes nothing.
+    /// A violation is reported as `PolicyRejected` naming the policy. An operation every policy
+    /// admits is finally counted against the circuit breaker's spend budgets.
+    pub async fn check_policies(&self, request: &SponsorRequest) -> PaymasterResult<PolicyReservation> {
+        let mut reservations = Vec::new();
+        let (trace, mut outcome) = self.evaluate(request, Some(&mut reservations)).await;
+        if outcome.is_ok() {
+            outcome = self.reserve_spend(request, &mut reservations).await;
+        }
+
+        if let Err(e) = outcome {
+            self.release(&reservations).await;
+            info!("Policy checks failed: {}; trace: {}", e, summarize(&trace));
+            return Err(e);
+        }
+
+        info!("All policy checks passed for request");
+        Ok(PolicyReservation { reservations })
+    }
+
+    /// Evaluate every enabled policy against a request without taking any budget
+    ///
+    /// Unlike `check_policies` this carries on past a rejection, so the trace covers every policy.
+    pub async fn evaluate_policies(&self, request: &SponsorRequest) -> PaymasterResult<PolicyEvaluation> {
+        let (trace, outcome) = self.evaluate(request, None).await;
+
+        let (rejected_by, reason) = match outcome {
+            Ok(()) => (None, None),
+            Err(PaymasterError::PolicyRejected { policy_id, reason }) => (Some(policy_id), Some(reason)),
+            Err(e) => return Err(e),
+        };
+        Ok(PolicyEvaluation { allowed: rejected_by.is_none(), rejected_by, reason, trace })
+    }
+
+    /// Count the operation's worst-case cost against the circuit breaker, settled like an amount limit
+    async fn reserve_spend(&self, request: &SponsorRequest, reservations: &mut Vec<Reservation>) -> PaymasterResult<()> {
+        let amount = self.calculate_gas_cost(&request.user_operation)?;
+        for key in self.circuit_breaker.reserve(request.chain_id, amount).await? {
+            reservations.push(Reservation { counter: ReservedCounter::Window { key }, amount, settles_to_gas_cost: true });
+        }
+        Ok(())
+    }
+
+    /// Run the global gas cap and then each enabled policy in order, recording what each decided
+    ///
+    /// Without `reservations` this is a dry run that evaluates every policy; otherwise it stops
+    /// at the first rejection. Any `Deny` policy covering the operation rejects it, and when
+    /// `Allow` policies exist one of them must cover it.
+    async fn evaluate(
+        &self,
+        request: &SponsorRequest,
+        mut reservations: Option<&mut Vec<Reservation>>,
+    ) -> (Vec<PolicyTraceEntry>, PaymasterResult<()>) {
+        let dry_run = reservations.is_none();
+        let gas_cap = self.check_global_gas_cap(request);
+        let mut trace = vec![PolicyTraceEntry::new(GLOBAL_GAS_CAP_POLICY_ID, i32::MIN, PolicyEffect::Limit, true, &gas_cap)];
+        let mut outcome = gas_cap.map_err(|e| e.rejected_by(GLOBAL_GAS_CAP_POLICY_ID));
+
+        // Webhooks may be slow, so evaluate a snapshot rather than block policy reloads
+        let candidates = self.candidates().await;
+        let mut allow_policies = false;
+        let mut allowed = false;
+
+        for candidate in &candidates {
+            if outcome.is_err() && !dry_run {
+                break;
             }
 
-            match policy.policy_type {
-                PolicyType::Project => {
-                    self.check_project_policy(policy, request).await?;
-                }
-                PolicyType::Contract => {
-                    if let Some(target) = &policy.target {
-                        if self.request_targets_contract(request, target) {
-                            self.check_contract_policy(policy, request).await?;
-                        }
-                    }
-                }
-                PolicyType::Wallet => {
-                    if let Some(target) = &policy.target {
-                        if request.user_operation.sender.eq_ignore_ascii_case(target) {
-                            self.check_wallet_policy(policy, request).await?;
-                        }
-                    }
-                }
-                PolicyType::Custom => {
-                    self.check_custom_policy(policy, request).await?;
+            let effect = candidate.policy.effect;
+            let (matched, result) = match &candidate.resolved {
+                // An unresolvable policy covers nothing rather than rejecting everything
+                Err(reason) => {
+                    warn!("Skipping policy {}: {}", candidate.policy.id, reason);
+                    (false, Ok(()))
                 }
+                Ok(policy) => self.apply_policy(policy, request, reservations.as_deref_mut()).await,
+            };
+
+            allow_policies |= effect == PolicyEffect::Allow && candidate.resolved.is_ok();
+            allowed |= effect == PolicyEffect::Allow && matched && result.is_ok();
+            let mut entry = PolicyTraceEntry::new(&candidate.policy.id, candidate.policy.priority, effect, matched, &result);
+            match &candidate.resolved {
+                Err(reason) => entry.reason = format!("Cannot be resolved: {}", reason),
+                Ok(policy) if !self.schedule_active(policy) => entry.reason = "Outside its schedule".to_string(),
+                Ok(_) => {}
+            }
+            trace.push(entry);
+            if let (Err(e), Ok(())) = (result, &outcome) {
+                outcome = Err(e.rejected_by(&candidate.policy.id));
             }
         }
 
-        info!("All policy checks passed for request");
-        Ok(())
+        if outcome.is_ok() && allow_policies && !allowed {
+            outcome = Err(PaymasterError::PolicyRejected {
+                policy_id: DEFAULT_DENY_POLICY_ID.to_string(),
+                reason: "No allow policy covers this operation".to_string(),
+            });
+        }
+        (trace, outcome)
     }
 
-    /// Check project-level policies
-    async fn check_project_policy(&self, policy: &GasPolicy, request: &SponsorRequest) -> PaymasterResult<()> {
-        for rate_limit in &policy.rate_limits {
-            self.check_rate_limit(rate_limit, request, "project").await?;
+    /// Whether a resolved policy covers the request, and its verdict: a `Deny` policy refuses
+    /// what it covers, the others check their limits and rules
+    async fn apply_policy(
+        &self,
+        policy: &GasPolicy,
+        request: &SponsorRequest,
+        reservations: Option<&mut Vec<Reservation>>,
+    ) -> (bool, PaymasterResult<()>) {
+        if !self.schedule_active(policy) {
+            return (false, Ok(()));
         }
-        Ok(())
+        let matched = self.policy_applies(policy, request);
+        let result = match policy.effect {
+            PolicyEffect::Deny if matched => Err(PaymasterError::PolicyViolation("Denied by policy".to_string())),
+            PolicyEffect::Deny => Ok(()),
+            PolicyEffect::Limit | PolicyEffect::Allow => self.check_policy(policy, request, reservations).await,
+        };
+        (matched, result)
     }
 
-    /// Check contract-specific policies
-    async fn check_contract_policy(&self, policy: &GasPolicy, request: &SponsorRequest) -> PaymasterResult<()> {
-        let contract_key = policy.target.as_ref().unwrap();
-        
-        for rate_limit in &policy.rate_limits {
-            self.check_rate_limit(rate_limit, request, contract_key).await?;
+    /// Enabled policies in evaluation order: by priority, then id
+    async fn candidates(&self) -> Vec<Candidate> {
+        let policies = self.policies.read().await;
+        let mut candidates: Vec<Candidate> = policies.values()
+            .filter(|loaded| loaded.policy.enabled)
+            .map(|loaded| Candidate {
+                policy: loaded.policy.clone(),
+                resolved: effective_policy(&policies, &loaded.policy),
+            })
+            .collect();
+        candidates.sort_by(|a, b| (a.policy.priority, &a.policy.id).cmp(&(b.policy.priority, &b.policy.id)));
+        candidates
+    }
+
+    /// Keep a signed operation's reservation until it is mined or its signature expires
+    ///
+    /// The reservation is stored beside the counters under its userOpHash, so whichever relay
+    /// instance sees the operation mined, or sweeps it once expired, can settle or release it.
+    pub async fn hold(&self, chain_id: u64, user_op_hash: H256, reservation: PolicyReservation, valid_until: u64) {
+        if reservation.reservations.is_empty() {
+            return;
+        }
+
+        let due_at = valid_until.saturating_add(self.settlement_grace_seconds);
+        let ttl_seconds = due_at.saturating_sub(self.clock.now_seconds()).saturating_add(PENDING_RETENTION_SECONDS);
+        let held = match serde_json::to_string(&reservation) {
+            Ok(record) => {
+                let key = pending_key(chain_id, user_op_hash);
+                self.counters.hold_pending(&pending_index(chain_id), &key, &record, due_at, ttl_seconds).await
+            }
+            Err(e) => Err(PaymasterError::DatabaseError(e.to_string())),
+        };
+        // The budget stays reserved until its windows roll over
+        if let Err(e) = held {
+            warn!("Failed to hold reservation for {:?}: {}", user_op_hash, e);
         }
-        Ok(())
     }
 
-    /// Check wallet-specific policies
-    async fn check_wallet_policy(&self, policy: &GasPolicy, request: &SponsorRequest) -> PaymasterResult<()> {
-        let wallet_key = &request.user_operation.sender;
-        
+    /// Return a reservation for an operation that was never signed
+    pub async fn cancel(&self, reservation: PolicyReservation) {
+        self.release(&reservation.reservations).await;
+    }
+
+    /// Take the reservation held for an operation, if this caller is the first to ask
+    async fn take_held(&self, chain_id: u64, key: &str) -> Option<PolicyReservation> {
+        match self.counters.take_pending(&pending_index(chain_id), key).await {
+            Ok(record) => record.and_then(|record| match serde_json::from_str(&record) {
+                Ok(reservation) => Some(reservation),
+                Err(e) => {
+                    warn!("Dropping unreadable reservation {}: {}", key, e);
+                    None
+                }
+            }),
+            Err(e) => {
+                warn!("Failed to take reservation {}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    /// Correct a mined operation's reserved amounts to its actual gas cost
+    ///
+    /// The actual cost never exceeds the reserved worst case, so settling only gives budget back.
+    /// Returns false when no budget is held for the operation, e.g. it was already settled
+    /// or released.
+    pub async fn settle(&self, chain_id: u64, user_op_hash: H256, actual_gas_cost: U256) -> bool {
+        let Some(reservation) = self.take_held(chain_id, &pending_key(chain_id, user_op_hash)).await else {
+            return false;
+        };
+
+        let actual_gas_cost = saturate_u64(actual_gas_cost);
+        for reservation in reservation.reservations.iter().filter(|r| r.settles_to_gas_cost) {
+            if actual_gas_cost >= reservation.amount {
+                continue;
+            }
+
+            if let Err(e) = self.give_back(reservation, reservation.amount - actual_gas_cost).await {
+                warn!("Failed to settle {:?} for {:?}: {}", reservation.counter, user_op_hash, e);
+            }
+        }
+
+        info!("Settled user operation {:?} at {} wei", user_op_hash, actual_gas_cost);
+        true
+    }
+
+    /// Release the budget of a chain's operations whose signature expired without being mined
+    ///
+    /// `scanned_until` is the timestamp of the last block searched for their events; an
+    /// operation can no longer be mined once a block past its validUntil exists. Returns how
+    /// many operations were released.
+    pub async fn release_expired(&self, chain_id: u64, scanned_until: u64) -> usize {
+        let keys = match self.counters.due_pending(&pending_index(chain_id), scanned_until).await {
+            Ok(keys) => keys,
+            Err(e) => {
+                warn!("Failed to list expired sponsorships: {}", e);
+                return 0;
+            }
+        };
+
+        let mut released = 0;
+        for key in keys {
+            // Another instance may have settled or released it first
+            if let Some(reservation) = self.take_held(chain_id, &key).await {
+                self.release(&reservation.reservations).await;
+                released += 1;
+            }
+        }
+        if released > 0 {
+            info!("Released budget of {} expired sponsorships on chain {}", released, chain_id);
+        }
+        released
+    }
+
+    /// First block of the chain that settlement has not scanned, as last recorded by any instance
+    pub async fn settlement_cursor(&self, chain_id: u64) -> PaymasterResult<Option<u64>> {
+        let next_block = self.counters.get(&settlement_cursor_key(chain_id)).await?;
+        Ok((next_block > 0).then_some(next_block))
+    }
+
+    /// Record that settlement has scanned the chain up to, not including, `next_block`
+    pub async fn record_settlement_cursor(&self, chain_id: u64, next_block: u64) -> PaymasterResult<()> {
+        self.counters.set(&settlement_cursor_key(chain_id), next_block).await
+    }
+
+    /// Run a single policy against the request
+    ///
+    /// Without `reservations` this is a dry run: counters are only read, never changed.
+    async fn check_policy(
+        &self,
+        policy: &GasPolicy,
+        request: &SponsorRequest,
+        mut reservations: Option<&mut Vec<Reservation>>,
+    ) -> PaymasterResult<()> {
+        if !policy.call_rules.is_empty() && self.policy_applies(policy, request) {
+            let calls = account_calls(request).ok_or_else(|| PaymasterError::PolicyViolation(
+                "Unrecognized account calldata: cannot check its calls against the call rules".to_string()
+            ))?;
+            check_call_rules(&policy.call_rules, &calls)?;
+        }
+        match policy.policy_type {
+            PolicyType::Custom => return self.check_custom_policy(policy, request, reservations).await,
+            PolicyType::Contract if self.unknown_call_data == UnknownCallData::Reject && call_targets(request).is_none() => {
+                return Err(PaymasterError::PolicyViolation(
+                    "Unrecognized account calldata: cannot tell which contracts it calls".to_string()
+                ));
+            }
+            _ => {}
+        }
+        let Some(scope) = counter_scope(policy).filter(|_| self.policy_applies(policy, request)) else {
+            return Ok(());
+        };
+
         for rate_limit in &policy.rate_limits {
-            self.check_rate_limit(rate_limit, request, wallet_key).await?;
+            self.check_rate_limit(&policy.id, rate_limit, request, &scope, reservations.as_deref_mut()).await?;
         }
         Ok(())
     }
 
-    /// Check custom policies (webhook-based)
-    async fn check_custom_policy(&self, policy: &GasPolicy, request: &SponsorRequest) -> PaymasterResult<()> {
-        // This would make HTTP calls to custom webhook endpoints
-        // For now, implement a placeholder
-        warn!("Custom policy check not implemented for policy: {}", policy.name);
+    /// Global safety cap on callGasLimit, applied before any configured policy
+    fn check_global_gas_cap(&self, request: &SponsorRequest) -> PaymasterResult<()> {
+        let call_gas_limit = parse_gas_value("call gas limit", request.user_operation.call_gas_limit())?;
+
+        if call_gas_limit > self.max_call_gas_limit {
+            return Err(PaymasterError::PolicyViolation(
+                format!("Call gas limit {} exceeds maximum {}", call_gas_limit, self.max_call_gas_limit)
+            ));
+        }
+
         Ok(())
     }
 
-    /// Check a specific rate limit
-    async fn check_rate_limit(&self, rate_limit: &RateLimit, request: &SponsorRequest, key_prefix: &str) -> PaymasterResult<()> {
-        let mut conn = self.redis_client.get_async_connection().await
-            .map_err(|e| PaymasterError::DatabaseError(format!("Redis connection failed: {}", e)))?;
+    /// Let the policy's webhook decide, then apply its rate limits or the ones the webhook returned
+    ///
+    /// Those limits count per sender unless the webhook names another scope. A webhook that
+    /// fails open and cannot be reached leaves the policy's own limits, per sender.
+    async fn check_custom_policy(
+        &self,
+        policy: &GasPolicy,
+        request: &SponsorRequest,
+        mut reservations: Option<&mut Vec<Reservation>>,
+    ) -> PaymasterResult<()> {
+        let Some(webhook) = &policy.webhook else {
+            return Err(PaymasterError::PolicyViolation("Custom policy has no webhook".to_string()));
+        };
 
-        match rate_limit.limit_type {
-            RateLimitType::Amount => {
-                self.check_amount_limit(&mut conn, rate_limit, request, key_prefix).await?;
+        let sender = request.user_operation.sender().to_lowercase();
+        let (scope, rate_limits) = match self.webhooks.decide(&policy.id, webhook, request).await {
+            Ok(decision) if !decision.allow => {
+                return Err(PaymasterError::PolicyViolation(decision.reason.unwrap_or_else(|| "Denied by webhook".to_string())));
             }
-            RateLimitType::Request => {
-                self.check_request_limit(&mut conn, rate_limit, request, key_prefix).await?;
+            Ok(decision) => (
+                decision.scope.unwrap_or(sender),
+                decision.rate_limits.unwrap_or_else(|| policy.rate_limits.clone()),
+            ),
+            // Failing open skips only the webhook's decision; the policy's own limits still apply
+            Err(e) if webhook.fail_open => {
+                warn!("Sponsoring without a decision, policy {} fails open: {}", policy.id, e);
+                (sender, policy.rate_limits.clone())
             }
+            Err(e) => return Err(e),
+        };
+
+        for rate_limit in &rate_limits {
+            self.check_rate_limit(&policy.id, rate_limit, request, &scope, reservations.as_deref_mut()).await?;
+        }
+        Ok(())
+    }
+
+    /// Check a specific rate limit
+    async fn check_rate_limit(
+        &self,
+        policy_id: &str,
+        rate_limit: &RateLimit,
+        request: &SponsorRequest,
+        scope: &str,
+        reservations: Option<&mut Vec<Reservation>>,
+    ) -> PaymasterResult<()> {
+        let (description, amount, settles_to_gas_cost) = match rate_limit.limit_type {
+            RateLimitType::Amount => ("Amount limit", self.calculate_gas_cost(&request.user_operation)?, true),
+            RateLimitType::Request => ("Request limit", 1, false),
             RateLimitType::GasPrice => {
-                self.check_gas_price_limit(rate_limit, request).await?;
+                return self.check_gas_price_limit(rate_limit, request).await;
             }
             RateLimitType::AmountPerTransaction => {
-                self.check_amount_per_transaction_limit(rate_limit, request).await?;
+                return self.check_amount_per_transaction_limit(rate_limit, request).await;
             }
-        }
+        };
 
+        let limit = parse_limit(&description.to_lowercase(), &rate_limit.limit)?;
+        let dry_run = reservations.is_none();
+        let counter_key = counter_key(policy_id, rate_limit, scope);
+        let counter = match rate_limit.algorithm {
+            RateLimitAlgorithm::FixedWindow => {
+                self.reserve_fixed_window(&counter_key, amount, limit, rate_limit.window, description, dry_run).await?
+            }
+            RateLimitAlgorithm::SlidingWindow => {
+                self.reserve_sliding_window(&counter_key, amount, limit, rate_limit.window, description, dry_run).await?
+            }
+            RateLimitAlgorithm::TokenBucket => {
+                let bucket = token_bucket(rate_limit, limit)?;
+                self.reserve_tokens(&counter_key, amount, bucket, description, dry_run).await?
+            }
+        };
+
+        if let Some(reservations) = reservations {
+            reservations.push(Reservation { counter, amount, settles_to_gas_cost });
+        }
         Ok(())
     }
 
-    /// Check amount-based rate limit
-    async fn check_amount_limit(
+    /// Atomically add `amount` to the current fixed window, undoing the increment if it overshoots `limit`
+    async fn reserve_fixed_window(
         &self,
-        conn: &mut redis::aio::Connection,
-        rate_limit: &RateLimit,
-        request: &SponsorRequest,
-        key_prefix: &str,
-    ) -> PaymasterResult<()> {
-        let window_key = format!("amount_limit:{}:{}", key_prefix, self.get_time_window(rate_limit.window));
-        let current_amount: u64 = conn.get(&window_key).await.unwrap_or(0);
-        
-        let gas_cost = self.calculate_gas_cost(&request.user_operation)?;
-        let limit: u64 = rate_limit.limit.parse()
-            .map_err(|_| PaymasterError::ConfigurationError("Invalid amount limit".to_string()))?;
+        counter_key: &str,
+        amount: u64,
+        limit: u64,
+        window_seconds: u64,
+        description: &str,
+        dry_run: bool,
+    ) -> PaymasterResult<ReservedCounter> {
+        if amount > limit {
+            return Err(PaymasterError::PolicyViolation(
+                format!("{} exceeded: {} > {}", description, amount, limit)
+            ));
+        }
 
-        if current_amount + gas_cost > limit {
+        let key = format!("{}:{}", counter_key, self.get_time_window(window_seconds));
+        let total = self.count(&key, amount, window_seconds, dry_run).await?;
+        if total > limit {
+            if !dry_run {
+                self.counters.decrement(&key, amount).await?;
+            }
             return Err(PaymasterError::PolicyViolation(
-                format!("Amount limit exceeded: {} + {} > {}", current_amount, gas_cost, limit)
+                format!("{} exceeded: {} + {} > {}", description, total - amount, amount, limit)
             ));
         }
 
-        // Update the counter
-        let _: () = conn.set_ex(&window_key, current_amount + gas_cost, rate_limit.window).await
-            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))?;
+        Ok(ReservedCounter::Window { key })
+    }
 
-        Ok(())
+    /// Sliding window counter: the previous window's total, weighted by the share of it the
+    /// sliding window still covers, plus the current window's total must stay within `limit`
+    async fn reserve_sliding_window(
+        &self,
+        counter_key: &str,
+        amount: u64,
+        limit: u64,
+        window_seconds: u64,
+        description: &str,
+        dry_run: bool,
+    ) -> PaymasterResult<ReservedCounter> {
+        if amount > limit {
+            return Err(PaymasterError::PolicyViolation(
+                format!("{} exceeded: {} > {}", description, amount, limit)
+            ));
+        }
+
+        let window_millis = window_seconds.max(1) * 1_000;
+        let now = self.clock.now_millis();
+        let index = now / window_millis;
+        let key = format!("{}:{}", counter_key, index);
+
+        // Each window is read again while the next one is current
+        let total = self.count(&key, amount, window_seconds.max(1) * 2, dry_run).await?;
+        let previous = match index.checked_sub(1) {
+            Some(previous_index) => self.counters.get(&format!("{}:{}", counter_key, previous_index)).await?,
+            None => 0,
+        };
+
+        // Compare in units of window_millis to stay in integers
+        let overlap = (window_millis - now % window_millis) as u128;
+        let weighted = previous as u128 * overlap + total as u128 * window_millis as u128;
+        if weighted > limit as u128 * window_millis as u128 {
+            if !dry_run {
+                self.counters.decrement(&key, amount).await?;
+            }
+            let used = (weighted / window_millis as u128) as u64 - amount;
+            return Err(PaymasterError::PolicyViolation(
+                format!("{} exceeded: {} + {} > {} within the sliding window", description, used, amount, limit)
+            ));
+        }
+
+        Ok(ReservedCounter::Window { key })
     }
 
-    /// Check request-based rate limit
-    async fn check_request_limit(
+    /// Take `amount` tokens from the bucket, which refills continuously
+    async fn reserve_tokens(
         &self,
-        conn: &mut redis::aio::Connection,
-        rate_limit: &RateLimit,
-        _request: &SponsorRequest,
-        key_prefix: &str,
-    ) -> PaymasterResult<()> {
-        let window_key = format!("request_limit:{}:{}", key_prefix, self.get_time_window(rate_limit.window));
-        let current_requests: u64 = conn.get(&window_key).await.unwrap_or(0);
-        
-        let limit: u64 = rate_limit.limit.parse()
-            .map_err(|_| PaymasterError::ConfigurationError("Invalid request limit".to_string()))?;
+        counter_key: &str,
+        amount: u64,
+        bucket: TokenBucket,
+        description: &str,
+        dry_run: bool,
+    ) -> PaymasterResult<ReservedCounter> {
+        if amount > bucket.capacity {
+            return Err(PaymasterError::PolicyViolation(
+                format!("{} exceeded: {} > burst capacity {}", description, amount, bucket.capacity)
+            ));
+        }
 
-        if current_requests >= limit {
+        let key = format!("{}:bucket", counter_key);
+        let now = self.clock.now_millis();
+        let taken = if dry_run {
+            self.counters.available_tokens(&key, &bucket, now).await? >= amount
+        } else {
+            self.counters.take_tokens(&key, amount, &bucket, now).await?
+        };
+        if !taken {
             return Err(PaymasterError::PolicyViolation(
-                format!("Request limit exceeded: {} >= {}", current_requests, limit)
+                format!("{} exceeded: fewer than {} tokens available", description, amount)
             ));
         }
 
-        // Increment the counter
-        let _: () = conn.set_ex(&window_key, current_requests + 1, rate_limit.window).await
-            .map_err(|e| PaymasterError::DatabaseError(e.to_string()))?;
+        Ok(ReservedCounter::Bucket { key, bucket })
+    }
 
-        Ok(())
+    /// Add `amount` to a window counter, or on a dry run only read what the total would become
+    async fn count(&self, key: &str, amount: u64, ttl_seconds: u64, dry_run: bool) -> PaymasterResult<u64> {
+        if dry_run {
+            return Ok(self.counters.get(key).await?.saturating_add(amount));
+        }
+        self.counters.increment(key, amount, ttl_seconds).await
+    }
+
+    /// Return part of a reservation to its counter
+    async fn give_back(&self, reservation: &Reservation, amount: u64) -> PaymasterResult<()> {
+        match &reservation.counter {
+            ReservedCounter::Window { key } => self.counters.decrement(key, amount).await,
+            ReservedCounter::Bucket { key, bucket } => self.counters.return_tokens(key, amount, bucket).await,
+        }
+    }
+
+    /// Return reserved budget to its counters
+    async fn release(&self, reservations: &[Reservation]) {
+        for reservation in reservations {
+            if let Err(e) = self.give_back(reservation, reservation.amount).await {
+                warn!("Failed to release {} from {:?}: {}", reservation.amount, reservation.counter, e);
+            }
+        }
     }
 
     /// Check gas price limit
     async fn check_gas_price_limit(&self, rate_limit: &RateLimit, request: &SponsorRequest) -> PaymasterResult<()> {
-        let max_fee_per_gas: u64 = request.user_operation.max_fee_per_gas.parse()
-            .map_err(|_| PaymasterError::InvalidUserOperation("Invalid max fee per gas".to_string()))?;
-
-        let limit: u64 = rate_limit.limit.parse()
-            .map_err(|_| PaymasterError::ConfigurationError("Invalid gas price limit".to_string()))?;
+        let max_fee_per_gas = parse_gas_value("max fee per gas", request.user_operation.max_fee_per_gas())?;
+        let limit = parse_limit("gas price limit", &rate_limit.limit)?;
 
         if max_fee_per_gas > limit {
             return Err(PaymasterError::PolicyViolation(
@@ -215,8 +877,7 @@ impl PolicyEngine {
     /// Check amount per transaction limit
     async fn check_amount_per_transaction_limit(&self, rate_limit: &RateLimit, request: &SponsorRequest) -> PaymasterResult<()> {
         let gas_cost = self.calculate_gas_cost(&request.user_operation)?;
-        let limit: u64 = rate_limit.limit.parse()
-            .map_err(|_| PaymasterError::ConfigurationError("Invalid amount per transaction limit".to_string()))?;
+        let limit = parse_limit("amount per transaction limit", &rate_limit.limit)?;
 
         if gas_cost > limit {
             return Err(PaymasterError::PolicyViolation(
@@ -227,72 +888,943 @@ impl PolicyEngine {
         Ok(())
     }
 
-    /// Calculate gas cost for a user operation
-    fn calculate_gas_cost(&self, user_op: &UserOperation) -> PaymasterResult<u64> {
-        let call_gas_limit: u64 = user_op.call_gas_limit.parse()
-            .map_err(|_| PaymasterError::InvalidUserOperation("Invalid call gas limit".to_string()))?;
-        
-        let verification_gas_limit: u64 = user_op.verification_gas_limit.parse()
-            .map_err(|_| PaymasterError::InvalidUserOperation("Invalid verification gas limit".to_string()))?;
-        
-        let pre_verification_gas: u64 = user_op.pre_verification_gas.parse()
-            .map_err(|_| PaymasterError::InvalidUserOperation("Invalid pre verification gas".to_string()))?;
-        
-        let max_fee_per_gas: u64 = user_op.max_fee_per_gas.parse()
-            .map_err(|_| PaymasterError::InvalidUserOperation("Invalid max fee per gas".to_string()))?;
-
-        let total_gas = call_gas_limit + verification_gas_limit + pre_verification_gas;
-        Ok(total_gas * max_fee_per_gas)
+    /// Calculate the maximum gas cost in wei for a user operation, saturating at `u64::MAX`
+    fn calculate_gas_cost(&self, user_op: &VersionedUserOperation) -> PaymasterResult<u64> {
+        Ok(saturate_u64(user_op.max_gas_cost()?))
     }
 
     /// Get time window key for rate limiting
     fn get_time_window(&self, window_seconds: u64) -> u64 {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        
-        now / window_seconds
+        self.clock.now_seconds() / window_seconds.max(1)
     }
 
-    /// Check if request targets a specific contract
+    /// Whether the account's calldata calls `contract_address`, directly or within a batch
     fn request_targets_contract(&self, request: &SponsorRequest, contract_address: &str) -> bool {
-        // This would analyze the call data to determine the target contract
-        // For now, implement a simple check
-        request.user_operation.call_data.contains(&contract_address.trim_start_matches("0x"))
+        let Ok(contract) = contract_address.parse::<Address>() else {
+            return false;
+        };
+
+        match call_targets(request) {
+            Some(targets) => targets.contains(&contract),
+            None => self.unknown_call_data == UnknownCallData::Apply,
+        }
     }
 
     /// Get policy status for monitoring
     pub async fn get_policy_status(&self, policy_id: &str) -> PaymasterResult<PolicyStatus> {
-        let policies = self.policies.read().await;
-        
-        if let Some(policy) = policies.get(policy_id) {
-            // This would include current usage statistics
-            Ok(PolicyStatus {
-                policy_id: policy_id.to_string(),
-                enabled: policy.enabled,
-                current_usage: self.get_current_usage(policy_id).await?,
-            })
-        } else {
-            Err(PaymasterError::ConfigurationError(
-                format!("Policy {} not found", policy_id)
-            ))
-        }
+        let policy = self.effective_policy(policy_id).await?;
+
+        Ok(PolicyStatus {
+            policy_id: policy_id.to_string(),
+            enabled: policy.enabled,
+            usage: self.get_current_usage(&policy).await?,
+        })
     }
 
-    /// Get current usage statistics for a policy
-    async fn get_current_usage(&self, policy_id: &str) -> PaymasterResult<HashMap<String, u64>> {
-        // This would query Redis for current usage statistics
-        let mut usage = HashMap::new();
-        usage.insert("requests_this_hour".to_string(), 0);
-        usage.insert("amount_spent_this_hour".to_string(), 0);
+    /// Current usage of each windowed rate limit in a policy, read without changing any counter
+    async fn get_current_usage(&self, policy: &GasPolicy) -> PaymasterResult<Vec<RateLimitUsage>> {
+        let Some(scope) = counter_scope(policy) else {
+            return Ok(Vec::new());
+        };
+
+        let mut usage = Vec::new();
+        for rate_limit in &policy.rate_limits {
+            if !matches!(rate_limit.limit_type, RateLimitType::Amount | RateLimitType::Request) {
+                continue;
+            }
+            let limit = parse_limit("limit", &rate_limit.limit)?;
+            let counter_key = counter_key(&policy.id, rate_limit, &scope);
+            let window_millis = rate_limit.window.max(1) * 1_000;
+            let now = self.clock.now_millis();
+            let elapsed = now % window_millis;
+
+            let (limit, used, resets_in_millis) = match rate_limit.algorithm {
+                RateLimitAlgorithm::FixedWindow => {
+                    let key = format!("{}:{}", counter_key, self.get_time_window(rate_limit.window));
+                    (limit, self.counters.get(&key).await?, window_millis - elapsed)
+                }
+                RateLimitAlgorithm::SlidingWindow => {
+                    let index = now / window_millis;
+                    let current = self.counters.get(&format!("{}:{}", counter_key, index)).await?;
+                    let previous = match index.checked_sub(1) {
+                        Some(previous_index) => self.counters.get(&format!("{}:{}", counter_key, previous_index)).await?,
+                        None => 0,
+                    };
+                    let weighted = previous as u128 * (window_millis - elapsed) as u128 / window_millis as u128;
+                    // Usage in the current window still counts, weighted, through the next one
+                    let resets_in = if current > 0 { 2 * window_millis - elapsed } else { window_millis - elapsed };
+                    (limit, (weighted as u64).saturating_add(current), resets_in)
+                }
+                RateLimitAlgorithm::TokenBucket => {
+                    let bucket = token_bucket(rate_limit, limit)?;
+                    let key = format!("{}:bucket", counter_key);
+                    let used = bucket.capacity - self.counters.available_tokens(&key, &bucket, now).await?.min(bucket.capacity);
+                    let refill_millis = (used as u128 * bucket.period_millis as u128).div_ceil(bucket.refill.max(1) as u128);
+                    (bucket.capacity, used, saturate_u64(U256::from(refill_millis)))
+                }
+            };
+
+            usage.push(RateLimitUsage {
+                limit_type: rate_limit.limit_type.clone(),
+                algorithm: rate_limit.algorithm,
+                scope: scope.clone(),
+                limit,
+                used,
+                remaining: limit.saturating_sub(used),
+                resets_in_seconds: resets_in_millis.div_ceil(1_000),
+            });
+        }
         Ok(usage)
     }
+
+}
+
+fn call_targets(request: &SponsorRequest) -> Option<Vec<Address>> {
+    let call_data = parse_hex_bytes("call data", request.user_operation.call_data()).ok()?;
+    decode_call_targets(&call_data)
+}
+
+fn account_calls(request: &SponsorRequest) -> Option<Vec<Call>> {
+    let call_data = parse_hex_bytes("call data", request.user_operation.call_data()).ok()?;
+    decode_calls(&call_data)
+}
+
+/// A policy with its ancestors' target, rate limits, call rules, webhook and schedule folded in
+///
+/// A child's rate limit replaces the inherited limits of the same type; the others carry over.
+/// Each policy's multipliers scale the limits it ends up with, so they compound down the chain.
+fn effective_policy(policies: &HashMap<String, LoadedPolicy>, policy: &GasPolicy) -> Result<GasPolicy, String> {
+    let mut lineage = vec![policy];
+    while let Some(parent_id) = &lineage[lineage.len() - 1].inherits {
+        let parent = policies.get(parent_id)
+            .map(|loaded| &loaded.policy)
+            .ok_or_else(|| format!("Policy {} inherits unknown policy {}", policy.id, parent_id))?;
+        if lineage.iter().any(|descendant| descendant.id == parent.id) {
+            return Err(format!("Policy {} inherits from itself through {}", policy.id, parent.id));
+        }
+        lineage.push(parent);
+    }
+
+    let mut lineage = lineage.into_iter().rev();
+    let root = lineage.next().expect("lineage starts with the policy itself");
+    Ok(lineage.fold(root.clone(), inherit))
+}
+
+/// Fold a child over its parent's effective policy; the child's multipliers scale only the
+/// limits it inherits, never the limits it sets itself
+fn inherit(parent: GasPolicy, child: &GasPolicy) -> GasPolicy {
+    let mut rate_limits: Vec<RateLimit> = parent.rate_limits.into_iter()
+        .filter(|inherited| !child.rate_limits.iter().any(|own| own.limit_type == inherited.limit_type))
+        .map(|inherited| scaled(inherited, &child.multipliers))
+        .collect();
+    rate_limits.extend(child.rate_limits.iter().cloned());
+
+    GasPolicy {
+        target: child.target.clone().or(parent.target),
+        rate_limits,
+        call_rules: if child.call_rules.is_empty() { parent.call_rules } else { child.call_rules.clone() },
+        webhook: child.webhook.clone().or(parent.webhook),
+        schedule: child.schedule.clone().or(parent.schedule),
+        ..child.clone()
+    }
+}
+
+fn scaled(mut rate_limit: RateLimit, multipliers: &LimitMultipliers) -> RateLimit {
+    let factor = match rate_limit.limit_type {
+        RateLimitType::Amount | RateLimitType::AmountPerTransaction => multipliers.amount,
+        RateLimitType::Request => multipliers.requests,
+        RateLimitType::GasPrice => None,
+    };
+    if let Some(factor) = factor {
+        rate_limit.limit = scale_limit(&rate_limit.limit, factor);
+        rate_limit.burst = rate_limit.burst.as_deref().map(|burst| scale_limit(burst, factor));
+    }
+    rate_limit
+}
+
+/// Ids of the loaded policies whose `inherits` names `policy_id`, ordered by id
+fn inheriting(policies: &HashMap<String, LoadedPolicy>, policy_id: &str) -> Vec<String> {
+    let mut children: Vec<String> = policies.values()
+        .filter(|loaded| loaded.policy.inherits.as_deref() == Some(policy_id))
+        .map(|loaded| loaded.policy.id.clone())
+        .collect();
+    children.sort();
+    children
+}
+
+/// `limit * factor` to a millionth, capped at u64::MAX; unparseable limits are left for validation to report
+fn scale_limit(limit: &str, factor: f64) -> String {
+    let Ok(limit) = parse_quantity("limit", limit) else {
+        return limit.to_string();
+    };
+    let millionths = U256::from((factor * 1_000_000.0).round() as u64);
+    (limit * millionths / U256::from(1_000_000)).min(U256::from(u64::MAX)).to_string()
+}
+
+/// Counters are scoped to what the policy limits: the whole project, a contract or a wallet
+fn counter_scope(policy: &GasPolicy) -> Option<String> {
+    match policy.policy_type {
+        PolicyType::Project => Some("project".to_string()),
+        PolicyType::Contract | PolicyType::Wallet => policy.target.as_ref().map(|target| target.to_lowercase()),
+        PolicyType::Custom => None,
+    }
+}
+
+/// Key prefix of a windowed limit's counters; the algorithm appends the window or bucket suffix
+fn counter_key(policy_id: &str, rate_limit: &RateLimit, scope: &str) -> String {
+    let counter_name = match rate_limit.limit_type {
+        RateLimitType::Amount => "amount_limit",
+        _ => "request_limit",
+    };
+    format!("{}:{}:{}", counter_name, policy_id, scope)
+}
+
+/// Token bucket refilling `limit` per window, holding up to `burst` (default `limit`)
+fn token_bucket(rate_limit: &RateLimit, limit: u64) -> PaymasterResult<TokenBucket> {
+    let capacity = match &rate_limit.burst {
+        Some(burst) => parse_limit("burst", burst)?,
+        None => limit,
+    };
+
+    Ok(TokenBucket {
+        capacity,
+        refill: limit,
+        period_millis: rate_limit.window.max(1) * 1_000,
+    })
+}
+
+/// Parse a hex or decimal user operation field, saturating at `u64::MAX`
+fn parse_gas_value(field: &str, value: &str) -> PaymasterResult<u64> {
+    parse_quantity(field, value).map(saturate_u64)
+}
+
+/// Parse a configured rate limit value
+fn parse_limit(field: &str, value: &str) -> PaymasterResult<u64> {
+    parse_quantity(field, value)
+        .ok()
+        .filter(|limit| *limit <= U256::from(u64::MAX))
+        .map(|limit| limit.as_u64())
+        .ok_or_else(|| PaymasterError::ConfigurationError(format!("Invalid {}: {}", field, value)))
+}
+
+fn saturate_u64(value: U256) -> u64 {
+    value.min(U256::from(u64::MAX)).as_u64()
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct PolicyStatus {
     pub policy_id: String,
     pub enabled: bool,
-    pub current_usage: HashMap<String, u64>,
+    pub usage: Vec<RateLimitUsage>,
+}
+
+/// Usage of one `Amount` or `Request` limit within its counter scope
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitUsage {
+    pub limit_type: RateLimitType,
+    pub algorithm: RateLimitAlgorithm,
+    pub scope: String, // "project", or the lowercase contract or wallet address
+    pub limit: u64,    // Token bucket capacity for token buckets
+    pub used: u64,
+    pub remaining: u64,
+    pub resets_in_seconds: u64, // Until everything counted now has stopped counting
+}
+
+/// Outcome of `evaluate_policies`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyEvaluation {
+    pub allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejected_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub trace: Vec<PolicyTraceEntry>, // In evaluation order, starting with the global gas cap
+}
+
+/// What one policy decided during an evaluation
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyTraceEntry {
+    pub policy_id: String,
+    pub priority: i32,
+    pub effect: PolicyEffect,
+    pub matched: bool, // Whether the policy covers the operation
+    pub outcome: TraceOutcome,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TraceOutcome {
+    /// The policy does not cover the operation
+    Skipped,
+    /// Within the policy's limits and rules
+    Passed,
+    /// Explicitly allowed by an `Allow` policy
+    Allowed,
+    /// Refused by a `Deny` policy
+    Denied,
+    /// Over a limit, against a rule, or the policy could not be evaluated
+    Rejected,
+}
+
+impl PolicyTraceEntry {
+    fn new(policy_id: &str, priority: i32, effect: PolicyEffect, matched: bool, result: &PaymasterResult<()>) -> Self {
+        let (outcome, reason) = match result {
+            Err(PaymasterError::PolicyViolation(reason)) if effect == PolicyEffect::Deny && matched => {
+                (TraceOutcome::Denied, reason.clone())
+            }
+            Err(PaymasterError::PolicyViolation(reason)) => (TraceOutcome::Rejected, reason.clone()),
+            Err(e) => (TraceOutcome::Rejected, e.to_string()),
+            Ok(()) if !matched => (TraceOutcome::Skipped, "Does not cover this operation".to_string()),
+            Ok(()) if effect == PolicyEffect::Allow => (TraceOutcome::Allowed, "Covers this operation within its limits".to_string()),
+            Ok(()) => (TraceOutcome::Passed, "Within its limits".to_string()),
+        };
+
+        Self { policy_id: policy_id.to_string(), priority, effect, matched, outcome, reason }
+    }
+}
+
+/// `policy:Outcome` for each traced policy, for logs
+fn summarize(trace: &[PolicyTraceEntry]) -> String {
+    trace.iter()
+        .map(|entry| format!("{}:{:?}", entry.policy_id, entry.outcome))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Outcome of `test_policy`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyDecision {
+    pub policy_id: String,
+    pub enabled: bool,
+    pub applies: bool, // Whether the policy's target covers the request at all
+    pub allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::test_support::*;
+
+    fn engine() -> PolicyEngine {
+        PolicyEngine::new("redis://127.0.0.1:6379", &Settings::default().policy).unwrap()
+    }
+
+    #[tokio::test]
+    async fn global_gas_cap_reports_its_policy_id() {
+        let engine = engine();
+        assert!(engine.check_policies(&request("0x989680")).await.is_ok());
+
+        match engine.check_policies(&request("0x989681")).await {
+            Err(PaymasterError::PolicyRejected { policy_id, .. }) => assert_eq!(policy_id, GLOBAL_GAS_CAP_POLICY_ID),
+            other => panic!("expected rejection by the global gas cap, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejection_names_the_configured_policy() {
+        let engine = engine();
+        engine.add_policy(GasPolicy {
+            id: "low_fees".to_string(),
+            name: "Low fees only".to_string(),
+            policy_type: PolicyType::Project,
+            target: None,
+            rate_limits: vec![RateLimit {
+                limit_type: RateLimitType::GasPrice,
+                limit: "0x3b9ac9ff".to_string(),
+                window: 0,
+                algorithm: RateLimitAlgorithm::FixedWindow,
+                burst: None,
+            }],
+            call_rules: vec![],
+            webhook: None,
+            priority: 0,
+            effect: PolicyEffect::Limit,
+            inherits: None,
+            multipliers: LimitMultipliers::default(),
+            schedule: None,
+            enabled: true,
+        }).await.unwrap();
+
+        match engine.check_policies(&request("0x186a0")).await {
+            Err(PaymasterError::PolicyRejected { policy_id, .. }) => assert_eq!(policy_id, "low_fees"),
+            other => panic!("expected rejection by low_fees, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn gas_cost_accepts_hex_quantities() {
+        // (0x186a0 + 0x186a0 + 0x5208) gas at 1 gwei
+        assert_eq!(engine().calculate_gas_cost(&request("0x186a0").user_operation).unwrap(), 221_000_000_000_000);
+    }
+
+    /// Fire `attempts` concurrent checks at one wallet and count how many were admitted
+    async fn hammer(engine: Arc<PolicyEngine>, attempts: usize) -> usize {
+        let tasks: Vec<_> = (0..attempts)
+            .map(|_| {
+                let engine = engine.clone();
+                tokio::spawn(async move { engine.check_policies(&request("0x186a0")).await.is_ok() })
+            })
+            .collect();
+
+        let mut admitted = 0;
+        for task in tasks {
+            if task.await.unwrap() {
+                admitted += 1;
+            }
+        }
+        admitted
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_requests_never_exceed_the_request_limit() {
+        let engine = Arc::new(PolicyEngine::with_store(
+            Arc::new(MemoryCounterStore::new()),
+            Arc::new(SystemClock),
+            &Settings::default().policy,
+        ));
+        engine.add_policy(wallet_policy(RateLimitType::Request, "10")).await.unwrap();
+
+        assert_eq!(hammer(engine, 200).await, 10);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_requests_never_exceed_the_amount_limit() {
+        let engine = Arc::new(PolicyEngine::with_store(
+            Arc::new(MemoryCounterStore::new()),
+            Arc::new(SystemClock),
+            &Settings::default().policy,
+        ));
+        // Room for exactly seven operations of 221000 gas at 1 gwei
+        engine.add_policy(wallet_policy(RateLimitType::Amount, "1600000000000000")).await.unwrap();
+
+        assert_eq!(hammer(engine, 200).await, 7);
+    }
+
+    #[tokio::test]
+    async fn rejected_operations_release_their_reservations() {
+        let counters = Arc::new(MemoryCounterStore::new());
+        let engine = PolicyEngine::with_store(counters.clone(), Arc::new(SystemClock), &Settings::default().policy);
+        engine.add_policy(wallet_policy(RateLimitType::Request, "10")).await.unwrap();
+        engine.add_policy(GasPolicy {
+            id: "zero_budget".to_string(),
+            name: "Zero budget".to_string(),
+            policy_type: PolicyType::Project,
+            target: None,
+            rate_limits: vec![RateLimit {
+                limit_type: RateLimitType::Amount,
+                limit: "0".to_string(),
+                window: 3600,
+                algorithm: RateLimitAlgorithm::FixedWindow,
+                burst: None,
+            }],
+            call_rules: vec![],
+            webhook: None,
+            priority: 0,
+            effect: PolicyEffect::Limit,
+            inherits: None,
+            multipliers: LimitMultipliers::default(),
+            schedule: None,
+            enabled: true,
+        }).await.unwrap();
+
+        for _ in 0..3 {
+            assert!(engine.check_policies(&request("0x186a0")).await.is_err());
+        }
+
+        let key = format!(
+            "request_limit:wallet_budget:0x1306b01bc3e4ad202612d3843387e94737673f53:{}",
+            engine.get_time_window(3600)
+        );
+        assert_eq!(counters.get(&key).await.unwrap(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    #[ignore = "requires a Redis server at REDIS_URL or redis://127.0.0.1:6379"]
+    async fn concurrent_requests_never_exceed_the_limit_in_redis() {
+        let redis_url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+        let engine = Arc::new(PolicyEngine::new(&redis_url, &Settings::default().policy).unwrap());
+        // A fresh policy id keeps reruns within one window independent
+        let mut policy = wallet_policy(RateLimitType::Request, "10");
+        policy.id = format!("wallet_budget_{}", uuid::Uuid::new_v4());
+        engine.add_policy(policy).await.unwrap();
+
+        assert_eq!(hammer(engine, 200).await, 10);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Redis server at REDIS_URL or redis://127.0.0.1:6379"]
+    async fn pending_sponsorships_outlive_the_instance_in_redis() {
+        let redis_url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+        let engine = PolicyEngine::new(&redis_url, &Settings::default().policy).unwrap();
+        let mut policy = wallet_policy(RateLimitType::Request, "10");
+        policy.id = format!("wallet_budget_{}", uuid::Uuid::new_v4());
+        engine.add_policy(policy).await.unwrap();
+
+        let user_op_hash = H256::random();
+        let reservation = engine.check_policies(&request("0x186a0")).await.unwrap();
+        engine.hold(1, user_op_hash, reservation, 1_000).await;
+        drop(engine);
+
+        let restarted = PolicyEngine::new(&redis_url, &Settings::default().policy).unwrap();
+        assert!(restarted.settle(1, user_op_hash, U256::zero()).await);
+        assert!(!restarted.settle(1, user_op_hash, U256::zero()).await);
+    }
+
+    #[tokio::test]
+    async fn settlement_corrects_the_amount_to_the_actual_cost() {
+        let counters = Arc::new(MemoryCounterStore::new());
+        let engine = PolicyEngine::with_store(counters.clone(), Arc::new(SystemClock), &Settings::default().policy);
+        engine.add_policy(wallet_policy(RateLimitType::Amount, "1000000000000000")).await.unwrap();
+        let key = format!(
+            "amount_limit:wallet_budget:0x1306b01bc3e4ad202612d3843387e94737673f53:{}",
+            engine.get_time_window(3600)
+        );
+
+        let reservation = engine.check_policies(&request("0x186a0")).await.unwrap();
+        engine.hold(1, H256::repeat_byte(1), reservation, 1_000).await;
+        assert_eq!(counters.get(&key).await.unwrap(), 221_000_000_000_000);
+
+        // Another instance sharing the store, or this one after a restart, settles it
+        let replica = PolicyEngine::with_store(counters.clone(), Arc::new(SystemClock), &Settings::default().policy);
+        assert!(replica.settle(1, H256::repeat_byte(1), U256::from(50_000_000_000_000u64)).await);
+        assert_eq!(counters.get(&key).await.unwrap(), 50_000_000_000_000);
+        assert!(!engine.settle(1, H256::repeat_byte(1), U256::zero()).await);
+    }
+
+    #[tokio::test]
+    async fn expired_sponsorships_release_their_budget() {
+        let counters = Arc::new(MemoryCounterStore::new());
+        let engine = PolicyEngine::with_store(counters.clone(), Arc::new(SystemClock), &Settings::default().policy);
+        engine.add_policy(wallet_policy(RateLimitType::Request, "10")).await.unwrap();
+        let key = format!(
+            "request_limit:wallet_budget:0x1306b01bc3e4ad202612d3843387e94737673f53:{}",
+            engine.get_time_window(3600)
+        );
+
+        let reservation = engine.check_policies(&request("0x186a0")).await.unwrap();
+        engine.hold(1, H256::repeat_byte(2), reservation, 1_000).await;
+
+        // Still within the grace period after validUntil
+        assert_eq!(engine.release_expired(1, 1_000 + 120).await, 0);
+        assert_eq!(counters.get(&key).await.unwrap(), 1);
+
+        // Nor is it released by another chain's settlement
+        assert_eq!(engine.release_expired(8453, 1_000 + 121).await, 0);
+
+        let replica = PolicyEngine::with_store(counters.clone(), Arc::new(SystemClock), &Settings::default().policy);
+        assert_eq!(replica.release_expired(1, 1_000 + 121).await, 1);
+        assert_eq!(counters.get(&key).await.unwrap(), 0);
+        assert_eq!(engine.release_expired(1, 1_000 + 121).await, 0);
+    }
+
+    /// A 10-per-minute request limit on one wallet, driven by a manual clock
+    async fn clocked_engine(algorithm: RateLimitAlgorithm, burst: Option<&str>) -> (PolicyEngine, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new(0));
+        let engine = memory_engine_at(clock.clone());
+
+        let mut policy = wallet_policy(RateLimitType::Request, "10");
+        policy.rate_limits[0].window = 60;
+        policy.rate_limits[0].algorithm = algorithm;
+        policy.rate_limits[0].burst = burst.map(str::to_string);
+        engine.add_policy(policy).await.unwrap();
+        (engine, clock)
+    }
+
+    /// Send `attempts` checks one after another and count how many were admitted
+    async fn admitted(engine: &PolicyEngine, attempts: usize) -> usize {
+        let mut admitted = 0;
+        for _ in 0..attempts {
+            if engine.check_policies(&request("0x186a0")).await.is_ok() {
+                admitted += 1;
+            }
+        }
+        admitted
+    }
+
+    #[tokio::test]
+    async fn fixed_window_admits_a_double_burst_across_the_boundary() {
+        let (engine, clock) = clocked_engine(RateLimitAlgorithm::FixedWindow, None).await;
+
+        clock.set_millis(59_000);
+        assert_eq!(admitted(&engine, 20).await, 10);
+        clock.set_millis(61_000);
+        assert_eq!(admitted(&engine, 20).await, 10);
+    }
+
+    #[tokio::test]
+    async fn sliding_window_weights_the_previous_window() {
+        let (engine, clock) = clocked_engine(RateLimitAlgorithm::SlidingWindow, None).await;
+
+        clock.set_millis(59_000);
+        assert_eq!(admitted(&engine, 20).await, 10);
+        // 59/60 of the previous window still counts
+        clock.set_millis(61_000);
+        assert_eq!(admitted(&engine, 20).await, 0);
+        // Half of it still counts
+        clock.set_millis(90_000);
+        assert_eq!(admitted(&engine, 20).await, 5);
+        // The whole previous window counts at its end
+        clock.set_millis(120_000);
+        assert_eq!(admitted(&engine, 20).await, 5);
+    }
+
+    #[tokio::test]
+    async fn token_bucket_allows_a_burst_then_refills_steadily() {
+        let (engine, clock) = clocked_engine(RateLimitAlgorithm::TokenBucket, Some("5")).await;
+
+        assert_eq!(admitted(&engine, 20).await, 5);
+        // One token every six seconds
+        clock.set_millis(6_500);
+        assert_eq!(admitted(&engine, 20).await, 1);
+        clock.set_millis(12_500);
+        assert_eq!(admitted(&engine, 20).await, 1);
+        // Never more than the burst after a long idle period
+        clock.set_millis(600_000);
+        assert_eq!(admitted(&engine, 20).await, 5);
+    }
+
+    #[tokio::test]
+    async fn token_bucket_returns_tokens_when_released() {
+        let (engine, _clock) = clocked_engine(RateLimitAlgorithm::TokenBucket, Some("1")).await;
+
+        let reservation = engine.check_policies(&request("0x186a0")).await.unwrap();
+        assert_eq!(admitted(&engine, 1).await, 0);
+        engine.cancel(reservation).await;
+        assert_eq!(admitted(&engine, 1).await, 1);
+    }
+
+    #[tokio::test]
+    async fn testing_a_policy_takes_no_tokens() {
+        let (engine, _clock) = clocked_engine(RateLimitAlgorithm::TokenBucket, Some("1")).await;
+
+        for _ in 0..3 {
+            assert!(engine.test_policy("wallet_budget", &request("0x186a0")).await.unwrap().allowed);
+        }
+        assert_eq!(admitted(&engine, 2).await, 1);
+
+        let decision = engine.test_policy("wallet_budget", &request("0x186a0")).await.unwrap();
+        assert!(!decision.allowed);
+        assert!(decision.reason.unwrap().contains("tokens"));
+    }
+
+    #[tokio::test]
+    async fn status_reports_usage_remaining_quota_and_reset() {
+        let (engine, clock) = clocked_engine(RateLimitAlgorithm::FixedWindow, None).await;
+        clock.set_millis(45_500);
+        assert_eq!(admitted(&engine, 3).await, 3);
+
+        let status = engine.get_policy_status("wallet_budget").await.unwrap();
+        let usage = &status.usage[0];
+        assert_eq!(usage.scope, "0x1306b01bc3e4ad202612d3843387e94737673f53");
+        assert_eq!((usage.limit, usage.used, usage.remaining), (10, 3, 7));
+        assert_eq!(usage.resets_in_seconds, 15);
+
+        let (engine, clock) = clocked_engine(RateLimitAlgorithm::TokenBucket, Some("5")).await;
+        assert_eq!(admitted(&engine, 2).await, 2);
+        clock.set_millis(3_000);
+
+        let usage = &engine.get_policy_status("wallet_budget").await.unwrap().usage[0];
+        assert_eq!((usage.limit, usage.used, usage.remaining), (5, 2, 3));
+        // Two tokens at one per six seconds
+        assert_eq!(usage.resets_in_seconds, 12);
+    }
+
+    fn calling(call_data: Vec<u8>) -> SponsorRequest {
+        let mut request = request("0x186a0");
+        if let VersionedUserOperation::V06(user_op) = &mut request.user_operation {
+            user_op.call_data = format!("0x{}", ethers::utils::hex::encode(call_data));
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn contract_policies_match_decoded_call_targets() {
+        use ethers::abi::{encode, Token};
+
+        let engine = engine();
+        let router = Address::repeat_byte(0x11);
+        let policy = contract_policy(&format!("{:?}", router));
+        let execute = |target: Address, data: Vec<u8>| {
+            let arguments = encode(&[Token::Address(target), Token::Uint(0.into()), Token::Bytes(data)]);
+            [ethers::utils::id("execute(address,uint256,bytes)").to_vec(), arguments].concat()
+        };
+
+        assert!(engine.policy_applies(&policy, &calling(execute(router, vec![]))));
+        // Only mentions the router as an argument of a call elsewhere
+        let mentions = encode(&[Token::Address(router)]);
+        assert!(!engine.policy_applies(&policy, &calling(execute(Address::repeat_byte(0x22), mentions))));
+
+        // Unknown layouts are rejected by default
+        engine.add_policy(policy).await.unwrap();
+        assert!(engine.check_policies(&calling(vec![0xde, 0xad, 0xbe, 0xef])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn call_rules_restrict_sponsored_functions() {
+        use ethers::abi::{encode, Token};
+
+        let engine = engine();
+        let nft = Address::repeat_byte(0x11);
+        let mut policy = contract_policy(&format!("{:?}", nft));
+        policy.call_rules = vec![CallRule {
+            target: format!("{:?}", nft),
+            allowed_selectors: vec!["mint(address,uint256)".to_string()],
+            ..CallRule::default()
+        }];
+        engine.add_policy(policy).await.unwrap();
+
+        let execute = |signature: &str| {
+            let inner = [ethers::utils::id(signature).to_vec(), encode(&[Token::Address(nft), Token::Uint(1.into())])].concat();
+            let arguments = encode(&[Token::Address(nft), Token::Uint(0.into()), Token::Bytes(inner)]);
+            [ethers::utils::id("execute(address,uint256,bytes)").to_vec(), arguments].concat()
+        };
+
+        assert!(engine.check_policies(&calling(execute("mint(address,uint256)"))).await.is_ok());
+        assert!(engine.check_policies(&calling(execute("burn(address,uint256)"))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn custom_policies_follow_their_webhook() {
+        use axum::{routing::post, Json, Router};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/decide", listener.local_addr().unwrap());
+        let router = Router::new().route("/decide", post(|| async {
+            Json(serde_json::json!({
+                "allow": true,
+                "rate_limits": [{ "limit_type": "Request", "limit": "1", "window": 60 }]
+            }))
+        }));
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        // The webhook's limits replace the policy's, counted per sender
+        let engine = memory_engine();
+        engine.add_policy(custom_policy(url, false)).await.unwrap();
+        assert!(engine.check_policies(&request("0x186a0")).await.is_ok());
+        assert!(engine.check_policies(&request("0x186a0")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unreachable_webhooks_fail_open_or_closed() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/decide", listener.local_addr().unwrap());
+        drop(listener);
+
+        let engine = memory_engine();
+        engine.add_policy(custom_policy(url.clone(), false)).await.unwrap();
+        assert!(engine.check_policies(&request("0x186a0")).await.is_err());
+
+        // Failing open still counts the policy's own limits per sender
+        let mut policy = custom_policy(url, true);
+        policy.rate_limits = wallet_policy(RateLimitType::Request, "1").rate_limits;
+        engine.add_policy(policy).await.unwrap();
+        let reservation = engine.check_policies(&request("0x186a0")).await.unwrap();
+        assert!(engine.check_policies(&request("0x186a0")).await.is_err());
+
+        engine.cancel(reservation).await;
+        assert!(engine.check_policies(&request("0x186a0")).await.is_ok());
+    }
+
+    fn outcomes(evaluation: &PolicyEvaluation) -> Vec<(&str, TraceOutcome)> {
+        evaluation.trace.iter().map(|entry| (entry.policy_id.as_str(), entry.outcome)).collect()
+    }
+
+    #[tokio::test]
+    async fn policies_inherit_and_scale_their_parents_limits() {
+        let engine = memory_engine();
+        let base = GasPolicy { id: "base".to_string(), enabled: false, ..wallet_policy(RateLimitType::Request, "10") };
+        let vip = GasPolicy {
+            id: "vip".to_string(),
+            target: None,
+            rate_limits: vec![],
+            inherits: Some("base".to_string()),
+            multipliers: LimitMultipliers { amount: None, requests: Some(2.5) },
+            schedule: None,
+            ..wallet_policy(RateLimitType::Request, "1")
+        };
+        engine.add_policy(base).await.unwrap();
+        engine.add_policy(vip).await.unwrap();
+
+        let effective = engine.effective_policy("vip").await.unwrap();
+        assert_eq!(effective.target.as_deref(), Some("0x1306b01bc3e4ad202612d3843387e94737673f53"));
+        assert_eq!(effective.rate_limits[0].limit, "25");
+
+        // A child's own limit of the same type replaces the inherited one and is not scaled
+        let mut strict = engine.get_policy("vip").await.unwrap();
+        strict.rate_limits = vec![RateLimit { limit: "4".to_string(), ..effective.rate_limits[0].clone() }];
+        engine.add_policy(strict).await.unwrap();
+        assert_eq!(engine.effective_policy("vip").await.unwrap().rate_limits[0].limit, "4");
+    }
+
+    #[tokio::test]
+    async fn inheritance_is_checked_when_policies_change() {
+        let engine = memory_engine();
+        let base = GasPolicy { id: "base".to_string(), ..wallet_policy(RateLimitType::Request, "10") };
+        let vip = GasPolicy { id: "vip".to_string(), inherits: Some("base".to_string()), ..wallet_policy(RateLimitType::Request, "20") };
+
+        // Unknown parent
+        assert!(engine.add_policy(vip.clone()).await.is_err());
+        engine.add_policy(base.clone()).await.unwrap();
+        engine.add_policy(vip).await.unwrap();
+
+        // A cycle is refused and the previous policy kept
+        let cycle = GasPolicy { inherits: Some("vip".to_string()), ..base.clone() };
+        assert!(engine.add_policy(cycle).await.is_err());
+        assert_eq!(engine.get_policy("base").await.unwrap().inherits, None);
+
+        // A parent cannot be removed while inherited
+        assert!(engine.remove_policy("base").await.is_err());
+        assert_eq!(engine.inheriting_policies("base").await, vec!["vip".to_string()]);
+
+        // Bulk loads skip orphans and their descendants
+        let orphan = GasPolicy { id: "orphan".to_string(), inherits: Some("missing".to_string()), ..base.clone() };
+        let grandchild = GasPolicy { id: "grandchild".to_string(), inherits: Some("orphan".to_string()), ..base.clone() };
+        engine.replace_policies(PolicySource::File, vec![orphan, grandchild]).await;
+        assert!(engine.get_policy("orphan").await.is_none());
+        assert!(engine.get_policy("grandchild").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn unresolvable_policies_are_skipped() {
+        let engine = memory_engine();
+        engine.add_policy(GasPolicy { id: "base".to_string(), ..wallet_policy(RateLimitType::Request, "10") }).await.unwrap();
+        engine.add_policy(GasPolicy {
+            id: "vip".to_string(),
+            inherits: Some("base".to_string()),
+            ..wallet_policy(RateLimitType::Request, "20")
+        }).await.unwrap();
+
+        // Its parent disappears with a database reload
+        assert!(engine.unload_policy(PolicySource::Runtime, "base").await);
+        assert!(engine.effective_policy("vip").await.is_err());
+
+        let evaluation = engine.evaluate_policies(&request("0x186a0")).await.unwrap();
+        assert!(evaluation.allowed);
+        assert_eq!(outcomes(&evaluation), vec![(GLOBAL_GAS_CAP_POLICY_ID, TraceOutcome::Passed), ("vip", TraceOutcome::Skipped)]);
+        assert!(engine.check_policies(&request("0x186a0")).await.is_ok());
+
+        // Nor does an unresolvable allow policy turn on default deny
+        engine.add_policy(GasPolicy { id: "base".to_string(), ..wallet_policy(RateLimitType::Request, "10") }).await.unwrap();
+        let vip = engine.get_policy("vip").await.unwrap();
+        engine.add_policy(GasPolicy { effect: PolicyEffect::Allow, ..vip }).await.unwrap();
+        assert!(engine.unload_policy(PolicySource::Runtime, "base").await);
+        assert!(engine.evaluate_policies(&request("0x186a0")).await.unwrap().allowed);
+        assert!(engine.check_policies(&request("0x186a0")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn deny_overrides_allow_in_priority_order() {
+        let engine = memory_engine();
+        let sender_allowed = GasPolicy {
+            id: "allow_sender".to_string(),
+            priority: 10,
+            effect: PolicyEffect::Allow,
+            ..wallet_policy(RateLimitType::Request, "10")
+        };
+        let other_denied = GasPolicy {
+            id: "deny_other".to_string(),
+            priority: 5,
+            effect: PolicyEffect::Deny,
+            target: Some("0x2222222222222222222222222222222222222222".to_string()),
+            ..wallet_policy(RateLimitType::Request, "10")
+        };
+        engine.add_policy(sender_allowed).await.unwrap();
+        engine.add_policy(other_denied).await.unwrap();
+
+        let evaluation = engine.evaluate_policies(&request("0x186a0")).await.unwrap();
+        assert!(evaluation.allowed);
+        assert_eq!(outcomes(&evaluation), vec![
+            (GLOBAL_GAS_CAP_POLICY_ID, TraceOutcome::Passed),
+            ("deny_other", TraceOutcome::Skipped),
+            ("allow_sender", TraceOutcome::Allowed),
+        ]);
+
+        // A later deny still wins over the earlier allow
+        engine.add_policy(GasPolicy {
+            id: "deny_sender".to_string(),
+            priority: 20,
+            effect: PolicyEffect::Deny,
+            ..wallet_policy(RateLimitType::Request, "10")
+        }).await.unwrap();
+        let evaluation = engine.evaluate_policies(&request("0x186a0")).await.unwrap();
+        assert_eq!(evaluation.rejected_by.as_deref(), Some("deny_sender"));
+        assert_eq!(evaluation.trace.last().unwrap().outcome, TraceOutcome::Denied);
+        match engine.check_policies(&request("0x186a0")).await {
+            Err(PaymasterError::PolicyRejected { policy_id, .. }) => assert_eq!(policy_id, "deny_sender"),
+            other => panic!("expected a rejection, got {:?}", other.map(|_| ())),
+        }
+
+        // Once allow policies exist, operations none of them covers are refused
+        engine.remove_policy("deny_sender").await.unwrap();
+        let mut elsewhere = engine.get_policy("allow_sender").await.unwrap();
+        elsewhere.target = Some("0x3333333333333333333333333333333333333333".to_string());
+        engine.add_policy(elsewhere).await.unwrap();
+        let evaluation = engine.evaluate_policies(&request("0x186a0")).await.unwrap();
+        assert_eq!(evaluation.rejected_by.as_deref(), Some(DEFAULT_DENY_POLICY_ID));
+    }
+
+    #[tokio::test]
+    async fn scheduled_policies_apply_within_their_window_then_expire() {
+        let clock = Arc::new(ManualClock::new(0));
+        let engine = memory_engine_at(clock.clone());
+        let mut events = engine.subscribe();
+        engine.add_policy(GasPolicy {
+            id: "campaign_blackout".to_string(),
+            effect: PolicyEffect::Deny,
+            schedule: Some(PolicySchedule { starts_at: Some(100), ends_at: Some(200), ..PolicySchedule::default() }),
+            ..wallet_policy(RateLimitType::Request, "10")
+        }).await.unwrap();
+
+        let evaluation = engine.evaluate_policies(&request("0x186a0")).await.unwrap();
+        assert!(evaluation.allowed);
+        assert_eq!(evaluation.trace[1].reason, "Outside its schedule");
+
+        clock.set_millis(150_000);
+        assert!(engine.check_policies(&request("0x186a0")).await.is_err());
+        assert!(engine.disable_ended_policies().await.is_empty());
+
+        clock.set_millis(200_000);
+        assert!(engine.check_policies(&request("0x186a0")).await.is_ok());
+        let expired = PolicyEvent::Expired {
+            policy_id: "campaign_blackout".to_string(),
+            source: PolicySource::Runtime,
+            ended_at: 200,
+        };
+        assert_eq!(engine.disable_ended_policies().await, vec![expired.clone()]);
+        assert_eq!(events.recv().await.unwrap(), expired);
+        assert!(!engine.get_policy("campaign_blackout").await.unwrap().enabled);
+        assert!(engine.disable_ended_policies().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn children_expire_with_an_inherited_schedule() {
+        let clock = Arc::new(ManualClock::new(0));
+        let engine = memory_engine_at(clock.clone());
+        engine.add_policy(GasPolicy {
+            id: "campaign".to_string(),
+            schedule: Some(PolicySchedule { ends_at: Some(200), ..PolicySchedule::default() }),
+            ..wallet_policy(RateLimitType::Request, "10")
+        }).await.unwrap();
+        engine.add_policy(GasPolicy {
+            id: "campaign_vip".to_string(),
+            inherits: Some("campaign".to_string()),
+            ..wallet_policy(RateLimitType::Request, "20")
+        }).await.unwrap();
+
+        clock.set_millis(200_000);
+        let mut expired: Vec<String> = engine.disable_ended_policies().await.into_iter()
+            .map(|PolicyEvent::Expired { policy_id, .. }| policy_id)
+            .collect();
+        expired.sort();
+        assert_eq!(expired, vec!["campaign".to_string(), "campaign_vip".to_string()]);
+        assert!(!engine.get_policy("campaign_vip").await.unwrap().enabled);
+    }
 }
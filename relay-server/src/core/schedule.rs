@@ -0,0 +1,209 @@
+use crate::core::policy_engine::PolicyEngine;
+use crate::core::types::PolicySchedule;
+use chrono::{DateTime, Datelike, Timelike};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+impl PolicySchedule {
+    /// Whether the schedule is in effect at `now_seconds`
+    ///
+    /// Start and end bound the policy's whole life; within them, a schedule with recurring
+    /// windows is only in effect during minutes one of them matches.
+    pub fn is_active(&self, now_seconds: u64) -> bool {
+        if self.starts_at.is_some_and(|starts_at| now_seconds < starts_at) || self.has_ended(now_seconds) {
+            return false;
+        }
+        if self.recurring.is_empty() {
+            return true;
+        }
+
+        let local = i64::try_from(now_seconds).unwrap_or(i64::MAX)
+            .saturating_add(i64::from(self.utc_offset_minutes) * 60);
+        let Some(time) = DateTime::from_timestamp(local, 0) else {
+            return false;
+        };
+        self.recurring.iter()
+            .filter_map(|expression| CronExpression::parse(expression).ok())
+            .any(|cron| cron.matches(&time))
+    }
+
+    /// Whether the end has passed, after which the policy never applies again
+    pub fn has_ended(&self, now_seconds: u64) -> bool {
+        self.ends_at.is_some_and(|ends_at| now_seconds >= ends_at)
+    }
+}
+
+/// A five-field cron expression: `minute hour day-of-month month day-of-week`
+///
+/// Fields take `*`, values, ranges `a-b`, steps `*/n` or `a-b/n`, and comma lists of those.
+/// Day of week runs 0-7 with both 0 and 7 meaning Sunday. As in cron, when both day fields
+/// are restricted a day matching either one matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpression {
+    minutes: Field,
+    hours: Field,
+    days_of_month: Field,
+    months: Field,
+    days_of_week: Field,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Field {
+    allowed: u64, // Bit n set when value n matches
+    any: bool,    // Written as `*`
+}
+
+impl CronExpression {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            return Err(format!("expected 5 fields, found {}", fields.len()));
+        };
+
+        let mut days_of_week = Field::parse(days_of_week, 0, 7, "day of week")?;
+        if days_of_week.allowed & (1 << 7) != 0 {
+            days_of_week.allowed |= 1;
+        }
+        Ok(Self {
+            minutes: Field::parse(minutes, 0, 59, "minute")?,
+            hours: Field::parse(hours, 0, 23, "hour")?,
+            days_of_month: Field::parse(days_of_month, 1, 31, "day of month")?,
+            months: Field::parse(months, 1, 12, "month")?,
+            days_of_week,
+        })
+    }
+
+    pub fn matches<Tz: chrono::TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let day_of_month = self.days_of_month.contains(time.day());
+        let day_of_week = self.days_of_week.contains(time.weekday().num_days_from_sunday());
+        let day = match (self.days_of_month.any, self.days_of_week.any) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        };
+
+        day && self.minutes.contains(time.minute()) && self.hours.contains(time.hour()) && self.months.contains(time.month())
+    }
+}
+
+impl Field {
+    fn parse(field: &str, min: u32, max: u32, name: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid {} field {:?}", name, field);
+        let mut allowed = 0u64;
+
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0).ok_or_else(invalid)?),
+                None => (part, 1),
+            };
+            let (first, last) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((first, last)) => (
+                        first.parse().map_err(|_| invalid())?,
+                        last.parse().map_err(|_| invalid())?,
+                    ),
+                    None => {
+                        let value = range.parse().map_err(|_| invalid())?;
+                        (value, value)
+                    }
+                },
+            };
+            if first < min || last > max || first > last {
+                return Err(invalid());
+            }
+            for value in (first..=last).step_by(step as usize) {
+                allowed |= 1 << value;
+            }
+        }
+
+        Ok(Self { allowed, any: field == "*" })
+    }
+
+    fn contains(&self, value: u32) -> bool {
+        self.allowed & (1 << value) != 0
+    }
+}
+
+/// Disables policies whose schedule has ended, so they show as inactive everywhere
+pub struct ScheduleWatcher {
+    policy_engine: Arc<PolicyEngine>,
+    poll_interval: Duration,
+}
+
+impl ScheduleWatcher {
+    pub fn new(policy_engine: Arc<PolicyEngine>, poll_interval: Duration) -> Self {
+        Self { policy_engine, poll_interval }
+    }
+
+    /// Check for ended schedules until the process exits
+    pub async fn run(self) {
+        info!("Checking policy schedules every {:?}", self.poll_interval);
+        let mut interval = tokio::time::interval(self.poll_interval);
+
+        loop {
+            interval.tick().await;
+            self.policy_engine.disable_ended_policies().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-01T00:00:00Z, a Monday
+    const NEW_YEAR_2024: u64 = 1_704_067_200;
+    const HOUR: u64 = 3_600;
+    const DAY: u64 = 24 * HOUR;
+
+    fn business_hours() -> PolicySchedule {
+        PolicySchedule { recurring: vec!["* 9-16 * * 1-5".to_string()], ..PolicySchedule::default() }
+    }
+
+    #[test]
+    fn campaigns_run_between_their_start_and_end() {
+        let campaign = PolicySchedule {
+            starts_at: Some(NEW_YEAR_2024),
+            ends_at: Some(NEW_YEAR_2024 + 7 * DAY),
+            ..PolicySchedule::default()
+        };
+
+        assert!(!campaign.is_active(NEW_YEAR_2024 - 1));
+        assert!(campaign.is_active(NEW_YEAR_2024));
+        assert!(campaign.is_active(NEW_YEAR_2024 + 7 * DAY - 1));
+        assert!(!campaign.is_active(NEW_YEAR_2024 + 7 * DAY));
+        assert!(campaign.has_ended(NEW_YEAR_2024 + 7 * DAY));
+    }
+
+    #[test]
+    fn recurring_windows_follow_cron_fields() {
+        let schedule = business_hours();
+        assert!(!schedule.is_active(NEW_YEAR_2024 + 8 * HOUR + 59 * 60));
+        assert!(schedule.is_active(NEW_YEAR_2024 + 9 * HOUR));
+        assert!(schedule.is_active(NEW_YEAR_2024 + 16 * HOUR + 59 * 60));
+        assert!(!schedule.is_active(NEW_YEAR_2024 + 17 * HOUR));
+        // Saturday
+        assert!(!schedule.is_active(NEW_YEAR_2024 + 5 * DAY + 10 * HOUR));
+
+        // 09:00 at UTC+8 is 01:00 UTC
+        let shanghai = PolicySchedule { utc_offset_minutes: 480, ..business_hours() };
+        assert!(shanghai.is_active(NEW_YEAR_2024 + HOUR));
+        assert!(!shanghai.is_active(NEW_YEAR_2024 + 9 * HOUR));
+    }
+
+    #[test]
+    fn parses_cron_syntax() {
+        let time = DateTime::from_timestamp(NEW_YEAR_2024 as i64 + 30 * 60, 0).unwrap();
+        assert!(CronExpression::parse("*/15 0 1 1 *").unwrap().matches(&time));
+        assert!(!CronExpression::parse("0,30 * * * 7").unwrap().matches(&time));
+        assert!(CronExpression::parse("30 * * * 1,7").unwrap().matches(&time));
+        // Either restricted day field may match: the 1st is a Monday, not a Friday
+        assert!(CronExpression::parse("30 0 1 * 5").unwrap().matches(&time));
+
+        assert!(CronExpression::parse("* * * *").is_err());
+        assert!(CronExpression::parse("60 * * * *").is_err());
+        assert!(CronExpression::parse("*/0 * * * *").is_err());
+        assert!(CronExpression::parse("5-1 * * * *").is_err());
+    }
+}
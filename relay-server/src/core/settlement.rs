@@ -0,0 +1,247 @@
+use crate::core::chain_registry::Chain;
+use crate::core::policy_engine::PolicyEngine;
+use crate::core::types::*;
+use ethers::prelude::*;
+use ethers::utils::keccak256;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// `UserOperationEvent`, identical across EntryPoint v0.6, v0.7 and v0.8
+pub const USER_OPERATION_EVENT: &str = "UserOperationEvent(bytes32,address,address,uint256,bool,uint256,uint256)";
+
+/// Upper bound on the blocks scanned per `eth_getLogs` call
+const MAX_BLOCK_RANGE: u64 = 1_000;
+
+/// A decoded `UserOperationEvent` log
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserOperationEvent {
+    pub user_op_hash: H256,
+    pub sender: Address,
+    pub paymaster: Address,
+    pub nonce: U256,
+    pub success: bool,
+    pub actual_gas_cost: U256,
+    pub actual_gas_used: U256,
+}
+
+impl UserOperationEvent {
+    /// Decode a log, returning `None` for any other event
+    pub fn from_log(log: &Log) -> Option<Self> {
+        if log.topics.len() != 4 || log.topics[0] != H256(keccak256(USER_OPERATION_EVENT)) {
+            return None;
+        }
+
+        let tokens = ethers::abi::decode(
+            &[
+                ethers::abi::ParamType::Uint(256),
+                ethers::abi::ParamType::Bool,
+                ethers::abi::ParamType::Uint(256),
+                ethers::abi::ParamType::Uint(256),
+            ],
+            &log.data,
+        ).ok()?;
+
+        Some(Self {
+            user_op_hash: log.topics[1],
+            sender: Address::from(log.topics[2]),
+            paymaster: Address::from(log.topics[3]),
+            nonce: tokens[0].clone().into_uint()?,
+            success: tokens[1].clone().into_bool()?,
+            actual_gas_cost: tokens[2].clone().into_uint()?,
+            actual_gas_used: tokens[3].clone().into_uint()?,
+        })
+    }
+}
+
+/// Settles reserved policy budgets against on-chain `UserOperationEvent`s
+///
+/// Polls a chain's EntryPoints for events naming its paymaster and settles each
+/// operation to its actual gas cost. Reservations whose signature expired before
+/// the last scanned block are released, so none is released while its event may
+/// still be unscanned. One watcher runs per chain; the scanned block is kept in
+/// the counter store, so a restarted watcher carries on where it stopped.
+pub struct SettlementWatcher {
+    chain_id: u64,
+    provider: Arc<Provider<Http>>,
+    policy_engine: Arc<PolicyEngine>,
+    entry_points: Vec<Address>,
+    paymaster: Address,
+    poll_interval: Duration,
+    next_block: Option<u64>,
+}
+
+impl SettlementWatcher {
+    pub fn new(chain: &Chain, policy_engine: Arc<PolicyEngine>, poll_interval: Duration) -> Self {
+        Self {
+            chain_id: chain.chain_id,
+            provider: chain.provider.clone(),
+            policy_engine,
+            entry_points: chain.entry_points.keys().copied().collect(),
+            paymaster: chain.paymaster_address,
+            poll_interval,
+            next_block: None,
+        }
+    }
+
+    /// Poll until the task is dropped
+    pub async fn run(mut self) {
+        info!("Settling sponsored operations on chain {} every {:?}", self.chain_id, self.poll_interval);
+        let mut interval = tokio::time::interval(self.poll_interval);
+
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.poll().await {
+                warn!("Settlement poll on chain {} failed: {}", self.chain_id, e);
+            }
+        }
+    }
+
+    /// Settle operations mined in the next unscanned blocks, then release the reservations
+    /// that expired before the last of them
+    pub async fn poll(&mut self) -> PaymasterResult<()> {
+        let latest = self.provider.get_block_number().await
+            .map_err(|e| PaymasterError::BlockchainError(e.to_string()))?
+            .as_u64();
+
+        // Without a recorded block start at the chain head; nothing signed before is held
+        let from = match self.next_block {
+            Some(block) => block,
+            None => self.policy_engine.settlement_cursor(self.chain_id).await?.unwrap_or(latest),
+        };
+        if from > latest {
+            return Ok(());
+        }
+
+        let to = latest.min(from + MAX_BLOCK_RANGE - 1);
+        let filter = Filter::new()
+            .address(self.entry_points.clone())
+            .topic0(H256(keccak256(USER_OPERATION_EVENT)))
+            .topic3(H256::from(self.paymaster))
+            .from_block(from)
+            .to_block(to);
+
+        let logs = self.provider.get_logs(&filter).await
+            .map_err(|e| PaymasterError::BlockchainError(e.to_string()))?;
+        let scanned_until = self.provider.get_block(to).await
+            .map_err(|e| PaymasterError::BlockchainError(e.to_string()))?
+            .ok_or_else(|| PaymasterError::BlockchainError(format!("Block {} not found", to)))?
+            .timestamp
+            .as_u64();
+
+        for event in logs.iter().filter_map(UserOperationEvent::from_log) {
+            self.policy_engine.settle(self.chain_id, event.user_op_hash, event.actual_gas_cost).await;
+        }
+        self.next_block = Some(to + 1);
+        self.policy_engine.record_settlement_cursor(self.chain_id, to + 1).await?;
+        self.policy_engine.release_expired(self.chain_id, scanned_until).await;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::core::chain_registry::ChainRegistry;
+    use crate::core::counter_store::{CounterStore, MemoryCounterStore};
+    use crate::core::user_operation::ENTRY_POINT_V07;
+    use crate::core::{Clock, RateLimitType, SystemClock};
+    use crate::test_support::*;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+    use std::sync::Mutex;
+
+    /// A chain at block 5000 whose blocks are timestamped with their number and hold no events;
+    /// records the block ranges searched for logs
+    async fn mock_node(searched: Arc<Mutex<Vec<(u64, u64)>>>) -> String {
+        let router = Router::new().route("/", post(move |Json(request): Json<Value>| async move {
+            let block = |value: &Value| u64::from_str_radix(value.as_str().unwrap().trim_start_matches("0x"), 16).unwrap();
+            let result = match request["method"].as_str().unwrap() {
+                "eth_blockNumber" => json!("0x1388"),
+                "eth_getLogs" => {
+                    let filter = &request["params"][0];
+                    searched.lock().unwrap().push((block(&filter["fromBlock"]), block(&filter["toBlock"])));
+                    json!([])
+                }
+                "eth_getBlockByNumber" => {
+                    let number = block(&request["params"][0]);
+                    json!(Block::<H256> { number: Some(number.into()), timestamp: number.into(), ..Block::default() })
+                }
+                method => panic!("unexpected {}", method),
+            };
+            Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+        }));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn releases_only_what_expired_before_the_scanned_blocks() {
+        let searched = Arc::new(Mutex::new(Vec::new()));
+        let mut settings = settings();
+        settings.blockchain.chains[0].rpc_url = mock_node(searched.clone()).await;
+        let registry = ChainRegistry::new(&settings).unwrap();
+        let counters = Arc::new(MemoryCounterStore::new());
+        let engine = Arc::new(PolicyEngine::with_store(counters.clone(), Arc::new(SystemClock), &Settings::default().policy));
+        engine.add_policy(wallet_policy(RateLimitType::Request, "10")).await.unwrap();
+        let key = format!("request_limit:wallet_budget:{}:{}", SENDER, SystemClock.now_seconds() / 3600);
+
+        // Valid until 1000, so due for release at 1120, long before now
+        let reservation = engine.check_policies(&request("0x186a0")).await.unwrap();
+        engine.hold(1, H256::repeat_byte(1), reservation, 1_000).await;
+        // An earlier watcher stopped after block 99
+        engine.record_settlement_cursor(1, 100).await.unwrap();
+
+        let mut watcher = SettlementWatcher::new(registry.get(1).unwrap(), engine.clone(), Duration::from_secs(1));
+        watcher.poll().await.unwrap();
+        assert_eq!(counters.get(&key).await.unwrap(), 1);
+        watcher.poll().await.unwrap();
+        assert_eq!(counters.get(&key).await.unwrap(), 0);
+
+        let mut restarted = SettlementWatcher::new(registry.get(1).unwrap(), engine.clone(), Duration::from_secs(1));
+        restarted.poll().await.unwrap();
+        assert_eq!(*searched.lock().unwrap(), vec![(100, 1_099), (1_100, 2_099), (2_100, 3_099)]);
+    }
+
+    #[test]
+    fn decodes_user_operation_event() {
+        let user_op_hash = H256::repeat_byte(0xab);
+        let sender: Address = "0x1306b01bc3e4ad202612d3843387e94737673f53".parse().unwrap();
+        let paymaster: Address = PAYMASTER.parse().unwrap();
+        let log = Log {
+            address: ENTRY_POINT_V07.parse().unwrap(),
+            topics: vec![
+                H256(keccak256(USER_OPERATION_EVENT)),
+                user_op_hash,
+                H256::from(sender),
+                H256::from(paymaster),
+            ],
+            data: ethers::abi::encode(&[
+                ethers::abi::Token::Uint(7.into()),
+                ethers::abi::Token::Bool(true),
+                ethers::abi::Token::Uint(123_456_789u64.into()),
+                ethers::abi::Token::Uint(98_765u64.into()),
+            ]).into(),
+            ..Default::default()
+        };
+
+        let event = UserOperationEvent::from_log(&log).unwrap();
+        assert_eq!(event.user_op_hash, user_op_hash);
+        assert_eq!(event.sender, sender);
+        assert_eq!(event.paymaster, paymaster);
+        assert_eq!(event.nonce, 7.into());
+        assert!(event.success);
+        assert_eq!(event.actual_gas_cost, 123_456_789u64.into());
+        assert_eq!(event.actual_gas_used, 98_765u64.into());
+
+        let mut other = log.clone();
+        other.topics[0] = H256::zero();
+        assert!(UserOperationEvent::from_log(&other).is_none());
+    }
+}
@@ -0,0 +1,757 @@
+use crate::core::types::*;
+use crate::core::user_operation::*;
+use ethers::abi::{self, ParamType, Token};
+use ethers::providers::{call_raw::spoof, Http, Middleware, Provider, ProviderError, RawCall, RpcError};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::{Address, Bytes, TransactionRequest, U256};
+use ethers::utils::id;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// Calldata and per-operation overheads of a bundle, as modelled by the reference bundler
+const FIXED_GAS: u64 = 21_000;
+const PER_USER_OP_GAS: u64 = 18_300;
+const PER_USER_OP_WORD_GAS: u64 = 4;
+const ZERO_BYTE_GAS: u64 = 4;
+const NON_ZERO_BYTE_GAS: u64 = 16;
+const BUNDLE_SIZE: u64 = 1;
+/// Signature length assumed for operations that are not signed yet
+const SIGNATURE_SIZE: usize = 65;
+
+/// Verification gas available to `simulateValidation`, far above any real validation
+const SIMULATION_VERIFICATION_GAS: u64 = 5_000_000;
+/// Headroom added to the measured validation gas
+const VERIFICATION_GAS_MARGIN_PERCENT: u64 = 10;
+/// The call gas search stops once the bracket is this narrow
+const CALL_GAS_PRECISION: u64 = 1_000;
+
+/// Well-formed placeholder signature; recovers to an unrelated address
+pub const DUMMY_SIGNATURE: &str = "0xfffffffffffffffffffffffffffffff0000000000000000000000000000000007aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa1c";
+
+/// Gas limits measured for an operation; `None` where it could not be simulated
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatedGas {
+    pub pre_verification_gas: U256,
+    pub verification_gas_limit: Option<U256>,
+    pub call_gas_limit: Option<U256>,
+}
+
+/// Estimates UserOperation gas limits by simulating them against the EntryPoint
+///
+/// v0.6 EntryPoints expose `simulateValidation` and `simulateHandleOp` themselves. From v0.7
+/// these live in `EntryPointSimulations`, whose code is read from a configured deployment and
+/// placed at the EntryPoint address through an `eth_call` state override.
+pub struct UserOperationSimulator {
+    provider: Arc<Provider<Http>>,
+    simulations: HashMap<EntryPointVersion, Address>, // EntryPointSimulations deployments, v0.7 and later
+    simulations_code: Mutex<HashMap<EntryPointVersion, Bytes>>,
+    max_call_gas_limit: u64,
+}
+
+/// What `simulateValidation` and `simulateHandleOp` report
+enum SimulationOutcome {
+    Validation { pre_op_gas: U256 },
+    Execution { pre_op_gas: U256, paid: U256, target_success: bool, target_result: Bytes },
+}
+
+impl UserOperationSimulator {
+    pub fn new(provider: Arc<Provider<Http>>, simulations: HashMap<EntryPointVersion, Address>, max_call_gas_limit: u64) -> Self {
+        Self {
+            provider,
+            simulations,
+            simulations_code: Mutex::new(HashMap::new()),
+            max_call_gas_limit,
+        }
+    }
+
+    /// Estimate the gas limits of an operation that carries its final paymaster fields
+    ///
+    /// `verificationGasLimit` is the gas `simulateValidation` reports plus a margin. `callGasLimit`
+    /// comes from `eth_estimateGas` of the call from the EntryPoint when the sender is deployed,
+    /// and otherwise from a binary search over `simulateHandleOp`, which deploys it first. So do
+    /// `executeUserOp` operations, whose call data the EntryPoint does not make the call with.
+    pub async fn estimate(
+        &self,
+        user_op: &VersionedUserOperation,
+        entry_point: Address,
+        version: EntryPointVersion,
+    ) -> PaymasterResult<SimulatedGas> {
+        let pre_verification_gas = pre_verification_gas(user_op)?;
+        if version != EntryPointVersion::V06 && !self.simulations.contains_key(&version) {
+            warn!("No EntryPointSimulations configured for {:?}; keeping the client's gas limits where simulation is needed", version);
+        }
+
+        // Without a gas price nothing is charged, so validation cannot fail on the prefund
+        let validation_op = with_gas(user_op, U256::zero(), SIMULATION_VERIFICATION_GAS.into(), None, U256::zero());
+        let verification_gas_limit = match self.simulate(&validation_op, entry_point, version, false).await? {
+            Some(SimulationOutcome::Validation { pre_op_gas }) => {
+                Some(pre_op_gas * (100 + VERIFICATION_GAS_MARGIN_PERCENT) / 100)
+            }
+            _ => None,
+        };
+
+        let deploys_sender = user_op.has_init_code() || user_op.eip7702_auth().is_some();
+        let call_gas_limit = if deploys_sender || executes_user_op(user_op, version)? {
+            let verification_gas = verification_gas_limit.unwrap_or(SIMULATION_VERIFICATION_GAS.into());
+            self.search_call_gas_limit(user_op, entry_point, version, verification_gas).await?
+        } else {
+            Some(self.estimate_call_gas(user_op, entry_point).await?)
+        };
+
+        info!(
+            "Estimated gas for {}: preVerificationGas {}, verificationGasLimit {:?}, callGasLimit {:?}",
+            user_op.sender(), pre_verification_gas, verification_gas_limit, call_gas_limit
+        );
+        Ok(SimulatedGas { pre_verification_gas, verification_gas_limit, call_gas_limit })
+    }
+
+    /// Gas of the sender's call as the EntryPoint makes it
+    ///
+    /// `eth_estimateGas` prices a whole transaction, so its intrinsic and calldata gas, which the
+    /// EntryPoint's call does not pay, are taken off.
+    async fn estimate_call_gas(&self, user_op: &VersionedUserOperation, entry_point: Address) -> PaymasterResult<U256> {
+        let call_data = parse_hex_bytes("call data", user_op.call_data())?;
+        let call: TypedTransaction = TransactionRequest::new()
+            .from(entry_point)
+            .to(user_op.sender_address()?)
+            .data(call_data.clone())
+            .into();
+
+        let gas = self.provider.estimate_gas(&call, None).await.map_err(|e| {
+            let reverted = e.as_error_response()
+                .is_some_and(|response| response.as_revert_data().is_some() || response.message.contains("revert"));
+            if reverted {
+                PaymasterError::InvalidUserOperation(format!("Call data reverts: {}", e))
+            } else {
+                PaymasterError::BlockchainError(format!("Failed to estimate the call's gas: {}", e))
+            }
+        })?;
+        Ok(gas.saturating_sub((FIXED_GAS + calldata_gas(&call_data)).into()))
+    }
+
+    /// Smallest call gas limit, to within `CALL_GAS_PRECISION`, whose execution does not run out
+    ///
+    /// With a gas price of 1 wei `paid` is the gas charged, so `paid - preOpGas` is the execution
+    /// gas. An execution that ran out of gas used all of its limit; one that fit used less, its
+    /// unused-gas penalty included. The EntryPoint's own execution overhead counts against the
+    /// limit, so the result errs high by that much.
+    ///
+    /// The EntryPoint swallows a reverting call, so `simulateHandleOp` repeats it as its target
+    /// call with all remaining gas; a call that reverts there reverts for a reason other than gas.
+    async fn search_call_gas_limit(
+        &self,
+        user_op: &VersionedUserOperation,
+        entry_point: Address,
+        version: EntryPointVersion,
+        verification_gas: U256,
+    ) -> PaymasterResult<Option<U256>> {
+        let post_op_gas = parse_quantity("paymaster post op gas limit", user_op.paymaster_gas_limits().1.unwrap_or("0"))?;
+        let execute = |call_gas: u64| {
+            let op = with_gas(user_op, U256::zero(), verification_gas, Some(call_gas.into()), U256::one());
+            async move {
+                match self.simulate(&op, entry_point, version, true).await? {
+                    Some(SimulationOutcome::Execution { pre_op_gas, paid, target_success, target_result }) => {
+                        let fits = paid.saturating_sub(pre_op_gas) < U256::from(call_gas) + post_op_gas;
+                        Ok(Some((fits, target_success, target_result)))
+                    }
+                    _ => Ok::<_, PaymasterError>(None),
+                }
+            }
+        };
+
+        let (mut low, mut high) = (0, self.max_call_gas_limit);
+        match execute(high).await? {
+            None => return Ok(None),
+            Some((_, false, reason)) => {
+                return Err(PaymasterError::InvalidUserOperation(format!("Call data reverts: {}", reason)));
+            }
+            Some((false, _, _)) => {
+                return Err(PaymasterError::InvalidUserOperation(format!(
+                    "Call runs out of gas within the {} gas cap", self.max_call_gas_limit
+                )));
+            }
+            Some((true, _, _)) => {}
+        }
+        while high - low > CALL_GAS_PRECISION {
+            let middle = low + (high - low) / 2;
+            if matches!(execute(middle).await?, Some((true, _, _))) {
+                high = middle;
+            } else {
+                low = middle;
+            }
+        }
+        Ok(Some(high.into()))
+    }
+
+    /// Run `simulateValidation`, or `simulateHandleOp` when `execute` is set
+    ///
+    /// Returns `None` when the EntryPoint version has no simulation code configured.
+    async fn simulate(
+        &self,
+        user_op: &VersionedUserOperation,
+        entry_point: Address,
+        version: EntryPointVersion,
+        execute: bool,
+    ) -> PaymasterResult<Option<SimulationOutcome>> {
+        let op = Token::Tuple(user_op_tokens(user_op)?);
+        let (signature, arguments) = match (version, execute) {
+            (EntryPointVersion::V06, false) => (SIMULATE_VALIDATION_V06, vec![op]),
+            (EntryPointVersion::V06, true) => (SIMULATE_HANDLE_OP_V06, handle_op_arguments(op, user_op, version)?),
+            (_, false) => (SIMULATE_VALIDATION_V07, vec![op]),
+            (_, true) => (SIMULATE_HANDLE_OP_V07, handle_op_arguments(op, user_op, version)?),
+        };
+        let mut data = id(signature).to_vec();
+        data.extend(abi::encode(&arguments));
+
+        let mut state = spoof::state();
+        if version != EntryPointVersion::V06 {
+            let Some(code) = self.simulations_code(version).await? else {
+                return Ok(None);
+            };
+            state.account(entry_point).code(code);
+        }
+        if let Some(auth) = user_op.eip7702_auth() {
+            let mut delegation = EIP7702_DELEGATION_PREFIX.to_vec();
+            delegation.extend_from_slice(auth.delegate()?.as_bytes());
+            state.account(user_op.sender_address()?).code(delegation.into());
+        }
+
+        let call: TypedTransaction = TransactionRequest::new().to(entry_point).data(data).into();
+        let result = if version == EntryPointVersion::V06 {
+            self.provider.call(&call, None).await
+        } else {
+            self.provider.call_raw(&call).state(&state).await
+        };
+
+        match (version, result) {
+            // v0.6 reports its results by reverting
+            (EntryPointVersion::V06, Err(e)) => decode_v06_revert(&revert_data(e)?).map(Some),
+            (EntryPointVersion::V06, Ok(_)) => Err(PaymasterError::BlockchainError(
+                "EntryPoint simulation returned instead of reverting".to_string()
+            )),
+            (_, Ok(output)) => decode_v07_output(&output, execute).map(Some),
+            (_, Err(e)) => Err(failed_op(&revert_data(e)?)),
+        }
+    }
+
+    /// Runtime code of the version's EntryPointSimulations, read once from its deployment
+    async fn simulations_code(&self, version: EntryPointVersion) -> PaymasterResult<Option<Bytes>> {
+        let Some(&address) = self.simulations.get(&version) else {
+            return Ok(None);
+        };
+        if let Some(code) = self.simulations_code.lock().unwrap().get(&version) {
+            return Ok(Some(code.clone()));
+        }
+
+        let code = self.provider.get_code(address, None).await
+            .map_err(|e| PaymasterError::BlockchainError(format!("Failed to read EntryPointSimulations code: {}", e)))?;
+        if code.is_empty() {
+            return Err(PaymasterError::ConfigurationError(
+                format!("No EntryPointSimulations deployed at {:?}", address)
+            ));
+        }
+        self.simulations_code.lock().unwrap().insert(version, code.clone());
+        Ok(Some(code))
+    }
+}
+
+const SIMULATE_VALIDATION_V06: &str =
+    "simulateValidation((address,uint256,bytes,bytes,uint256,uint256,uint256,uint256,uint256,bytes,bytes))";
+const SIMULATE_HANDLE_OP_V06: &str =
+    "simulateHandleOp((address,uint256,bytes,bytes,uint256,uint256,uint256,uint256,uint256,bytes,bytes),address,bytes)";
+const SIMULATE_VALIDATION_V07: &str =
+    "simulateValidation((address,uint256,bytes,bytes,bytes32,uint256,bytes32,bytes,bytes))";
+const SIMULATE_HANDLE_OP_V07: &str =
+    "simulateHandleOp((address,uint256,bytes,bytes,bytes32,uint256,bytes32,bytes,bytes),address,bytes)";
+/// Account function the v0.7 EntryPoint calls with the whole operation instead of its call data
+const EXECUTE_USER_OP: &str =
+    "executeUserOp((address,uint256,bytes,bytes,bytes32,uint256,bytes32,bytes,bytes),bytes32)";
+
+const VALIDATION_RESULT_V06: &str =
+    "ValidationResult((uint256,uint256,bool,uint48,uint48,bytes),(uint256,uint256),(uint256,uint256),(uint256,uint256))";
+const VALIDATION_RESULT_WITH_AGGREGATION_V06: &str =
+    "ValidationResultWithAggregation((uint256,uint256,bool,uint48,uint48,bytes),(uint256,uint256),(uint256,uint256),(uint256,uint256),(address,(uint256,uint256)))";
+const EXECUTION_RESULT_V06: &str = "ExecutionResult(uint256,uint256,uint48,uint48,bool,bytes)";
+const FAILED_OP: &str = "FailedOp(uint256,string)";
+const FAILED_OP_WITH_REVERT: &str = "FailedOpWithRevert(uint256,string,bytes)";
+
+/// `preVerificationGas` covering the operation's share of the bundle transaction
+///
+/// Charges the calldata cost of the ABI-encoded operation, the fixed transaction cost spread
+/// over the bundle, and the per-operation overhead of `handleOps`.
+pub fn pre_verification_gas(user_op: &VersionedUserOperation) -> PaymasterResult<U256> {
+    let mut tokens = signed_user_op_tokens(user_op)?;
+    let index = pre_verification_gas_index(user_op);
+    if is_empty(&tokens[index]) {
+        tokens[index] = Token::Uint(FIXED_GAS.into());
+    }
+
+    let packed = abi::encode(&tokens);
+    let words = packed.len().div_ceil(32) as u64;
+    Ok((calldata_gas(&packed) + FIXED_GAS / BUNDLE_SIZE + PER_USER_OP_GAS + PER_USER_OP_WORD_GAS * words).into())
+}
+
+/// The operation's fields in the order the EntryPoint's struct declares them
+pub fn user_op_tokens(user_op: &VersionedUserOperation) -> PaymasterResult<Vec<Token>> {
+    Ok(match user_op {
+        VersionedUserOperation::V06(op) => vec![
+            Token::Address(op.sender_address()?),
+            Token::Uint(parse_quantity("nonce", &op.nonce)?),
+            Token::Bytes(parse_hex_bytes("init code", &op.init_code)?.to_vec()),
+            Token::Bytes(parse_hex_bytes("call data", &op.call_data)?.to_vec()),
+            Token::Uint(parse_quantity("call gas limit", &op.call_gas_limit)?),
+            Token::Uint(parse_quantity("verification gas limit", &op.verification_gas_limit)?),
+            Token::Uint(parse_quantity("pre verification gas", &op.pre_verification_gas)?),
+            Token::Uint(parse_quantity("max fee per gas", &op.max_fee_per_gas)?),
+            Token::Uint(parse_quantity("max priority fee per gas", &op.max_priority_fee_per_gas)?),
+            Token::Bytes(parse_hex_bytes("paymaster and data", &op.paymaster_and_data)?.to_vec()),
+            Token::Bytes(parse_hex_bytes("signature", &op.signature)?.to_vec()),
+        ],
+        VersionedUserOperation::V07(op) => {
+            let packed = op.pack()?;
+            vec![
+                Token::Address(packed.sender),
+                Token::Uint(packed.nonce),
+                Token::Bytes(packed.init_code.to_vec()),
+                Token::Bytes(packed.call_data.to_vec()),
+                Token::FixedBytes(packed.account_gas_limits.as_bytes().to_vec()),
+                Token::Uint(packed.pre_verification_gas),
+                Token::FixedBytes(packed.gas_fees.as_bytes().to_vec()),
+                Token::Bytes(packed.paymaster_and_data.to_vec()),
+                Token::Bytes(packed.signature.to_vec()),
+            ]
+        }
+    })
+}
+
+/// `user_op_tokens` with a `SIGNATURE_SIZE`-byte non-zero placeholder for a missing signature
+pub fn signed_user_op_tokens(user_op: &VersionedUserOperation) -> PaymasterResult<Vec<Token>> {
+    let mut tokens = user_op_tokens(user_op)?;
+    let signature = tokens.len() - 1;
+    if is_empty(&tokens[signature]) {
+        tokens[signature] = Token::Bytes(vec![1; SIGNATURE_SIZE]);
+    }
+    Ok(tokens)
+}
+
+fn calldata_gas(data: &[u8]) -> u64 {
+    data.iter().map(|byte| if *byte == 0 { ZERO_BYTE_GAS } else { NON_ZERO_BYTE_GAS }).sum()
+}
+
+/// Whether the EntryPoint runs the operation through the sender's `executeUserOp`
+fn executes_user_op(user_op: &VersionedUserOperation, version: EntryPointVersion) -> PaymasterResult<bool> {
+    Ok(version != EntryPointVersion::V06 && parse_hex_bytes("call data", user_op.call_data())?.starts_with(&id(EXECUTE_USER_OP)))
+}
+
+fn pre_verification_gas_index(user_op: &VersionedUserOperation) -> usize {
+    match user_op {
+        VersionedUserOperation::V06(_) => 6,
+        VersionedUserOperation::V07(_) => 5,
+    }
+}
+
+fn is_empty(token: &Token) -> bool {
+    match token {
+        Token::Uint(value) => value.is_zero(),
+        Token::Bytes(bytes) => bytes.is_empty(),
+        _ => false,
+    }
+}
+
+/// `simulateHandleOp` arguments whose target call repeats the operation's call from the EntryPoint
+///
+/// An `executeUserOp` call cannot be repeated this way, so those operations get no target call.
+fn handle_op_arguments(op: Token, user_op: &VersionedUserOperation, version: EntryPointVersion) -> PaymasterResult<Vec<Token>> {
+    if executes_user_op(user_op, version)? {
+        return Ok(vec![op, Token::Address(Address::zero()), Token::Bytes(vec![])]);
+    }
+    Ok(vec![
+        op,
+        Token::Address(user_op.sender_address()?),
+        Token::Bytes(parse_hex_bytes("call data", user_op.call_data())?.to_vec()),
+    ])
+}
+
+/// The operation with gas fields replaced for a simulation; `call_gas` keeps the client's when unset
+///
+/// Both fee fields take `gas_price`, which the EntryPoint then charges regardless of the base fee.
+fn with_gas(
+    user_op: &VersionedUserOperation,
+    pre_verification_gas: U256,
+    verification_gas: U256,
+    call_gas: Option<U256>,
+    gas_price: U256,
+) -> VersionedUserOperation {
+    let quantity = |value: U256| format!("{:#x}", value);
+    match user_op {
+        VersionedUserOperation::V06(op) => {
+            let mut op = op.clone();
+            op.pre_verification_gas = quantity(pre_verification_gas);
+            op.verification_gas_limit = quantity(verification_gas);
+            op.call_gas_limit = call_gas.map(quantity).unwrap_or(op.call_gas_limit);
+            op.max_fee_per_gas = quantity(gas_price);
+            op.max_priority_fee_per_gas = quantity(gas_price);
+            VersionedUserOperation::V06(op)
+        }
+        VersionedUserOperation::V07(op) => {
+            let mut op = op.clone();
+            op.pre_verification_gas = quantity(pre_verification_gas);
+            op.verification_gas_limit = quantity(verification_gas);
+            op.call_gas_limit = call_gas.map(quantity).unwrap_or(op.call_gas_limit);
+            op.max_fee_per_gas = quantity(gas_price);
+            op.max_priority_fee_per_gas = quantity(gas_price);
+            VersionedUserOperation::V07(op)
+        }
+    }
+}
+
+fn revert_data(error: ProviderError) -> PaymasterResult<Bytes> {
+    error.as_error_response()
+        .and_then(|response| response.as_revert_data())
+        .filter(|data| data.len() >= 4)
+        .ok_or_else(|| PaymasterError::BlockchainError(format!("EntryPoint simulation failed: {}", error)))
+}
+
+fn decode_v06_revert(data: &Bytes) -> PaymasterResult<SimulationOutcome> {
+    let (selector, arguments) = data.split_at(4);
+    let return_info = ParamType::Tuple(vec![
+        ParamType::Uint(256), ParamType::Uint(256), ParamType::Bool, ParamType::Uint(48), ParamType::Uint(48), ParamType::Bytes,
+    ]);
+
+    if selector == id(VALIDATION_RESULT_V06) || selector == id(VALIDATION_RESULT_WITH_AGGREGATION_V06) {
+        let decoded = decode(&[return_info], arguments)?;
+        Ok(SimulationOutcome::Validation { pre_op_gas: uint_field(&decoded[0], 0)? })
+    } else if selector == id(EXECUTION_RESULT_V06) {
+        let decoded = decode(&[
+            ParamType::Uint(256), ParamType::Uint(256), ParamType::Uint(48), ParamType::Uint(48), ParamType::Bool, ParamType::Bytes,
+        ], arguments)?;
+        execution_result(&Token::Tuple(decoded))
+    } else {
+        Err(failed_op(data))
+    }
+}
+
+fn decode_v07_output(output: &Bytes, executed: bool) -> PaymasterResult<SimulationOutcome> {
+    let uint = ParamType::Uint(256);
+    let stake_info = ParamType::Tuple(vec![uint.clone(), uint.clone()]);
+    if executed {
+        let result = ParamType::Tuple(vec![
+            uint.clone(), uint.clone(), uint.clone(), uint.clone(), ParamType::Bool, ParamType::Bytes,
+        ]);
+        let decoded = decode(&[result], output)?;
+        return execution_result(&decoded[0]);
+    }
+
+    let return_info = ParamType::Tuple(vec![uint.clone(), uint.clone(), uint.clone(), uint.clone(), ParamType::Bytes]);
+    let aggregator_info = ParamType::Tuple(vec![ParamType::Address, stake_info.clone()]);
+    let result = ParamType::Tuple(vec![return_info, stake_info.clone(), stake_info.clone(), stake_info, aggregator_info]);
+    let decoded = decode(&[result], output)?;
+    let return_info = decoded[0].clone().into_tuple().unwrap_or_default();
+    Ok(SimulationOutcome::Validation { pre_op_gas: uint_field(&return_info[0], 0)? })
+}
+
+/// `ExecutionResult`, whose fields are laid out alike in every version
+fn execution_result(result: &Token) -> PaymasterResult<SimulationOutcome> {
+    let fields = result.clone().into_tuple().unwrap_or_default();
+    match (fields.get(4).cloned().and_then(Token::into_bool), fields.get(5).cloned().and_then(Token::into_bytes)) {
+        (Some(target_success), Some(target_result)) => Ok(SimulationOutcome::Execution {
+            pre_op_gas: uint_field(result, 0)?,
+            paid: uint_field(result, 1)?,
+            target_success,
+            target_result: target_result.into(),
+        }),
+        _ => Err(PaymasterError::BlockchainError("Unexpected EntryPoint simulation result".to_string())),
+    }
+}
+
+/// The reason an operation failed simulation, e.g. "AA25 invalid account nonce"
+fn failed_op(data: &Bytes) -> PaymasterError {
+    let (selector, arguments) = data.split_at(4.min(data.len()));
+    let reason = if selector == id(FAILED_OP) || selector == id(FAILED_OP_WITH_REVERT) {
+        abi::decode(&[ParamType::Uint(256), ParamType::String], arguments).ok()
+            .and_then(|decoded| decoded[1].clone().into_string())
+    } else {
+        None
+    };
+
+    match reason {
+        Some(reason) => PaymasterError::InvalidUserOperation(format!("User operation failed simulation: {}", reason)),
+        None => PaymasterError::BlockchainError(format!("EntryPoint simulation reverted: {}", data)),
+    }
+}
+
+fn decode(types: &[ParamType], data: &[u8]) -> PaymasterResult<Vec<Token>> {
+    abi::decode(types, data)
+        .map_err(|e| PaymasterError::BlockchainError(format!("Unexpected EntryPoint simulation result: {}", e)))
+}
+
+fn uint_field(tuple: &Token, index: usize) -> PaymasterResult<U256> {
+    tuple.clone().into_tuple()
+        .and_then(|fields| fields.get(index).cloned())
+        .and_then(Token::into_uint)
+        .ok_or_else(|| PaymasterError::BlockchainError("Unexpected EntryPoint simulation result".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+
+    const PRE_OP_GAS: u64 = 100_000;
+    /// Call data the mock accounts revert on
+    const REVERTING_CALL: &str = "0x0badca11";
+    /// Code of the mock EntryPointSimulations deployment
+    const SIMULATIONS_CODE: &str = "0x6001";
+
+    fn user_op(init_code: &str, nonce: &str) -> VersionedUserOperation {
+        VersionedUserOperation::V06(UserOperation {
+            sender: "0x1306b01bc3e4ad202612d3843387e94737673f53".to_string(),
+            nonce: nonce.to_string(),
+            init_code: init_code.to_string(),
+            call_data: "0xb61d27f6".to_string(),
+            call_gas_limit: "100000".to_string(),
+            verification_gas_limit: "150000".to_string(),
+            pre_verification_gas: "21000".to_string(),
+            max_fee_per_gas: "20000000000".to_string(),
+            max_priority_fee_per_gas: "1000000000".to_string(),
+            paymaster_and_data: "0x".to_string(),
+            signature: "0x".to_string(),
+        })
+    }
+
+    fn revert(signature: &str, arguments: &[Token]) -> Value {
+        let mut data = id(signature).to_vec();
+        data.extend(abi::encode(arguments));
+        json!({ "code": 3, "message": "execution reverted", "data": Bytes::from(data) })
+    }
+
+    fn user_op_v07(factory: Option<&str>, call_data: &str) -> VersionedUserOperation {
+        let VersionedUserOperation::V06(op) = user_op("0x", "0") else { unreachable!() };
+        VersionedUserOperation::V07(UserOperationV07 {
+            sender: op.sender,
+            nonce: op.nonce,
+            factory: factory.map(str::to_string),
+            factory_data: factory.map(|_| "0x5fbfb9cf".to_string()),
+            call_data: call_data.to_string(),
+            call_gas_limit: op.call_gas_limit,
+            verification_gas_limit: op.verification_gas_limit,
+            pre_verification_gas: op.pre_verification_gas,
+            max_fee_per_gas: op.max_fee_per_gas,
+            max_priority_fee_per_gas: op.max_priority_fee_per_gas,
+            paymaster: None,
+            paymaster_verification_gas_limit: None,
+            paymaster_post_op_gas_limit: None,
+            paymaster_data: None,
+            signature: op.signature,
+            eip7702_auth: None,
+        })
+    }
+
+    /// Gas the mock call uses under `call_gas`: 50k, plus 3k of EntryPoint overhead
+    fn execution_gas(call_gas: u64) -> u64 {
+        if call_gas <= 50_000 { call_gas + 3_000 } else { 53_000 }
+    }
+
+    /// Whether the mock target call succeeds, and what it returns
+    fn target_call(arguments: &[u8], op_type: ParamType) -> (Token, Token) {
+        let decoded = abi::decode(&[op_type, ParamType::Address, ParamType::Bytes], arguments).unwrap();
+        let reverts = decoded[2].clone().into_bytes().unwrap() == REVERTING_CALL.parse::<Bytes>().unwrap().to_vec();
+        let result = if reverts { id("Error(string)").to_vec() } else { vec![] };
+        (Token::Bool(!reverts), Token::Bytes(result))
+    }
+
+    /// A v0.6 EntryPoint whose validation uses `PRE_OP_GAS` and whose call needs `execution_gas`;
+    /// operations with nonce 0xbad fail validation
+    fn simulate_v06(data: &Bytes) -> Value {
+        let (selector, arguments) = data.split_at(4);
+        let op_type = ParamType::Tuple(vec![
+            ParamType::Address, ParamType::Uint(256), ParamType::Bytes, ParamType::Bytes, ParamType::Uint(256),
+            ParamType::Uint(256), ParamType::Uint(256), ParamType::Uint(256), ParamType::Uint(256), ParamType::Bytes, ParamType::Bytes,
+        ]);
+        let op = abi::decode(std::slice::from_ref(&op_type), arguments).unwrap()[0].clone().into_tuple().unwrap();
+        if op[1].clone().into_uint().unwrap() == U256::from(0xbad) {
+            return revert(FAILED_OP, &[Token::Uint(0.into()), Token::String("AA25 invalid account nonce".to_string())]);
+        }
+
+        let stake_info = Token::Tuple(vec![Token::Uint(0.into()), Token::Uint(0.into())]);
+        if selector == id(SIMULATE_VALIDATION_V06) {
+            let return_info = Token::Tuple(vec![
+                Token::Uint(PRE_OP_GAS.into()), Token::Uint(0.into()), Token::Bool(true),
+                Token::Uint(0.into()), Token::Uint(0.into()), Token::Bytes(vec![]),
+            ]);
+            return revert(VALIDATION_RESULT_V06, &[return_info, stake_info.clone(), stake_info.clone(), stake_info]);
+        }
+
+        let call_gas = op[4].clone().into_uint().unwrap().as_u64();
+        let (target_success, target_result) = target_call(arguments, op_type);
+        revert(EXECUTION_RESULT_V06, &[
+            Token::Uint(PRE_OP_GAS.into()), Token::Uint((PRE_OP_GAS + execution_gas(call_gas)).into()),
+            Token::Uint(0.into()), Token::Uint(0.into()), target_success, target_result,
+        ])
+    }
+
+    /// The same EntryPoint as `simulate_v06` at v0.7, where EntryPointSimulations returns its results
+    fn simulate_v07(data: &Bytes) -> Value {
+        let (selector, arguments) = data.split_at(4);
+        let op_type = ParamType::Tuple(vec![
+            ParamType::Address, ParamType::Uint(256), ParamType::Bytes, ParamType::Bytes, ParamType::FixedBytes(32),
+            ParamType::Uint(256), ParamType::FixedBytes(32), ParamType::Bytes, ParamType::Bytes,
+        ]);
+        let op = abi::decode(std::slice::from_ref(&op_type), arguments).unwrap()[0].clone().into_tuple().unwrap();
+
+        let uint = |value: u64| Token::Uint(value.into());
+        let stake_info = Token::Tuple(vec![uint(0), uint(0)]);
+        let result = if selector == id(SIMULATE_VALIDATION_V07) {
+            let return_info = Token::Tuple(vec![uint(PRE_OP_GAS), uint(0), uint(0), uint(0), Token::Bytes(vec![])]);
+            let aggregator_info = Token::Tuple(vec![Token::Address(Address::zero()), stake_info.clone()]);
+            Token::Tuple(vec![return_info, stake_info.clone(), stake_info.clone(), stake_info, aggregator_info])
+        } else {
+            let account_gas_limits = op[4].clone().into_fixed_bytes().unwrap();
+            let call_gas = U256::from_big_endian(&account_gas_limits[16..]).as_u64();
+            let (target_success, target_result) = target_call(arguments, op_type);
+            Token::Tuple(vec![
+                uint(PRE_OP_GAS), uint(PRE_OP_GAS + execution_gas(call_gas)), uint(0), uint(0), target_success, target_result,
+            ])
+        };
+        json!(Bytes::from(abi::encode(&[result])))
+    }
+
+    async fn mock_node() -> Arc<Provider<Http>> {
+        let router = Router::new().route("/", post(|Json(request): Json<Value>| async move {
+            let outcome = match request["method"].as_str().unwrap() {
+                "eth_call" => {
+                    let data: Bytes = serde_json::from_value(request["params"][0]["data"].clone()).unwrap();
+                    // EntryPointSimulations is only reachable through the state override
+                    let entry_point = request["params"][0]["to"].as_str().unwrap();
+                    match request["params"][2][entry_point]["code"].as_str() {
+                        Some(code) => {
+                            assert_eq!(code, SIMULATIONS_CODE);
+                            Ok(simulate_v07(&data))
+                        }
+                        None => Err(simulate_v06(&data)),
+                    }
+                }
+                "eth_getCode" => Ok(json!(SIMULATIONS_CODE)),
+                // 40k for the call on top of 21k of intrinsic gas and 64 for its four call data bytes
+                "eth_estimateGas" => Ok(json!("0xee88")),
+                method => panic!("unexpected {}", method),
+            };
+            Json(match outcome {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }),
+                Err(error) => json!({ "jsonrpc": "2.0", "id": request["id"], "error": error }),
+            })
+        }));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        Arc::new(Provider::<Http>::try_from(format!("http://{}", address)).unwrap())
+    }
+
+    #[test]
+    fn pre_verification_gas_prices_the_encoded_operation() {
+        // 608 encoded bytes: 19 words and a 65-byte placeholder signature
+        let unsigned = user_op("0x", "0");
+        assert_eq!(pre_verification_gas(&unsigned).unwrap(), U256::from(43_176));
+
+        let VersionedUserOperation::V06(mut signed) = unsigned.clone() else { unreachable!() };
+        signed.signature = format!("0x{}", "01".repeat(SIGNATURE_SIZE));
+        assert_eq!(pre_verification_gas(&VersionedUserOperation::V06(signed.clone())).unwrap(), U256::from(43_176));
+
+        // Four more non-zero calldata bytes fit in the same word
+        signed.call_data.push_str("ffffffff");
+        assert_eq!(pre_verification_gas(&VersionedUserOperation::V06(signed)).unwrap(), U256::from(43_176 + 4 * 12));
+    }
+
+    #[tokio::test]
+    async fn simulates_validation_and_searches_call_gas_for_new_accounts() {
+        let simulator = UserOperationSimulator::new(mock_node().await, HashMap::new(), 10_000_000);
+        let entry_point: Address = ENTRY_POINT_V06.parse().unwrap();
+
+        let deploying = user_op("0x9406cc6185a346906296840746125a0e449764545fbfb9cf", "0");
+        let estimate = simulator.estimate(&deploying, entry_point, EntryPointVersion::V06).await.unwrap();
+        assert_eq!(estimate.verification_gas_limit, Some(U256::from(110_000)));
+        // The smallest limit the call and the EntryPoint's 3k overhead fit in, to within 1k
+        let call_gas_limit = estimate.call_gas_limit.unwrap();
+        assert!(call_gas_limit > U256::from(53_000) && call_gas_limit <= U256::from(54_000), "{}", call_gas_limit);
+
+        let deployed = simulator.estimate(&user_op("0x", "0"), entry_point, EntryPointVersion::V06).await.unwrap();
+        assert_eq!(deployed.call_gas_limit, Some(U256::from(40_000)));
+    }
+
+    #[tokio::test]
+    async fn simulates_v07_operations_through_entry_point_simulations() {
+        let simulations = HashMap::from([(EntryPointVersion::V07, Address::repeat_byte(0x5a))]);
+        let simulator = UserOperationSimulator::new(mock_node().await, simulations, 10_000_000);
+        let entry_point: Address = ENTRY_POINT_V07.parse().unwrap();
+
+        let deploying = user_op_v07(Some("0x9406cc6185a346906296840746125a0e44976454"), "0xb61d27f6");
+        let estimate = simulator.estimate(&deploying, entry_point, EntryPointVersion::V07).await.unwrap();
+        assert_eq!(estimate.verification_gas_limit, Some(U256::from(110_000)));
+        let call_gas_limit = estimate.call_gas_limit.unwrap();
+        assert!(call_gas_limit > U256::from(53_000) && call_gas_limit <= U256::from(54_000), "{}", call_gas_limit);
+
+        let deployed = simulator.estimate(&user_op_v07(None, "0xb61d27f6"), entry_point, EntryPointVersion::V07).await.unwrap();
+        assert_eq!((deployed.verification_gas_limit, deployed.call_gas_limit), (Some(U256::from(110_000)), Some(U256::from(40_000))));
+    }
+
+    #[tokio::test]
+    async fn rejects_calls_that_revert_with_gas_to_spare() {
+        let simulations = HashMap::from([(EntryPointVersion::V07, Address::repeat_byte(0x5a))]);
+        let simulator = UserOperationSimulator::new(mock_node().await, simulations, 10_000_000);
+
+        let VersionedUserOperation::V06(mut v06) = user_op("0x9406cc6185a346906296840746125a0e449764545fbfb9cf", "0") else { unreachable!() };
+        v06.call_data = REVERTING_CALL.to_string();
+        let v07 = user_op_v07(Some("0x9406cc6185a346906296840746125a0e44976454"), REVERTING_CALL);
+        for (op, entry_point, version) in [
+            (VersionedUserOperation::V06(v06), ENTRY_POINT_V06, EntryPointVersion::V06),
+            (v07, ENTRY_POINT_V07, EntryPointVersion::V07),
+        ] {
+            let error = simulator.estimate(&op, entry_point.parse().unwrap(), version).await.unwrap_err();
+            assert!(matches!(&error, PaymasterError::InvalidUserOperation(reason) if reason.starts_with("Call data reverts")), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_why_an_operation_fails_simulation() {
+        let simulator = UserOperationSimulator::new(mock_node().await, HashMap::new(), 10_000_000);
+        let entry_point: Address = ENTRY_POINT_V06.parse().unwrap();
+
+        let error = simulator.estimate(&user_op("0x", "0xbad"), entry_point, EntryPointVersion::V06).await.unwrap_err();
+        assert!(matches!(error, PaymasterError::InvalidUserOperation(reason) if reason.contains("AA25 invalid account nonce")));
+
+        // v0.7 validation needs EntryPointSimulations; without it only the call is estimated
+        let v07 = user_op_v07(None, "0xb61d27f6");
+        let estimate = simulator.estimate(&v07, ENTRY_POINT_V07.parse().unwrap(), EntryPointVersion::V07).await.unwrap();
+        assert_eq!((estimate.verification_gas_limit, estimate.call_gas_limit), (None, Some(U256::from(40_000))));
+    }
+
+    #[tokio::test]
+    async fn searches_call_gas_for_execute_user_op_calls() {
+        let simulations = HashMap::from([(EntryPointVersion::V07, Address::repeat_byte(0x5a))]);
+        let simulator = UserOperationSimulator::new(mock_node().await, simulations, 10_000_000);
+        let entry_point: Address = ENTRY_POINT_V07.parse().unwrap();
+
+        // Kernel v3 prefixes its execute call with executeUserOp
+        let call_data = format!("0x{}b61d27f6", ethers::utils::hex::encode(id(EXECUTE_USER_OP)));
+        let estimate = simulator.estimate(&user_op_v07(None, &call_data), entry_point, EntryPointVersion::V07).await.unwrap();
+        let call_gas_limit = estimate.call_gas_limit.unwrap();
+        assert!(call_gas_limit > U256::from(53_000) && call_gas_limit <= U256::from(54_000), "{}", call_gas_limit);
+    }
+
+    #[tokio::test]
+    async fn reports_an_unreachable_node_as_a_blockchain_error() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let provider = Arc::new(Provider::<Http>::try_from(format!("http://{}", address)).unwrap());
+        let simulator = UserOperationSimulator::new(provider, HashMap::new(), 10_000_000);
+
+        let v07 = user_op_v07(None, "0xb61d27f6");
+        let error = simulator.estimate(&v07, ENTRY_POINT_V07.parse().unwrap(), EntryPointVersion::V07).await.unwrap_err();
+        assert!(matches!(error, PaymasterError::BlockchainError(_)), "{}", error);
+    }
+}
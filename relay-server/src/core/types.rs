@@ -1,8 +1,14 @@
+use crate::core::call_rules::parse_selector;
+use crate::core::schedule::CronExpression;
+use crate::core::user_operation::{VersionedUserOperation, ENTRY_POINT_V06, ENTRY_POINT_V07, ENTRY_POINT_V08};
+use ethers::abi::{self, Token};
+use ethers::types::{Address, Bytes, H256, U256};
+use ethers::utils::keccak256;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 
 // ERC-4337 UserOperation structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct UserOperation {
     pub sender: String,
     pub nonce: String,
@@ -17,27 +23,165 @@ pub struct UserOperation {
     pub signature: String,
 }
 
+impl UserOperation {
+    pub fn sender_address(&self) -> PaymasterResult<Address> {
+        self.sender.parse()
+            .map_err(|_| PaymasterError::InvalidUserOperation("Invalid sender address".to_string()))
+    }
+
+    /// EntryPoint v0.6 `getUserOpHash`, as emitted in `UserOperationEvent`
+    pub fn user_op_hash(&self, entry_point: Address, chain_id: u64) -> PaymasterResult<H256> {
+        let inner = abi::encode(&[
+            Token::Address(self.sender_address()?),
+            Token::Uint(parse_quantity("nonce", &self.nonce)?),
+            Token::FixedBytes(keccak256(parse_hex_bytes("init code", &self.init_code)?).to_vec()),
+            Token::FixedBytes(keccak256(parse_hex_bytes("call data", &self.call_data)?).to_vec()),
+            Token::Uint(parse_quantity("call gas limit", &self.call_gas_limit)?),
+            Token::Uint(parse_quantity("verification gas limit", &self.verification_gas_limit)?),
+            Token::Uint(parse_quantity("pre verification gas", &self.pre_verification_gas)?),
+            Token::Uint(parse_quantity("max fee per gas", &self.max_fee_per_gas)?),
+            Token::Uint(parse_quantity("max priority fee per gas", &self.max_priority_fee_per_gas)?),
+            Token::FixedBytes(keccak256(parse_hex_bytes("paymaster and data", &self.paymaster_and_data)?).to_vec()),
+        ]);
+
+        Ok(H256(keccak256(abi::encode(&[
+            Token::FixedBytes(keccak256(inner).to_vec()),
+            Token::Address(entry_point),
+            Token::Uint(chain_id.into()),
+        ]))))
+    }
+}
+
+/// Parse a numeric field given either as 0x-prefixed hex or as a decimal string
+pub fn parse_quantity(field: &str, value: &str) -> PaymasterResult<U256> {
+    let parsed = match value.strip_prefix("0x") {
+        Some("") => Some(U256::zero()),
+        Some(hex) => U256::from_str_radix(hex, 16).ok(),
+        None if value.is_empty() => Some(U256::zero()),
+        None => U256::from_dec_str(value).ok(),
+    };
+
+    parsed.ok_or_else(|| PaymasterError::InvalidUserOperation(format!("Invalid {}: {}", field, value)))
+}
+
+/// Parse a 0x-prefixed hex byte string, treating an empty string as empty bytes
+pub fn parse_hex_bytes(field: &str, value: &str) -> PaymasterResult<Bytes> {
+    if value.is_empty() {
+        return Ok(Bytes::new());
+    }
+
+    value.parse::<Bytes>()
+        .map_err(|_| PaymasterError::InvalidUserOperation(format!("Invalid {}: {}", field, value)))
+}
+
 // Paymaster request/response types
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawSponsorRequest")]
 pub struct SponsorRequest {
-    pub user_operation: UserOperation,
+    pub user_operation: VersionedUserOperation,
     pub entry_point: String,
     pub chain_id: u64,
+    #[serde(default)]
+    pub validity_seconds: Option<u64>, // Requested signature lifetime, capped by the server
+    #[serde(default)]
+    pub sponsorship_policy_id: Option<String>, // GasPolicy.id the client asks to be sponsored under
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct PaymasterResult {
+pub struct PaymasterResponse {
     pub paymaster_and_data: String,
     pub pre_verification_gas: String,
     pub verification_gas_limit: String,
     pub call_gas_limit: String,
+    pub valid_until: u64, // Unix timestamp after which the signature is rejected
+    pub valid_after: u64, // Unix timestamp before which the signature is rejected
+    // EntryPoint v0.7 splits paymasterAndData into separate fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster_data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster_verification_gas_limit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster_post_op_gas_limit: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(try_from = "RawERC20PaymentRequest")]
 pub struct ERC20PaymentRequest {
-    pub user_operation: UserOperation,
+    pub user_operation: VersionedUserOperation,
+    pub entry_point: String,
+    pub chain_id: u64,
     pub token: String,
     pub max_token_amount: String,
+    #[serde(default)]
+    pub validity_seconds: Option<u64>,
+}
+
+/// `SponsorRequest` as received, before the operation is parsed for its EntryPoint
+#[derive(Deserialize)]
+struct RawSponsorRequest {
+    user_operation: serde_json::Value,
+    entry_point: String,
+    chain_id: u64,
+    #[serde(default)]
+    validity_seconds: Option<u64>,
+    #[serde(default)]
+    sponsorship_policy_id: Option<String>,
+}
+
+impl TryFrom<RawSponsorRequest> for SponsorRequest {
+    type Error = PaymasterError;
+
+    fn try_from(raw: RawSponsorRequest) -> PaymasterResult<Self> {
+        Ok(Self {
+            user_operation: VersionedUserOperation::from_value(raw.user_operation, &raw.entry_point)?,
+            entry_point: raw.entry_point,
+            chain_id: raw.chain_id,
+            validity_seconds: raw.validity_seconds,
+            sponsorship_policy_id: raw.sponsorship_policy_id,
+        })
+    }
+}
+
+/// `ERC20PaymentRequest` as received, before the operation is parsed for its EntryPoint
+#[derive(Deserialize)]
+struct RawERC20PaymentRequest {
+    user_operation: serde_json::Value,
+    entry_point: String,
+    chain_id: u64,
+    token: String,
+    max_token_amount: String,
+    #[serde(default)]
+    validity_seconds: Option<u64>,
+}
+
+impl TryFrom<RawERC20PaymentRequest> for ERC20PaymentRequest {
+    type Error = PaymasterError;
+
+    fn try_from(raw: RawERC20PaymentRequest) -> PaymasterResult<Self> {
+        Ok(Self {
+            user_operation: VersionedUserOperation::from_value(raw.user_operation, &raw.entry_point)?,
+            entry_point: raw.entry_point,
+            chain_id: raw.chain_id,
+            token: raw.token,
+            max_token_amount: raw.max_token_amount,
+            validity_seconds: raw.validity_seconds,
+        })
+    }
+}
+
+impl ERC20PaymentRequest {
+    /// The same operation as a sponsorship request, for policy checks
+    pub fn to_sponsor_request(&self) -> SponsorRequest {
+        SponsorRequest {
+            user_operation: self.user_operation.clone(),
+            entry_point: self.entry_point.clone(),
+            chain_id: self.chain_id,
+            validity_seconds: self.validity_seconds,
+            sponsorship_policy_id: None,
+        }
+    }
 }
 
 // Gas estimation types
@@ -53,6 +197,7 @@ pub struct GasEstimate {
     pub gas_price: String,
     pub total_cost_eth: String,
     pub total_cost_token: Option<String>,
+    pub l1_data_fee: String, // Wei, included in the gas limit and cost; zero off rollups
 }
 
 // Policy types
@@ -63,9 +208,135 @@ pub struct GasPolicy {
     pub policy_type: PolicyType,
     pub target: Option<String>, // Contract address or wallet address
     pub rate_limits: Vec<RateLimit>,
+    /// When set, every call the operation makes must be to a listed contract and pass its rule
+    #[serde(default)]
+    pub call_rules: Vec<CallRule>,
+    /// Required by `Custom` policies, which let it decide each operation
+    #[serde(default)]
+    pub webhook: Option<PolicyWebhook>,
+    /// Lower priorities are evaluated first; ties are broken by id
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub effect: PolicyEffect,
+    /// Id of the policy whose target, limits, call rules, webhook and schedule this one starts from
+    #[serde(default)]
+    pub inherits: Option<String>,
+    /// Scale the limits inherited from `inherits`, e.g. for a higher tier; the policy's own limits are not scaled
+    #[serde(default)]
+    pub multipliers: LimitMultipliers,
+    /// When the policy is in effect; outside it the policy is skipped
+    #[serde(default)]
+    pub schedule: Option<PolicySchedule>,
     pub enabled: bool,
 }
 
+/// What a policy does with the operations it covers
+///
+/// Deny overrides allow: an operation covered by any enabled `Deny` policy is refused, and
+/// once an enabled `Allow` policy exists only operations one of them covers are sponsored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PolicyEffect {
+    /// Enforce the policy's rate limits and rules
+    #[default]
+    Limit,
+    /// Enforce them, and explicitly allow what the policy covers
+    Allow,
+    /// Refuse what the policy covers
+    Deny,
+}
+
+/// Factors applied to the limits a policy inherits; limits it sets itself are used as written
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LimitMultipliers {
+    /// `Amount` and `AmountPerTransaction` limits
+    #[serde(default)]
+    pub amount: Option<f64>,
+    /// `Request` limits
+    #[serde(default)]
+    pub requests: Option<f64>,
+}
+
+impl LimitMultipliers {
+    pub fn is_empty(&self) -> bool {
+        self.amount.is_none() && self.requests.is_none()
+    }
+}
+
+/// Activation window and recurring hours of a policy
+///
+/// A policy whose `ends_at` has passed is disabled, as a finished campaign.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicySchedule {
+    /// Unix seconds
+    #[serde(default)]
+    pub starts_at: Option<u64>,
+    /// Unix seconds, exclusive
+    #[serde(default)]
+    pub ends_at: Option<u64>,
+    /// Cron expressions (`minute hour day-of-month month day-of-week`); when any are given the
+    /// policy is only in effect during minutes one of them matches
+    #[serde(default)]
+    pub recurring: Vec<String>,
+    /// Offset from UTC the recurring expressions are read in, e.g. 480 for UTC+8
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+}
+
+/// Where a custom policy asks whether to sponsor an operation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyWebhook {
+    pub url: String,
+    /// HMAC-SHA256 key; requests carry `X-Paymaster-Signature: sha256=<hex digest of the body>`
+    ///
+    /// Write-only: never serialized, so the admin API does not echo it.
+    #[serde(default, skip_serializing)]
+    pub secret: Option<String>,
+    #[serde(default = "default_webhook_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Further attempts after a timeout, connection error or 5xx response
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+    /// How long a decision is reused for the same operation; 0 disables caching
+    #[serde(default)]
+    pub cache_seconds: u64,
+    /// Sponsor when the webhook cannot be reached, instead of refusing
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+fn default_webhook_timeout_ms() -> u64 {
+    2_000
+}
+
+fn default_webhook_retries() -> u32 {
+    2
+}
+
+/// What a policy sponsors on one contract
+///
+/// Selectors are given as a signature like `mint(address,uint256)` or as 4 hex bytes.
+/// Recipients and amounts are read from ERC-20/721 `transfer`, `transferFrom`, `approve`,
+/// `safeTransferFrom` and `mint(address,uint256)` calls; a call with no recipient the rule
+/// can read is rejected when `allowed_recipients` is set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CallRule {
+    pub target: String,
+    /// Empty allows every selector not denied
+    #[serde(default)]
+    pub allowed_selectors: Vec<String>,
+    #[serde(default)]
+    pub denied_selectors: Vec<String>,
+    /// Maximum native value per call, in wei
+    #[serde(default)]
+    pub max_value: Option<String>,
+    #[serde(default)]
+    pub allowed_recipients: Vec<String>,
+    /// Maximum token amount per transfer or approval; calls whose amount cannot be read are refused
+    #[serde(default)]
+    pub max_transfer_amount: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PolicyType {
     Project,
@@ -79,9 +350,25 @@ pub struct RateLimit {
     pub limit_type: RateLimitType,
     pub limit: String,
     pub window: u64, // Time window in seconds
+    #[serde(default)]
+    pub algorithm: RateLimitAlgorithm,
+    #[serde(default)]
+    pub burst: Option<String>, // Token bucket capacity; defaults to `limit`
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// How a windowed limit (`Amount` or `Request`) counts usage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RateLimitAlgorithm {
+    /// Counters reset every `window` seconds, aligned to the epoch
+    #[default]
+    FixedWindow,
+    /// The previous window's usage, weighted by how much of it still overlaps, plus the current window's
+    SlidingWindow,
+    /// `limit` refills evenly over each `window`, up to `burst` available at once
+    TokenBucket,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RateLimitType {
     Amount,
     Request,
@@ -89,22 +376,173 @@ pub enum RateLimitType {
     AmountPerTransaction,
 }
 
-// Chain configuration
-#[derive(Debug, Clone)]
+impl GasPolicy {
+    /// Check the fields the policy engine would otherwise only reject at request time
+    pub fn validate(&self) -> Result<(), PolicyFieldError> {
+        let invalid = |field: String, message: &str| Err(PolicyFieldError { field, message: message.to_string() });
+
+        if self.id.trim().is_empty() {
+            return invalid("id".to_string(), "must not be empty");
+        }
+        match (&self.policy_type, &self.target) {
+            (PolicyType::Contract | PolicyType::Wallet, None) if self.inherits.is_none() => {
+                return invalid("target".to_string(), "is required for contract and wallet policies that inherit none");
+            }
+            (PolicyType::Contract | PolicyType::Wallet, Some(target)) if target.parse::<Address>().is_err() => {
+                return invalid("target".to_string(), "is not a valid address");
+            }
+            _ => {}
+        }
+        if self.inherits.as_deref() == Some(self.id.as_str()) {
+            return invalid("inherits".to_string(), "must name another policy");
+        }
+        if self.inherits.is_none() && !self.multipliers.is_empty() {
+            return invalid("multipliers".to_string(), "only scale inherited limits, so require inherits");
+        }
+        for (name, factor) in [("multipliers.amount", self.multipliers.amount), ("multipliers.requests", self.multipliers.requests)] {
+            if factor.is_some_and(|factor| !factor.is_finite() || factor <= 0.0) {
+                return invalid(name.to_string(), "must be a positive number");
+            }
+        }
+        match (&self.policy_type, &self.webhook) {
+            (PolicyType::Custom, None) if self.inherits.is_none() => {
+                return invalid("webhook".to_string(), "is required for custom policies that inherit none");
+            }
+            (_, Some(webhook)) if !is_http_url(&webhook.url) => {
+                return invalid("webhook.url".to_string(), "must be an http or https URL");
+            }
+            (_, Some(webhook)) if webhook.timeout_ms == 0 => {
+                return invalid("webhook.timeout_ms".to_string(), "must be positive");
+            }
+            _ => {}
+        }
+        // A custom policy covers every operation, so denying what it covers would deny everything
+        if matches!(self.policy_type, PolicyType::Custom) && self.effect == PolicyEffect::Deny {
+            return invalid("effect".to_string(), "cannot be Deny for custom policies; their webhook refuses operations");
+        }
+        if let Some(schedule) = &self.schedule {
+            if let (Some(starts_at), Some(ends_at)) = (schedule.starts_at, schedule.ends_at) {
+                if starts_at >= ends_at {
+                    return invalid("schedule.ends_at".to_string(), "must be after starts_at");
+                }
+            }
+            for (index, expression) in schedule.recurring.iter().enumerate() {
+                if let Err(e) = CronExpression::parse(expression) {
+                    return invalid(format!("schedule.recurring[{}]", index), &e);
+                }
+            }
+            if schedule.utc_offset_minutes.abs() >= 24 * 60 {
+                return invalid("schedule.utc_offset_minutes".to_string(), "must be less than a day");
+            }
+        }
+
+        for (index, rate_limit) in self.rate_limits.iter().enumerate() {
+            if !is_limit(&rate_limit.limit) {
+                return invalid(format!("rate_limits[{}].limit", index), "must be a decimal or 0x-prefixed integer");
+            }
+            let windowed = matches!(rate_limit.limit_type, RateLimitType::Amount | RateLimitType::Request);
+            if windowed && rate_limit.window == 0 {
+                return invalid(format!("rate_limits[{}].window", index), "must be positive");
+            }
+            if rate_limit.burst.as_deref().is_some_and(|burst| !is_limit(burst)) {
+                return invalid(format!("rate_limits[{}].burst", index), "must be a decimal or 0x-prefixed integer");
+            }
+        }
+
+        for (index, rule) in self.call_rules.iter().enumerate() {
+            let field = |name: &str| format!("call_rules[{}].{}", index, name);
+            let Ok(target) = rule.target.parse::<Address>() else {
+                return invalid(field("target"), "is not a valid address");
+            };
+            if self.call_rules[..index].iter().any(|earlier| earlier.target.parse::<Address>().ok() == Some(target)) {
+                return invalid(field("target"), "already has a rule");
+            }
+            for (name, selectors) in [("allowed_selectors", &rule.allowed_selectors), ("denied_selectors", &rule.denied_selectors)] {
+                if let Some(position) = selectors.iter().position(|selector| parse_selector(selector).is_none()) {
+                    return invalid(format!("{}[{}]", field(name), position), "must be a function signature or 4 hex bytes");
+                }
+            }
+            for (name, amount) in [("max_value", &rule.max_value), ("max_transfer_amount", &rule.max_transfer_amount)] {
+                if amount.as_deref().is_some_and(|amount| amount.is_empty() || parse_quantity(name, amount).is_err()) {
+                    return invalid(field(name), "must be a decimal or 0x-prefixed integer");
+                }
+            }
+            if let Some(position) = rule.allowed_recipients.iter().position(|recipient| recipient.parse::<Address>().is_err()) {
+                return invalid(format!("{}[{}]", field("allowed_recipients"), position), "is not a valid address");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn is_http_url(url: &str) -> bool {
+    reqwest::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+fn is_limit(value: &str) -> bool {
+    !value.is_empty() && parse_quantity("limit", value).is_ok_and(|limit| limit <= U256::from(u64::MAX))
+}
+
+/// A policy field that failed validation, e.g. `rate_limits[0].window`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyFieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for PolicyFieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.field, self.message)
+    }
+}
+
+/// What contract policies do with account calldata whose call targets cannot be decoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownCallData {
+    /// Refuse the operation while any contract policy is enabled
+    #[default]
+    Reject,
+    /// Count the operation against every contract policy
+    Apply,
+    /// Contract policies do not cover the operation
+    Skip,
+}
+
+// Chain configuration, one `[[blockchain.chains]]` entry
+#[derive(Debug, Clone, Deserialize)]
 pub struct ChainConfig {
     pub chain_id: u64,
     pub name: String,
     pub rpc_url: String,
-    pub entry_point: String,
-    pub supported_tokens: HashMap<String, TokenConfig>,
+    #[serde(default = "default_entry_points")]
+    pub entry_points: Vec<String>, // EntryPoints served on this chain; the version follows from the address
+    #[serde(default)]
+    pub paymaster_address: Option<String>, // Defaults to paymaster.address
+    #[serde(default)]
+    pub private_key: Option<String>, // Defaults to paymaster.private_key
+    #[serde(default)]
+    pub entry_point_simulations_v07: Option<String>, // EntryPointSimulations deployment for v0.7 gas estimation; unset skips simulation
+    #[serde(default)]
+    pub entry_point_simulations_v08: Option<String>,
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>, // Accepted for ERC20 gas payment
+    #[serde(default)]
+    pub l1_fee_model: Option<String>, // "none", "op_stack" or "arbitrum"; defaults by chain id
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct TokenConfig {
     pub address: String,
     pub symbol: String,
     pub decimals: u8,
     pub price_feed: String,
+    pub price_path: String, // JSON Pointer to the USD price in the feed's response, e.g. "/usd-coin/usd"
+}
+
+fn default_entry_points() -> Vec<String> {
+    [ENTRY_POINT_V06, ENTRY_POINT_V07, ENTRY_POINT_V08].map(String::from).to_vec()
 }
 
 // Error types
@@ -113,8 +551,17 @@ pub enum PaymasterError {
     #[error("Invalid user operation: {0}")]
     InvalidUserOperation(String),
     
+    #[error("Chain {0} is not supported")]
+    UnsupportedChain(u64),
+
     #[error("Policy violation: {0}")]
     PolicyViolation(String),
+
+    #[error("Rejected by policy {policy_id}: {reason}")]
+    PolicyRejected { policy_id: String, reason: String },
+
+    #[error("Circuit breaker open for {scope}: {reason}")]
+    CircuitBreakerOpen { scope: String, reason: String },
     
     #[error("Insufficient balance: {0}")]
     InsufficientBalance(String),
@@ -132,4 +579,17 @@ pub enum PaymasterError {
     ConfigurationError(String),
 }
 
+impl PaymasterError {
+    /// Attribute a policy violation to the policy that raised it
+    pub fn rejected_by(self, policy_id: &str) -> Self {
+        match self {
+            PaymasterError::PolicyViolation(reason) => PaymasterError::PolicyRejected {
+                policy_id: policy_id.to_string(),
+                reason,
+            },
+            other => other,
+        }
+    }
+}
+
 pub type PaymasterResult<T> = Result<T, PaymasterError>;
@@ -0,0 +1,654 @@
+use crate::core::types::*;
+use ethers::abi::{self, Token};
+use ethers::types::{Address, Bytes, Signature, H256, U256};
+use ethers::utils::{keccak256, rlp::RlpStream, to_checksum};
+use serde::{Deserialize, Serialize};
+
+/// Canonical EntryPoint v0.6 deployment
+pub const ENTRY_POINT_V06: &str = "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789";
+/// Canonical EntryPoint v0.7 deployment
+pub const ENTRY_POINT_V07: &str = "0x0000000071727De22E5E9d8BAf0edAc6f37da032";
+/// Canonical EntryPoint v0.8 deployment
+pub const ENTRY_POINT_V08: &str = "0x4337084D9E255Ff0702461CF8895CE9E3b5Ff108";
+
+/// `factory` value marking an EIP-7702 delegated EOA instead of a deployment (v0.8)
+pub const EIP7702_FACTORY_MARKER: &str = "0x7702";
+/// Code prefix of an EOA delegated through EIP-7702
+pub const EIP7702_DELEGATION_PREFIX: [u8; 3] = [0xef, 0x01, 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EntryPointVersion {
+    V06,
+    V07,
+    V08,
+}
+
+impl EntryPointVersion {
+    /// Resolve the EntryPoint version from its deployment address
+    pub fn from_address(entry_point: &str) -> PaymasterResult<Self> {
+        if entry_point.eq_ignore_ascii_case(ENTRY_POINT_V06) {
+            Ok(Self::V06)
+        } else if entry_point.eq_ignore_ascii_case(ENTRY_POINT_V07) {
+            Ok(Self::V07)
+        } else if entry_point.eq_ignore_ascii_case(ENTRY_POINT_V08) {
+            Ok(Self::V08)
+        } else {
+            Err(PaymasterError::InvalidUserOperation(
+                format!("Unsupported entry point: {}", entry_point)
+            ))
+        }
+    }
+
+    /// The user operation layout this EntryPoint accepts; v0.8 reuses the v0.7 layout
+    pub fn layout(&self) -> Self {
+        match self {
+            Self::V06 => Self::V06,
+            Self::V07 | Self::V08 => Self::V07,
+        }
+    }
+}
+
+// EIP-7702 authorization tuple delegating an EOA sender to a smart account implementation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Eip7702Auth {
+    pub chain_id: String,
+    pub address: String, // Delegate implementation
+    pub nonce: String,
+    pub y_parity: String,
+    pub r: String,
+    pub s: String,
+}
+
+impl Eip7702Auth {
+    pub fn delegate(&self) -> PaymasterResult<Address> {
+        parse_address("authorization address", &self.address)
+    }
+
+    pub fn chain_id(&self) -> PaymasterResult<U256> {
+        parse_quantity("authorization chain id", &self.chain_id)
+    }
+
+    pub fn nonce(&self) -> PaymasterResult<U256> {
+        parse_quantity("authorization nonce", &self.nonce)
+    }
+
+    /// keccak256(0x05 || rlp([chain_id, address, nonce])), the digest signed by the authority
+    pub fn signing_hash(&self) -> PaymasterResult<H256> {
+        let mut stream = RlpStream::new_list(3);
+        stream.append(&self.chain_id()?);
+        stream.append(&self.delegate()?);
+        stream.append(&self.nonce()?);
+
+        let mut payload = vec![0x05];
+        payload.extend_from_slice(&stream.out());
+        Ok(H256(keccak256(payload)))
+    }
+
+    /// Recover the EOA that signed this authorization
+    pub fn recover_authority(&self) -> PaymasterResult<Address> {
+        let y_parity = parse_quantity("authorization y parity", &self.y_parity)?;
+        if y_parity > U256::one() {
+            return Err(PaymasterError::InvalidUserOperation(
+                format!("Invalid authorization y parity: {}", self.y_parity)
+            ));
+        }
+
+        let signature = Signature {
+            r: parse_quantity("authorization r", &self.r)?,
+            s: parse_quantity("authorization s", &self.s)?,
+            v: y_parity.as_u64() + 27,
+        };
+
+        signature.recover(self.signing_hash()?)
+            .map_err(|e| PaymasterError::InvalidUserOperation(format!("Invalid authorization signature: {}", e)))
+    }
+}
+
+// ERC-4337 v0.7 UserOperation as exchanged over RPC (unpacked); also used for v0.8
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationV07 {
+    pub sender: String,
+    pub nonce: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub factory: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub factory_data: Option<String>,
+    pub call_data: String,
+    pub call_gas_limit: String,
+    pub verification_gas_limit: String,
+    pub pre_verification_gas: String,
+    pub max_fee_per_gas: String,
+    pub max_priority_fee_per_gas: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster_verification_gas_limit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster_post_op_gas_limit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster_data: Option<String>,
+    pub signature: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eip7702_auth: Option<Box<Eip7702Auth>>, // v0.8 only
+}
+
+// ERC-4337 v0.7 PackedUserOperation as passed to the EntryPoint
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PackedUserOperation {
+    pub sender: Address,
+    pub nonce: U256,
+    pub init_code: Bytes,
+    pub call_data: Bytes,
+    pub account_gas_limits: H256, // verificationGasLimit (16 bytes) || callGasLimit (16 bytes)
+    pub pre_verification_gas: U256,
+    pub gas_fees: H256, // maxPriorityFeePerGas (16 bytes) || maxFeePerGas (16 bytes)
+    pub paymaster_and_data: Bytes,
+    pub signature: Bytes,
+}
+
+const EIP712_DOMAIN_TYPE: &str =
+    "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)";
+const PACKED_USER_OPERATION_TYPE: &str =
+    "PackedUserOperation(address sender,uint256 nonce,bytes initCode,bytes callData,bytes32 accountGasLimits,uint256 preVerificationGas,bytes32 gasFees,bytes paymasterAndData)";
+
+/// Byte offsets into v0.7 `paymasterAndData`
+pub const PAYMASTER_VALIDATION_GAS_OFFSET: usize = 20;
+pub const PAYMASTER_POST_OP_GAS_OFFSET: usize = 36;
+pub const PAYMASTER_DATA_OFFSET: usize = 52;
+
+impl UserOperationV07 {
+    /// Convert to the packed on-chain layout
+    pub fn pack(&self) -> PaymasterResult<PackedUserOperation> {
+        let init_code = match &self.factory {
+            Some(factory) if !factory.is_empty() => {
+                let mut init_code = if factory.eq_ignore_ascii_case(EIP7702_FACTORY_MARKER) {
+                    // The EntryPoint matches the marker zero-padded to an address
+                    let mut marker = [0u8; 20];
+                    marker[..2].copy_from_slice(&[0x77, 0x02]);
+                    marker.to_vec()
+                } else {
+                    parse_address("factory", factory)?.as_bytes().to_vec()
+                };
+                init_code.extend_from_slice(&parse_hex_bytes("factory data", self.factory_data.as_deref().unwrap_or(""))?);
+                init_code
+            }
+            _ => Vec::new(),
+        };
+
+        let paymaster_and_data = match &self.paymaster {
+            Some(paymaster) if !paymaster.is_empty() => {
+                let mut paymaster_and_data = parse_address("paymaster", paymaster)?.as_bytes().to_vec();
+                paymaster_and_data.extend_from_slice(&to_u128_bytes(
+                    "paymaster verification gas limit",
+                    self.paymaster_verification_gas_limit.as_deref().unwrap_or(""),
+                )?);
+                paymaster_and_data.extend_from_slice(&to_u128_bytes(
+                    "paymaster post op gas limit",
+                    self.paymaster_post_op_gas_limit.as_deref().unwrap_or(""),
+                )?);
+                paymaster_and_data.extend_from_slice(&parse_hex_bytes("paymaster data", self.paymaster_data.as_deref().unwrap_or(""))?);
+                paymaster_and_data
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(PackedUserOperation {
+            sender: parse_address("sender", &self.sender)?,
+            nonce: parse_quantity("nonce", &self.nonce)?,
+            init_code: init_code.into(),
+            call_data: parse_hex_bytes("call data", &self.call_data)?,
+            account_gas_limits: pack_u128_pair(
+                ("verification gas limit", &self.verification_gas_limit),
+                ("call gas limit", &self.call_gas_limit),
+            )?,
+            pre_verification_gas: parse_quantity("pre verification gas", &self.pre_verification_gas)?,
+            gas_fees: pack_u128_pair(
+                ("max priority fee per gas", &self.max_priority_fee_per_gas),
+                ("max fee per gas", &self.max_fee_per_gas),
+            )?,
+            paymaster_and_data: paymaster_and_data.into(),
+            signature: parse_hex_bytes("signature", &self.signature)?,
+        })
+    }
+
+    /// Whether `factory` is the EIP-7702 marker rather than a real factory
+    pub fn is_eip7702(&self) -> bool {
+        self.factory.as_deref().is_some_and(|factory| factory.eq_ignore_ascii_case(EIP7702_FACTORY_MARKER))
+    }
+}
+
+impl PackedUserOperation {
+    /// Convert back to the unpacked RPC representation
+    pub fn unpack(&self) -> PaymasterResult<UserOperationV07> {
+        let (factory, factory_data) = match self.init_code.len() {
+            0 => (None, None),
+            len if len < 20 => {
+                return Err(PaymasterError::InvalidUserOperation(
+                    "Init code is shorter than a factory address".to_string()
+                ));
+            }
+            _ => (
+                Some(to_checksum(&Address::from_slice(&self.init_code[..20]), None)),
+                Some(Bytes::from(self.init_code[20..].to_vec()).to_string()),
+            ),
+        };
+
+        let (paymaster, paymaster_verification_gas_limit, paymaster_post_op_gas_limit, paymaster_data) =
+            match self.paymaster_and_data.len() {
+                0 => (None, None, None, None),
+                len if len < PAYMASTER_DATA_OFFSET => {
+                    return Err(PaymasterError::InvalidUserOperation(
+                        "Paymaster and data is shorter than the static paymaster fields".to_string()
+                    ));
+                }
+                _ => {
+                    let data = &self.paymaster_and_data;
+                    (
+                        Some(to_checksum(&Address::from_slice(&data[..PAYMASTER_VALIDATION_GAS_OFFSET]), None)),
+                        Some(format!("{:#x}", U256::from_big_endian(&data[PAYMASTER_VALIDATION_GAS_OFFSET..PAYMASTER_POST_OP_GAS_OFFSET]))),
+                        Some(format!("{:#x}", U256::from_big_endian(&data[PAYMASTER_POST_OP_GAS_OFFSET..PAYMASTER_DATA_OFFSET]))),
+                        Some(Bytes::from(data[PAYMASTER_DATA_OFFSET..].to_vec()).to_string()),
+                    )
+                }
+            };
+
+        let (verification_gas_limit, call_gas_limit) = unpack_u128_pair(&self.account_gas_limits);
+        let (max_priority_fee_per_gas, max_fee_per_gas) = unpack_u128_pair(&self.gas_fees);
+
+        Ok(UserOperationV07 {
+            sender: to_checksum(&self.sender, None),
+            nonce: format!("{:#x}", self.nonce),
+            factory,
+            factory_data,
+            call_data: self.call_data.to_string(),
+            call_gas_limit: format!("{:#x}", call_gas_limit),
+            verification_gas_limit: format!("{:#x}", verification_gas_limit),
+            pre_verification_gas: format!("{:#x}", self.pre_verification_gas),
+            max_fee_per_gas: format!("{:#x}", max_fee_per_gas),
+            max_priority_fee_per_gas: format!("{:#x}", max_priority_fee_per_gas),
+            paymaster,
+            paymaster_verification_gas_limit,
+            paymaster_post_op_gas_limit,
+            paymaster_data,
+            signature: self.signature.to_string(),
+            eip7702_auth: None,
+        })
+    }
+
+    /// EntryPoint v0.7 `getUserOpHash`, as emitted in `UserOperationEvent`
+    pub fn user_op_hash(&self, entry_point: Address, chain_id: u64) -> H256 {
+        let inner = abi::encode(&[
+            Token::Address(self.sender),
+            Token::Uint(self.nonce),
+            Token::FixedBytes(keccak256(&self.init_code).to_vec()),
+            Token::FixedBytes(keccak256(&self.call_data).to_vec()),
+            Token::FixedBytes(self.account_gas_limits.as_bytes().to_vec()),
+            Token::Uint(self.pre_verification_gas),
+            Token::FixedBytes(self.gas_fees.as_bytes().to_vec()),
+            Token::FixedBytes(keccak256(&self.paymaster_and_data).to_vec()),
+        ]);
+
+        H256(keccak256(abi::encode(&[
+            Token::FixedBytes(keccak256(inner).to_vec()),
+            Token::Address(entry_point),
+            Token::Uint(chain_id.into()),
+        ])))
+    }
+
+    /// EntryPoint v0.8 `getUserOpHash`: the EIP-712 digest of the packed operation
+    ///
+    /// For EIP-7702 senders the init code hash commits to the delegate
+    /// instead of the marker, as the EntryPoint does.
+    pub fn eip712_hash(&self, entry_point: Address, chain_id: u64, eip7702_delegate: Option<Address>) -> H256 {
+        let init_code_hash = match eip7702_delegate {
+            Some(delegate) if self.init_code.starts_with(&[0x77, 0x02]) => {
+                let mut init_code = delegate.as_bytes().to_vec();
+                init_code.extend_from_slice(self.init_code.get(20..).unwrap_or_default());
+                keccak256(init_code)
+            }
+            _ => keccak256(&self.init_code),
+        };
+
+        let struct_hash = keccak256(abi::encode(&[
+            Token::FixedBytes(keccak256(PACKED_USER_OPERATION_TYPE).to_vec()),
+            Token::Address(self.sender),
+            Token::Uint(self.nonce),
+            Token::FixedBytes(init_code_hash.to_vec()),
+            Token::FixedBytes(keccak256(&self.call_data).to_vec()),
+            Token::FixedBytes(self.account_gas_limits.as_bytes().to_vec()),
+            Token::Uint(self.pre_verification_gas),
+            Token::FixedBytes(self.gas_fees.as_bytes().to_vec()),
+            Token::FixedBytes(keccak256(&self.paymaster_and_data).to_vec()),
+        ]));
+
+        let domain_separator = keccak256(abi::encode(&[
+            Token::FixedBytes(keccak256(EIP712_DOMAIN_TYPE).to_vec()),
+            Token::FixedBytes(keccak256("ERC4337").to_vec()),
+            Token::FixedBytes(keccak256("1").to_vec()),
+            Token::Uint(chain_id.into()),
+            Token::Address(entry_point),
+        ]));
+
+        let mut digest = vec![0x19, 0x01];
+        digest.extend_from_slice(&domain_separator);
+        digest.extend_from_slice(&struct_hash);
+        H256(keccak256(digest))
+    }
+
+    /// The 32 bytes of `paymasterAndData` holding the paymaster gas limits, as covered by the paymaster hash
+    pub fn paymaster_gas_limits(&self) -> PaymasterResult<U256> {
+        self.paymaster_and_data
+            .get(PAYMASTER_VALIDATION_GAS_OFFSET..PAYMASTER_DATA_OFFSET)
+            .map(U256::from_big_endian)
+            .ok_or_else(|| PaymasterError::InvalidUserOperation(
+                "Paymaster and data is shorter than the static paymaster fields".to_string()
+            ))
+    }
+}
+
+/// Fields only the v0.6 user operation layout has
+const V06_ONLY_FIELDS: [&str; 2] = ["initCode", "paymasterAndData"];
+
+/// Fields only the v0.7 user operation layout (shared by v0.8) has
+const V07_ONLY_FIELDS: [&str; 7] = [
+    "factory",
+    "factoryData",
+    "paymaster",
+    "paymasterVerificationGasLimit",
+    "paymasterPostOpGasLimit",
+    "paymasterData",
+    "eip7702Auth",
+];
+
+/// A user operation for any supported EntryPoint version
+///
+/// Parsed with `from_value` in the layout of the EntryPoint it is sent to; v0.8 shares the v0.7 layout.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum VersionedUserOperation {
+    V06(UserOperation),
+    V07(UserOperationV07),
+}
+
+impl VersionedUserOperation {
+    /// Parse an RPC user operation in the layout of `entry_point`'s version
+    ///
+    /// Fields of the other layout are rejected rather than ignored, so an operation
+    /// built for another EntryPoint version cannot lose fields silently.
+    pub fn from_value(value: serde_json::Value, entry_point: &str) -> PaymasterResult<Self> {
+        let version = EntryPointVersion::from_address(entry_point)?;
+        let foreign_fields: &[&str] = match version.layout() {
+            EntryPointVersion::V06 => &V07_ONLY_FIELDS,
+            _ => &V06_ONLY_FIELDS,
+        };
+        if let Some(field) = foreign_fields.iter().find(|field| value.get(field).is_some_and(|value| !value.is_null())) {
+            return Err(PaymasterError::InvalidUserOperation(
+                format!("{:?} user operation for entry point {} must not have {}", version, entry_point, field)
+            ));
+        }
+
+        let user_op = match version.layout() {
+            EntryPointVersion::V06 => serde_json::from_value(value).map(Self::V06),
+            _ => serde_json::from_value(value).map(Self::V07),
+        };
+        user_op.map_err(|e| PaymasterError::InvalidUserOperation(format!("Invalid {:?} user operation: {}", version, e)))
+    }
+
+    /// The layout of this operation; v0.8 operations report `V07`
+    pub fn version(&self) -> EntryPointVersion {
+        match self {
+            Self::V06(_) => EntryPointVersion::V06,
+            Self::V07(_) => EntryPointVersion::V07,
+        }
+    }
+
+    pub fn sender(&self) -> &str {
+        match self {
+            Self::V06(op) => &op.sender,
+            Self::V07(op) => &op.sender,
+        }
+    }
+
+    pub fn sender_address(&self) -> PaymasterResult<Address> {
+        parse_address("sender", self.sender())
+    }
+
+    pub fn call_data(&self) -> &str {
+        match self {
+            Self::V06(op) => &op.call_data,
+            Self::V07(op) => &op.call_data,
+        }
+    }
+
+    pub fn call_gas_limit(&self) -> &str {
+        match self {
+            Self::V06(op) => &op.call_gas_limit,
+            Self::V07(op) => &op.call_gas_limit,
+        }
+    }
+
+    pub fn verification_gas_limit(&self) -> &str {
+        match self {
+            Self::V06(op) => &op.verification_gas_limit,
+            Self::V07(op) => &op.verification_gas_limit,
+        }
+    }
+
+    pub fn pre_verification_gas(&self) -> &str {
+        match self {
+            Self::V06(op) => &op.pre_verification_gas,
+            Self::V07(op) => &op.pre_verification_gas,
+        }
+    }
+
+    pub fn max_fee_per_gas(&self) -> &str {
+        match self {
+            Self::V06(op) => &op.max_fee_per_gas,
+            Self::V07(op) => &op.max_fee_per_gas,
+        }
+    }
+
+    pub fn max_priority_fee_per_gas(&self) -> &str {
+        match self {
+            Self::V06(op) => &op.max_priority_fee_per_gas,
+            Self::V07(op) => &op.max_priority_fee_per_gas,
+        }
+    }
+
+    /// Paymaster (verification, post-op) gas limits; v0.6 folds these into `verification_gas_limit`
+    pub fn paymaster_gas_limits(&self) -> (Option<&str>, Option<&str>) {
+        match self {
+            Self::V06(_) => (None, None),
+            Self::V07(op) => (
+                op.paymaster_verification_gas_limit.as_deref(),
+                op.paymaster_post_op_gas_limit.as_deref(),
+            ),
+        }
+    }
+
+    /// The most the operation can be charged in wei: every gas limit at `maxFeePerGas`
+    ///
+    /// On rollups `preVerificationGas` includes the L1 data fee, so this covers it too.
+    pub fn max_gas_cost(&self) -> PaymasterResult<U256> {
+        let (paymaster_verification_gas, paymaster_post_op_gas) = self.paymaster_gas_limits();
+        let gas_limits = [
+            ("call gas limit", self.call_gas_limit()),
+            ("verification gas limit", self.verification_gas_limit()),
+            ("pre verification gas", self.pre_verification_gas()),
+            // v0.7 budgets the paymaster's own gas separately from the account's
+            ("paymaster verification gas limit", paymaster_verification_gas.unwrap_or("0")),
+            ("paymaster post op gas limit", paymaster_post_op_gas.unwrap_or("0")),
+        ];
+
+        let mut total_gas = U256::zero();
+        for (field, gas) in gas_limits {
+            total_gas = total_gas.saturating_add(parse_quantity(field, gas)?);
+        }
+        Ok(total_gas.saturating_mul(parse_quantity("max fee per gas", self.max_fee_per_gas())?))
+    }
+
+    /// Whether the operation deploys its sender (init code or factory)
+    pub fn has_init_code(&self) -> bool {
+        match self {
+            Self::V06(op) => !op.init_code.is_empty() && op.init_code != "0x",
+            Self::V07(op) => op.factory.as_deref().is_some_and(|factory| !factory.is_empty()) && !op.is_eip7702(),
+        }
+    }
+
+    pub fn eip7702_auth(&self) -> Option<&Eip7702Auth> {
+        match self {
+            Self::V06(_) => None,
+            Self::V07(op) => op.eip7702_auth.as_deref(),
+        }
+    }
+}
+
+fn parse_address(field: &str, value: &str) -> PaymasterResult<Address> {
+    value.parse()
+        .map_err(|_| PaymasterError::InvalidUserOperation(format!("Invalid {}: {}", field, value)))
+}
+
+fn to_u128_bytes(field: &str, value: &str) -> PaymasterResult<[u8; 16]> {
+    let quantity = parse_quantity(field, value)?;
+    if quantity > U256::from(u128::MAX) {
+        return Err(PaymasterError::InvalidUserOperation(
+            format!("{} does not fit in 128 bits: {}", field, value)
+        ));
+    }
+
+    Ok(quantity.as_u128().to_be_bytes())
+}
+
+fn pack_u128_pair(high: (&str, &str), low: (&str, &str)) -> PaymasterResult<H256> {
+    let mut packed = [0u8; 32];
+    packed[..16].copy_from_slice(&to_u128_bytes(high.0, high.1)?);
+    packed[16..].copy_from_slice(&to_u128_bytes(low.0, low.1)?);
+    Ok(H256(packed))
+}
+
+fn unpack_u128_pair(packed: &H256) -> (U256, U256) {
+    (
+        U256::from_big_endian(&packed[..16]),
+        U256::from_big_endian(&packed[16..]),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_op_v07() -> UserOperationV07 {
+        UserOperationV07 {
+            sender: "0x1306b01bC3e4AD202612D3843387e94737673F53".to_string(),
+            nonce: "0x3".to_string(),
+            factory: Some("0x91E60e0613810449d098b0b5Ec8b51A0FE8c8985".to_string()),
+            factory_data: Some("0x5fbfb9cf".to_string()),
+            call_data: "0xb61d27f6".to_string(),
+            call_gas_limit: "0x88b8".to_string(),
+            verification_gas_limit: "0x61a80".to_string(),
+            pre_verification_gas: "0xbb80".to_string(),
+            max_fee_per_gas: "0xb2d05e00".to_string(),
+            max_priority_fee_per_gas: "0x59682f00".to_string(),
+            paymaster: Some("0x9d7F74d0C41E726EC95884E0e97Fa6129e3b5E99".to_string()),
+            paymaster_verification_gas_limit: Some("0xea60".to_string()),
+            paymaster_post_op_gas_limit: Some("0x0".to_string()),
+            paymaster_data: Some("0x1234".to_string()),
+            signature: "0xdead".to_string(),
+            eip7702_auth: None,
+        }
+    }
+
+    #[test]
+    fn pack_matches_entry_point_layout() {
+        let packed = user_op_v07().pack().unwrap();
+
+        assert_eq!(
+            packed.init_code.to_string(),
+            "0x91e60e0613810449d098b0b5ec8b51a0fe8c89855fbfb9cf"
+        );
+        assert_eq!(
+            format!("{:?}", packed.account_gas_limits),
+            "0x00000000000000000000000000061a80000000000000000000000000000088b8"
+        );
+        assert_eq!(
+            format!("{:?}", packed.gas_fees),
+            "0x00000000000000000000000059682f00000000000000000000000000b2d05e00"
+        );
+        assert_eq!(
+            packed.paymaster_and_data.to_string(),
+            "0x9d7f74d0c41e726ec95884e0e97fa6129e3b5e990000000000000000000000000000ea60000000000000000000000000000000001234"
+        );
+        assert_eq!(packed.paymaster_gas_limits().unwrap(), U256::from(60000u64) << 128);
+    }
+
+    #[test]
+    fn unpack_round_trips() {
+        let packed = user_op_v07().pack().unwrap();
+        let unpacked = packed.unpack().unwrap();
+
+        assert_eq!(unpacked.sender, "0x1306b01bC3e4AD202612D3843387e94737673F53");
+        assert_eq!(unpacked.factory.as_deref(), Some("0x91E60e0613810449d098b0b5Ec8b51A0FE8c8985"));
+        assert_eq!(unpacked.factory_data.as_deref(), Some("0x5fbfb9cf"));
+        assert_eq!(unpacked.call_gas_limit, "0x88b8");
+        assert_eq!(unpacked.max_priority_fee_per_gas, "0x59682f00");
+        assert_eq!(unpacked.paymaster_data.as_deref(), Some("0x1234"));
+        assert_eq!(unpacked.pack().unwrap(), packed);
+    }
+
+    #[test]
+    fn unpack_without_factory_or_paymaster() {
+        let mut user_op = user_op_v07();
+        user_op.factory = None;
+        user_op.paymaster = None;
+
+        let unpacked = user_op.pack().unwrap().unpack().unwrap();
+        assert!(unpacked.factory.is_none());
+        assert!(unpacked.paymaster.is_none());
+        assert!(unpacked.paymaster_verification_gas_limit.is_none());
+    }
+
+    #[test]
+    fn deserializes_by_entry_point_version() {
+        let v06_json = serde_json::json!({
+            "sender": "0x1306b01bc3e4ad202612d3843387e94737673f53",
+            "nonce": "0x0",
+            "initCode": "0x",
+            "callData": "0x",
+            "callGasLimit": "0x0",
+            "verificationGasLimit": "0x0",
+            "preVerificationGas": "0x0",
+            "maxFeePerGas": "0x0",
+            "maxPriorityFeePerGas": "0x0",
+            "paymasterAndData": "0x",
+            "signature": "0x"
+        });
+        let v06 = VersionedUserOperation::from_value(v06_json.clone(), ENTRY_POINT_V06).unwrap();
+        assert_eq!(v06.version(), EntryPointVersion::V06);
+
+        let v07_json = serde_json::to_value(user_op_v07()).unwrap();
+        let v07 = VersionedUserOperation::from_value(v07_json.clone(), ENTRY_POINT_V07).unwrap();
+        assert_eq!(v07.version(), EntryPointVersion::V07);
+        assert!(v07.has_init_code());
+        assert_eq!(VersionedUserOperation::from_value(v07_json.clone(), ENTRY_POINT_V08).unwrap().version(), EntryPointVersion::V07);
+
+        // A body shaped for the other version is refused, naming the offending field
+        let error = VersionedUserOperation::from_value(v06_json, ENTRY_POINT_V07).unwrap_err();
+        assert!(error.to_string().contains("V07 user operation") && error.to_string().contains("initCode"));
+        let error = VersionedUserOperation::from_value(v07_json, ENTRY_POINT_V06).unwrap_err();
+        assert!(error.to_string().contains("V06 user operation"));
+
+        // Missing v0.6 fields are reported against the v0.6 layout
+        let error = VersionedUserOperation::from_value(serde_json::json!({ "sender": "0x" }), ENTRY_POINT_V06).unwrap_err();
+        assert!(error.to_string().contains("Invalid V06 user operation"));
+    }
+
+    #[test]
+    fn resolves_entry_point_version() {
+        assert_eq!(EntryPointVersion::from_address(&ENTRY_POINT_V06.to_lowercase()).unwrap(), EntryPointVersion::V06);
+        assert_eq!(EntryPointVersion::from_address(ENTRY_POINT_V07).unwrap(), EntryPointVersion::V07);
+        assert!(EntryPointVersion::from_address("0x0000000000000000000000000000000000000001").is_err());
+    }
+}
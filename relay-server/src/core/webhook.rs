@@ -0,0 +1,252 @@
+use crate::core::clock::Clock;
+use crate::core::types::*;
+use crate::core::user_operation::VersionedUserOperation;
+use ethers::utils::keccak256;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+/// Header carrying `sha256=<hex HMAC of the body>` when the webhook has a secret
+pub const SIGNATURE_HEADER: &str = "X-Paymaster-Signature";
+
+/// Wait before the first retry; doubled on each further attempt
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// What a custom policy's webhook decided about an operation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookDecision {
+    pub allow: bool,
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Replaces the policy's own rate limits for this operation
+    #[serde(default)]
+    pub rate_limits: Option<Vec<RateLimit>>,
+    /// Who the rate limits count against, e.g. an end user id; defaults to the sender
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+/// Body POSTed to the webhook
+#[derive(Debug, Serialize)]
+struct WebhookRequest<'a> {
+    policy_id: &'a str,
+    chain_id: u64,
+    entry_point: &'a str,
+    sender: &'a str,
+    user_operation: &'a VersionedUserOperation,
+    /// Unix seconds, so receivers can refuse replayed requests
+    timestamp: u64,
+}
+
+struct CachedDecision {
+    decision: WebhookDecision,
+    expires_at_millis: u64,
+}
+
+/// Asks custom policy webhooks for decisions, caching them per policy and operation
+pub struct WebhookClient {
+    http: reqwest::Client,
+    clock: Arc<dyn Clock>,
+    cache: Mutex<HashMap<String, CachedDecision>>,
+}
+
+impl WebhookClient {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            clock,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Ask the webhook about `request`, retrying failed attempts
+    ///
+    /// Any failure, including an unreadable response, is a `PolicyViolation`.
+    pub async fn decide(
+        &self,
+        policy_id: &str,
+        webhook: &PolicyWebhook,
+        request: &SponsorRequest,
+    ) -> PaymasterResult<WebhookDecision> {
+        let cache_key = cache_key(policy_id, request)?;
+        if let Some(decision) = self.cached(&cache_key) {
+            return Ok(decision);
+        }
+
+        let body = serde_json::to_vec(&WebhookRequest {
+            policy_id,
+            chain_id: request.chain_id,
+            entry_point: &request.entry_point,
+            sender: request.user_operation.sender(),
+            user_operation: &request.user_operation,
+            timestamp: self.clock.now_seconds(),
+        })
+        .map_err(|e| webhook_error(policy_id, e))?;
+        let signature = webhook.secret.as_deref().map(|secret| sign(secret, &body));
+
+        let mut attempt = 0;
+        let decision = loop {
+            match self.send(webhook, &body, signature.as_deref()).await {
+                Ok(decision) => break decision,
+                Err(Failure::Final(e)) => return Err(webhook_error(policy_id, e)),
+                Err(Failure::Retryable(e)) if attempt >= webhook.retries => return Err(webhook_error(policy_id, e)),
+                Err(Failure::Retryable(e)) => {
+                    warn!("Webhook for policy {} failed, retrying: {}", policy_id, e);
+                    tokio::time::sleep(RETRY_DELAY * 2u32.saturating_pow(attempt)).await;
+                    attempt += 1;
+                }
+            }
+        };
+
+        if webhook.cache_seconds > 0 {
+            let expires_at_millis = self.clock.now_millis().saturating_add(webhook.cache_seconds.saturating_mul(1_000));
+            let mut cache = self.cache.lock().expect("webhook cache poisoned");
+            let now = self.clock.now_millis();
+            cache.retain(|_, cached| cached.expires_at_millis > now);
+            cache.insert(cache_key, CachedDecision { decision: decision.clone(), expires_at_millis });
+        }
+        Ok(decision)
+    }
+
+    fn cached(&self, cache_key: &str) -> Option<WebhookDecision> {
+        let cache = self.cache.lock().expect("webhook cache poisoned");
+        cache.get(cache_key)
+            .filter(|cached| cached.expires_at_millis > self.clock.now_millis())
+            .map(|cached| cached.decision.clone())
+    }
+
+    async fn send(&self, webhook: &PolicyWebhook, body: &[u8], signature: Option<&str>) -> Result<WebhookDecision, Failure> {
+        let mut http_request = self.http
+            .post(&webhook.url)
+            .timeout(Duration::from_millis(webhook.timeout_ms))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_vec());
+        if let Some(signature) = signature {
+            http_request = http_request.header(SIGNATURE_HEADER, signature);
+        }
+
+        let response = http_request.send().await.map_err(|e| Failure::Retryable(e.to_string()))?;
+        let status = response.status();
+        if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(Failure::Retryable(format!("status {}", status)));
+        }
+        if !status.is_success() {
+            return Err(Failure::Final(format!("status {}", status)));
+        }
+
+        response.json().await.map_err(|e| Failure::Final(format!("invalid response: {}", e)))
+    }
+}
+
+enum Failure {
+    Retryable(String),
+    Final(String),
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `body`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={}", ethers::utils::hex::encode(mac.finalize().into_bytes()))
+}
+
+/// The same operation under the same policy, whatever the request timestamp
+fn cache_key(policy_id: &str, request: &SponsorRequest) -> PaymasterResult<String> {
+    let operation = serde_json::to_vec(&(request.chain_id, &request.entry_point, &request.user_operation))
+        .map_err(|e| webhook_error(policy_id, e))?;
+    Ok(format!("{}:{}", policy_id, ethers::utils::hex::encode(keccak256(operation))))
+}
+
+fn webhook_error(policy_id: &str, error: impl std::fmt::Display) -> PaymasterError {
+    PaymasterError::PolicyViolation(format!("Webhook for policy {} failed: {}", policy_id, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::clock::ManualClock;
+    use crate::test_support::request;
+    use axum::body::Bytes;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Serve `router` on a local port; returns the webhook URL
+    async fn mock_server(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        format!("http://{}/decide", address)
+    }
+
+    fn webhook(url: String) -> PolicyWebhook {
+        PolicyWebhook {
+            url,
+            secret: Some("shared-secret".to_string()),
+            timeout_ms: 500,
+            retries: 2,
+            cache_seconds: 0,
+            fail_open: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn signs_requests_and_caches_decisions() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let url = mock_server(Router::new().route("/decide", post(move |headers: HeaderMap, body: Bytes| async move {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let signed = headers.get(SIGNATURE_HEADER).and_then(|value| value.to_str().ok()) == Some(&sign("shared-secret", &body));
+            let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            Json(serde_json::json!({ "allow": signed && payload["policy_id"] == "custom", "reason": "checked" }))
+        }))).await;
+
+        let clock = Arc::new(ManualClock::new(1_000));
+        let client = WebhookClient::new(clock.clone());
+        let webhook = PolicyWebhook { cache_seconds: 60, ..webhook(url) };
+
+        assert!(client.decide("custom", &webhook, &request("0x186a0")).await.unwrap().allow);
+        assert!(client.decide("custom", &webhook, &request("0x186a0")).await.unwrap().allow);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        clock.advance_millis(60_000);
+        assert!(client.decide("custom", &webhook, &request("0x186a0")).await.unwrap().allow);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_but_not_client_errors() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let url = mock_server(Router::new().route("/decide", post(move || async move {
+            match counted.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(StatusCode::SERVICE_UNAVAILABLE),
+                _ => Ok(Json(serde_json::json!({ "allow": true }))),
+            }
+        }))).await;
+
+        let client = WebhookClient::new(Arc::new(ManualClock::new(0)));
+        assert!(client.decide("custom", &webhook(url.clone()), &request("0x186a0")).await.unwrap().allow);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let rejecting = mock_server(Router::new().route("/decide", post(|| async { StatusCode::BAD_REQUEST }))).await;
+        assert!(client.decide("custom", &webhook(rejecting), &request("0x186a0")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_webhooks() {
+        let url = mock_server(Router::new().route("/decide", post(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Json(serde_json::json!({ "allow": true }))
+        }))).await;
+
+        let client = WebhookClient::new(Arc::new(ManualClock::new(0)));
+        let webhook = PolicyWebhook { timeout_ms: 50, retries: 0, ..webhook(url) };
+        assert!(client.decide("custom", &webhook, &request("0x186a0")).await.is_err());
+    }
+}
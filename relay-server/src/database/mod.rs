@@ -0,0 +1,7 @@
+pub mod models;
+pub mod policy_sync;
+pub mod repositories;
+
+pub use models::*;
+pub use policy_sync::*;
+pub use repositories::*;
@@ -0,0 +1,303 @@
+use crate::core::types::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::FromRow;
+
+/// A `gas_policies` row
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct GasPolicyRow {
+    pub id: String,
+    pub name: String,
+    pub policy_type: String,
+    pub target: Option<String>,
+    pub enabled: bool,
+    pub priority: i32,
+    pub effect: String,
+    pub inherits: Option<String>,
+    pub amount_multiplier: Option<f64>,
+    pub request_multiplier: Option<f64>,
+    /// Schedule columns; all empty means the policy has no schedule
+    pub starts_at: Option<i64>,
+    pub ends_at: Option<i64>,
+    pub recurring: Vec<String>,
+    pub utc_offset_minutes: i32,
+}
+
+/// A `rate_limits` row; `position` keeps a policy's limits in their configured order
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct RateLimitRow {
+    pub policy_id: String,
+    pub position: i32,
+    pub limit_type: String,
+    pub limit_value: String,
+    pub window_seconds: i64,
+    pub algorithm: String,
+    pub burst: Option<String>,
+}
+
+/// A `call_rules` row; `position` keeps a policy's rules in their configured order
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct CallRuleRow {
+    pub policy_id: String,
+    pub position: i32,
+    pub target: String,
+    pub allowed_selectors: Vec<String>,
+    pub denied_selectors: Vec<String>,
+    pub max_value: Option<String>,
+    pub allowed_recipients: Vec<String>,
+    pub max_transfer_amount: Option<String>,
+}
+
+/// A `policy_webhooks` row
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct WebhookRow {
+    pub policy_id: String,
+    pub url: String,
+    pub secret: Option<String>,
+    pub timeout_ms: i64,
+    pub retries: i32,
+    pub cache_seconds: i64,
+    pub fail_open: bool,
+}
+
+impl GasPolicyRow {
+    pub fn from_policy(policy: &GasPolicy) -> PaymasterResult<Self> {
+        let schedule = policy.schedule.clone().unwrap_or_default();
+        let timestamp = |value: Option<u64>| value
+            .map(|value| i64::try_from(value).map_err(|_| PaymasterError::DatabaseError(format!("Timestamp {} out of range", value))))
+            .transpose();
+
+        Ok(Self {
+            id: policy.id.clone(),
+            name: policy.name.clone(),
+            policy_type: to_text(&policy.policy_type)?,
+            target: policy.target.clone(),
+            enabled: policy.enabled,
+            priority: policy.priority,
+            effect: to_text(&policy.effect)?,
+            inherits: policy.inherits.clone(),
+            amount_multiplier: policy.multipliers.amount,
+            request_multiplier: policy.multipliers.requests,
+            starts_at: timestamp(schedule.starts_at)?,
+            ends_at: timestamp(schedule.ends_at)?,
+            recurring: schedule.recurring,
+            utc_offset_minutes: schedule.utc_offset_minutes,
+        })
+    }
+
+    /// Assemble the policy from this row, its rate limit and call rule rows (in position order) and its webhook
+    pub fn into_policy(
+        self,
+        rate_limits: Vec<RateLimitRow>,
+        call_rules: Vec<CallRuleRow>,
+        webhook: Option<WebhookRow>,
+    ) -> PaymasterResult<GasPolicy> {
+        let timestamp = |value: Option<i64>| value
+            .map(|value| u64::try_from(value).map_err(|_| PaymasterError::DatabaseError(format!("Negative timestamp {}", value))))
+            .transpose();
+        let schedule = PolicySchedule {
+            starts_at: timestamp(self.starts_at)?,
+            ends_at: timestamp(self.ends_at)?,
+            recurring: self.recurring,
+            utc_offset_minutes: self.utc_offset_minutes,
+        };
+
+        Ok(GasPolicy {
+            policy_type: from_text("policy_type", &self.policy_type)?,
+            rate_limits: rate_limits.into_iter()
+                .map(RateLimitRow::into_rate_limit)
+                .collect::<PaymasterResult<_>>()?,
+            call_rules: call_rules.into_iter().map(CallRuleRow::into_call_rule).collect(),
+            webhook: webhook.map(WebhookRow::into_webhook).transpose()?,
+            effect: from_text("effect", &self.effect)?,
+            multipliers: LimitMultipliers {
+                amount: self.amount_multiplier,
+                requests: self.request_multiplier,
+            },
+            schedule: (schedule != PolicySchedule::default()).then_some(schedule),
+            id: self.id,
+            name: self.name,
+            target: self.target,
+            enabled: self.enabled,
+            priority: self.priority,
+            inherits: self.inherits,
+        })
+    }
+}
+
+impl RateLimitRow {
+    pub fn from_rate_limit(policy_id: &str, position: usize, rate_limit: &RateLimit) -> PaymasterResult<Self> {
+        Ok(Self {
+            policy_id: policy_id.to_string(),
+            position: i32::try_from(position)
+                .map_err(|_| PaymasterError::DatabaseError("Too many rate limits".to_string()))?,
+            limit_type: to_text(&rate_limit.limit_type)?,
+            limit_value: rate_limit.limit.clone(),
+            window_seconds: i64::try_from(rate_limit.window)
+                .map_err(|_| PaymasterError::DatabaseError(format!("Window {} out of range", rate_limit.window)))?,
+            algorithm: to_text(&rate_limit.algorithm)?,
+            burst: rate_limit.burst.clone(),
+        })
+    }
+
+    pub fn into_rate_limit(self) -> PaymasterResult<RateLimit> {
+        Ok(RateLimit {
+            limit_type: from_text("limit_type", &self.limit_type)?,
+            limit: self.limit_value,
+            window: u64::try_from(self.window_seconds)
+                .map_err(|_| PaymasterError::DatabaseError(format!("Negative window {}", self.window_seconds)))?,
+            algorithm: from_text("algorithm", &self.algorithm)?,
+            burst: self.burst,
+        })
+    }
+}
+
+impl CallRuleRow {
+    pub fn from_call_rule(policy_id: &str, position: usize, rule: &CallRule) -> PaymasterResult<Self> {
+        Ok(Self {
+            policy_id: policy_id.to_string(),
+            position: i32::try_from(position)
+                .map_err(|_| PaymasterError::DatabaseError("Too many call rules".to_string()))?,
+            target: rule.target.clone(),
+            allowed_selectors: rule.allowed_selectors.clone(),
+            denied_selectors: rule.denied_selectors.clone(),
+            max_value: rule.max_value.clone(),
+            allowed_recipients: rule.allowed_recipients.clone(),
+            max_transfer_amount: rule.max_transfer_amount.clone(),
+        })
+    }
+
+    pub fn into_call_rule(self) -> CallRule {
+        CallRule {
+            target: self.target,
+            allowed_selectors: self.allowed_selectors,
+            denied_selectors: self.denied_selectors,
+            max_value: self.max_value,
+            allowed_recipients: self.allowed_recipients,
+            max_transfer_amount: self.max_transfer_amount,
+        }
+    }
+}
+
+impl WebhookRow {
+    pub fn from_webhook(policy_id: &str, webhook: &PolicyWebhook) -> PaymasterResult<Self> {
+        let out_of_range = |field: &str| PaymasterError::DatabaseError(format!("Webhook {} out of range", field));
+        Ok(Self {
+            policy_id: policy_id.to_string(),
+            url: webhook.url.clone(),
+            secret: webhook.secret.clone(),
+            timeout_ms: i64::try_from(webhook.timeout_ms).map_err(|_| out_of_range("timeout_ms"))?,
+            retries: i32::try_from(webhook.retries).map_err(|_| out_of_range("retries"))?,
+            cache_seconds: i64::try_from(webhook.cache_seconds).map_err(|_| out_of_range("cache_seconds"))?,
+            fail_open: webhook.fail_open,
+        })
+    }
+
+    pub fn into_webhook(self) -> PaymasterResult<PolicyWebhook> {
+        let negative = |field: &str| PaymasterError::DatabaseError(format!("Negative webhook {}", field));
+        Ok(PolicyWebhook {
+            url: self.url,
+            secret: self.secret,
+            timeout_ms: u64::try_from(self.timeout_ms).map_err(|_| negative("timeout_ms"))?,
+            retries: u32::try_from(self.retries).map_err(|_| negative("retries"))?,
+            cache_seconds: u64::try_from(self.cache_seconds).map_err(|_| negative("cache_seconds"))?,
+            fail_open: self.fail_open,
+        })
+    }
+}
+
+/// Store an enum by its serde name, the same name policies use in JSON
+fn to_text<T: Serialize>(value: &T) -> PaymasterResult<String> {
+    match serde_json::to_value(value) {
+        Ok(Value::String(text)) => Ok(text),
+        _ => Err(PaymasterError::DatabaseError("Value has no text form".to_string())),
+    }
+}
+
+fn from_text<T: DeserializeOwned>(column: &str, text: &str) -> PaymasterResult<T> {
+    serde_json::from_value(Value::String(text.to_string()))
+        .map_err(|_| PaymasterError::DatabaseError(format!("Invalid {} {:?}", column, text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_round_trip_through_rows() {
+        let policy = GasPolicy {
+            id: "wallet_budget".to_string(),
+            name: "Wallet budget".to_string(),
+            policy_type: PolicyType::Wallet,
+            target: Some("0x1306b01bc3e4ad202612d3843387e94737673f53".to_string()),
+            rate_limits: vec![RateLimit {
+                limit_type: RateLimitType::Request,
+                limit: "10".to_string(),
+                window: 60,
+                algorithm: RateLimitAlgorithm::TokenBucket,
+                burst: Some("5".to_string()),
+            }],
+            call_rules: vec![CallRule {
+                target: "0x2222222222222222222222222222222222222222".to_string(),
+                allowed_selectors: vec!["mint(address,uint256)".to_string()],
+                max_transfer_amount: Some("1000".to_string()),
+                ..CallRule::default()
+            }],
+            webhook: Some(PolicyWebhook {
+                url: "https://policies.example.com/decide".to_string(),
+                secret: Some("shared-secret".to_string()),
+                timeout_ms: 1_000,
+                retries: 1,
+                cache_seconds: 30,
+                fail_open: true,
+            }),
+            priority: 20,
+            effect: PolicyEffect::Allow,
+            inherits: Some("project_budget".to_string()),
+            multipliers: LimitMultipliers { amount: Some(5.0), requests: None },
+            schedule: Some(PolicySchedule {
+                starts_at: Some(1_704_067_200),
+                ends_at: None,
+                recurring: vec!["* 9-16 * * 1-5".to_string()],
+                utc_offset_minutes: 480,
+            }),
+            enabled: true,
+        };
+
+        let row = GasPolicyRow::from_policy(&policy).unwrap();
+        assert_eq!(row.policy_type, "Wallet");
+        let limit = RateLimitRow::from_rate_limit(&policy.id, 0, &policy.rate_limits[0]).unwrap();
+        assert_eq!(limit.algorithm, "TokenBucket");
+
+        let rule = CallRuleRow::from_call_rule(&policy.id, 0, &policy.call_rules[0]).unwrap();
+
+        let webhook = WebhookRow::from_webhook(&policy.id, policy.webhook.as_ref().unwrap()).unwrap();
+
+        let loaded = row.into_policy(vec![limit], vec![rule], Some(webhook)).unwrap();
+        // The secret is not serialized, so compare it separately
+        assert_eq!(loaded.webhook.as_ref().unwrap().secret, policy.webhook.as_ref().unwrap().secret);
+        assert_eq!(serde_json::to_value(loaded).unwrap(), serde_json::to_value(policy).unwrap());
+    }
+
+    #[test]
+    fn rejects_unknown_enum_names() {
+        let row = GasPolicyRow {
+            id: "p".to_string(),
+            name: "p".to_string(),
+            policy_type: "Galaxy".to_string(),
+            target: None,
+            enabled: true,
+            priority: 0,
+            effect: "Limit".to_string(),
+            inherits: None,
+            amount_multiplier: None,
+            request_multiplier: None,
+            starts_at: None,
+            ends_at: None,
+            recurring: Vec::new(),
+            utc_offset_minutes: 0,
+        };
+        assert!(matches!(row.into_policy(Vec::new(), Vec::new(), None), Err(PaymasterError::DatabaseError(_))));
+    }
+}
@@ -0,0 +1,107 @@
+use crate::core::policy_engine::{PolicyEngine, PolicyEvent, PolicySource};
+use crate::core::types::*;
+use crate::database::repositories::*;
+use sqlx::postgres::PgListener;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Wait before listening again after the notification connection failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Keeps a `PolicyEngine` in step with the policies stored in Postgres
+///
+/// Every policy is reloaded whenever the listener (re)connects, since notifications sent
+/// while it was disconnected are lost; after that each notification reloads one policy.
+#[derive(Clone)]
+pub struct PolicySync {
+    repository: PolicyRepository,
+    policy_engine: Arc<PolicyEngine>,
+}
+
+impl PolicySync {
+    pub fn new(repository: PolicyRepository, policy_engine: Arc<PolicyEngine>) -> Self {
+        Self { repository, policy_engine }
+    }
+
+    /// Replace the engine's policies with every stored policy
+    pub async fn load(&self) -> PaymasterResult<usize> {
+        let policies = self.repository.list().await?;
+        let count = policies.len();
+        self.policy_engine.replace_policies(PolicySource::Database, policies).await;
+
+        info!("Loaded {} policies from the database", count);
+        Ok(count)
+    }
+
+    /// Apply policy changes made by any relay instance until the process exits
+    pub async fn run(self) {
+        loop {
+            if let Err(e) = self.listen().await {
+                warn!("Policy change listener failed: {}", e);
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn listen(&self) -> PaymasterResult<()> {
+        let mut listener = PgListener::connect_with(self.repository.pool()).await.map_err(database_error)?;
+        listener.listen(POLICY_CHANGED_CHANNEL).await.map_err(database_error)?;
+        // Catch up on changes made before the LISTEN took effect
+        self.load().await?;
+
+        loop {
+            match listener.try_recv().await.map_err(database_error)? {
+                Some(notification) => self.reload(notification.payload()).await?,
+                None => {
+                    return Err(PaymasterError::DatabaseError(
+                        "Policy change listener disconnected".to_string()
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Store stored policies the engine disabled when their schedule ended, so reloads keep them disabled
+    pub async fn persist_expirations(self, mut events: broadcast::Receiver<PolicyEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(PolicyEvent::Expired { policy_id, source: PolicySource::Database, .. }) => {
+                    if let Err(e) = self.disable(&policy_id).await {
+                        warn!("Failed to store expiry of policy {}: {}", policy_id, e);
+                    }
+                }
+                Ok(PolicyEvent::Expired { .. }) => {}
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Missed {} policy events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+
+    async fn disable(&self, policy_id: &str) -> PaymasterResult<()> {
+        let Some(mut policy) = self.repository.get(policy_id).await?.filter(|policy| policy.enabled) else {
+            return Ok(());
+        };
+
+        policy.enabled = false;
+        self.repository.upsert(&policy).await
+    }
+
+    /// Reload one policy, dropping it from the engine if it was deleted
+    async fn reload(&self, policy_id: &str) -> PaymasterResult<()> {
+        match self.repository.get(policy_id).await? {
+            Some(policy) => {
+                if let Err(e) = self.policy_engine.load_policy(PolicySource::Database, policy).await {
+                    warn!("Ignoring stored policy {}: {}", policy_id, e);
+                }
+            }
+            None => {
+                self.policy_engine.unload_policy(PolicySource::Database, policy_id).await;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,366 @@
+use crate::config::settings::DatabaseSettings;
+use crate::core::types::*;
+use crate::database::models::*;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use std::collections::HashMap;
+use tracing::info;
+
+/// Channel the `gas_policies` triggers notify with the id of a changed policy
+pub const POLICY_CHANGED_CHANNEL: &str = "gas_policy_changed";
+
+const SELECT_POLICIES: &str =
+    "SELECT id, name, policy_type, target, enabled, priority, effect, inherits, amount_multiplier, request_multiplier,
+            starts_at, ends_at, recurring, utc_offset_minutes
+     FROM gas_policies";
+
+const SELECT_CALL_RULES: &str =
+    "SELECT policy_id, position, target, allowed_selectors, denied_selectors, max_value, allowed_recipients, max_transfer_amount
+     FROM call_rules";
+
+const SELECT_WEBHOOKS: &str =
+    "SELECT policy_id, url, secret, timeout_ms, retries, cache_seconds, fail_open FROM policy_webhooks";
+
+/// Postgres storage for gas policies, their rate limits, call rules and webhooks
+#[derive(Clone)]
+pub struct PolicyRepository {
+    pool: PgPool,
+}
+
+impl PolicyRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Connect to the configured database
+    pub async fn connect(settings: &DatabaseSettings) -> PaymasterResult<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(settings.max_connections)
+            .connect(&settings.url)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(format!("Postgres connection failed: {}", e)))?;
+
+        Ok(Self::new(pool))
+    }
+
+    pub fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    /// Apply pending migrations from `migrations/`
+    pub async fn migrate(&self) -> PaymasterResult<()> {
+        sqlx::migrate!("./migrations")
+            .run(&self.pool)
+            .await
+            .map_err(|e| PaymasterError::DatabaseError(format!("Migration failed: {}", e)))?;
+
+        info!("Database migrations applied");
+        Ok(())
+    }
+
+    /// Load every stored policy
+    pub async fn list(&self) -> PaymasterResult<Vec<GasPolicy>> {
+        let rows: Vec<GasPolicyRow> = sqlx::query_as(
+            &format!("{} ORDER BY id", SELECT_POLICIES)
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        let limits: Vec<RateLimitRow> = sqlx::query_as(
+            "SELECT policy_id, position, limit_type, limit_value, window_seconds, algorithm, burst
+             FROM rate_limits ORDER BY policy_id, position"
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        let rules: Vec<CallRuleRow> = sqlx::query_as(&format!("{} ORDER BY policy_id, position", SELECT_CALL_RULES))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        let webhooks: Vec<WebhookRow> = sqlx::query_as(SELECT_WEBHOOKS)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        let mut limits_by_policy: HashMap<String, Vec<RateLimitRow>> = HashMap::new();
+        for limit in limits {
+            limits_by_policy.entry(limit.policy_id.clone()).or_default().push(limit);
+        }
+        let mut rules_by_policy: HashMap<String, Vec<CallRuleRow>> = HashMap::new();
+        for rule in rules {
+            rules_by_policy.entry(rule.policy_id.clone()).or_default().push(rule);
+        }
+        let mut webhooks_by_policy: HashMap<String, WebhookRow> = webhooks.into_iter()
+            .map(|webhook| (webhook.policy_id.clone(), webhook))
+            .collect();
+
+        rows.into_iter()
+            .map(|row| {
+                let limits = limits_by_policy.remove(&row.id).unwrap_or_default();
+                let rules = rules_by_policy.remove(&row.id).unwrap_or_default();
+                let webhook = webhooks_by_policy.remove(&row.id);
+                row.into_policy(limits, rules, webhook)
+            })
+            .collect()
+    }
+
+    /// Load one policy by id
+    pub async fn get(&self, policy_id: &str) -> PaymasterResult<Option<GasPolicy>> {
+        let row: Option<GasPolicyRow> = sqlx::query_as(
+            &format!("{} WHERE id = $1", SELECT_POLICIES)
+        )
+        .bind(policy_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let limits: Vec<RateLimitRow> = sqlx::query_as(
+            "SELECT policy_id, position, limit_type, limit_value, window_seconds, algorithm, burst
+             FROM rate_limits WHERE policy_id = $1 ORDER BY position"
+        )
+        .bind(policy_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        let rules: Vec<CallRuleRow> = sqlx::query_as(&format!("{} WHERE policy_id = $1 ORDER BY position", SELECT_CALL_RULES))
+            .bind(policy_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        let webhook: Option<WebhookRow> = sqlx::query_as(&format!("{} WHERE policy_id = $1", SELECT_WEBHOOKS))
+            .bind(policy_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        row.into_policy(limits, rules, webhook).map(Some)
+    }
+
+    /// Insert or replace a policy, its rate limits, call rules and webhook in one transaction
+    ///
+    /// Every relay listening on `POLICY_CHANGED_CHANNEL` reloads the policy once this commits.
+    pub async fn upsert(&self, policy: &GasPolicy) -> PaymasterResult<()> {
+        let row = GasPolicyRow::from_policy(policy)?;
+        let limits = policy.rate_limits.iter()
+            .enumerate()
+            .map(|(position, rate_limit)| RateLimitRow::from_rate_limit(&policy.id, position, rate_limit))
+            .collect::<PaymasterResult<Vec<_>>>()?;
+        let rules = policy.call_rules.iter()
+            .enumerate()
+            .map(|(position, rule)| CallRuleRow::from_call_rule(&policy.id, position, rule))
+            .collect::<PaymasterResult<Vec<_>>>()?;
+        let webhook = policy.webhook.as_ref()
+            .map(|webhook| WebhookRow::from_webhook(&policy.id, webhook))
+            .transpose()?;
+
+        let mut tx = self.pool.begin().await.map_err(database_error)?;
+
+        sqlx::query(
+            "INSERT INTO gas_policies (id, name, policy_type, target, enabled, priority, effect, inherits,
+                                       amount_multiplier, request_multiplier, starts_at, ends_at, recurring,
+                                       utc_offset_minutes)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+             ON CONFLICT (id) DO UPDATE SET
+                 name = EXCLUDED.name,
+                 policy_type = EXCLUDED.policy_type,
+                 target = EXCLUDED.target,
+                 enabled = EXCLUDED.enabled,
+                 priority = EXCLUDED.priority,
+                 effect = EXCLUDED.effect,
+                 inherits = EXCLUDED.inherits,
+                 amount_multiplier = EXCLUDED.amount_multiplier,
+                 request_multiplier = EXCLUDED.request_multiplier,
+                 starts_at = EXCLUDED.starts_at,
+                 ends_at = EXCLUDED.ends_at,
+                 recurring = EXCLUDED.recurring,
+                 utc_offset_minutes = EXCLUDED.utc_offset_minutes,
+                 updated_at = NOW()"
+        )
+        .bind(&row.id)
+        .bind(&row.name)
+        .bind(&row.policy_type)
+        .bind(&row.target)
+        .bind(row.enabled)
+        .bind(row.priority)
+        .bind(&row.effect)
+        .bind(&row.inherits)
+        .bind(row.amount_multiplier)
+        .bind(row.request_multiplier)
+        .bind(row.starts_at)
+        .bind(row.ends_at)
+        .bind(&row.recurring)
+        .bind(row.utc_offset_minutes)
+        .execute(&mut *tx)
+        .await
+        .map_err(database_error)?;
+
+        sqlx::query("DELETE FROM rate_limits WHERE policy_id = $1")
+            .bind(&row.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+
+        for limit in &limits {
+            sqlx::query(
+                "INSERT INTO rate_limits (policy_id, position, limit_type, limit_value, window_seconds, algorithm, burst)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)"
+            )
+            .bind(&limit.policy_id)
+            .bind(limit.position)
+            .bind(&limit.limit_type)
+            .bind(&limit.limit_value)
+            .bind(limit.window_seconds)
+            .bind(&limit.algorithm)
+            .bind(&limit.burst)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+        }
+
+        sqlx::query("DELETE FROM call_rules WHERE policy_id = $1")
+            .bind(&row.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+
+        for rule in &rules {
+            sqlx::query(
+                "INSERT INTO call_rules (policy_id, position, target, allowed_selectors, denied_selectors,
+                                         max_value, allowed_recipients, max_transfer_amount)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"
+            )
+            .bind(&rule.policy_id)
+            .bind(rule.position)
+            .bind(&rule.target)
+            .bind(&rule.allowed_selectors)
+            .bind(&rule.denied_selectors)
+            .bind(&rule.max_value)
+            .bind(&rule.allowed_recipients)
+            .bind(&rule.max_transfer_amount)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+        }
+
+        sqlx::query("DELETE FROM policy_webhooks WHERE policy_id = $1")
+            .bind(&row.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+
+        if let Some(webhook) = &webhook {
+            sqlx::query(
+                "INSERT INTO policy_webhooks (policy_id, url, secret, timeout_ms, retries, cache_seconds, fail_open)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)"
+            )
+            .bind(&webhook.policy_id)
+            .bind(&webhook.url)
+            .bind(&webhook.secret)
+            .bind(webhook.timeout_ms)
+            .bind(webhook.retries)
+            .bind(webhook.cache_seconds)
+            .bind(webhook.fail_open)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+        }
+
+        tx.commit().await.map_err(database_error)?;
+
+        info!("Stored policy: {}", policy.id);
+        Ok(())
+    }
+
+    /// Delete a policy; returns false when it did not exist
+    pub async fn delete(&self, policy_id: &str) -> PaymasterResult<bool> {
+        let result = sqlx::query("DELETE FROM gas_policies WHERE id = $1")
+            .bind(policy_id)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+pub(crate) fn database_error(error: sqlx::Error) -> PaymasterError {
+    PaymasterError::DatabaseError(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use sqlx::postgres::PgListener;
+
+    #[tokio::test]
+    #[ignore = "requires a Postgres server at DATABASE_URL or the default database.url"]
+    async fn stores_policies_and_notifies_changes() {
+        let mut settings = Settings::default().database;
+        if let Ok(url) = std::env::var("DATABASE_URL") {
+            settings.url = url;
+        }
+        let repository = PolicyRepository::connect(&settings).await.unwrap();
+        repository.migrate().await.unwrap();
+
+        let mut listener = PgListener::connect_with(repository.pool()).await.unwrap();
+        listener.listen(POLICY_CHANGED_CHANNEL).await.unwrap();
+
+        let policy = GasPolicy {
+            id: format!("policy_{}", uuid::Uuid::new_v4()),
+            name: "Project budget".to_string(),
+            policy_type: PolicyType::Project,
+            target: None,
+            rate_limits: vec![RateLimit {
+                limit_type: RateLimitType::Request,
+                limit: "10".to_string(),
+                window: 60,
+                algorithm: RateLimitAlgorithm::SlidingWindow,
+                burst: None,
+            }],
+            call_rules: vec![CallRule {
+                target: "0x2222222222222222222222222222222222222222".to_string(),
+                allowed_selectors: vec!["mint(address,uint256)".to_string()],
+                ..CallRule::default()
+            }],
+            webhook: Some(PolicyWebhook {
+                url: "https://policies.example.com/decide".to_string(),
+                secret: None,
+                timeout_ms: 1_000,
+                retries: 2,
+                cache_seconds: 0,
+                fail_open: false,
+            }),
+            priority: 10,
+            effect: PolicyEffect::Allow,
+            inherits: Some("base_budget".to_string()),
+            multipliers: LimitMultipliers { amount: None, requests: Some(2.0) },
+            schedule: Some(PolicySchedule {
+                starts_at: Some(1_704_067_200),
+                ends_at: Some(1_706_745_600),
+                recurring: vec!["0 9 * * 1-5".to_string()],
+                utc_offset_minutes: -300,
+            }),
+            enabled: true,
+        };
+        repository.upsert(&policy).await.unwrap();
+        assert_eq!(listener.recv().await.unwrap().payload(), policy.id);
+
+        let stored = repository.get(&policy.id).await.unwrap().unwrap();
+        assert_eq!(stored.webhook.as_ref().unwrap().secret, policy.webhook.as_ref().unwrap().secret);
+        assert_eq!(serde_json::to_value(&stored).unwrap(), serde_json::to_value(&policy).unwrap());
+        assert!(repository.list().await.unwrap().iter().any(|p| p.id == policy.id));
+
+        assert!(repository.delete(&policy.id).await.unwrap());
+        assert_eq!(listener.recv().await.unwrap().payload(), policy.id);
+        assert!(repository.get(&policy.id).await.unwrap().is_none());
+    }
+}
@@ -1,9 +1,9 @@
 pub mod api;
-pub mod blockchain;
 pub mod config;
 pub mod core;
 pub mod database;
-pub mod utils;
+#[cfg(test)]
+pub mod test_support;
 
 pub use config::Settings;
 pub use core::*;
@@ -1,3 +1,91 @@
-fn main() {
-    println!("Hello, world!");
+use anode_paymaster_relay::api::{router, ApiState};
+use anode_paymaster_relay::config::policies::PolicyFileWatcher;
+use anode_paymaster_relay::database::{PolicyRepository, PolicySync};
+use anode_paymaster_relay::{PolicySource, ScheduleWatcher, Settings, SettlementWatcher};
+use anyhow::Context;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::signal;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().ok();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    let settings = Settings::new().context("Invalid configuration")?;
+    let address = format!("{}:{}", settings.server.host, settings.server.port);
+
+    let repository = PolicyRepository::connect(&settings.database).await.context("Failed to connect to the database")?;
+    repository.migrate().await.context("Failed to migrate the database")?;
+
+    let state = ApiState::new(settings.clone())
+        .await
+        .context("Failed to initialize services")?
+        .with_policy_repository(repository.clone());
+
+    state.policy_engine.replace_policies(PolicySource::File, settings.policies.clone()).await;
+    let policy_files = PolicyFileWatcher::new(
+        &settings.policy.directory,
+        state.policy_engine.clone(),
+        Duration::from_secs(settings.policy.reload_seconds.max(1)),
+    );
+    tokio::spawn(policy_files.run());
+
+    let policy_sync = PolicySync::new(repository, state.policy_engine.clone());
+    policy_sync.load().await.context("Failed to load policies")?;
+    tokio::spawn(policy_sync.clone().persist_expirations(state.policy_engine.subscribe()));
+    tokio::spawn(policy_sync.run());
+
+    let schedules = ScheduleWatcher::new(
+        state.policy_engine.clone(),
+        Duration::from_secs(settings.policy.schedule_poll_seconds.max(1)),
+    );
+    tokio::spawn(schedules.run());
+
+    let settlement_interval = Duration::from_secs(settings.policy.settlement_poll_seconds.max(1));
+    for chain in state.chains.chains() {
+        let settlement = SettlementWatcher::new(chain, state.policy_engine.clone(), settlement_interval);
+        tokio::spawn(settlement.run());
+    }
+    let listener = TcpListener::bind(&address)
+        .await
+        .with_context(|| format!("Failed to bind {}", address))?;
+    info!("Paymaster relay listening on {}", address);
+
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .context("Server error")?;
+
+    info!("Paymaster relay stopped");
+    Ok(())
+}
+
+/// Resolve on Ctrl+C or SIGTERM; in-flight requests are drained before `serve` returns
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("Shutdown signal received, draining in-flight requests");
 }
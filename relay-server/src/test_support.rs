@@ -0,0 +1,105 @@
+//! Fixtures shared by the unit tests
+
+use crate::api::ApiState;
+use crate::config::Settings;
+use crate::core::*;
+use std::sync::Arc;
+
+/// Sender of the operation `request` builds
+pub const SENDER: &str = "0x1306b01bc3e4ad202612d3843387e94737673f53";
+
+/// Paymaster address `settings` configures
+pub const PAYMASTER: &str = "0x9d7f74d0c41e726ec95884e0e97fa6129e3b5e99";
+
+/// Default settings with a paymaster signer (Hardhat account #0)
+pub fn settings() -> Settings {
+    let mut settings = Settings::default();
+    settings.paymaster.private_key = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80".to_string();
+    settings.paymaster.address = PAYMASTER.to_string();
+    settings
+}
+
+/// API state over `settings` whose policy engine keeps its counters in memory rather than Redis
+pub async fn api_state(settings: Settings) -> ApiState {
+    let policy_engine = PolicyEngine::with_store(Arc::new(MemoryCounterStore::new()), Arc::new(SystemClock), &settings.policy);
+    ApiState::with_policy_engine(settings, Arc::new(policy_engine)).await.unwrap()
+}
+
+/// Policy engine counting in memory on `clock`
+pub fn memory_engine_at(clock: Arc<ManualClock>) -> PolicyEngine {
+    PolicyEngine::with_store(Arc::new(MemoryCounterStore::with_clock(clock.clone())), clock, &Settings::default().policy)
+}
+
+/// Policy engine counting in memory, with time standing still at 0
+pub fn memory_engine() -> PolicyEngine {
+    memory_engine_at(Arc::new(ManualClock::new(0)))
+}
+
+/// A v0.6 sponsorship request on chain 1 from `SENDER`, with no call data
+pub fn request(call_gas_limit: &str) -> SponsorRequest {
+    SponsorRequest {
+        user_operation: VersionedUserOperation::V06(UserOperation {
+            sender: SENDER.to_string(),
+            nonce: "0x0".to_string(),
+            init_code: "0x".to_string(),
+            call_data: "0x".to_string(),
+            call_gas_limit: call_gas_limit.to_string(),
+            verification_gas_limit: "0x186a0".to_string(),
+            pre_verification_gas: "0x5208".to_string(),
+            max_fee_per_gas: "0x3b9aca00".to_string(),
+            max_priority_fee_per_gas: "0x3b9aca00".to_string(),
+            paymaster_and_data: "0x".to_string(),
+            signature: "0x".to_string(),
+        }),
+        entry_point: ENTRY_POINT_V06.to_string(),
+        chain_id: 1,
+        validity_seconds: None,
+        sponsorship_policy_id: None,
+    }
+}
+
+/// An enabled policy with no limits or rules
+fn policy(id: &str, name: &str, policy_type: PolicyType, target: Option<String>) -> GasPolicy {
+    GasPolicy {
+        id: id.to_string(),
+        name: name.to_string(),
+        policy_type,
+        target,
+        rate_limits: vec![],
+        call_rules: vec![],
+        webhook: None,
+        priority: 0,
+        effect: PolicyEffect::Limit,
+        inherits: None,
+        multipliers: LimitMultipliers::default(),
+        schedule: None,
+        enabled: true,
+    }
+}
+
+/// "wallet_budget": one hourly fixed-window limit on `SENDER`
+pub fn wallet_policy(limit_type: RateLimitType, limit: &str) -> GasPolicy {
+    GasPolicy {
+        rate_limits: vec![RateLimit {
+            limit_type,
+            limit: limit.to_string(),
+            window: 3600,
+            algorithm: RateLimitAlgorithm::FixedWindow,
+            burst: None,
+        }],
+        ..policy("wallet_budget", "Wallet budget", PolicyType::Wallet, Some(SENDER.to_string()))
+    }
+}
+
+/// "contract_budget": matches calls to `target`, with no limits
+pub fn contract_policy(target: &str) -> GasPolicy {
+    policy("contract_budget", "Contract budget", PolicyType::Contract, Some(target.to_string()))
+}
+
+/// "custom": decided by the webhook at `url`, which is given 500ms and no retries
+pub fn custom_policy(url: String, fail_open: bool) -> GasPolicy {
+    GasPolicy {
+        webhook: Some(PolicyWebhook { url, secret: None, timeout_ms: 500, retries: 0, cache_seconds: 0, fail_open }),
+        ..policy("custom", "Custom", PolicyType::Custom, None)
+    }
+}